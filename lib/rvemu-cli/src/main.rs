@@ -1,15 +1,86 @@
 use clap::{App, Arg};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::io::BufWriter;
 use std::iter::FromIterator;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::process;
 
 use rvemu_core::bus::DRAM_BASE;
 use rvemu_core::cpu::Cpu;
-use rvemu_core::emulator::Emulator;
+use rvemu_core::devices::clint::TimerMode;
+use rvemu_core::elf::{Elf, ElfError};
+use rvemu_core::emulator::{Config, Emulator, ExitReason};
+use rvemu_core::gdb::{GdbStub, SessionEnd};
+
+/// A logger which outputs messages from the emulator to stderr, so that they don't mix with the
+/// output of the guest via UART.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// The maximum number of checkpoints kept for reverse execution.
+const MAX_CHECKPOINTS: usize = 100;
+
+/// Parse the size of DRAM in MiB, and return it in bytes.
+fn parse_memory(mib: &str) -> Result<u64, String> {
+    let mib: u64 = mib.parse().map_err(|e| format!("{}", e))?;
+    if mib == 0 {
+        return Err(String::from("the size must be greater than 0"));
+    }
+    mib.checked_mul(1024 * 1024)
+        .ok_or_else(|| String::from("the size is too large"))
+}
+
+/// Parse the mode of the timer, which is `wall-clock` or the ticks per instruction.
+fn parse_timer(timer: &str) -> Result<TimerMode, String> {
+    if timer == "wall-clock" {
+        return Ok(TimerMode::WallClock);
+    }
+    let ticks: u64 = timer.parse().map_err(|e| format!("{}", e))?;
+    // The timer never reaches a deadline if it doesn't advance.
+    if ticks == 0 {
+        return Err(String::from("the ticks must be greater than 0"));
+    }
+    Ok(TimerMode::Deterministic {
+        ticks_per_instruction: ticks,
+    })
+}
+
+/// Convert an exit code of the guest to the exit status of the process. Only the low 8 bits of
+/// the status are seen on Unix, so a failure without any of them set exits with 1.
+fn exit_status(code: u64) -> i32 {
+    match code & 0xff {
+        0 if code != 0 => 1,
+        status => status as i32,
+    }
+}
 
 /// Output current registers to the console.
-fn dump_registers(cpu: &Cpu) {
+fn dump_registers(emu: &mut Emulator) {
+    let inst = emu
+        .disassemble_pc()
+        .unwrap_or_else(|| String::from("(unavailable)"));
+    let cpu = &emu.cpu;
     println!("-------------------------------------------------------------------------------------------");
     println!("{}", cpu.xregs);
     println!("-------------------------------------------------------------------------------------------");
@@ -17,7 +88,7 @@ fn dump_registers(cpu: &Cpu) {
     println!("-------------------------------------------------------------------------------------------");
     println!("{}", cpu.state);
     println!("-------------------------------------------------------------------------------------------");
-    println!("pc: {:#x}", cpu.pc);
+    println!("pc: {}, inst: {}", emu.symbols.symbolize(cpu.pc), inst);
 }
 
 /// Output the count of each instruction executed.
@@ -33,6 +104,39 @@ fn dump_count(cpu: &Cpu) {
     }
 }
 
+/// Wait for GDB to connect to `address`, which is a TCP address such as `localhost:1234` or a path
+/// of a Unix socket, and serve it until the debug session ends.
+fn serve_gdb(emu: &mut Emulator, address: &str) -> io::Result<SessionEnd> {
+    eprintln!("Waiting for GDB to connect to {}", address);
+    if address.contains(':') {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        // Packets are small and each of them waits for a response.
+        stream.set_nodelay(true)?;
+        GdbStub::new(stream).serve(emu)
+    } else {
+        serve_gdb_unix(emu, address)
+    }
+}
+
+/// Wait for GDB to connect to a Unix socket at `path`, and serve it.
+#[cfg(unix)]
+fn serve_gdb_unix(emu: &mut Emulator, path: &str) -> io::Result<SessionEnd> {
+    let listener = UnixListener::bind(path)?;
+    let result = listener
+        .accept()
+        .and_then(|(stream, _)| GdbStub::new(stream).serve(emu));
+    fs::remove_file(path)?;
+    result
+}
+
+#[cfg(not(unix))]
+fn serve_gdb_unix(_emu: &mut Emulator, _path: &str) -> io::Result<SessionEnd> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Unix sockets are not supported on this platform",
+    ))
+}
+
 /// Main function of RISC-V emulator for the CLI version.
 fn main() -> io::Result<()> {
     let matches = App::new("rvemu: RISC-V emulator")
@@ -43,8 +147,29 @@ fn main() -> io::Result<()> {
                 .short("k")
                 .long("kernel")
                 .takes_value(true)
-                .required(true)
-                .help("A kernel ELF image without headers"),
+                .required_unless("bios")
+                .help("A kernel ELF image or a raw binary loaded at the beginning of DRAM, or at 0x80200000 with --bios"),
+        )
+        .arg(
+            Arg::with_name("bios")
+                .short("b")
+                .long("bios")
+                .takes_value(true)
+                .help("A firmware ELF image or a raw binary (e.g. OpenSBI fw_jump or fw_dynamic) loaded at the beginning of DRAM"),
+        )
+        .arg(
+            Arg::with_name("initrd")
+                .short("i")
+                .long("initrd")
+                .takes_value(true)
+                .help("An initial ramdisk (initrd or initramfs) passed to a Linux kernel"),
+        )
+        .arg(
+            Arg::with_name("append")
+                .short("a")
+                .long("append")
+                .takes_value(true)
+                .help("A kernel command line set to the bootargs property of the device tree"),
         )
         .arg(
             Arg::with_name("file")
@@ -53,11 +178,70 @@ fn main() -> io::Result<()> {
                 .takes_value(true)
                 .help("A raw disk image"),
         )
+        .arg(
+            Arg::with_name("memory")
+                .short("m")
+                .long("memory")
+                .takes_value(true)
+                .validator(|mib| parse_memory(&mib).map(|_| ()))
+                .help("The size of DRAM in MiB (default: 1024)"),
+        )
+        .arg(
+            Arg::with_name("timer")
+                .short("t")
+                .long("timer")
+                .takes_value(true)
+                .validator(|timer| parse_timer(&timer).map(|_| ()))
+                .help("The ticks of the timer per instruction, or wall-clock to follow the host's clock (default: 1)"),
+        )
+        .arg(
+            Arg::with_name("signature")
+                .short("s")
+                .long("signature")
+                .takes_value(true)
+                .help("A file to dump the signature of a riscv-arch-test program to after the execution"),
+        )
+        .arg(
+            Arg::with_name("log-commits")
+                .short("l")
+                .long("log-commits")
+                .takes_value(true)
+                .help("A file to write the commit log of each instruction to in Spike's format"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .short("r")
+                .long("record")
+                .takes_value(true)
+                .conflicts_with("replay")
+                .help("A file to record the inputs to the guest (e.g. keys typed to UART) to with the cycles when they're received"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .short("p")
+                .long("replay")
+                .takes_value(true)
+                .help("A file recorded with --record to replay the inputs to the guest from, instead of stdin"),
+        )
+        .arg(
+            Arg::with_name("gdb")
+                .short("g")
+                .long("gdb")
+                .takes_value(true)
+                .help("A TCP address (e.g. localhost:1234) or a Unix socket path to wait for GDB to connect to before starting"),
+        )
+        .arg(
+            Arg::with_name("reverse")
+                .long("reverse")
+                .takes_value(true)
+                .requires("gdb")
+                .help("Enables GDB to execute the guest backwards, with a checkpoint taken every given number of cycles"),
+        )
         .arg(
             Arg::with_name("debug")
                 .short("d")
                 .long("debug")
-                .help("Enables to output debug messages"),
+                .help("Enables to output debug messages to stderr"),
         )
         .arg(
             Arg::with_name("count")
@@ -67,37 +251,134 @@ fn main() -> io::Result<()> {
         )
         .get_matches();
 
-    let mut kernel_file = File::open(
-        &matches
-            .value_of("kernel")
-            .expect("failed to get a kernel file from a command option"),
-    )?;
     let mut kernel_data = Vec::new();
-    kernel_file.read_to_end(&mut kernel_data)?;
+    if let Some(kernel_file) = matches.value_of("kernel") {
+        File::open(kernel_file)?.read_to_end(&mut kernel_data)?;
+    }
+
+    let mut bios_data = Vec::new();
+    if let Some(bios_file) = matches.value_of("bios") {
+        File::open(bios_file)?.read_to_end(&mut bios_data)?;
+    }
+
+    let mut initrd_data = Vec::new();
+    if let Some(initrd_file) = matches.value_of("initrd") {
+        File::open(initrd_file)?.read_to_end(&mut initrd_data)?;
+    }
 
     let mut img_data = Vec::new();
     if let Some(img_file) = matches.value_of("file") {
         File::open(img_file)?.read_to_end(&mut img_data)?;
     }
 
-    let mut emu = Emulator::new();
+    let mut config = Config::default();
+    if let Some(memory) = matches.value_of("memory") {
+        // The value has been validated by clap.
+        config.dram_size = parse_memory(memory).expect("invalid size of DRAM");
+    }
+
+    let mut emu = Emulator::with_config(config);
+
+    if let Some(timer) = matches.value_of("timer") {
+        emu.set_timer_mode(parse_timer(timer).expect("invalid mode of the timer"));
+    }
 
-    emu.initialize_dram(kernel_data);
+    if !bios_data.is_empty() {
+        emu.load_bios(&bios_data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if !kernel_data.is_empty() {
+            emu.load_kernel(&kernel_data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+    } else if Elf::is_elf(&kernel_data) {
+        emu.load_elf(&kernel_data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    } else {
+        let size = kernel_data.len() as u64;
+        emu.initialize_dram(kernel_data)
+            .map_err(|_| ElfError::SegmentOutOfRange {
+                addr: DRAM_BASE,
+                size,
+            })
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+    if !initrd_data.is_empty() {
+        emu.load_initrd(&initrd_data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+    if let Some(bootargs) = matches.value_of("append") {
+        emu.set_bootargs(bootargs);
+    }
     emu.initialize_disk(img_data);
-    emu.initialize_pc(DRAM_BASE);
 
+    let mut level = LevelFilter::Warn;
     if matches.occurrences_of("debug") == 1 {
         emu.is_debug = true;
+        level = LevelFilter::Debug;
     }
+    log::set_logger(&LOGGER).expect("failed to set a logger");
+    log::set_max_level(level);
 
     if matches.occurrences_of("count") == 1 {
         emu.cpu.is_count = true;
     }
 
-    emu.start();
+    if let Some(log_file) = matches.value_of("log-commits") {
+        emu.set_commit_log(Box::new(BufWriter::new(File::create(log_file)?)));
+    }
 
-    dump_registers(&emu.cpu);
+    if let Some(record_file) = matches.value_of("record") {
+        emu.record_inputs(Box::new(BufWriter::new(File::create(record_file)?)));
+    }
+    if let Some(replay_file) = matches.value_of("replay") {
+        emu.replay_inputs(&fs::read_to_string(replay_file)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+
+    if let Some(interval) = matches.value_of("reverse") {
+        let interval = interval
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        emu.enable_reverse_execution(interval, MAX_CHECKPOINTS);
+    }
+
+    // The exit code of the guest is forwarded as the exit status of the process. A reset stops
+    // the emulator as well, because rebooting isn't supported.
+    let reason = match matches.value_of("gdb") {
+        Some(address) => match serve_gdb(&mut emu, address)? {
+            SessionEnd::Exited(reason) => reason,
+            // The guest keeps running after the debugger detaches.
+            SessionEnd::Detached => emu.start(),
+            SessionEnd::Killed => return Ok(()),
+        },
+        None => emu.start(),
+    };
+    let code = match reason {
+        ExitReason::Shutdown { code } => exit_status(code),
+        ExitReason::Reset => 0,
+        // The emulator stops after `COUNT_LIMIT` cycles when the instructions are counted.
+        ExitReason::LimitReached => {
+            dump_registers(&mut emu);
+            0
+        }
+        // A fatal trap or a breakpoint hit after GDB detaches.
+        reason => {
+            dump_registers(&mut emu);
+            eprintln!("The emulator stopped abnormally: {:?}", reason);
+            1
+        }
+    };
     dump_count(&emu.cpu);
 
+    if let Some(signature_file) = matches.value_of("signature") {
+        let signature = emu
+            .signature()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(signature_file, signature)?;
+    }
+
+    if code != 0 {
+        process::exit(code);
+    }
     Ok(())
 }
@@ -0,0 +1,81 @@
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The maximum time to boot Linux to a shell.
+const BOOT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Wait until the output received from the guest after the first `from` bytes of `output` contains
+/// `pattern`, and return false if it doesn't come before `deadline`.
+fn wait_for(
+    receiver: &mpsc::Receiver<u8>,
+    output: &mut String,
+    from: usize,
+    pattern: &str,
+    deadline: Instant,
+) -> bool {
+    while !output[from..].contains(pattern) {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(timeout) {
+            Ok(byte) => output.push(byte as char),
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+/// Boot OpenSBI's fw_dynamic, a Linux kernel `Image` and a busybox initramfs in `bin/linux`
+/// through the reset vector in the ROM and the device tree placed in DRAM, and run a command in
+/// the shell. The images aren't checked in. They can be built with Buildroot's
+/// `qemu_riscv64_virt_defconfig` with `BR2_TARGET_ROOTFS_CPIO=y`, and copied as
+/// `fw_dynamic.elf`, `Image` and `rootfs.cpio`.
+#[test]
+#[ignore = "needs fw_dynamic.elf, Image and rootfs.cpio in bin/linux"]
+fn boot_linux_to_shell() {
+    let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    dir.push("../../bin/linux");
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rvemu-cli"))
+        .arg("--bios")
+        .arg(dir.join("fw_dynamic.elf"))
+        .arg("--kernel")
+        .arg(dir.join("Image"))
+        .arg("--initrd")
+        .arg(dir.join("rootfs.cpio"))
+        .arg("--append")
+        .arg("console=ttyS0 rdinit=/bin/sh")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run rvemu-cli");
+
+    let (sender, receiver) = mpsc::channel();
+    let stdout = child.stdout.take().unwrap();
+    thread::spawn(move || {
+        for byte in stdout.bytes() {
+            match byte {
+                Ok(byte) if sender.send(byte).is_ok() => {}
+                _ => break,
+            }
+        }
+    });
+
+    let deadline = Instant::now() + BOOT_TIMEOUT;
+    let mut output = String::new();
+    let booted = wait_for(&receiver, &mut output, 0, "# ", deadline);
+    let prompt = output.len();
+    if booted {
+        let stdin = child.stdin.as_mut().unwrap();
+        stdin.write_all(b"echo $((6 * 7))\n").unwrap();
+        stdin.flush().unwrap();
+    }
+    let answered = booted && wait_for(&receiver, &mut output, prompt, "\n42", deadline);
+
+    child.kill().ok();
+    child.wait().ok();
+    assert!(booted, "no shell prompt:\n{}", output);
+    assert!(answered, "no output of the command:\n{}", output);
+}
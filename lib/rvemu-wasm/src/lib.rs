@@ -7,7 +7,6 @@ use std::ops::{Generator, GeneratorState};
 use std::pin::Pin;
 use std::rc::Rc;
 
-use rvemu_core::bus::DRAM_BASE;
 use rvemu_core::cpu::Cpu;
 use rvemu_core::emulator;
 use rvemu_core::exception::Trap;
@@ -94,11 +93,13 @@ pub fn emulator_start(kernel: Vec<u8>, fsimg: Option<Vec<u8>>) {
     utils::set_panic_hook();
 
     let mut emu = emulator::Emulator::new();
-    emu.initialize_dram(kernel);
+    if emu.initialize_dram(kernel).is_err() {
+        log("the kernel is larger than the memory");
+        return;
+    }
     if let Some(fsimg) = fsimg {
         emu.initialize_disk(fsimg);
     }
-    emu.initialize_pc(DRAM_BASE);
 
     set_executing();
 
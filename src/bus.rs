@@ -1,75 +1,277 @@
 //! The bus module contains the system bus which can access the memroy or memory-mapped peripheral
 //! devices.
 
-use crate::devices::{clint::Clint, plic::Plic, uart::Uart, virtio_blk::Virtio};
-use crate::dram::{Dram, DRAM_SIZE};
+use std::fmt;
+use std::io::prelude::*;
+
+use crate::cpu::{HART_COUNT, POINTER_TO_DTB};
+use crate::devices::{
+    clint::Clint,
+    htif::Htif,
+    plic::{Plic, PLIC_NDEV},
+    test_finisher::TestFinisher,
+    uart::{Uart, UART_IRQ},
+    virtio_blk::{Virtio, VIRTIO_IRQ},
+    Device,
+};
+use crate::dram::{Dram, DramCheckpoint, DRAM_SIZE};
+use crate::dtb;
 use crate::exception::Exception;
 use crate::rom::Rom;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::watchpoint::{WatchKind, Watchpoints};
 
 // QEMU virt machine:
 // https://github.com/qemu/qemu/blob/master/hw/riscv/virt.c#L46-L63
 
 /// The address which the mask ROM starts.
 pub const MROM_BASE: u64 = 0x1000;
-/// The address which the mask ROM ends.
-const MROM_END: u64 = MROM_BASE + 0xf000;
+/// The size of the mask ROM.
+pub const MROM_SIZE: u64 = 0xf000;
+/// The address which the mask ROM ends (exclusive).
+const MROM_END: u64 = MROM_BASE + MROM_SIZE;
+
+/// The address which the SiFive test finisher starts. A guest writes to it to power off or reset
+/// the machine.
+pub const TEST_BASE: u64 = 0x10_0000;
+/// The size of the SiFive test finisher.
+pub const TEST_SIZE: u64 = 0x1000;
+/// The address which the SiFive test finisher ends (exclusive).
+const TEST_END: u64 = TEST_BASE + TEST_SIZE;
 
 /// The address which the core-local interruptor (CLINT) starts. It contains the timer and generates
 /// per-hart software interrupts and timer interrupts.
 pub const CLINT_BASE: u64 = 0x200_0000;
-/// The address which the core-local interruptor (CLINT) ends.
-const CLINT_END: u64 = CLINT_BASE + 0x10000;
+/// The size of the core-local interruptor (CLINT).
+pub const CLINT_SIZE: u64 = 0x10000;
+/// The address which the core-local interruptor (CLINT) ends (exclusive).
+const CLINT_END: u64 = CLINT_BASE + CLINT_SIZE;
 
 /// The address which the platform-level interrupt controller (PLIC) starts. The PLIC connects all
 /// external interrupts in the system to all hart contexts in the system, via the external interrupt
 /// source in each hart.
 pub const PLIC_BASE: u64 = 0xc00_0000;
-/// The address which the platform-level interrupt controller (PLIC) ends.
-const PLIC_END: u64 = PLIC_BASE + 0x208000;
+/// The size of the platform-level interrupt controller (PLIC).
+pub const PLIC_SIZE: u64 = 0x208000;
+/// The address which the platform-level interrupt controller (PLIC) ends (exclusive).
+const PLIC_END: u64 = PLIC_BASE + PLIC_SIZE;
 
 /// The address which UART starts. QEMU puts UART registers here in physical memory.
 pub const UART_BASE: u64 = 0x1000_0000;
 /// The size of UART.
 pub const UART_SIZE: u64 = 0x100;
-/// The address which UART ends.
-const UART_END: u64 = UART_BASE + 0x100;
+/// The address which UART ends (exclusive).
+const UART_END: u64 = UART_BASE + UART_SIZE;
 
 /// The address which virtio starts.
 pub const VIRTIO_BASE: u64 = 0x1000_1000;
-/// The address which virtio ends.
-const VIRTIO_END: u64 = VIRTIO_BASE + 0x1000;
+/// The size of virtio.
+pub const VIRTIO_SIZE: u64 = 0x1000;
+/// The address which virtio ends (exclusive).
+const VIRTIO_END: u64 = VIRTIO_BASE + VIRTIO_SIZE;
 
 /// The address which DRAM starts.
 pub const DRAM_BASE: u64 = 0x8000_0000;
-/// The address which DRAM ends.
-const DRAM_END: u64 = DRAM_BASE + DRAM_SIZE;
 
-/// The system bus.
+/// The address which a kernel is loaded when a firmware (e.g. OpenSBI) is loaded at `DRAM_BASE`.
+/// It's the default jump address of OpenSBI's fw_jump for the QEMU virt machine.
+pub const KERNEL_BASE: u64 = 0x8020_0000;
+/// The size of the region at the end of DRAM for a device tree blob placed in DRAM. A firmware
+/// can expand the blob in place within the region.
+pub const DTB_REGION_SIZE: u64 = 0x20_0000;
+/// The default kernel command line passed in the `bootargs` property of the device tree.
+const DEFAULT_BOOTARGS: &str = "root=/dev/vda ro console=ttyS0";
+
+/// The error type for attaching a device to the system bus.
+#[derive(Debug, PartialEq)]
+pub enum AttachError {
+    /// The size is zero or the range goes beyond the address space.
+    InvalidRange { base: u64, size: u64 },
+    /// The range overlaps the memory or another device.
+    Overlap { base: u64, size: u64 },
+    /// The interrupt request number is out of the range of PLIC or used by another device.
+    InvalidIrq(u64),
+}
+
+impl fmt::Display for AttachError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttachError::InvalidRange { base, size } => {
+                write!(f, "invalid range at {:#x} with {:#x} bytes", base, size)
+            }
+            AttachError::Overlap { base, size } => write!(
+                f,
+                "range at {:#x} with {:#x} bytes overlaps another device",
+                base, size
+            ),
+            AttachError::InvalidIrq(irq) => write!(f, "invalid interrupt request number {}", irq),
+        }
+    }
+}
+
+impl std::error::Error for AttachError {}
+
+/// A built-in device of the system bus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BuiltinDevice {
+    Rom,
+    TestFinisher,
+    Clint,
+    Plic,
+    Uart,
+    Virtio,
+}
+
+/// The address map of the built-in devices except DRAM, whose size is configurable. Both the
+/// dispatch of accesses and the check of the ranges of attached devices use it.
+const BUILTIN_DEVICES: [(u64, u64, BuiltinDevice); 6] = [
+    (MROM_BASE, MROM_END, BuiltinDevice::Rom),
+    (TEST_BASE, TEST_END, BuiltinDevice::TestFinisher),
+    (CLINT_BASE, CLINT_END, BuiltinDevice::Clint),
+    (PLIC_BASE, PLIC_END, BuiltinDevice::Plic),
+    (UART_BASE, UART_END, BuiltinDevice::Uart),
+    (VIRTIO_BASE, VIRTIO_END, BuiltinDevice::Virtio),
+];
+
+/// The device which an access on the system bus goes to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    Dram,
+    Builtin(BuiltinDevice),
+    /// The attached device at the index of `Bus::devices`.
+    Attached(usize),
+}
+
+/// A device attached to the system bus and the range of addresses which it occupies.
+struct MappedDevice {
+    base: u64,
+    size: u64,
+    device: Box<dyn Device>,
+}
+
+/// The system bus. The built-in devices of the QEMU virt machine are always connected, and other
+/// devices can be attached to the free ranges of the address space.
 pub struct Bus {
+    pub test_finisher: TestFinisher,
+    /// The host-target interface, which is a part of DRAM rather than a memory-mapped device.
+    pub htif: Htif,
     pub clint: Clint,
     pub plic: Plic,
     pub uart: Uart,
     pub virtio: Virtio,
     dram: Dram,
     pub rom: Rom,
+    /// The address of the device tree blob passed to the guest. It's in the ROM by default.
+    dtb_addr: u64,
+    /// The kernel command line passed in the `bootargs` property of the device tree.
+    bootargs: String,
+    /// The start and end addresses of an initial ramdisk placed in DRAM, if any.
+    initrd: Option<(u64, u64)>,
+    /// The devices attached in addition to the built-in devices.
+    devices: Vec<MappedDevice>,
+    /// The watchpoints on physical addresses, which are checked for accesses by both the CPU and
+    /// devices.
+    pub watchpoints: Watchpoints,
 }
 
 impl Bus {
-    /// Create a new bus object.
+    /// Create a new bus object with the default memory size.
     pub fn new() -> Bus {
-        Self {
+        Self::with_dram_size(DRAM_SIZE)
+    }
+
+    /// Create a new bus object with `dram_size` bytes of memory.
+    pub fn with_dram_size(dram_size: u64) -> Bus {
+        let mut bus = Self {
+            test_finisher: TestFinisher::new(),
+            htif: Htif::new(),
             clint: Clint::new(),
             plic: Plic::new(),
             uart: Uart::new(),
             virtio: Virtio::new(),
-            dram: Dram::new(),
-            rom: Rom::new(),
+            dram: Dram::new(dram_size),
+            rom: Rom::new(Vec::new()),
+            dtb_addr: POINTER_TO_DTB,
+            bootargs: String::from(DEFAULT_BOOTARGS),
+            initrd: None,
+            devices: Vec::new(),
+            watchpoints: Watchpoints::new(),
+        };
+        bus.update_dtb();
+        bus
+    }
+
+    /// Generate a device tree blob from the current configuration of the bus and set it to the
+    /// ROM. The blob is also copied to DRAM if it has been placed in DRAM.
+    pub fn update_dtb(&mut self) {
+        let dtb = dtb::create(self, HART_COUNT);
+        if self.dtb_addr != POINTER_TO_DTB {
+            self.dram
+                .initialize_at(self.dtb_addr, &dtb, dtb.len() as u64)
+                .expect("failed to place a device tree blob in DRAM");
+        }
+        self.rom.set_dtb(dtb);
+        self.rom.set_fdt_addr(self.dtb_addr);
+    }
+
+    /// Place the device tree blob at the end of DRAM instead of the ROM, and pass the address to
+    /// the guest. A firmware like OpenSBI's fw_dynamic modifies the blob in place, so it needs to
+    /// be in writable memory. Raises an exception if DRAM is smaller than the region for the blob.
+    pub fn place_dtb_in_dram(&mut self) -> Result<(), Exception> {
+        if self.dram_size() < DTB_REGION_SIZE {
+            return Err(Exception::StoreAMOAccessFault);
         }
+        self.dtb_addr = DRAM_BASE + self.dram_size() - DTB_REGION_SIZE;
+        self.update_dtb();
+        Ok(())
+    }
+
+    /// Return the address of the device tree blob passed to the guest.
+    pub fn dtb_addr(&self) -> u64 {
+        self.dtb_addr
+    }
+
+    /// Return the kernel command line passed to the guest.
+    pub fn bootargs(&self) -> &str {
+        &self.bootargs
+    }
+
+    /// Set the kernel command line passed to the guest and regenerate the device tree blob.
+    pub fn set_bootargs(&mut self, bootargs: &str) {
+        self.bootargs = String::from(bootargs);
+        self.update_dtb();
+    }
+
+    /// Return the start and end addresses of the initial ramdisk, if any.
+    pub fn initrd(&self) -> Option<(u64, u64)> {
+        self.initrd
+    }
+
+    /// Set the start and end addresses of the initial ramdisk and regenerate the device tree blob.
+    pub fn set_initrd(&mut self, start: u64, end: u64) {
+        self.initrd = Some((start, end));
+        self.update_dtb();
+    }
+
+    /// Return the size of DRAM in bytes.
+    pub fn dram_size(&self) -> u64 {
+        self.dram.size()
     }
 
-    /// Set the binary data to the memory.
-    pub fn initialize_dram(&mut self, data: Vec<u8>) {
-        self.dram.initialize(data);
+    /// Set the binary data to the memory. Raises an exception if the data is larger than the
+    /// memory.
+    pub fn initialize_dram(&mut self, data: Vec<u8>) -> Result<(), Exception> {
+        self.dram.initialize(data)
+    }
+
+    /// Set the binary data to the memory at `addr` and fill the rest of `size` bytes with zeros.
+    pub fn initialize_dram_at(
+        &mut self,
+        addr: u64,
+        data: &[u8],
+        size: u64,
+    ) -> Result<(), Exception> {
+        self.dram.initialize_at(addr, data, size)
     }
 
     /// Set the binary data to the virtIO disk.
@@ -77,28 +279,270 @@ impl Bus {
         self.virtio.initialize(data);
     }
 
-    /// Load a `size`-bit data from the device that connects to the system bus.
-    pub fn read(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
-        match addr {
-            MROM_BASE..=MROM_END => self.rom.read(addr, size),
-            CLINT_BASE..=CLINT_END => self.clint.read(addr, size),
-            PLIC_BASE..=PLIC_END => self.plic.read(addr, size),
-            UART_BASE..=UART_END => self.uart.read(addr, size),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio.read(addr, size),
-            DRAM_BASE..=DRAM_END => self.dram.read(addr, size),
-            _ => Err(Exception::LoadAccessFault),
+    /// Attach a memory-mapped `device` which occupies `size` bytes from `base`, and regenerate the
+    /// device tree blob to describe it. The range must not overlap the memory or other devices.
+    pub fn attach(
+        &mut self,
+        base: u64,
+        size: u64,
+        device: Box<dyn Device>,
+    ) -> Result<(), AttachError> {
+        let end = match base.checked_add(size) {
+            Some(end) if size > 0 => end,
+            _ => return Err(AttachError::InvalidRange { base, size }),
+        };
+        if self.is_mapped(base, end) {
+            return Err(AttachError::Overlap { base, size });
+        }
+        if let Some(irq) = device.irq() {
+            if irq == 0 || irq > PLIC_NDEV || self.is_irq_used(irq) {
+                return Err(AttachError::InvalidIrq(irq));
+            }
+        }
+
+        self.devices.push(MappedDevice { base, size, device });
+        self.update_dtb();
+        Ok(())
+    }
+
+    /// Write the location of the device tree blob, the kernel command line, the location of the
+    /// initial ramdisk and the state of the built-in devices to a snapshot. The state of the
+    /// attached devices isn't saved, and the contents of the memory are saved by
+    /// `Bus::save_dram_snapshot`.
+    pub(crate) fn save_snapshot<W: Write>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<(), SnapshotError> {
+        writer.write_u64(self.dtb_addr)?;
+        writer.write_vec(self.bootargs.as_bytes())?;
+        writer.write_option(self.initrd.map(|(start, _)| start))?;
+        writer.write_option(self.initrd.map(|(_, end)| end))?;
+        self.rom.save_snapshot(writer)?;
+        self.test_finisher.save_snapshot(writer)?;
+        self.htif.save_snapshot(writer)?;
+        self.clint.save_snapshot(writer)?;
+        self.plic.save_snapshot(writer)?;
+        self.uart.save_snapshot(writer)?;
+        self.virtio.save_snapshot(writer)
+    }
+
+    /// Restore the configuration and the state of the built-in devices from a snapshot. The
+    /// device tree blob isn't regenerated because it's restored with the ROM and the memory,
+    /// where a firmware may have modified it.
+    pub(crate) fn load_snapshot<R: Read>(
+        &mut self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<(), SnapshotError> {
+        let dtb_addr = reader.read_u64()?;
+        let in_dram = dtb_addr >= DRAM_BASE
+            && matches!(dtb_addr.checked_add(DTB_REGION_SIZE),
+                Some(end) if end <= DRAM_BASE + self.dram_size());
+        if dtb_addr != POINTER_TO_DTB && !in_dram {
+            return Err(SnapshotError::Corrupted("device tree address"));
+        }
+        let bootargs = String::from_utf8(reader.read_vec(MROM_SIZE)?)
+            .map_err(|_| SnapshotError::Corrupted("kernel command line"))?;
+        let initrd = match (reader.read_option()?, reader.read_option()?) {
+            (Some(start), Some(end)) if start <= end => Some((start, end)),
+            (None, None) => None,
+            _ => return Err(SnapshotError::Corrupted("initial ramdisk")),
+        };
+        self.dtb_addr = dtb_addr;
+        self.bootargs = bootargs;
+        self.initrd = initrd;
+
+        self.rom.load_snapshot(reader)?;
+        self.test_finisher.load_snapshot(reader)?;
+        self.htif.load_snapshot(reader)?;
+        self.clint.load_snapshot(reader)?;
+        self.plic.load_snapshot(reader)?;
+        self.uart.load_snapshot(reader)?;
+        self.virtio.load_snapshot(reader)
+    }
+
+    /// Write the contents of the memory to a snapshot.
+    pub(crate) fn save_dram_snapshot<W: Write>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<(), SnapshotError> {
+        self.dram.save_snapshot(writer)
+    }
+
+    /// Restore the contents of the memory from a snapshot.
+    pub(crate) fn load_dram_snapshot<R: Read>(
+        &mut self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<(), SnapshotError> {
+        self.dram.load_snapshot(reader)
+    }
+
+    /// Take a checkpoint of the memory for reverse execution, which shares the pages not written
+    /// since `base` with it.
+    pub(crate) fn checkpoint_dram(&mut self, base: Option<&DramCheckpoint>) -> DramCheckpoint {
+        self.dram.checkpoint(base)
+    }
+
+    /// Restore the memory from a checkpoint. `base` is the checkpoint taken or restored last.
+    pub(crate) fn restore_dram(&mut self, checkpoint: &DramCheckpoint, base: &DramCheckpoint) {
+        self.dram.restore(checkpoint, base)
+    }
+
+    /// Return the base addresses, the sizes and the attached devices.
+    pub(crate) fn devices(&self) -> impl Iterator<Item = (u64, u64, &dyn Device)> + '_ {
+        self.devices
+            .iter()
+            .map(|mapped| (mapped.base, mapped.size, mapped.device.as_ref()))
+    }
+
+    /// Execute a cycle on the attached devices.
+    pub fn tick(&mut self) {
+        for mapped in self.devices.iter_mut() {
+            mapped.device.tick();
+        }
+    }
+
+    /// Return true if any attached device has work in progress.
+    pub fn is_device_busy(&self) -> bool {
+        self.devices.iter().any(|mapped| mapped.device.is_busy())
+    }
+
+    /// Forward the interrupts raised by the attached devices to PLIC.
+    pub fn update_device_interrupts(&mut self) {
+        for mapped in self.devices.iter_mut() {
+            if let Some(irq) = mapped.device.irq() {
+                if mapped.device.is_interrupting() {
+                    self.plic.update_pending(irq);
+                }
+            }
+        }
+    }
+
+    /// Return true if any address from `base` to `end` (exclusive) is used by the memory or a
+    /// device.
+    fn is_mapped(&self, base: u64, end: u64) -> bool {
+        let builtin = BUILTIN_DEVICES
+            .iter()
+            .map(|(start, stop, _)| (*start, *stop));
+        let attached = self
+            .devices
+            .iter()
+            .map(|mapped| (mapped.base, mapped.base + mapped.size));
+        builtin
+            .chain(std::iter::once((DRAM_BASE, DRAM_BASE + self.dram.size())))
+            .chain(attached)
+            .any(|(start, stop)| base < stop && start < end)
+    }
+
+    /// Return true if the interrupt request number is used by a device.
+    fn is_irq_used(&self, irq: u64) -> bool {
+        irq == UART_IRQ
+            || irq == VIRTIO_IRQ
+            || self
+                .devices
+                .iter()
+                .any(|mapped| mapped.device.irq() == Some(irq))
+    }
+
+    /// Return the device which a `size`-bit access at `addr` is entirely within, if any.
+    fn target(&self, addr: u64, size: u8) -> Option<Target> {
+        // DRAM is checked first because it's accessed the most frequently.
+        if fits(addr, size, DRAM_BASE, DRAM_BASE + self.dram.size()) {
+            return Some(Target::Dram);
         }
+        if let Some((_, _, device)) = BUILTIN_DEVICES
+            .iter()
+            .find(|(base, end, _)| fits(addr, size, *base, *end))
+        {
+            return Some(Target::Builtin(*device));
+        }
+        self.devices
+            .iter()
+            .position(|mapped| fits(addr, size, mapped.base, mapped.base + mapped.size))
+            .map(Target::Attached)
+    }
+
+    /// Load a `size`-bit data from the device that connects to the system bus. An access which
+    /// isn't entirely within a device raises an access fault.
+    pub fn read(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
+        let value = self.read_device(addr, size)?;
+        self.watchpoints.check(WatchKind::Read, addr, size, value);
+        Ok(value)
+    }
+
+    /// Load a `size`-bit instruction from the device that connects to the system bus. It's the
+    /// same as `read` except that it hits watchpoints on execution instead of reads.
+    pub fn fetch(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
+        let value = self.read_device(addr, size)?;
+        self.watchpoints
+            .check(WatchKind::Execute, addr, size, value);
+        Ok(value)
+    }
+
+    /// Load a `size`-bit data from the device that connects to the system bus without checking
+    /// watchpoints. It's used for the accesses which aren't made by the guest, e.g. by a debugger.
+    pub fn peek(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
+        self.read_device(addr, size)
     }
 
-    /// Store a `size`-bit data to the device that connects to the system bus.
+    /// Store a `size`-bit data to the device that connects to the system bus. An access which
+    /// isn't entirely within a device raises an access fault.
     pub fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
-        match addr {
-            CLINT_BASE..=CLINT_END => self.clint.write(addr, value, size),
-            PLIC_BASE..=PLIC_END => self.plic.write(addr, value, size),
-            UART_BASE..=UART_END => self.uart.write(addr, value as u8, size),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio.write(addr, value as u32, size),
-            DRAM_BASE..=DRAM_END => self.dram.write(addr, value, size),
-            _ => Err(Exception::StoreAMOAccessFault),
+        self.write_device(addr, value, size)?;
+        self.watchpoints.check(WatchKind::Write, addr, size, value);
+        Ok(())
+    }
+
+    /// Load a `size`-bit data from the device at `addr`.
+    fn read_device(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
+        match self.target(addr, size) {
+            Some(Target::Dram) => self.dram.read(addr, size),
+            Some(Target::Builtin(device)) => match device {
+                BuiltinDevice::Rom => self.rom.read(addr, size),
+                BuiltinDevice::TestFinisher => self.test_finisher.read(addr, size),
+                BuiltinDevice::Clint => self.clint.read(addr, size),
+                BuiltinDevice::Plic => self.plic.read(addr, size),
+                BuiltinDevice::Uart => self.uart.read(addr, size),
+                BuiltinDevice::Virtio => self.virtio.read(addr, size),
+            },
+            Some(Target::Attached(index)) => {
+                let mapped = &mut self.devices[index];
+                mapped.device.read(addr - mapped.base, size)
+            }
+            None => Err(Exception::LoadAccessFault),
         }
     }
+
+    /// Store a `size`-bit data to the device at `addr`.
+    fn write_device(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
+        match self.target(addr, size) {
+            Some(Target::Dram) => {
+                self.dram.write(addr, value, size)?;
+                if self.htif.is_tohost(addr, size) {
+                    self.htif.handle(&mut self.dram);
+                }
+                Ok(())
+            }
+            Some(Target::Builtin(device)) => match device {
+                BuiltinDevice::Rom => self.rom.write(addr, value, size),
+                BuiltinDevice::TestFinisher => self.test_finisher.write(addr, value, size),
+                BuiltinDevice::Clint => self.clint.write(addr, value, size),
+                BuiltinDevice::Plic => self.plic.write(addr, value, size),
+                BuiltinDevice::Uart => self.uart.write(addr, value as u8, size),
+                BuiltinDevice::Virtio => self.virtio.write(addr, value as u32, size),
+            },
+            Some(Target::Attached(index)) => {
+                let mapped = &mut self.devices[index];
+                mapped.device.write(addr - mapped.base, value, size)
+            }
+            None => Err(Exception::StoreAMOAccessFault),
+        }
+    }
+}
+
+/// Return true if a `size`-bit access at `addr` is entirely from `base` to `end` (exclusive).
+fn fits(addr: u64, size: u8, base: u64, end: u64) -> bool {
+    match addr.checked_add((size / 8) as u64) {
+        Some(access_end) => base <= addr && access_end <= end,
+        None => false,
+    }
 }
@@ -0,0 +1,80 @@
+//! The commit_log module contains the instruction trace in the format of Spike's `--log-commits`
+//! option. Each committed instruction is written as a line with the privilege mode, the program
+//! counter, the raw instruction, and the writes to registers, CSRs and memory, so that a trace can
+//! be compared with Spike's one line by line.
+
+// Reference:
+// https://github.com/riscv-software-src/riscv-isa-sim/blob/master/riscv/execute.cc
+
+use crate::cpu::{Mode, DOUBLEWORD, HALFWORD, WORD};
+use crate::csr::{csr_name, CsrAddress};
+
+/// The effects of an instruction recorded for the commit log.
+#[derive(Debug, Default, PartialEq)]
+pub struct Commit {
+    /// The integer registers written and their new values.
+    pub xregs: Vec<(u64, u64)>,
+    /// The floating-point registers written and the bits of their new values.
+    pub fregs: Vec<(u64, u64)>,
+    /// The CSRs written and their new values.
+    pub csrs: Vec<(CsrAddress, u64)>,
+    /// The virtual addresses loaded from.
+    pub loads: Vec<u64>,
+    /// The virtual addresses stored to, the stored values and the sizes in bits.
+    pub stores: Vec<(u64, u64, u8)>,
+}
+
+/// Format a committed instruction executed by `hart` like Spike, e.g.
+/// `core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000`.
+pub fn format(hart: u64, mode: Mode, pc: u64, inst: u64, commit: &Commit) -> String {
+    // The lowest 2 bits of a 32-bit instruction are 0b11.
+    let inst_size = if inst & 0b11 == 0b11 { WORD } else { HALFWORD };
+    let mut line = format!(
+        "core{:>4}: {} {} ({})",
+        hart,
+        mode as u8,
+        hex(DOUBLEWORD, pc),
+        hex(inst_size, inst)
+    );
+
+    for (index, value) in last_writes(&commit.xregs) {
+        line.push_str(&format!(" x{:<2} {}", index, hex(DOUBLEWORD, value)));
+    }
+    for (index, value) in last_writes(&commit.fregs) {
+        line.push_str(&format!(" f{:<2} {}", index, hex(DOUBLEWORD, value)));
+    }
+    for (addr, value) in last_writes(&commit.csrs) {
+        let name = csr_name(addr).unwrap_or_else(|| String::from("unknown"));
+        line.push_str(&format!(" c{}_{} {}", addr, name, hex(DOUBLEWORD, value)));
+    }
+    for addr in commit.loads.iter() {
+        line.push_str(&format!(" mem {}", hex(DOUBLEWORD, *addr)));
+    }
+    for (addr, value, size) in commit.stores.iter() {
+        line.push_str(&format!(
+            " mem {} {}",
+            hex(DOUBLEWORD, *addr),
+            hex(*size, *value)
+        ));
+    }
+    line
+}
+
+/// Return the last value written to each register, in the order of the first write. An
+/// instruction can write a register more than once, e.g. `mret` updates the fields of `mstatus`
+/// one by one, but Spike shows only the final value.
+fn last_writes<T: PartialEq + Copy>(writes: &[(T, u64)]) -> Vec<(T, u64)> {
+    let mut result: Vec<(T, u64)> = Vec::new();
+    for (reg, value) in writes.iter() {
+        match result.iter_mut().find(|(r, _)| r == reg) {
+            Some(write) => write.1 = *value,
+            None => result.push((*reg, *value)),
+        }
+    }
+    result
+}
+
+/// Format `size`-bit `value` as a zero-padded hexadecimal number.
+fn hex(size: u8, value: u64) -> String {
+    format!("{:#0width$x}", value, width = size as usize / 4 + 2)
+}
@@ -4,10 +4,12 @@ use std::cmp;
 use std::cmp::PartialEq;
 use std::collections::BTreeMap;
 use std::fmt;
+use std::io::prelude::*;
 use std::num::FpCategory;
 
 use crate::{
-    bus::{Bus, DRAM_BASE},
+    bus::{Bus, DRAM_BASE, MROM_BASE},
+    commit_log::Commit,
     csr::*,
     devices::{
         uart::UART_IRQ,
@@ -16,10 +18,14 @@ use crate::{
     dram::DRAM_SIZE,
     exception::Exception,
     interrupt::Interrupt,
+    snapshot::{SnapshotError, SnapshotReader, SnapshotWriter},
+    watchpoint::{WatchKind, WatchpointHit, Watchpoints},
 };
 
 /// The number of registers.
 pub const REGISTERS_COUNT: usize = 32;
+/// The number of harts.
+pub const HART_COUNT: u64 = 1;
 /// The page size (4 KiB) for the virtual memory system.
 const PAGE_SIZE: u64 = 4096;
 
@@ -34,7 +40,8 @@ pub const DOUBLEWORD: u8 = 64;
 
 /// riscv-pk is passing x10 and x11 registers to kernel. x11 is expected to have the pointer to DTB.
 /// https://github.com/riscv/riscv-pk/blob/master/machine/mentry.S#L233-L235
-pub const POINTER_TO_DTB: u64 = 0x1020;
+/// The DTB is placed in the mask ROM after the reset vector and the fw_dynamic information.
+pub const POINTER_TO_DTB: u64 = 0x1100;
 
 macro_rules! inst_count {
     ($cpu:ident, $inst_name:expr) => {
@@ -69,14 +76,16 @@ pub enum Mode {
 #[derive(Debug)]
 pub struct XRegisters {
     xregs: [u64; REGISTERS_COUNT],
+    /// The registers written and their new values, which are recorded only for the commit log.
+    writes: Option<Vec<(u64, u64)>>,
 }
 
 impl XRegisters {
-    /// Create a new `XRegisters` object.
-    pub fn new() -> Self {
+    /// Create a new `XRegisters` object for a memory of `dram_size` bytes.
+    pub fn new(dram_size: u64) -> Self {
         let mut xregs = [0; REGISTERS_COUNT];
-        // The stack pointer is set in the default maximum memory size + the start address of dram.
-        xregs[2] = DRAM_BASE + DRAM_SIZE;
+        // The stack pointer is set in the maximum memory size + the start address of dram.
+        xregs[2] = DRAM_BASE + dram_size;
         // From riscv-pk:
         // https://github.com/riscv/riscv-pk/blob/master/machine/mentry.S#L233-L235
         //   save a0 and a1; arguments from previous boot loader stage:
@@ -90,7 +99,10 @@ impl XRegisters {
         // So, we need to set registers register to the state as they are when a bootloader finished.
         xregs[10] = 0;
         xregs[11] = POINTER_TO_DTB;
-        Self { xregs }
+        Self {
+            xregs,
+            writes: None,
+        }
     }
 
     /// Read the value from a register.
@@ -99,12 +111,35 @@ impl XRegisters {
     }
 
     /// Write the value to a register.
+    #[inline]
     pub fn write(&mut self, index: u64, value: u64) {
         // Register x0 is hardwired with all bits equal to 0.
         if index != 0 {
             self.xregs[index as usize] = value;
+            if self.writes.is_some() {
+                self.record_write(index, value);
+            }
         }
     }
+
+    /// Record a write to a register. It's out of line to keep `write` cheap while the commit log
+    /// is disabled.
+    #[cold]
+    fn record_write(&mut self, index: u64, value: u64) {
+        if let Some(writes) = self.writes.as_mut() {
+            writes.push((index, value));
+        }
+    }
+
+    /// Start recording the registers written and their new values.
+    pub fn record_writes(&mut self) {
+        self.writes = Some(Vec::new());
+    }
+
+    /// Return the registers written since `record_writes` is called, and stop recording.
+    pub fn take_writes(&mut self) -> Vec<(u64, u64)> {
+        self.writes.take().unwrap_or_default()
+    }
 }
 
 impl fmt::Display for XRegisters {
@@ -144,6 +179,9 @@ impl fmt::Display for XRegisters {
 #[derive(Debug)]
 pub struct FRegisters {
     fregs: [f64; REGISTERS_COUNT],
+    /// The registers written and the bits of their new values, which are recorded only for the
+    /// commit log.
+    writes: Option<Vec<(u64, u64)>>,
 }
 
 impl FRegisters {
@@ -151,6 +189,7 @@ impl FRegisters {
     pub fn new() -> Self {
         Self {
             fregs: [0.0; REGISTERS_COUNT],
+            writes: None,
         }
     }
 
@@ -160,8 +199,31 @@ impl FRegisters {
     }
 
     /// Write the value to a register.
+    #[inline]
     pub fn write(&mut self, index: u64, value: f64) {
         self.fregs[index as usize] = value;
+        if self.writes.is_some() {
+            self.record_write(index, value);
+        }
+    }
+
+    /// Record a write to a register. It's out of line to keep `write` cheap while the commit log
+    /// is disabled.
+    #[cold]
+    fn record_write(&mut self, index: u64, value: f64) {
+        if let Some(writes) = self.writes.as_mut() {
+            writes.push((index, value.to_bits()));
+        }
+    }
+
+    /// Start recording the registers written and their new values.
+    pub fn record_writes(&mut self) {
+        self.writes = Some(Vec::new());
+    }
+
+    /// Return the registers written since `record_writes` is called, and stop recording.
+    pub fn take_writes(&mut self) -> Vec<(u64, u64)> {
+        self.writes.take().unwrap_or_default()
     }
 }
 
@@ -238,18 +300,31 @@ pub struct Cpu {
     pub is_count: bool,
     /// Previous instruction. This is for debug.
     pub pre_inst: u64,
+    /// The effects of the current instruction, which are recorded only for the commit log.
+    commit: Option<Commit>,
+    /// The values of the CSRs before the current instruction, which are compared with the new
+    /// values for the commit log.
+    csr_snapshot: Vec<u64>,
+    /// The watchpoints on virtual addresses, which are checked before the translation.
+    pub watchpoints: Watchpoints,
 }
 
 impl Cpu {
-    /// Create a new `Cpu` object.
+    /// Create a new `Cpu` object with the default memory size.
     pub fn new() -> Cpu {
+        Self::with_dram_size(DRAM_SIZE)
+    }
+
+    /// Create a new `Cpu` object connected to `dram_size` bytes of memory.
+    pub fn with_dram_size(dram_size: u64) -> Cpu {
         Cpu {
-            xregs: XRegisters::new(),
+            xregs: XRegisters::new(dram_size),
             fregs: FRegisters::new(),
-            pc: 0,
+            // The reset vector in the mask ROM.
+            pc: MROM_BASE,
             state: State::new(),
             mode: Mode::Machine,
-            bus: Bus::new(),
+            bus: Bus::with_dram_size(dram_size),
             enable_paging: false,
             page_table: 0,
             reservation_set: Vec::new(),
@@ -257,6 +332,9 @@ impl Cpu {
             inst_counter: BTreeMap::new(),
             is_count: false,
             pre_inst: 0,
+            commit: None,
+            csr_snapshot: Vec::new(),
+            watchpoints: Watchpoints::new(),
         }
     }
 
@@ -305,7 +383,7 @@ impl Cpu {
 
     /// Reset CPU states.
     pub fn reset(&mut self) {
-        self.pc = 0;
+        self.pc = MROM_BASE;
         self.mode = Mode::Machine;
         self.state.reset();
         for i in 0..REGISTERS_COUNT {
@@ -314,6 +392,101 @@ impl Cpu {
         }
     }
 
+    /// Write the state of the hart and the built-in devices on the system bus to a snapshot. The
+    /// contents of the memory aren't included.
+    pub(crate) fn save_snapshot<W: Write>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<(), SnapshotError> {
+        writer.write_u64s(&self.xregs.xregs)?;
+        for freg in self.fregs.fregs.iter() {
+            writer.write_u64(freg.to_bits())?;
+        }
+        writer.write_u64(self.pc)?;
+        writer.write_u8(self.mode as u8)?;
+        self.state.save_snapshot(writer)?;
+        writer.write_u64(self.reservation_set.len() as u64)?;
+        writer.write_u64s(&self.reservation_set)?;
+        writer.write_bool(self.idle)?;
+        self.bus.save_snapshot(writer)
+    }
+
+    /// Restore the state of the hart and the built-in devices from a snapshot. The paging is
+    /// updated by the restored `satp`.
+    pub(crate) fn load_snapshot<R: Read>(
+        &mut self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<(), SnapshotError> {
+        reader.read_u64s(&mut self.xregs.xregs)?;
+        for freg in self.fregs.fregs.iter_mut() {
+            *freg = f64::from_bits(reader.read_u64()?);
+        }
+        self.pc = reader.read_u64()?;
+        let mode = reader.read_u8()?;
+        self.mode = [Mode::User, Mode::Supervisor, Mode::Machine, Mode::Debug]
+            .iter()
+            .copied()
+            .find(|m| *m as u8 == mode)
+            .ok_or(SnapshotError::Corrupted("privilege mode"))?;
+        self.state.load_snapshot(reader)?;
+        self.update_paging();
+        self.reservation_set.clear();
+        for _ in 0..reader.read_u64()? {
+            self.reservation_set.push(reader.read_u64()?);
+        }
+        self.idle = reader.read_bool()?;
+        self.bus.load_snapshot(reader)
+    }
+
+    /// Start recording the effects of the next instruction for the commit log.
+    pub fn begin_commit(&mut self) {
+        self.xregs.record_writes();
+        self.fregs.record_writes();
+        self.csr_snapshot = self.state.snapshot();
+        self.commit = Some(Commit::default());
+    }
+
+    /// Record a write to the CSR at `addr` by a CSR instruction for the commit log. It's
+    /// recorded even if the value doesn't change.
+    #[cold]
+    fn record_csr_write(&mut self, addr: CsrAddress) {
+        let value = self.state.read(addr);
+        if let Some(commit) = self.commit.as_mut() {
+            commit.csrs.push((addr, value));
+        }
+    }
+
+    /// Record a load from the virtual address `v_addr` for the commit log.
+    #[cold]
+    fn record_load(&mut self, v_addr: u64) {
+        if let Some(commit) = self.commit.as_mut() {
+            commit.loads.push(v_addr);
+        }
+    }
+
+    /// Record a `size`-bit store of `value` to the virtual address `v_addr` for the commit log.
+    #[cold]
+    fn record_store(&mut self, v_addr: u64, value: u64, size: u8) {
+        if let Some(commit) = self.commit.as_mut() {
+            commit.stores.push((v_addr, value, size));
+        }
+    }
+
+    /// Stop recording and return the effects of the instruction since `begin_commit`.
+    pub fn end_commit(&mut self) -> Commit {
+        let mut commit = self.commit.take().unwrap_or_default();
+        commit.xregs = self.xregs.take_writes();
+        commit.fregs = self.fregs.take_writes();
+        // Side effects of an instruction on CSRs, e.g. updating `mstatus` by `mret`, are found by
+        // comparing the values.
+        for (addr, value) in self.state.changed_since(&self.csr_snapshot) {
+            if !commit.csrs.iter().any(|(a, _)| *a == addr) {
+                commit.csrs.push((addr, value));
+            }
+        }
+        commit
+    }
+
     /// Check interrupt flags for all devices that can interrupt.
     pub fn check_pending_interrupt(&mut self) -> Option<Interrupt> {
         // global interrupt: PLIC (Platform Local Interrupt Controller) dispatches global
@@ -321,44 +494,32 @@ impl Cpu {
         // local interrupt: CLINT (Core Local Interrupter) dispatches local interrupts to a hart
         //                  which directly connected to CLINT.
 
-        // 3.1.6.1 Privilege and Global Interrupt-Enable Stack in mstatus register
-        // "When a hart is executing in privilege mode x, interrupts are globally enabled when
-        // xIE=1 and globally disabled when xIE=0."
-        match self.mode {
-            Mode::Machine => {
-                // Check if the MIE bit is enabled.
-                if self.state.read_mstatus(MSTATUS_MIE) == 0 {
-                    return None;
-                }
-            }
-            Mode::Supervisor => {
-                // Check if the SIE bit is enabled.
-                if self.state.read_sstatus(XSTATUS_SIE) == 0 {
-                    return None;
-                }
+        // Check external interrupt for uart, virtio and the attached devices.
+        self.bus.update_device_interrupts();
+        if self.bus.uart.is_interrupting() {
+            self.bus.plic.update_pending(UART_IRQ);
+        }
+        if self.bus.virtio.is_interrupting() {
+            // An interrupt is raised after a disk access is done. A malformed request from the
+            // guest is dropped without an interrupt.
+            if Virtio::disk_access(self).is_ok() {
+                self.bus.plic.update_pending(VIRTIO_IRQ);
             }
-            _ => {}
         }
 
-        // TODO: Take interrupts based on priorities.
-
-        // Check external interrupt for uart and virtio.
-        let irq;
-        if self.bus.uart.is_interrupting() {
-            irq = UART_IRQ;
-        } else if self.bus.virtio.is_interrupting() {
-            // An interrupt is raised after a disk access is done.
-            Virtio::disk_access(self).expect("failed to access the disk");
-            irq = VIRTIO_IRQ;
-        } else {
-            irq = 0;
+        // PLIC context 0 is connected to the machine external interrupt and context 1 is
+        // connected to the supervisor external interrupt of hart 0. MEIP and SEIP follow the
+        // interrupt notifications, so they are cleared once the interrupts are claimed.
+        let mip = self.state.read(MIP);
+        let mut external = mip & !(MEIP_BIT | SEIP_BIT);
+        if self.bus.plic.is_interrupting(0) {
+            external |= MEIP_BIT;
         }
-
-        if irq != 0 {
-            // TODO: assume that hart is 0
-            // TODO: write a value to MCLAIM if the mode is machine
-            self.bus.plic.update_pending(irq);
-            self.state.write(MIP, self.state.read(MIP) | SEIP_BIT);
+        if self.bus.plic.is_interrupting(1) {
+            external |= SEIP_BIT;
+        }
+        if external != mip {
+            self.state.write(MIP, external);
         }
 
         // 3.1.9 Machine Interrupt Registers (mip and mie)
@@ -370,7 +531,34 @@ impl Cpu {
         // delegated privilege mode (S or U) and that mode’s interrupt enable bit (SIE or UIE in
         // mstatus) is set, or if the current privilege mode is less than the delegated privilege
         // mode."
-        let pending = self.state.read(MIE) & self.state.read(MIP);
+        let local = self.state.read(MIE) & self.state.read(MIP);
+
+        // 3.3.3 Wait for Interrupt
+        // "The WFI instruction can also be executed when interrupts are disabled. The operation of
+        // WFI must be unaffected by the global interrupt bits in mstatus (MIE and SIE) and the
+        // delegation register mideleg (i.e., the hart must resume if a locally enabled interrupt
+        // becomes pending, even if it has been delegated to a less-privileged mode)"
+        if local != 0 {
+            self.idle = false;
+        }
+
+        let mideleg = self.state.read(MIDELEG);
+        let mut pending = 0;
+        let machine_enabled = match self.mode {
+            Mode::Machine => self.state.read_mstatus(MSTATUS_MIE) == 1,
+            _ => true,
+        };
+        if machine_enabled {
+            pending |= local & !mideleg;
+        }
+        let supervisor_enabled = match self.mode {
+            Mode::Machine => false,
+            Mode::Supervisor => self.state.read_sstatus(XSTATUS_SIE) == 1,
+            _ => true,
+        };
+        if supervisor_enabled {
+            pending |= local & mideleg;
+        }
 
         if (pending & MEIP_BIT) != 0 {
             self.state.write(MIP, self.state.read(MIP) & !MEIP_BIT);
@@ -388,20 +576,62 @@ impl Cpu {
             self.state.write(MIP, self.state.read(MIP) & !SEIP_BIT);
             return Some(Interrupt::SupervisorExternalInterrupt);
         }
+        // SSIP and STIP are written only by software, so they stay pending until software clears
+        // them.
         if (pending & SSIP_BIT) != 0 {
-            self.state.write(MIP, self.state.read(MIP) & !SSIP_BIT);
             return Some(Interrupt::SupervisorSoftwareInterrupt);
         }
         if (pending & STIP_BIT) != 0 {
-            self.state.write(MIP, self.state.read(MIP) & !STIP_BIT);
             return Some(Interrupt::SupervisorTimerInterrupt);
         }
 
         return None;
     }
 
+    /// Set the FS field in the status register to Dirty because an instruction may modify the
+    /// floating-point state. An OS checks the field to decide whether to save the floating-point
+    /// registers on a context switch. Raises an illegal instruction exception if FS is Off.
+    fn mark_fs_dirty(&mut self, inst: u64) -> Result<(), Exception> {
+        // 3.1.6.5 Extension Context Status in mstatus Register
+        // "When an extension's status is set to Off, any instruction that attempts to read or
+        // write the corresponding state will cause an illegal instruction exception."
+        // "Implementations may choose to not track the dirtiness of the floating-point register
+        // file precisely, and may simply set FS to Dirty whenever an instruction that could
+        // modify the floating-point state is executed."
+        match self.state.read_mstatus(XSTATUS_FS) {
+            0b00 => return Err(Exception::IllegalInstruction(inst)),
+            0b11 => {}
+            _ => self.state.write_mstatus(XSTATUS_FS, 0b11),
+        }
+        Ok(())
+    }
+
+    /// Return true if the CSR at `addr` can be read, and written if `is_write` is true, by a CSR
+    /// instruction in the current privilege mode.
+    fn can_access_csr(&self, addr: CsrAddress, is_write: bool) -> bool {
+        if !self.state.is_implemented(addr) {
+            return false;
+        }
+        // 2.1 CSR Address Mapping Conventions
+        // "The top two bits (csr[11:10]) indicate whether the register is read/write (00, 01, or
+        // 10) or read-only (11). The next two bits (csr[9:8]) encode the lowest privilege level
+        // that can access the CSR."
+        // "Attempts to access a CSR without appropriate privilege level or to write a read-only
+        // register also raise illegal instruction exceptions."
+        if (self.mode as u16) < ((addr >> 8) & 0b11) || (is_write && (addr >> 10) == 0b11) {
+            return false;
+        }
+        // The floating-point CSRs can't be accessed either while FS is Off, and satp while TVM is
+        // set in S-mode.
+        match addr {
+            FFLAGS | FRM | FCSR => self.state.read_mstatus(XSTATUS_FS) != 0,
+            SATP => self.mode != Mode::Supervisor || self.state.read_mstatus(MSTATUS_TVM) == 0,
+            _ => true,
+        }
+    }
+
     /// Update the physical page number (PPN) and the addressing mode.
-    fn update_paging(&mut self) {
+    pub(crate) fn update_paging(&mut self) {
         // Read the physical page number (PPN) of the root page table, i.e., its
         // supervisor physical address divided by 4 KiB.
         self.page_table = self.state.read_bits(SATP, ..44) * PAGE_SIZE;
@@ -418,66 +648,33 @@ impl Cpu {
     }
 
     /// Translate a virtual address to a physical address for the paged virtual-memory system.
-    fn translate(&mut self, addr: u64, access_type: AccessType) -> Result<u64, Exception> {
-        if !self.enable_paging || self.mode == Mode::Machine {
+    pub(crate) fn translate(
+        &mut self,
+        addr: u64,
+        access_type: AccessType,
+    ) -> Result<u64, Exception> {
+        // 3.1.6.3 Memory Privilege in mstatus Register
+        // "When MPRV=1, load and store memory addresses are translated and protected, and
+        // endianness is applied, as though the current privilege mode were set to MPP.
+        // Instruction address-translation and protection are unaffected by the setting of MPRV."
+        let mode = if self.mode == Mode::Machine
+            && access_type != AccessType::Instruction
+            && self.state.read_mstatus(MSTATUS_MPRV) == 1
+        {
+            match self.state.read_mstatus(MSTATUS_MPP) {
+                0b00 => Mode::User,
+                0b01 => Mode::Supervisor,
+                _ => Mode::Machine,
+            }
+        } else {
+            self.mode
+        };
+        if !self.enable_paging || mode == Mode::Machine {
             return Ok(addr);
         }
 
-        // 4.3.2 Virtual Address Translation Process
-        // (The RISC-V Instruction Set Manual Volume II-Privileged Architecture_20190608)
-        // A virtual address va is translated into a physical address pa as follows:
-        let levels = 3;
-        let vpn = [
-            (addr >> 12) & 0x1ff,
-            (addr >> 21) & 0x1ff,
-            (addr >> 30) & 0x1ff,
-        ];
-
-        // 1. Let a be satp.ppn × PAGESIZE, and let i = LEVELS − 1. (For Sv32, PAGESIZE=212
-        //    and LEVELS=2.)
-        let mut a = self.page_table;
-        let mut i: i64 = levels - 1;
-        let mut pte;
-        loop {
-            // 2. Let pte be the value of the PTE at address a+va.vpn[i]×PTESIZE. (For Sv32,
-            //    PTESIZE=4.) If accessing pte violates a PMA or PMP check, raise an access
-            //    exception corresponding to the original access type.
-            pte = self.bus.read(a + vpn[i as usize] * 8, DOUBLEWORD)?;
-
-            // 3. If pte.v = 0, or if pte.r = 0 and pte.w = 1, stop and raise a page-fault
-            //    exception corresponding to the original access type.
-            let v = pte & 1;
-            let r = (pte >> 1) & 1;
-            let w = (pte >> 2) & 1;
-            let x = (pte >> 3) & 1;
-            if v == 0 || (r == 0 && w == 1) {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
-                    AccessType::Load => return Err(Exception::LoadPageFault(addr)),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
-                }
-            }
+        let (mut pte, pte_addr, level) = self.walk_page_table(addr, &access_type, false)?;
 
-            // 4. Otherwise, the PTE is valid. If pte.r = 1 or pte.x = 1, go to step 5.
-            //    Otherwise, this PTE is a pointer to the next level of the page table.
-            //    Let i = i − 1. If i < 0, stop and raise a page-fault exception
-            //    corresponding to the original access type. Otherwise,
-            //    let a = pte.ppn × PAGESIZE and go to step 2.
-            if r == 1 || x == 1 {
-                break;
-            }
-            i -= 1;
-            let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
-            a = ppn * PAGE_SIZE;
-            if i < 0 {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
-                    AccessType::Load => return Err(Exception::LoadPageFault(addr)),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
-                }
-            }
-        }
-        // TODO: implement step 5
         // 5. A leaf PTE has been found. Determine if the requested memory access is
         //    allowed by the pte.r, pte.w, pte.x, and pte.u bits, given the current
         //    privilege mode and the value of the SUM and MXR fields of the mstatus
@@ -497,27 +694,29 @@ impl Cpu {
         // accesses are permitted.  SUM has no effect when page-based virtual memory is not in
         // effect. Note that, while SUM is ordinarily ignored when not executing in S-mode, it is
         // in effect when MPRV=1 and MPP=S. SUM is hardwired to 0 if S-mode is not supported."
-
-        // 6. If i > 0 and pte.ppn[i−1:0] != 0, this is a misaligned superpage; stop and
-        //    raise a page-fault exception corresponding to the original access type.
-        let ppn = [
-            (pte >> 10) & 0x1ff,
-            (pte >> 19) & 0x1ff,
-            (pte >> 28) & 0x03ff_ffff,
-        ];
-        if i > 0 {
-            for j in (0..i).rev() {
-                if ppn[j as usize] != 0 {
-                    // A misaligned superpage.
-                    match access_type {
-                        AccessType::Instruction => {
-                            return Err(Exception::InstructionPageFault(addr))
-                        }
-                        AccessType::Load => return Err(Exception::LoadPageFault(addr)),
-                        AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
-                    }
-                }
+        let r = (pte >> 1) & 1;
+        let w = (pte >> 2) & 1;
+        let x = (pte >> 3) & 1;
+        let u = (pte >> 4) & 1;
+        let readable = r == 1 || (x == 1 && self.state.read_sstatus(XSTATUS_MXR) == 1);
+        let permitted = match access_type {
+            AccessType::Instruction => x == 1,
+            AccessType::Load => readable,
+            AccessType::Store => w == 1,
+        };
+        // 4.3.1 Addressing and Memory Protection
+        // "Irrespective of SUM, the supervisor may not execute code on pages with U=1."
+        let privileged = match mode {
+            Mode::User => u == 1,
+            Mode::Supervisor => {
+                u == 0
+                    || (access_type != AccessType::Instruction
+                        && self.state.read_sstatus(XSTATUS_SUM) == 1)
             }
+            _ => true,
+        };
+        if !permitted || !privileged {
+            return Err(page_fault(addr, &access_type));
         }
 
         // 7. If pte.a = 0, or if the memory access is a store and pte.d = 0, either raise
@@ -541,86 +740,118 @@ impl Cpu {
 
             // TODO: PMA or PMP check.
 
-            // Update the value of address satp.ppn × PAGESIZE + va.vpn[i] × PTESIZE with new pte
-            // value.
-            // TODO: If this is enabled, running xv6 fails.
-            //self.bus.write(self.page_table + vpn[i as usize] * 8, pte, 64)?;
+            // Update the leaf PTE, which is located at a+va.vpn[i]×PTESIZE, with the new value.
+            self.bus
+                .write(pte_addr, pte, DOUBLEWORD)
+                .map_err(|_| access_fault(&access_type))?;
         }
 
-        // 8. The translation is successful. The translated physical address is given as
-        //    follows:
-        //    • pa.pgoff = va.pgoff.
-        //    • If i > 0, then this is a superpage translation and pa.ppn[i−1:0] =
-        //    va.vpn[i−1:0].
-        //    • pa.ppn[LEVELS−1:i] = pte.ppn[LEVELS−1:i].
-        let offset = addr & 0xfff;
-        match i {
-            0 => {
-                let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
-                Ok((ppn << 12) | offset)
+        Ok(physical_address(addr, pte, level))
+    }
+
+    /// Translate a virtual address from a debugger to a physical address in the current privilege
+    /// mode. Unlike `translate`, it ignores MPRV and the permissions of the page, and it has no
+    /// side effect: the A and D bits aren't updated and no watchpoint is hit. Returns `None` if
+    /// the address isn't mapped.
+    pub fn translate_for_debugger(&mut self, addr: u64) -> Option<u64> {
+        if !self.enable_paging || self.mode == Mode::Machine {
+            return Some(addr);
+        }
+        let (pte, _, level) = self.walk_page_table(addr, &AccessType::Load, true).ok()?;
+        Some(physical_address(addr, pte, level))
+    }
+
+    /// Walk the page table for the virtual address `addr`, and return the leaf PTE, its address
+    /// and its level. The PTEs are read without checking watchpoints if `peek` is true.
+    fn walk_page_table(
+        &mut self,
+        addr: u64,
+        access_type: &AccessType,
+        peek: bool,
+    ) -> Result<(u64, u64, i64), Exception> {
+        // 4.3.2 Virtual Address Translation Process
+        // (The RISC-V Instruction Set Manual Volume II-Privileged Architecture_20190608)
+        // A virtual address va is translated into a physical address pa as follows:
+        let levels = 3;
+        let vpn = [
+            (addr >> 12) & 0x1ff,
+            (addr >> 21) & 0x1ff,
+            (addr >> 30) & 0x1ff,
+        ];
+
+        // 1. Let a be satp.ppn × PAGESIZE, and let i = LEVELS − 1. (For Sv32, PAGESIZE=212
+        //    and LEVELS=2.)
+        let mut a = self.page_table;
+        let mut i: i64 = levels - 1;
+        let mut pte;
+        let mut pte_addr;
+        loop {
+            // 2. Let pte be the value of the PTE at address a+va.vpn[i]×PTESIZE. (For Sv32,
+            //    PTESIZE=4.) If accessing pte violates a PMA or PMP check, raise an access
+            //    exception corresponding to the original access type.
+            pte_addr = a + vpn[i as usize] * 8;
+            let result = if peek {
+                self.bus.peek(pte_addr, DOUBLEWORD)
+            } else {
+                self.bus.read(pte_addr, DOUBLEWORD)
+            };
+            pte = result.map_err(|_| access_fault(access_type))?;
+
+            // 3. If pte.v = 0, or if pte.r = 0 and pte.w = 1, stop and raise a page-fault
+            //    exception corresponding to the original access type.
+            let v = pte & 1;
+            let r = (pte >> 1) & 1;
+            let w = (pte >> 2) & 1;
+            let x = (pte >> 3) & 1;
+            if v == 0 || (r == 0 && w == 1) {
+                return Err(page_fault(addr, access_type));
             }
-            1 => {
-                // Superpage translation. A superpage is a memory page of larger size than an
-                // ordinary page (4 KiB). It reduces TLB misses and improves performance.
-                Ok((ppn[2] << 30) | (ppn[1] << 21) | (vpn[0] << 12) | offset)
+
+            // 4. Otherwise, the PTE is valid. If pte.r = 1 or pte.x = 1, go to step 5.
+            //    Otherwise, this PTE is a pointer to the next level of the page table.
+            //    Let i = i − 1. If i < 0, stop and raise a page-fault exception
+            //    corresponding to the original access type. Otherwise,
+            //    let a = pte.ppn × PAGESIZE and go to step 2.
+            if r == 1 || x == 1 {
+                break;
             }
-            2 => {
-                // Superpage translation. A superpage is a memory page of larger size than an
-                // ordinary page (4 KiB). It reduces TLB misses and improves performance.
-                Ok((ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset)
+            i -= 1;
+            let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
+            a = ppn * PAGE_SIZE;
+            if i < 0 {
+                return Err(page_fault(addr, access_type));
             }
-            _ => match access_type {
-                AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
-                AccessType::Load => return Err(Exception::LoadPageFault(addr)),
-                AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
-            },
         }
+
+        // 6. If i > 0 and pte.ppn[i−1:0] != 0, this is a misaligned superpage; stop and
+        //    raise a page-fault exception corresponding to the original access type.
+        //    The permissions in step 5 are checked by the caller, and both steps raise the
+        //    same exception.
+        let ppn = [(pte >> 10) & 0x1ff, (pte >> 19) & 0x1ff];
+        if (0..i).any(|j| ppn[j as usize] != 0) {
+            return Err(page_fault(addr, access_type));
+        }
+        Ok((pte, pte_addr, i))
     }
 
     /// Read `size`-bit data from the system bus with the translation a virtual address to a physical address
     /// if it is enabled.
     fn read(&mut self, v_addr: u64, size: u8) -> Result<u64, Exception> {
-        let previous_mode = self.mode;
-
-        // 3.1.6.3 Memory Privilege in mstatus Register
-        // "When MPRV=1, load and store memory addresses are translated and protected, and
-        // endianness is applied, as though the current privilege mode were set to MPP."
-        if self.state.read_mstatus(MSTATUS_MPRV) == 1 {
-            self.mode = match self.state.read_mstatus(MSTATUS_MPP) {
-                0b00 => Mode::User,
-                0b01 => Mode::Supervisor,
-                0b11 => Mode::Machine,
-                _ => Mode::Debug,
-            };
-        }
-
         let p_addr = self.translate(v_addr, AccessType::Load)?;
         let result = self.bus.read(p_addr, size);
 
-        if self.state.read_mstatus(MSTATUS_MPRV) == 1 {
-            self.mode = previous_mode;
+        if let Ok(value) = result {
+            self.watchpoints.check(WatchKind::Read, v_addr, size, value);
+            if self.commit.is_some() {
+                self.record_load(v_addr);
+            }
         }
-
         result
     }
 
     /// Write `size`-bit data to the system bus with the translation a virtual address to a physical
     /// address if it is enabled.
     fn write(&mut self, v_addr: u64, value: u64, size: u8) -> Result<(), Exception> {
-        let previous_mode = self.mode;
-
-        // 3.1.6.3 Memory Privilege in mstatus Register
-        // "When MPRV=1, load and store memory addresses are translated and protected, and
-        // endianness is applied, as though the current privilege mode were set to MPP."
-        if self.state.read_mstatus(MSTATUS_MPRV) == 1 {
-            self.mode = match self.state.read_mstatus(MSTATUS_MPP) {
-                0b00 => Mode::User,
-                0b01 => Mode::Supervisor,
-                0b11 => Mode::Machine,
-                _ => Mode::Debug,
-            };
-        }
-
         // "The SC must fail if a write from some other device to the bytes accessed by the LR can
         // be observed to occur between the LR and SC."
         if self.reservation_set.contains(&v_addr) {
@@ -630,10 +861,13 @@ impl Cpu {
         let p_addr = self.translate(v_addr, AccessType::Store)?;
         let result = self.bus.write(p_addr, value, size);
 
-        if self.state.read_mstatus(MSTATUS_MPRV) == 1 {
-            self.mode = previous_mode;
+        if result.is_ok() {
+            self.watchpoints
+                .check(WatchKind::Write, v_addr, size, value);
+            if self.commit.is_some() {
+                self.record_store(v_addr, value, size);
+            }
         }
-
         result
     }
 
@@ -643,23 +877,64 @@ impl Cpu {
             return Err(Exception::InstructionAccessFault);
         }
 
-        let p_pc = self.translate(self.pc, AccessType::Instruction)?;
+        // A 32-bit instruction at the end of a page continues on the next page, which isn't
+        // contiguous in the physical memory when paging is enabled.
+        if size == WORD && (self.pc & (PAGE_SIZE - 1)) == PAGE_SIZE - 2 {
+            let low = self.fetch_at(self.pc, HALFWORD)?;
+            let high = self.fetch_at(self.pc.wrapping_add(2), HALFWORD)?;
+            return Ok(low | (high << 16));
+        }
+        self.fetch_at(self.pc, size)
+    }
+
+    /// Fetch `size`-bit data of an instruction from the memory at the virtual address `v_addr`.
+    fn fetch_at(&mut self, v_addr: u64, size: u8) -> Result<u64, Exception> {
+        let p_addr = self.translate(v_addr, AccessType::Instruction)?;
 
         // The result of the read method can be `Exception::LoadAccessFault`. In fetch(), an error
         // should be `Exception::InstructionAccessFault`.
-        match self.bus.read(p_pc, size) {
-            Ok(value) => Ok(value),
+        match self.bus.fetch(p_addr, size) {
+            Ok(value) => {
+                self.watchpoints
+                    .check(WatchKind::Execute, v_addr, size, value);
+                Ok(value)
+            }
             Err(_) => Err(Exception::InstructionAccessFault),
         }
     }
 
+    /// Return the first access which hit a watchpoint on virtual or physical addresses since the
+    /// last call, if any.
+    pub fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+        if self.watchpoints.is_empty() && self.bus.watchpoints.is_empty() {
+            return None;
+        }
+        let virtual_hit = self.watchpoints.take_hit();
+        let physical_hit = self.bus.watchpoints.take_hit();
+        virtual_hit.or(physical_hit)
+    }
+
     /// Execute a cycle on peripheral devices.
     pub fn devices_increment(&mut self) {
-        // TODO: mtime in Clint and TIME in CSR should be the same value.
-        // Increment the timer register (mtimer) in Clint.
+        // Advance the timer register (mtime) in CLINT, and sync the TIME register in CSR with it.
         self.bus.clint.increment(&mut self.state);
-        // Increment the value in the TIME and CYCLE registers in CSR.
-        self.state.increment_time();
+        self.state.set_time(self.bus.clint.mtime());
+        self.bus.tick();
+    }
+
+    /// Return true if an interrupt enabled in `mie` is pending, which wakes up the hart waiting for
+    /// an interrupt (WFI) even if it isn't taken.
+    pub fn is_interrupt_pending(&self) -> bool {
+        (self.state.read(MIE) & self.state.read(MIP)) != 0
+    }
+
+    /// Return true if the hart waits for an interrupt (WFI) and no interrupt is pending, and the
+    /// machine timer interrupt is disabled or disarmed. Only an interrupt from a device can wake up
+    /// the hart.
+    pub fn is_waiting_without_timer(&self) -> bool {
+        self.idle
+            && !self.is_interrupt_pending()
+            && ((self.state.read(MIE) & MTIP_BIT) == 0 || !self.bus.clint.is_timer_armed())
     }
 
     /// Execute an instruction. Raises an exception if something is wrong, otherwise, returns
@@ -682,13 +957,13 @@ impl Cpu {
                 inst = inst16;
                 self.execute_compressed(inst)?;
                 // Add 2 bytes to the program counter.
-                self.pc += 2;
+                self.pc = self.pc.wrapping_add(2);
             }
             _ => {
                 inst = self.fetch(WORD)?;
                 self.execute_general(inst)?;
                 // Add 4 bytes to the program counter.
-                self.pc += 4;
+                self.pc = self.pc.wrapping_add(4);
             }
         }
         self.pre_inst = inst;
@@ -702,6 +977,11 @@ impl Cpu {
         let opcode = inst & 0x3;
         let funct3 = (inst >> 13) & 0x7;
 
+        // c.fld, c.fsd, c.fldsp and c.fsdsp access the floating-point registers.
+        if (opcode == 0 || opcode == 2) && (funct3 == 0x1 || funct3 == 0x5) {
+            self.mark_fs_dirty(inst)?;
+        }
+
         // 3. Execute.
         // Compressed instructions have 3-bit field for popular registers, which correspond to
         // registers x8 to x15.
@@ -1324,6 +1604,11 @@ impl Cpu {
         let funct3 = (inst & 0x00007000) >> 12;
         let funct7 = (inst & 0xfe000000) >> 25;
 
+        // Floating-point loads, stores and computational instructions.
+        if matches!(opcode, 0x07 | 0x27 | 0x43 | 0x47 | 0x4b | 0x4f | 0x53) {
+            self.mark_fs_dirty(inst)?;
+        }
+
         // 3. Execute.
         match opcode {
             0x03 => {
@@ -2543,9 +2828,9 @@ impl Cpu {
                 let funct2 = (inst & 0x03000000) >> 25;
                 match funct2 {
                     0x0 => {
-                        // fnmadd.s
-                        inst_count!(self, "fnmadd.s");
-                        self.debug(inst, "fnmadd.s");
+                        // fnmsub.s
+                        inst_count!(self, "fnmsub.s");
+                        self.debug(inst, "fnmsub.s");
 
                         self.fregs.write(
                             rd,
@@ -2555,9 +2840,9 @@ impl Cpu {
                         );
                     }
                     0x1 => {
-                        // fnmadd.d
-                        inst_count!(self, "fnmadd.d");
-                        self.debug(inst, "fnmadd.d");
+                        // fnmsub.d
+                        inst_count!(self, "fnmsub.d");
+                        self.debug(inst, "fnmsub.d");
 
                         self.fregs.write(
                             rd,
@@ -2577,9 +2862,9 @@ impl Cpu {
                 let funct2 = (inst & 0x03000000) >> 25;
                 match funct2 {
                     0x0 => {
-                        // fnmsub.s
-                        inst_count!(self, "fnmsub.s");
-                        self.debug(inst, "fnmsub.s");
+                        // fnmadd.s
+                        inst_count!(self, "fnmadd.s");
+                        self.debug(inst, "fnmadd.s");
 
                         self.fregs.write(
                             rd,
@@ -2589,9 +2874,9 @@ impl Cpu {
                         );
                     }
                     0x1 => {
-                        // fnmsub.d
-                        inst_count!(self, "fnmsub.d");
-                        self.debug(inst, "fnmsub.d");
+                        // fnmadd.d
+                        inst_count!(self, "fnmadd.d");
+                        self.debug(inst, "fnmadd.d");
 
                         self.fregs.write(
                             rd,
@@ -3314,6 +3599,13 @@ impl Cpu {
             0x73 => {
                 // RV32I, RVZicsr, and supervisor ISA
                 let csr_addr = ((inst >> 20) & 0xfff) as u16;
+                if funct3 != 0x0 {
+                    // csrrs, csrrc, csrrsi and csrrci with rs1=x0 or uimm=0 don't write the CSR.
+                    let is_write = funct3 == 0x1 || funct3 == 0x5 || rs1 != 0;
+                    if !self.can_access_csr(csr_addr, is_write) {
+                        return Err(Exception::IllegalInstruction(inst));
+                    }
+                }
                 match funct3 {
                     0x0 => {
                         match (rs2, funct7) {
@@ -3359,6 +3651,16 @@ impl Cpu {
                                 inst_count!(self, "sret");
                                 self.debug(inst, "sret");
 
+                                // 3.1.6.4 Virtualization Support in mstatus Register
+                                // "When TSR=1, attempts to execute SRET while executing in S-mode
+                                // will raise an illegal instruction exception."
+                                if self.mode == Mode::User
+                                    || (self.mode == Mode::Supervisor
+                                        && self.state.read_mstatus(MSTATUS_TSR) == 1)
+                                {
+                                    return Err(Exception::IllegalInstruction(inst));
+                                }
+
                                 // "The RISC-V Reader" book says:
                                 // "Returns from a supervisor-mode exception handler. Sets the pc to
                                 // CSRs[sepc], the privilege mode to CSRs[sstatus].SPP,
@@ -3370,8 +3672,6 @@ impl Cpu {
                                 // counter (SEPC).
                                 self.pc = self.state.read(SEPC).wrapping_sub(4);
 
-                                // TODO: Check TSR field
-
                                 // Set the current privileged mode depending on a previous
                                 // privilege mode for supervisor mode (SPP, 8).
                                 self.mode = match self.state.read_sstatus(XSTATUS_SPP) {
@@ -3403,6 +3703,10 @@ impl Cpu {
                                 inst_count!(self, "mret");
                                 self.debug(inst, "mret");
 
+                                if self.mode != Mode::Machine {
+                                    return Err(Exception::IllegalInstruction(inst));
+                                }
+
                                 // "The RISC-V Reader" book says:
                                 // "Returns from a machine-mode exception handler. Sets the pc to
                                 // CSRs[mepc], the privilege mode to CSRs[mstatus].MPP,
@@ -3459,6 +3763,17 @@ impl Cpu {
                                 // sfence.vma
                                 inst_count!(self, "sfence.vma");
                                 self.debug(inst, "sfence.vma");
+
+                                // 3.1.6.4 Virtualization Support in mstatus Register
+                                // "When TVM=1, attempts to read or write the satp CSR or execute
+                                // an SFENCE.VMA instruction while executing in S-mode will raise
+                                // an illegal instruction exception."
+                                if self.mode == Mode::User
+                                    || (self.mode == Mode::Supervisor
+                                        && self.state.read_mstatus(MSTATUS_TVM) == 1)
+                                {
+                                    return Err(Exception::IllegalInstruction(inst));
+                                }
                                 // "SFENCE.VMA is used to synchronize updates to in-memory
                                 // memory-management data structures with current execution"
                             }
@@ -3484,6 +3799,7 @@ impl Cpu {
 
                         let t = self.state.read(csr_addr);
                         self.state.write(csr_addr, self.xregs.read(rs1));
+                        self.record_csr_write(csr_addr);
                         self.xregs.write(rd, t);
 
                         if csr_addr == SATP {
@@ -3495,8 +3811,12 @@ impl Cpu {
                         inst_count!(self, "csrrs");
                         self.debug(inst, "csrrs");
 
+                        // "If rs1=x0, then the instruction will not write to the CSR at all."
                         let t = self.state.read(csr_addr);
-                        self.state.write(csr_addr, t | self.xregs.read(rs1));
+                        if rs1 != 0 {
+                            self.state.write(csr_addr, t | self.xregs.read(rs1));
+                            self.record_csr_write(csr_addr);
+                        }
                         self.xregs.write(rd, t);
 
                         if csr_addr == SATP {
@@ -3509,7 +3829,10 @@ impl Cpu {
                         self.debug(inst, "csrrc");
 
                         let t = self.state.read(csr_addr);
-                        self.state.write(csr_addr, t & (!self.xregs.read(rs1)));
+                        if rs1 != 0 {
+                            self.state.write(csr_addr, t & (!self.xregs.read(rs1)));
+                            self.record_csr_write(csr_addr);
+                        }
                         self.xregs.write(rd, t);
 
                         if csr_addr == SATP {
@@ -3524,6 +3847,7 @@ impl Cpu {
                         let zimm = rs1;
                         self.xregs.write(rd, self.state.read(csr_addr));
                         self.state.write(csr_addr, zimm);
+                        self.record_csr_write(csr_addr);
 
                         if csr_addr == SATP {
                             self.update_paging();
@@ -3534,9 +3858,14 @@ impl Cpu {
                         inst_count!(self, "csrrsi");
                         self.debug(inst, "csrrsi");
 
+                        // "If the uimm[4:0] field is zero, then these instructions will not write
+                        // to the CSR."
                         let zimm = rs1;
                         let t = self.state.read(csr_addr);
-                        self.state.write(csr_addr, t | zimm);
+                        if zimm != 0 {
+                            self.state.write(csr_addr, t | zimm);
+                            self.record_csr_write(csr_addr);
+                        }
                         self.xregs.write(rd, t);
 
                         if csr_addr == SATP {
@@ -3550,7 +3879,10 @@ impl Cpu {
 
                         let zimm = rs1;
                         let t = self.state.read(csr_addr);
-                        self.state.write(csr_addr, t & (!zimm));
+                        if zimm != 0 {
+                            self.state.write(csr_addr, t & (!zimm));
+                            self.record_csr_write(csr_addr);
+                        }
                         self.xregs.write(rd, t);
 
                         if csr_addr == SATP {
@@ -3569,3 +3901,49 @@ impl Cpu {
         Ok(())
     }
 }
+
+/// Return the page-fault exception corresponding to the original access type.
+fn page_fault(addr: u64, access_type: &AccessType) -> Exception {
+    match access_type {
+        AccessType::Instruction => Exception::InstructionPageFault(addr),
+        AccessType::Load => Exception::LoadPageFault(addr),
+        AccessType::Store => Exception::StoreAMOPageFault(addr),
+    }
+}
+
+/// Return the access-fault exception corresponding to the original access type.
+fn access_fault(access_type: &AccessType) -> Exception {
+    match access_type {
+        AccessType::Instruction => Exception::InstructionAccessFault,
+        AccessType::Load => Exception::LoadAccessFault,
+        AccessType::Store => Exception::StoreAMOAccessFault,
+    }
+}
+
+/// Return the physical address of the virtual address `addr` mapped by the leaf PTE `pte` at the
+/// level `level`.
+fn physical_address(addr: u64, pte: u64, level: i64) -> u64 {
+    // 8. The translation is successful. The translated physical address is given as
+    //    follows:
+    //    • pa.pgoff = va.pgoff.
+    //    • If i > 0, then this is a superpage translation and pa.ppn[i−1:0] =
+    //    va.vpn[i−1:0].
+    //    • pa.ppn[LEVELS−1:i] = pte.ppn[LEVELS−1:i].
+    let offset = addr & 0xfff;
+    let vpn = [(addr >> 12) & 0x1ff, (addr >> 21) & 0x1ff];
+    let ppn = [
+        (pte >> 10) & 0x1ff,
+        (pte >> 19) & 0x1ff,
+        (pte >> 28) & 0x03ff_ffff,
+    ];
+    match level {
+        0 => {
+            let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
+            (ppn << 12) | offset
+        }
+        // Superpage translation. A superpage is a memory page of larger size than an ordinary
+        // page (4 KiB). It reduces TLB misses and improves performance.
+        1 => (ppn[2] << 30) | (ppn[1] << 21) | (vpn[0] << 12) | offset,
+        _ => (ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset,
+    }
+}
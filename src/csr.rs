@@ -1,8 +1,11 @@
 //! The csr module contains all the control and status registers.
 
 use std::fmt;
+use std::io::prelude::*;
 use std::ops::{Bound, Range, RangeBounds, RangeInclusive};
 
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
 pub type CsrAddress = u16;
 pub type CsrFieldRange = RangeInclusive<usize>;
 
@@ -29,15 +32,23 @@ const _UTVAL: CsrAddress = 0x043;
 
 // User floating-point CSRs.
 /// Flating-point accrued exceptions.
-const _FFLAGS: CsrAddress = 0x001;
+pub const FFLAGS: CsrAddress = 0x001;
 /// Floating-point dynamic rounding mode.
-const _FRB: CsrAddress = 0x002;
+pub const FRM: CsrAddress = 0x002;
 /// Floating-point control and status register (frm + fflags).
 pub const FCSR: CsrAddress = 0x003;
 
 // User Counter/Timers.
+/// Cycle counter for RDCYCLE instruction.
+const CYCLE: CsrAddress = 0xc00;
 /// Timer for RDTIME instruction.
 const TIME: CsrAddress = 0xc01;
+/// Instructions-retired counter for RDINSTRET instruction.
+const INSTRET: CsrAddress = 0xc02;
+/// Performance-monitoring counter.
+const HPMCOUNTER3: CsrAddress = 0xc03;
+/// Performance-monitoring counter.
+const HPMCOUNTER31: CsrAddress = 0xc1f;
 
 /////////////////////////////////////
 // Supervisor-level CSR addresses //
@@ -53,10 +64,12 @@ const SIDELEG: CsrAddress = 0x103;
 pub const SIE: CsrAddress = 0x104;
 /// Supervisor trap handler base address.
 pub const STVEC: CsrAddress = 0x105;
+/// Supervisor counter enable.
+const SCOUNTEREN: CsrAddress = 0x106;
 
 // Supervisor trap handling.
 /// Scratch register for supervisor trap handlers.
-const _SSCRATCH: CsrAddress = 0x140;
+const SSCRATCH: CsrAddress = 0x140;
 /// Supervisor exception program counter.
 pub const SEPC: CsrAddress = 0x141;
 /// Supervisor trap cause.
@@ -81,6 +94,12 @@ const SSTATUS_SUM_MASK: u64 = 0x40000; // sstatus[18]
 const SSTATUS_MXR_MASK: u64 = 0x80000; // sstatus[19]
 const SSTATUS_UXL_MASK: u64 = 0x3_00000000; // sstatus[33:32]
 const SSTATUS_SD_MASK: u64 = 0x80000000_00000000; // sstatus[63]
+const MSTATUS_SXL_MASK: u64 = 0xc_00000000; // mstatus[35:34]
+/// UXL and SXL are read-only and 2, which means that XLEN is 64 in U-mode and S-mode.
+const MSTATUS_XL_VALUE: u64 = 0xa_00000000;
+/// The value of mstatus at reset. FS is Initial, so programs running in M-mode can use the
+/// floating-point unit without enabling it.
+const MSTATUS_RESET_VALUE: u64 = MSTATUS_XL_VALUE | 0x2000;
 const SSTATUS_MASK: u64 = SSTATUS_SIE_MASK
     | SSTATUS_SPIE_MASK
     | SSTATUS_UBE_MASK
@@ -97,6 +116,12 @@ pub const XSTATUS_SIE: CsrFieldRange = 1..=1;
 pub const XSTATUS_SPIE: CsrFieldRange = 5..=5;
 /// Previous privilege mode for supervisor mode.
 pub const XSTATUS_SPP: CsrFieldRange = 8..=8;
+/// Floating-point unit status.
+pub const XSTATUS_FS: CsrFieldRange = 13..=14;
+/// Permit supervisor user memory access bit.
+pub const XSTATUS_SUM: CsrFieldRange = 18..=18;
+/// Make executable readable bit.
+pub const XSTATUS_MXR: CsrFieldRange = 19..=19;
 
 /////////////////////////////////
 // Machine-level CSR addresses //
@@ -125,11 +150,11 @@ pub const MIE: CsrAddress = 0x304;
 /// Machine trap-handler base address.
 pub const MTVEC: CsrAddress = 0x305;
 /// Machine counter enable.
-const _MCOUNTEREN: CsrAddress = 0x306;
+pub const MCOUNTEREN: CsrAddress = 0x306;
 
 // Machine trap handling.
 /// Scratch register for machine trap handlers.
-const _MSCRATCH: CsrAddress = 0x340;
+pub const MSCRATCH: CsrAddress = 0x340;
 /// Machine exception program counter.
 pub const MEPC: CsrAddress = 0x341;
 /// Machine trap cause.
@@ -140,10 +165,46 @@ pub const MTVAL: CsrAddress = 0x343;
 pub const MIP: CsrAddress = 0x344;
 
 // Machine memory protection.
-/// Physical memory protection configuration.
-const _PMPCFG0: CsrAddress = 0x3a0;
-/// Physical memory protection address register.
-const _PMPADDR0: CsrAddress = 0x3b0;
+/// Physical memory protection configuration for PMP entries 0-7.
+pub const PMPCFG0: CsrAddress = 0x3a0;
+/// Physical memory protection configuration for PMP entries 8-15. Odd-numbered configuration
+/// registers are illegal for RV64.
+pub const PMPCFG2: CsrAddress = 0x3a2;
+/// Physical memory protection address register for PMP entry 0.
+pub const PMPADDR0: CsrAddress = 0x3b0;
+/// Physical memory protection address register for PMP entry 15.
+pub const PMPADDR15: CsrAddress = 0x3bf;
+/// The bits which are writable in the PMP address registers. For RV64, each PMP address register
+/// encodes bits 55-2 of a 56-bit physical address.
+const PMPADDR_MASK: u64 = (1 << 54) - 1;
+
+// Machine counter/timers.
+/// Machine cycle counter.
+const MCYCLE: CsrAddress = 0xb00;
+/// Machine instructions-retired counter.
+const MINSTRET: CsrAddress = 0xb02;
+/// Machine performance-monitoring counter.
+const MHPMCOUNTER3: CsrAddress = 0xb03;
+/// Machine performance-monitoring counter.
+const MHPMCOUNTER31: CsrAddress = 0xb1f;
+
+// Machine counter setup.
+/// Machine counter-inhibit register.
+const MCOUNTINHIBIT: CsrAddress = 0x320;
+/// Machine performance-monitoring event selector.
+const MHPMEVENT3: CsrAddress = 0x323;
+/// Machine performance-monitoring event selector.
+const MHPMEVENT31: CsrAddress = 0x33f;
+
+// Debug/trace registers shared with debug mode.
+/// Debug/trace trigger register select.
+const TSELECT: CsrAddress = 0x7a0;
+/// First debug/trace trigger data register.
+const TDATA1: CsrAddress = 0x7a1;
+/// Second debug/trace trigger data register.
+const TDATA2: CsrAddress = 0x7a2;
+/// Third debug/trace trigger data register.
+const TDATA3: CsrAddress = 0x7a3;
 
 // MSTATUS fields.
 /// Global interrupt-enable bit for machine mode.
@@ -154,6 +215,10 @@ pub const MSTATUS_MPIE: CsrFieldRange = 7..=7;
 pub const MSTATUS_MPP: CsrFieldRange = 11..=12;
 /// Modify privilege bit.
 pub const MSTATUS_MPRV: CsrFieldRange = 17..=17;
+/// Trap virtual memory bit.
+pub const MSTATUS_TVM: CsrFieldRange = 20..=20;
+/// Trap SRET bit.
+pub const MSTATUS_TSR: CsrFieldRange = 22..=22;
 
 // MIP fields.
 /// Supervisor software interrupt.
@@ -168,6 +233,21 @@ pub const MTIP_BIT: u64 = 1 << 7;
 pub const SEIP_BIT: u64 = 1 << 9;
 /// Machine external interrupt.
 pub const MEIP_BIT: u64 = 1 << 11;
+/// The interrupts which can be delegated to supervisor mode.
+const MIDELEG_MASK: u64 = SSIP_BIT | STIP_BIT | SEIP_BIT;
+/// An environment call from M-mode can't be delegated to a lower privilege mode.
+const MEDELEG_MASK: u64 = !(1 << 11);
+
+/// The value of the misa register. It's read-only because all the extensions can't be disabled.
+const MISA_VALUE: u64 = (2 << 62) | // MXL[1:0]=2 (XLEN is 64)
+    (1 << 20) | // Extensions[20] (User mode implemented)
+    (1 << 18) | // Extensions[18] (Supervisor mode implemented)
+    (1 << 12) | // Extensions[12] (Integer Multiply/Divide extension)
+    (1 << 8) | // Extensions[8] (RV32I/64I/128I base ISA)
+    (1 << 5) | // Extensions[5] (Single-precision floating-point extension)
+    (1 << 3) | // Extensions[3] (Double-precision floating-point extension)
+    (1 << 2) | // Extensions[2] (Compressed extension)
+    1; // Extensions[0] (Atomic extension)
 
 /// The state to contains all the CSRs.
 pub struct State {
@@ -215,23 +295,83 @@ impl State {
     /// Create a new `state` object.
     pub fn new() -> Self {
         let mut csrs = [0; CSR_SIZE];
-        let misa: u64 = (2 << 62) | // MXL[1:0]=2 (XLEN is 64)
-            (1 << 20) | // Extensions[20] (User mode implemented)
-            (1 << 18) | // Extensions[18] (Supervisor mode implemented)
-            (1 << 12) | // Extensions[12] (Integer Multiply/Divide extension)
-            (1 << 8) | // Extensions[8] (RV32I/64I/128I base ISA)
-            (1 << 5) | // Extensions[5] (Single-precision floating-point extension)
-            (1 << 3) | // Extensions[3] (Double-precision floating-point extension)
-            (1 << 2) | // Extensions[2] (Compressed extension)
-            1; // Extensions[0] (Atomic extension)
-        csrs[MISA as usize] = misa;
+        csrs[MISA as usize] = MISA_VALUE;
+        csrs[MSTATUS as usize] = MSTATUS_RESET_VALUE;
 
         Self { csrs }
     }
 
-    /// Increment the value in the TIME register.
-    pub fn increment_time(&mut self) {
-        self.csrs[TIME as usize] = self.csrs[TIME as usize].wrapping_add(1);
+    /// Return the raw values of all the CSRs, which can be compared with the current ones by
+    /// `State::changed_since` later.
+    pub fn snapshot(&self) -> Vec<u64> {
+        self.csrs.to_vec()
+    }
+
+    /// Return the CSRs whose values changed since `snapshot` was taken and their new values.
+    pub fn changed_since(&self, snapshot: &[u64]) -> Vec<(CsrAddress, u64)> {
+        (0..CSR_SIZE)
+            .filter(|&i| snapshot.get(i) != Some(&self.csrs[i]))
+            .map(|i| (i as CsrAddress, self.read(i as CsrAddress)))
+            .collect()
+    }
+
+    /// Set the value in the TIME register, which is the same as `mtime` in CLINT.
+    pub fn set_time(&mut self, time: u64) {
+        self.csrs[TIME as usize] = time;
+    }
+
+    /// Return true if the CSR is implemented. Accessing a CSR that is not implemented by the CSR
+    /// instructions raises an illegal instruction exception, and software like OpenSBI probes
+    /// features by it.
+    pub fn is_implemented(&self, addr: CsrAddress) -> bool {
+        matches!(
+            addr,
+            FFLAGS
+                | FRM
+                | FCSR
+                | CYCLE
+                | TIME
+                | INSTRET
+                | HPMCOUNTER3..=HPMCOUNTER31
+                | SSTATUS
+                | SIE
+                | STVEC
+                | SCOUNTEREN
+                | SSCRATCH
+                | SEPC
+                | SCAUSE
+                | STVAL
+                | SIP
+                | SATP
+                | MVENDORID
+                | MARCHID
+                | MIMPID
+                | MHARTID
+                | MSTATUS
+                | MISA
+                | MEDELEG
+                | MIDELEG
+                | MIE
+                | MTVEC
+                | MCOUNTEREN
+                | MSCRATCH
+                | MEPC
+                | MCAUSE
+                | MTVAL
+                | MIP
+                | PMPCFG0
+                | PMPCFG2
+                | PMPADDR0..=PMPADDR15
+                | MCYCLE
+                | MINSTRET
+                | MHPMCOUNTER3..=MHPMCOUNTER31
+                | MCOUNTINHIBIT
+                | MHPMEVENT3..=MHPMEVENT31
+                | TSELECT
+                | TDATA1
+                | TDATA2
+                | TDATA3
+        )
     }
 
     /// Read the val from the CSR.
@@ -265,9 +405,34 @@ impl State {
             MARCHID => {}
             MIMPID => {}
             MHARTID => {}
+            // 3.1.1 Machine ISA Register misa
+            // "The misa CSR is a WARL read-write register reporting the ISA supported by the hart."
+            // All the extensions are always enabled, so writes are ignored.
+            MISA => {}
+            // The performance-monitoring counters and events are hardwired to zero.
+            HPMCOUNTER3..=HPMCOUNTER31 | MHPMCOUNTER3..=MHPMCOUNTER31 => {}
+            MHPMEVENT3..=MHPMEVENT31 => {}
+            // No triggers are implemented, so the debug/trace registers are hardwired to zero.
+            // The type 0 in tdata1 means that no trigger exists at the index in tselect.
+            TSELECT..=TDATA3 => {}
+            PMPADDR0..=PMPADDR15 => self.csrs[addr as usize] = val & PMPADDR_MASK,
+            MSTATUS => self.csrs[MSTATUS as usize] = with_sd_bit(with_xl_fields(val)),
             SSTATUS => {
-                self.csrs[MSTATUS as usize] =
-                    (self.csrs[MSTATUS as usize] & !SSTATUS_MASK) | (val & SSTATUS_MASK);
+                self.csrs[MSTATUS as usize] = with_sd_bit(with_xl_fields(
+                    (self.csrs[MSTATUS as usize] & !SSTATUS_MASK) | (val & SSTATUS_MASK),
+                ));
+            }
+            MEDELEG => self.csrs[MEDELEG as usize] = val & MEDELEG_MASK,
+            MIDELEG => self.csrs[MIDELEG as usize] = val & MIDELEG_MASK,
+            // 4.1.11 Supervisor Address Translation and Protection (satp) Register
+            // "Implementations are not required to support all MODE settings, and if satp is
+            // written with an unsupported MODE, the entire write has no effect; no fields in satp
+            // are modified."
+            SATP => {
+                let mode = val >> 60;
+                if mode == 0 || mode == 8 {
+                    self.csrs[SATP as usize] = val;
+                }
             }
             SIE => {
                 self.csrs[MIE as usize] = (self.csrs[MIE as usize] & !self.csrs[MIDELEG as usize])
@@ -367,17 +532,78 @@ impl State {
     /// Reset all the CSRs.
     pub fn reset(&mut self) {
         self.csrs = [0; CSR_SIZE];
+        self.csrs[MISA as usize] = MISA_VALUE;
+        self.csrs[MSTATUS as usize] = MSTATUS_RESET_VALUE;
+    }
 
-        let misa: u64 = (2 << 62) | // MXL[1:0]=2 (XLEN is 64)
-            (1 << 18) | // Extensions[18] (Supervisor mode implemented)
-            (1 << 12) | // Extensions[12] (Integer Multiply/Divide extension)
-            (1 << 8) | // Extensions[8] (RV32I/64I/128I base ISA)
-            (1 << 5) | // Extensions[5] (Single-precision floating-point extension)
-            (1 << 3) | // Extensions[3] (Double-precision floating-point extension)
-            (1 << 2) | // Extensions[2] (Compressed extension)
-            1; // Extensions[0] (Atomic extension)
-        self.csrs[MISA as usize] = misa;
+    /// Write the raw values of all the CSRs to a snapshot.
+    pub(crate) fn save_snapshot<W: Write>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<(), SnapshotError> {
+        writer.write_u64s(&self.csrs)
     }
+
+    /// Restore the raw values of all the CSRs from a snapshot.
+    pub(crate) fn load_snapshot<R: Read>(
+        &mut self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<(), SnapshotError> {
+        reader.read_u64s(&mut self.csrs)
+    }
+}
+
+/// Return the name of the CSR at `addr`, e.g. `mstatus`, if it's implemented.
+pub fn csr_name(addr: CsrAddress) -> Option<String> {
+    let name = match addr {
+        FFLAGS => "fflags",
+        FRM => "frm",
+        FCSR => "fcsr",
+        CYCLE => "cycle",
+        TIME => "time",
+        INSTRET => "instret",
+        HPMCOUNTER3..=HPMCOUNTER31 => return Some(format!("hpmcounter{}", addr - CYCLE)),
+        SSTATUS => "sstatus",
+        SIE => "sie",
+        STVEC => "stvec",
+        SCOUNTEREN => "scounteren",
+        SSCRATCH => "sscratch",
+        SEPC => "sepc",
+        SCAUSE => "scause",
+        STVAL => "stval",
+        SIP => "sip",
+        SATP => "satp",
+        MVENDORID => "mvendorid",
+        MARCHID => "marchid",
+        MIMPID => "mimpid",
+        MHARTID => "mhartid",
+        MSTATUS => "mstatus",
+        MISA => "misa",
+        MEDELEG => "medeleg",
+        MIDELEG => "mideleg",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MCOUNTEREN => "mcounteren",
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        PMPCFG0 => "pmpcfg0",
+        PMPCFG2 => "pmpcfg2",
+        PMPADDR0..=PMPADDR15 => return Some(format!("pmpaddr{}", addr - PMPADDR0)),
+        MCYCLE => "mcycle",
+        MINSTRET => "minstret",
+        MHPMCOUNTER3..=MHPMCOUNTER31 => return Some(format!("mhpmcounter{}", addr - MCYCLE)),
+        MCOUNTINHIBIT => "mcountinhibit",
+        MHPMEVENT3..=MHPMEVENT31 => return Some(format!("mhpmevent{}", addr - MCOUNTINHIBIT)),
+        TSELECT => "tselect",
+        TDATA1 => "tdata1",
+        TDATA2 => "tdata2",
+        TDATA3 => "tdata3",
+        _ => return None,
+    };
+    Some(name.to_string())
 }
 
 /// Convert the val implement `RangeBounds` to the `Range` struct.
@@ -395,3 +621,20 @@ fn to_range<T: RangeBounds<usize>>(generic_range: &T, bit_length: usize) -> Rang
 
     start..end
 }
+
+/// Set the read-only UXL and SXL fields in the status register.
+fn with_xl_fields(status: u64) -> u64 {
+    (status & !(SSTATUS_UXL_MASK | MSTATUS_SXL_MASK)) | MSTATUS_XL_VALUE
+}
+
+/// Set or clear the SD bit in the status register. SD summarizes whether either the FS field or
+/// the XS field signals the presence of some dirty state.
+fn with_sd_bit(status: u64) -> u64 {
+    if (status & SSTATUS_FS_MASK) == SSTATUS_FS_MASK
+        || (status & SSTATUS_XS_MASK) == SSTATUS_XS_MASK
+    {
+        status | SSTATUS_SD_MASK
+    } else {
+        status & !SSTATUS_SD_MASK
+    }
+}
@@ -12,29 +12,85 @@
 // - https://github.com/qemu/qemu/blob/master/hw/intc/sifive_clint.c
 // - https://github.com/qemu/qemu/blob/master/include/hw/intc/sifive_clint.h
 
+use std::io::prelude::*;
+use std::time::{Duration, Instant};
+
 use crate::bus::CLINT_BASE;
 use crate::cpu::{BYTE, DOUBLEWORD, HALFWORD, WORD};
 use crate::csr::{State, MIP, MSIP_BIT, MTIP_BIT};
+use crate::dtb::TIMEBASE_FREQUENCY;
 use crate::exception::Exception;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 
 /// The address that a msip register starts. A msip is a machine mode software interrupt pending
 /// register, used to assert a software interrupt for a CPU.
 const MSIP: u64 = CLINT_BASE;
-/// The address that a msip register ends. `msip` is a 4-byte register.
+/// The address that a msip register ends (exclusive). `msip` is a 4-byte register.
 const MSIP_END: u64 = MSIP + 0x4;
 
 /// The address that a mtimecmp register starts. A mtimecmp is a memory mapped machine mode timer
 /// compare register, used to trigger an interrupt when mtimecmp is greater than or equal to mtime.
 const MTIMECMP: u64 = CLINT_BASE + 0x4000;
-/// The address that a mtimecmp register ends. `mtimecmp` is a 8-byte register.
+/// The address that a mtimecmp register ends (exclusive). `mtimecmp` is a 8-byte register.
 const MTIMECMP_END: u64 = MTIMECMP + 0x8;
 
 /// The address that a timer register starts. A mtime is a machine mode timer register which runs
 /// at a constant frequency.
 const MTIME: u64 = CLINT_BASE + 0xbff8;
-/// The address that a timer register ends. `mtime` is a 8-byte register.
+/// The address that a timer register ends (exclusive). `mtime` is a 8-byte register.
 const MTIME_END: u64 = MTIME + 0x8;
 
+/// The number of cycles between samples of the host clock in the wall-clock mode. The guest sees
+/// the time advance in steps of this many cycles.
+const HOST_CLOCK_INTERVAL: u64 = 10_000;
+
+/// The mode of the timer, which `mtime` and the `time` CSR count with the frequency in the
+/// `timebase-frequency` property of the device tree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimerMode {
+    /// The timer advances by `ticks_per_instruction` in every cycle, which executes an instruction
+    /// or waits for an interrupt. The guest's time only depends on the instructions executed, so
+    /// an execution is reproducible.
+    Deterministic { ticks_per_instruction: u64 },
+    /// The timer follows the monotonic clock of the host. The clock is sampled periodically, and
+    /// the samples are inputs to the guest, which are recorded and replayed like the inputs via
+    /// UART.
+    WallClock,
+}
+
+impl Default for TimerMode {
+    fn default() -> Self {
+        TimerMode::Deterministic {
+            ticks_per_instruction: 1,
+        }
+    }
+}
+
+/// The monotonic clock of the host, which counts from `mtime` at a point of time.
+struct HostClock {
+    /// The time when `mtime` was `base_mtime`.
+    base: Instant,
+    base_mtime: u64,
+    /// The number of cycles until the next sample.
+    countdown: u64,
+}
+
+impl HostClock {
+    fn new(mtime: u64) -> Self {
+        Self {
+            base: Instant::now(),
+            base_mtime: mtime,
+            countdown: HOST_CLOCK_INTERVAL,
+        }
+    }
+
+    /// Return the current value of `mtime`.
+    fn now(&self) -> u64 {
+        let ticks = self.base.elapsed().as_nanos() * TIMEBASE_FREQUENCY as u128 / 1_000_000_000;
+        self.base_mtime.wrapping_add(ticks as u64)
+    }
+}
+
 /// The core-local interruptor (CLINT).
 /// 0x0000 msip for hart 0 (4 bytes)
 /// 0x4000 mtimecmp for hart 0 (8 bytes)
@@ -48,6 +104,10 @@ pub struct Clint {
     mtimecmp: u64,
     /// Machine mode timer register which runs at a constant frequency.
     mtime: u64,
+    /// The ticks added to `mtime` in every cycle. It's 0 in the wall-clock mode.
+    ticks_per_cycle: u64,
+    /// The clock which `mtime` follows in the wall-clock mode.
+    host_clock: Option<HostClock>,
 }
 
 impl Clint {
@@ -57,19 +117,104 @@ impl Clint {
             msip: 0,
             mtimecmp: 0,
             mtime: 0,
+            ticks_per_cycle: 1,
+            host_clock: None,
+        }
+    }
+
+    /// Set the mode of the timer. `mtime` keeps counting from the current value.
+    pub fn set_mode(&mut self, mode: TimerMode) {
+        match mode {
+            TimerMode::Deterministic {
+                ticks_per_instruction,
+            } => {
+                self.ticks_per_cycle = ticks_per_instruction;
+                self.host_clock = None;
+            }
+            TimerMode::WallClock => {
+                self.ticks_per_cycle = 0;
+                self.host_clock = Some(HostClock::new(self.mtime));
+            }
+        }
+    }
+
+    /// Return the value of the timer, which is the same as the `time` CSR.
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    /// Sample the host clock if it's time to do so in the wall-clock mode, and return the new
+    /// value of `mtime`.
+    #[inline]
+    pub fn sample_host_clock(&mut self) -> Option<u64> {
+        let clock = self.host_clock.as_mut()?;
+        clock.countdown -= 1;
+        if clock.countdown > 0 {
+            return None;
+        }
+        clock.countdown = HOST_CLOCK_INTERVAL;
+        self.mtime = clock.now();
+        Some(self.mtime)
+    }
+
+    /// Set `mtime` to a sample of the host clock, which is replayed from a log. The host clock
+    /// counts from the sample afterward.
+    pub fn receive_time(&mut self, mtime: u64) {
+        self.mtime = mtime;
+        self.rebase_host_clock();
+    }
+
+    /// Advance `mtime` to the cycle before the deadline in `mtimecmp` while the hart waits for the
+    /// timer interrupt, so that the interrupt is raised in the next cycle. It returns false without
+    /// doing anything in the wall-clock mode, where the emulator waits for the host clock instead,
+    /// or if the timer doesn't advance and never reaches the deadline.
+    pub fn skip_to_deadline(&mut self) -> bool {
+        if self.host_clock.is_some() || self.ticks_per_cycle == 0 {
+            return false;
+        }
+        let deadline = self.mtimecmp.saturating_sub(self.ticks_per_cycle);
+        self.mtime = self.mtime.max(deadline);
+        true
+    }
+
+    /// Return how long the host clock takes to reach the deadline in `mtimecmp` in the wall-clock
+    /// mode. It's zero if the deadline has passed.
+    pub fn host_time_until_deadline(&self) -> Option<Duration> {
+        let clock = self.host_clock.as_ref()?;
+        let ticks = self.mtimecmp.saturating_sub(clock.now()) as u128;
+        let nanos = ticks * 1_000_000_000 / TIMEBASE_FREQUENCY as u128;
+        Some(Duration::from_nanos(nanos.min(u64::MAX as u128) as u64))
+    }
+
+    /// Sample the host clock in the next cycle instead of after the interval, e.g. when the
+    /// deadline has passed while the emulator waited for it.
+    pub fn request_host_clock_sample(&mut self) {
+        if let Some(clock) = self.host_clock.as_mut() {
+            clock.countdown = 1;
+        }
+    }
+
+    /// Make the host clock count from the current value of `mtime`, after `mtime` is set.
+    fn rebase_host_clock(&mut self) {
+        if let Some(clock) = self.host_clock.as_mut() {
+            clock.base = Instant::now();
+            clock.base_mtime = self.mtime;
         }
     }
 
-    /// Increment the mtimer register. It's not a real-time value. The MTIP bit (MIP, 7) is enabled
-    /// when `mtime` is greater than or equal to `mtimecmp`.
+    /// Advance the timer by a cycle. The MTIP bit (MIP, 7) is enabled when `mtime` is greater
+    /// than or equal to `mtimecmp`.
     pub fn increment(&mut self, state: &mut State) {
-        self.mtime = self.mtime.wrapping_add(1);
-        // Sync TIME csr.
-        //state.write(TIME, self.mtime);
+        self.mtime = self.mtime.wrapping_add(self.ticks_per_cycle);
 
+        // The MSIP bit (MIP, 3) reflects the least significant bit of `msip`, so the software
+        // interrupt is cleared when `msip` is written to 0.
         if (self.msip & 1) != 0 {
             // Enable the MSIP bit (MIP, 3).
             state.write(MIP, state.read(MIP) | MSIP_BIT);
+        } else {
+            // Clear the MSIP bit (MIP, 3).
+            state.write(MIP, state.read(MIP) & !MSIP_BIT);
         }
 
         // 3.1.10 Machine Timer Registers (mtime and mtimecmp)
@@ -89,14 +234,42 @@ impl Clint {
         }
     }
 
+    /// Return true if the timer interrupt will be raised in the future. The timer is disarmed by
+    /// setting `mtimecmp` to the maximum value.
+    pub fn is_timer_armed(&self) -> bool {
+        self.mtimecmp != u64::MAX
+    }
+
+    /// Write the registers to a snapshot.
+    pub(crate) fn save_snapshot<W: Write>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<(), SnapshotError> {
+        writer.write_u32(self.msip)?;
+        writer.write_u64(self.mtimecmp)?;
+        writer.write_u64(self.mtime)
+    }
+
+    /// Restore the registers from a snapshot.
+    pub(crate) fn load_snapshot<R: Read>(
+        &mut self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<(), SnapshotError> {
+        self.msip = reader.read_u32()?;
+        self.mtimecmp = reader.read_u64()?;
+        self.mtime = reader.read_u64()?;
+        self.rebase_host_clock();
+        Ok(())
+    }
+
     /// Load `size`-bit data from a register located at `addr` in CLINT.
     pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
         // `reg` is the value of a target register in CLINT and `offset` is the byte of the start
         // position in the register.
         let (reg, offset) = match addr {
-            MSIP..=MSIP_END => (self.msip as u64, addr - MSIP),
-            MTIMECMP..=MTIMECMP_END => (self.mtimecmp, addr - MTIMECMP),
-            MTIME..=MTIME_END => (self.mtime, addr - MTIME),
+            _ if (MSIP..MSIP_END).contains(&addr) => (self.msip as u64, addr - MSIP),
+            _ if (MTIMECMP..MTIMECMP_END).contains(&addr) => (self.mtimecmp, addr - MTIMECMP),
+            _ if (MTIME..MTIME_END).contains(&addr) => (self.mtime, addr - MTIME),
             _ => return Err(Exception::LoadAccessFault),
         };
 
@@ -114,9 +287,9 @@ impl Clint {
         // `reg` is the value of a target register in CLINT and `offset` is the byte of the start
         // position in the register.
         let (mut reg, offset) = match addr {
-            MSIP..=MSIP_END => (self.msip as u64, addr - MSIP),
-            MTIMECMP..=MTIMECMP_END => (self.mtimecmp, addr - MTIMECMP),
-            MTIME..=MTIME_END => (self.mtime, addr - MTIME),
+            _ if (MSIP..MSIP_END).contains(&addr) => (self.msip as u64, addr - MSIP),
+            _ if (MTIMECMP..MTIMECMP_END).contains(&addr) => (self.mtimecmp, addr - MTIMECMP),
+            _ if (MTIME..MTIME_END).contains(&addr) => (self.mtime, addr - MTIME),
             _ => return Err(Exception::StoreAMOAccessFault),
         };
 
@@ -144,9 +317,14 @@ impl Clint {
 
         // Store the new value to the target register.
         match addr {
-            MSIP..=MSIP_END => self.msip = reg as u32,
-            MTIMECMP..=MTIMECMP_END => self.mtimecmp = reg,
-            MTIME..=MTIME_END => self.mtime = reg,
+            // Only the least significant bit of `msip` is writable and other bits are hardwired to
+            // zero.
+            _ if (MSIP..MSIP_END).contains(&addr) => self.msip = (reg as u32) & 1,
+            _ if (MTIMECMP..MTIMECMP_END).contains(&addr) => self.mtimecmp = reg,
+            _ if (MTIME..MTIME_END).contains(&addr) => {
+                self.mtime = reg;
+                self.rebase_host_clock();
+            }
             _ => return Err(Exception::StoreAMOAccessFault),
         }
 
@@ -0,0 +1,204 @@
+//! The htif module contains the host-target interface (HTIF) used by Spike and the riscv-tests. A
+//! guest sends a command to the host by writing it to the `tohost` variable in memory, and the host
+//! responds via the `fromhost` variable.
+
+// Reference:
+// - https://github.com/riscv-software-src/riscv-isa-sim/blob/master/fesvr/htif.cc
+// - https://github.com/riscv-software-src/riscv-isa-sim/blob/master/fesvr/syscall.cc
+// - https://github.com/riscv-software-src/riscv-isa-sim/blob/master/fesvr/device.cc
+
+use std::io;
+use std::io::prelude::*;
+
+use log::{debug, error};
+
+use crate::cpu::{BYTE, DOUBLEWORD};
+use crate::dram::Dram;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+/// The device which proxies system calls to the host, or ends the emulation.
+const DEVICE_SYSCALL: u64 = 0;
+/// The device which reads and writes characters from/to the console of the host.
+const DEVICE_CONSOLE: u64 = 1;
+
+/// The command of the console device to output a character.
+const CONSOLE_PUTCHAR: u64 = 1;
+
+/// The system call to write data to a file descriptor.
+const SYS_WRITE: u64 = 64;
+/// The system call to end the program.
+const SYS_EXIT: u64 = 93;
+/// The error number for an unsupported system call, which is returned as a negative value.
+const ENOSYS: u64 = 38;
+
+/// The maximum number of bytes buffered at once by a `write` system call. The length is
+/// controlled by a guest, so the data is written to the host in chunks of this size.
+const WRITE_CHUNK_SIZE: usize = 4096;
+
+/// The mask for the payload in the lower 48 bits of a command.
+const PAYLOAD_MASK: u64 = 0xffff_ffff_ffff;
+
+/// The host-target interface. It's disabled until the address of `tohost` is set.
+/// A command written to `tohost` has the following format:
+/// 63:56 device
+/// 55:48 command
+/// 47:0 payload
+pub struct Htif {
+    /// The address of the `tohost` variable, if any.
+    tohost: Option<u64>,
+    /// The address of the `fromhost` variable, if any. The host doesn't respond to commands if
+    /// it's `None`.
+    fromhost: Option<u64>,
+    /// The exit code sent by a guest and not taken yet.
+    exit_code: Option<u64>,
+}
+
+impl Htif {
+    /// Create a new disabled HTIF object.
+    pub fn new() -> Self {
+        Self {
+            tohost: None,
+            fromhost: None,
+            exit_code: None,
+        }
+    }
+
+    /// Enable the interface with the addresses of `tohost` and `fromhost`.
+    pub fn enable(&mut self, tohost: u64, fromhost: Option<u64>) {
+        self.tohost = Some(tohost);
+        self.fromhost = fromhost;
+    }
+
+    /// Return the addresses of `tohost` and `fromhost` if the interface is enabled.
+    pub fn addresses(&self) -> Option<(u64, Option<u64>)> {
+        self.tohost.map(|tohost| (tohost, self.fromhost))
+    }
+
+    /// Return the exit code sent by a guest, if any, and clear it.
+    pub fn take_exit_code(&mut self) -> Option<u64> {
+        self.exit_code.take()
+    }
+
+    /// Write the addresses of `tohost` and `fromhost`, and the exit code not taken yet, to a
+    /// snapshot.
+    pub(crate) fn save_snapshot<W: Write>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<(), SnapshotError> {
+        writer.write_option(self.tohost)?;
+        writer.write_option(self.fromhost)?;
+        writer.write_option(self.exit_code)
+    }
+
+    /// Restore the addresses of `tohost` and `fromhost`, and the exit code not taken yet, from a
+    /// snapshot.
+    pub(crate) fn load_snapshot<R: Read>(
+        &mut self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<(), SnapshotError> {
+        self.tohost = reader.read_option()?;
+        self.fromhost = reader.read_option()?;
+        self.exit_code = reader.read_option()?;
+        Ok(())
+    }
+
+    /// Return true if a `size`-bit access at `addr` overlaps `tohost`.
+    pub fn is_tohost(&self, addr: u64, size: u8) -> bool {
+        match self.tohost {
+            Some(tohost) => {
+                addr < tohost.wrapping_add(8) && tohost < addr.wrapping_add((size / 8) as u64)
+            }
+            None => false,
+        }
+    }
+
+    /// Handle a command in `tohost` after it's written. The host clears `tohost` to accept the
+    /// command, and writes a response to `fromhost` if the command has one.
+    pub fn handle(&mut self, dram: &mut Dram) {
+        let tohost = match self.tohost {
+            Some(tohost) => tohost,
+            None => return,
+        };
+        let value = match dram.read(tohost, DOUBLEWORD) {
+            Ok(value) if value != 0 => value,
+            _ => return,
+        };
+        let _ = dram.write(tohost, 0, DOUBLEWORD);
+
+        let device = value >> 56;
+        let command = (value >> 48) & 0xff;
+        let payload = value & PAYLOAD_MASK;
+        match device {
+            // The least significant bit is set when the guest ends with an exit code.
+            DEVICE_SYSCALL if payload & 1 == 1 => self.exit_code = Some(payload >> 1),
+            DEVICE_SYSCALL => {
+                self.syscall(dram, payload);
+                self.respond(dram, DEVICE_SYSCALL, command, 1);
+            }
+            DEVICE_CONSOLE if command == CONSOLE_PUTCHAR => {
+                output(&[payload as u8]);
+                self.respond(dram, DEVICE_CONSOLE, command, 0x100 | (payload & 0xff));
+            }
+            _ => debug!("unsupported HTIF command: {:#x}", value),
+        }
+    }
+
+    /// Execute a system call whose number and arguments are stored at `addr` as 64-bit values,
+    /// and store the return value at `addr`.
+    fn syscall(&mut self, dram: &mut Dram, addr: u64) {
+        let arg = |i: u64| dram.read(addr + i * 8, DOUBLEWORD).unwrap_or(0);
+        let (number, arg0, arg1, arg2) = (arg(0), arg(1), arg(2), arg(3));
+
+        let ret = match number {
+            SYS_WRITE if arg0 == 1 || arg0 == 2 => {
+                let mut written = 0;
+                let mut chunk = Vec::with_capacity(WRITE_CHUNK_SIZE);
+                while written < arg2 {
+                    // Stop at the first byte which can't be read.
+                    match dram.read(arg1.wrapping_add(written), BYTE) {
+                        Ok(byte) => chunk.push(byte as u8),
+                        Err(_) => break,
+                    }
+                    written += 1;
+                    if chunk.len() == WRITE_CHUNK_SIZE {
+                        output(&chunk);
+                        chunk.clear();
+                    }
+                }
+                output(&chunk);
+                written
+            }
+            SYS_EXIT => {
+                self.exit_code = Some(arg0);
+                0
+            }
+            _ => {
+                debug!("unsupported HTIF system call: {}", number);
+                ENOSYS.wrapping_neg()
+            }
+        };
+        let _ = dram.write(addr, ret, DOUBLEWORD);
+    }
+
+    /// Write a response to `fromhost`.
+    fn respond(&self, dram: &mut Dram, device: u64, command: u64, payload: u64) {
+        if let Some(fromhost) = self.fromhost {
+            let value = (device << 56) | (command << 48) | (payload & PAYLOAD_MASK);
+            let _ = dram.write(fromhost, value, DOUBLEWORD);
+        }
+    }
+}
+
+impl Default for Htif {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Output bytes from a guest to the console.
+fn output(bytes: &[u8]) {
+    let mut stdout = io::stdout();
+    if let Err(e) = stdout.write_all(bytes).and_then(|_| stdout.flush()) {
+        error!("failed to write the output of HTIF: {}", e);
+    }
+}
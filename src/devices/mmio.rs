@@ -0,0 +1,38 @@
+//! The mmio module contains a memory-mapped region whose registers are implemented by closures on
+//! the host. It allows embedders to co-simulate their own hardware models without implementing
+//! the `Device` trait.
+
+use crate::devices::Device;
+use crate::exception::Exception;
+
+/// A closure to load `size`-bit data from a physical address. It returns the loaded value.
+pub type MmioRead = dyn FnMut(u64, u8) -> u64;
+/// A closure to store `size`-bit data to a physical address. The arguments are the address, the
+/// size and the value.
+pub type MmioWrite = dyn FnMut(u64, u8, u64);
+
+/// A memory-mapped region which calls closures for every access. The closures receive the
+/// physical address, not the offset in the region.
+pub struct Mmio {
+    base: u64,
+    read: Box<MmioRead>,
+    write: Box<MmioWrite>,
+}
+
+impl Mmio {
+    /// Create a new region at `base` with closures for loads and stores.
+    pub fn new(base: u64, read: Box<MmioRead>, write: Box<MmioWrite>) -> Self {
+        Self { base, read, write }
+    }
+}
+
+impl Device for Mmio {
+    fn read(&mut self, offset: u64, size: u8) -> Result<u64, Exception> {
+        Ok((self.read)(self.base + offset, size))
+    }
+
+    fn write(&mut self, offset: u64, value: u64, size: u8) -> Result<(), Exception> {
+        (self.write)(self.base + offset, size, value);
+        Ok(())
+    }
+}
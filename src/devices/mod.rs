@@ -1,7 +1,11 @@
-//! The devices module contains peripheral devices.
+//! The devices module contains peripheral devices and the `Device` trait for memory-mapped devices
+//! attached to the system bus.
 
 pub mod clint;
+pub mod htif;
+pub mod mmio;
 pub mod plic;
+pub mod test_finisher;
 pub mod virtio_blk;
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -14,3 +18,47 @@ pub use uart_cli as uart;
 
 #[cfg(target_arch = "wasm32")]
 pub use uart_wasm as uart;
+
+use crate::dtb::FdtWriter;
+use crate::exception::Exception;
+
+/// A memory-mapped device which can be attached to the system bus by `Bus::attach`. Addresses
+/// passed to the device are offsets from the base address where the device is attached.
+pub trait Device {
+    /// Load `size`-bit data from a register located at `offset` in the device.
+    fn read(&mut self, offset: u64, size: u8) -> Result<u64, Exception>;
+
+    /// Store `size`-bit data to a register located at `offset` in the device.
+    fn write(&mut self, offset: u64, value: u64, size: u8) -> Result<(), Exception>;
+
+    /// Execute a cycle on the device. It's called once per CPU cycle.
+    fn tick(&mut self) {}
+
+    /// Return true if the device has work in progress which completes in a later cycle, e.g. a
+    /// transfer which raises an interrupt when it's done. The emulator doesn't skip the time while
+    /// the hart waits for an interrupt and a device is busy.
+    fn is_busy(&self) -> bool {
+        false
+    }
+
+    /// Return the interrupt request number which the device raises via PLIC, if any.
+    fn irq(&self) -> Option<u64> {
+        None
+    }
+
+    /// Return true if the device raises an interrupt. It's checked once per CPU cycle, and PLIC
+    /// latches the interrupt until it's claimed.
+    fn is_interrupting(&mut self) -> bool {
+        false
+    }
+
+    /// Return the name of the device tree node for the device, e.g. "uart". The node is named
+    /// "<name>@<base address>". The device isn't described in the device tree if it's `None`.
+    fn node_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Add the properties specific to the device, e.g. "compatible", to the device tree node. The
+    /// "reg", "interrupts" and "interrupt-parent" properties are added by the bus.
+    fn node_properties(&self, _fdt: &mut FdtWriter) {}
+}
@@ -12,9 +12,12 @@
 // - https://github.com/qemu/qemu/blob/master/hw/intc/sifive_plic.c
 // - https://github.com/qemu/qemu/blob/master/include/hw/intc/sifive_plic.h
 
+use std::io::prelude::*;
+
 use crate::bus::PLIC_BASE;
 use crate::cpu::WORD;
 use crate::exception::Exception;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 
 /// The address for interrupt source priority. 1024 4-byte registers exist. Each interrupt into the
 /// PLIC has a configurable priority, from 1-7, with 7 being the highest priority. A value of 0
@@ -63,6 +66,13 @@ const WORD_SIZE: u64 = 0x4;
 const CONTEXT_OFFSET: u64 = 0x1000;
 const SOURCE_NUM: u64 = 1024;
 
+/// The number of interrupt sources advertised to the guest, which is the same as the QEMU virt
+/// machine. Devices can use the interrupt request numbers from 1 to `PLIC_NDEV`.
+pub const PLIC_NDEV: u64 = 0x35;
+
+/// The number of contexts. Context 0 is M-mode and context 1 is S-mode of hart 0.
+const CONTEXT_NUM: usize = 2;
+
 /// The platform-level-interrupt controller (PLIC).
 pub struct Plic {
     /// The interrupt priority for each interrupt source. A priority value of 0 is reserved to mean
@@ -76,10 +86,10 @@ pub struct Plic {
     enable: [u32; 64],
     /// The settings of a interrupt priority threshold of each context. The PLIC will mask all PLIC
     /// interrupts of a priority less than or equal to `threshold`.
-    threshold: [u32; 2],
-    /// The ID of the highest priority pending interrupt or zero if there is no pending interrupt
-    /// for each context.
-    claim: [u32; 2],
+    threshold: [u32; CONTEXT_NUM],
+    /// True if an interrupt notification is sent to each context, i.e., the context has a pending
+    /// and enabled interrupt whose priority is greater than the threshold.
+    interrupting: [bool; CONTEXT_NUM],
 }
 
 impl Plic {
@@ -89,34 +99,64 @@ impl Plic {
             priority: [0; 1024],
             pending: [0; 32],
             enable: [0; 64],
-            threshold: [0; 2],
-            claim: [0; 2],
+            threshold: [0; CONTEXT_NUM],
+            interrupting: [false; CONTEXT_NUM],
         }
     }
 
     /// Sets IRQ bit in `pending`.
     pub fn update_pending(&mut self, irq: u64) {
-        let index = irq.wrapping_div(WORD_SIZE);
-        self.pending[index as usize] = self.pending[index as usize] | (1 << irq);
+        let index = (irq.wrapping_rem(SOURCE_NUM)).wrapping_div(WORD_SIZE * 8);
+        let offset = (irq.wrapping_rem(SOURCE_NUM)).wrapping_rem(WORD_SIZE * 8);
+        self.pending[index as usize] |= 1 << offset;
 
-        self.update_claim(irq);
+        self.update_interrupting();
     }
 
     /// Clears IRQ bit in `pending`.
     fn clear_pending(&mut self, irq: u64) {
-        let index = irq.wrapping_div(WORD_SIZE);
-        self.pending[index as usize] = self.pending[index as usize] & !(1 << irq);
+        let index = (irq.wrapping_rem(SOURCE_NUM)).wrapping_div(WORD_SIZE * 8);
+        let offset = (irq.wrapping_rem(SOURCE_NUM)).wrapping_rem(WORD_SIZE * 8);
+        self.pending[index as usize] &= !(1 << offset);
+
+        self.update_interrupting();
+    }
 
-        self.update_claim(0);
+    /// Returns true if the PLIC notifies the `context` of an interrupt. Context 0 is connected to
+    /// the machine external interrupt and context 1 is connected to the supervisor external
+    /// interrupt.
+    pub fn is_interrupting(&self, context: u64) -> bool {
+        self.interrupting[context as usize]
+    }
+
+    /// Update the interrupt notifications for all contexts.
+    fn update_interrupting(&mut self) {
+        for context in 0..CONTEXT_NUM {
+            self.interrupting[context] = self.highest_pending(context as u64) != 0;
+        }
     }
 
-    /// Sets IRQ bit in `claim` for context 1.
-    fn update_claim(&mut self, irq: u64) {
-        // TODO: Support highest priority to the `claim` register.
-        // claim[1] is claim/complete registers for S-mode (context 1). SCLAIM.
-        if self.is_enable(1, irq) || irq == 0 {
-            self.claim[1] = irq as u32;
+    /// Returns the ID of the highest priority pending interrupt for the `context`, or zero if there
+    /// is no pending interrupt. Ties are broken by the lowest ID.
+    fn highest_pending(&self, context: u64) -> u64 {
+        let mut max_irq = 0;
+        let mut max_priority = self.threshold[context as usize];
+        for (index, pending) in self.pending.iter().enumerate() {
+            if *pending == 0 {
+                continue;
+            }
+            for offset in 0..(WORD_SIZE * 8) {
+                let irq = index as u64 * WORD_SIZE * 8 + offset;
+                if ((pending >> offset) & 1) == 1
+                    && self.is_enable(context, irq)
+                    && self.priority[irq as usize] > max_priority
+                {
+                    max_irq = irq;
+                    max_priority = self.priority[irq as usize];
+                }
+            }
         }
+        max_irq
     }
 
     /// Returns true if the enable bit for the `irq` of the `context` is set.
@@ -126,8 +166,38 @@ impl Plic {
         return ((self.enable[(context * 32 + index) as usize] >> offset) & 1) == 1;
     }
 
+    /// Write the registers and the interrupt notifications to a snapshot.
+    pub(crate) fn save_snapshot<W: Write>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<(), SnapshotError> {
+        writer.write_u32s(&self.priority)?;
+        writer.write_u32s(&self.pending)?;
+        writer.write_u32s(&self.enable)?;
+        writer.write_u32s(&self.threshold)?;
+        for interrupting in self.interrupting.iter() {
+            writer.write_bool(*interrupting)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the registers and the interrupt notifications from a snapshot.
+    pub(crate) fn load_snapshot<R: Read>(
+        &mut self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<(), SnapshotError> {
+        reader.read_u32s(&mut self.priority)?;
+        reader.read_u32s(&mut self.pending)?;
+        reader.read_u32s(&mut self.enable)?;
+        reader.read_u32s(&mut self.threshold)?;
+        for interrupting in self.interrupting.iter_mut() {
+            *interrupting = reader.read_bool()?;
+        }
+        Ok(())
+    }
+
     /// Load `size`-bit data from a register located at `addr` in PLIC.
-    pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
+    pub fn read(&mut self, addr: u64, size: u8) -> Result<u64, Exception> {
         // TODO: should support byte-base access.
         if size != WORD {
             return Err(Exception::LoadAccessFault);
@@ -161,7 +231,12 @@ impl Plic {
                 if offset == 0 {
                     Ok(self.threshold[context as usize] as u64)
                 } else if offset == 4 {
-                    Ok(self.claim[context as usize] as u64)
+                    // 9 Interrupt Claim Process
+                    // "A successful claim will also atomically clear the corresponding pending
+                    // bit on the interrupt source."
+                    let irq = self.highest_pending(context);
+                    self.clear_pending(irq);
+                    Ok(irq)
                 } else {
                     return Err(Exception::LoadAccessFault);
                 }
@@ -184,6 +259,7 @@ impl Plic {
                 }
                 let index = (addr - SOURCE_PRIORITY).wrapping_div(WORD_SIZE);
                 self.priority[index as usize] = value as u32;
+                self.update_interrupting();
             }
             PENDING..=PENDING_END => {
                 if (addr - PENDING).wrapping_rem(WORD_SIZE) != 0 {
@@ -191,6 +267,7 @@ impl Plic {
                 }
                 let index = (addr - PENDING).wrapping_div(WORD_SIZE);
                 self.pending[index as usize] = value as u32;
+                self.update_interrupting();
             }
             ENABLE..=ENABLE_END => {
                 if (addr - ENABLE).wrapping_rem(WORD_SIZE) != 0 {
@@ -198,17 +275,18 @@ impl Plic {
                 }
                 let index = (addr - ENABLE).wrapping_div(WORD_SIZE);
                 self.enable[index as usize] = value as u32;
+                self.update_interrupting();
             }
             THRESHOLD_AND_CLAIM..=THRESHOLD_AND_CLAIM_END => {
                 let context = (addr - THRESHOLD_AND_CLAIM).wrapping_div(CONTEXT_OFFSET);
                 let offset = addr - (THRESHOLD_AND_CLAIM + CONTEXT_OFFSET * context);
                 if offset == 0 {
                     self.threshold[context as usize] = value as u32;
+                    self.update_interrupting();
                 } else if offset == 4 {
-                    //self.claim[context as usize] = value as u32;
-
-                    // Clear pending bit.
-                    self.clear_pending(value);
+                    // 10 Interrupt Completion
+                    // The pending bit is already cleared by the claim, and the gateway forwards a
+                    // new request immediately, so nothing to do.
                 } else {
                     return Err(Exception::StoreAMOAccessFault);
                 }
@@ -0,0 +1,113 @@
+//! The test_finisher module contains the SiFive test finisher, which is a syscon (system
+//! controller) that a guest writes to in order to power off or reset the machine. Linux's
+//! `poweroff` and `reboot`, OpenSBI's system reset and test programs use it to end the emulation.
+
+// QEMU SiFive test device used in the virt machine:
+// - https://github.com/qemu/qemu/blob/master/hw/misc/sifive_test.c
+// - https://github.com/qemu/qemu/blob/master/include/hw/misc/sifive_test.h
+
+use std::io::prelude::*;
+
+use crate::bus::TEST_BASE;
+use crate::cpu::WORD;
+use crate::exception::Exception;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+/// The address of the finisher register.
+const FINISHER: u64 = TEST_BASE;
+
+/// The status written to the lower 16 bits of the register to report a failure. The upper 16 bits
+/// hold the exit code.
+const FINISHER_FAIL: u64 = 0x3333;
+/// The status written to the register to report a success.
+const FINISHER_PASS: u64 = 0x5555;
+/// The status written to the register to reset the machine.
+const FINISHER_RESET: u64 = 0x7777;
+
+/// The request made by a guest via the test finisher.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FinisherRequest {
+    /// Power off the machine after a success.
+    Pass,
+    /// Power off the machine after a failure with an exit code.
+    Fail(u16),
+    /// Reset the machine.
+    Reset,
+}
+
+/// The SiFive test finisher.
+/// 0x0 finisher (4 bytes, write-only)
+pub struct TestFinisher {
+    /// The request written by a guest and not taken yet.
+    request: Option<FinisherRequest>,
+}
+
+impl TestFinisher {
+    /// Create a new test finisher object.
+    pub fn new() -> Self {
+        Self { request: None }
+    }
+
+    /// Return the request written by a guest, if any, and clear it.
+    pub fn take_request(&mut self) -> Option<FinisherRequest> {
+        self.request.take()
+    }
+
+    /// Write the request not taken yet to a snapshot, as the value written to the register, or 0
+    /// if there's none.
+    pub(crate) fn save_snapshot<W: Write>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<(), SnapshotError> {
+        let value = match self.request {
+            None => 0,
+            Some(FinisherRequest::Pass) => FINISHER_PASS,
+            Some(FinisherRequest::Fail(code)) => ((code as u64) << 16) | FINISHER_FAIL,
+            Some(FinisherRequest::Reset) => FINISHER_RESET,
+        };
+        writer.write_u32(value as u32)
+    }
+
+    /// Restore the request not taken yet from a snapshot.
+    pub(crate) fn load_snapshot<R: Read>(
+        &mut self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<(), SnapshotError> {
+        let value = reader.read_u32()? as u64;
+        self.request = match value & 0xffff {
+            _ if value == 0 => None,
+            FINISHER_FAIL => Some(FinisherRequest::Fail((value >> 16) as u16)),
+            FINISHER_PASS => Some(FinisherRequest::Pass),
+            FINISHER_RESET => Some(FinisherRequest::Reset),
+            _ => return Err(SnapshotError::Corrupted("test finisher request")),
+        };
+        Ok(())
+    }
+
+    /// Load `size`-bit data from a register located at `addr` in the test finisher. The register
+    /// always reads as zero.
+    pub fn read(&self, _addr: u64, _size: u8) -> Result<u64, Exception> {
+        Ok(0)
+    }
+
+    /// Store `size`-bit data to a register located at `addr` in the test finisher. A write of an
+    /// unknown status is ignored.
+    pub fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
+        if addr != FINISHER || size != WORD {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+        match value & 0xffff {
+            FINISHER_FAIL => self.request = Some(FinisherRequest::Fail((value >> 16) as u16)),
+            FINISHER_PASS => self.request = Some(FinisherRequest::Pass),
+            FINISHER_RESET => self.request = Some(FinisherRequest::Reset),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Default for TestFinisher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
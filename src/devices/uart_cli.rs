@@ -5,14 +5,19 @@
 use std::io;
 use std::io::prelude::*;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Condvar, Mutex,
+    atomic::{AtomicUsize, Ordering},
+    mpsc::{self, Receiver, RecvTimeoutError},
+    Arc,
 };
 use std::thread;
+use std::time::Duration;
+
+use log::error;
 
 use crate::bus::{UART_BASE, UART_SIZE};
 use crate::cpu::BYTE;
 use crate::exception::Exception;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 
 /// The interrupt request of UART.
 pub const UART_IRQ: u64 = 10;
@@ -21,18 +26,27 @@ pub const UART_IRQ: u64 = 10;
 const UART_RHR: u64 = UART_BASE + 0;
 /// Transmit holding register (for output bytes).
 const UART_THR: u64 = UART_BASE + 0;
+/// Divisor latch LSB. It's accessed instead of RHR/THR when the DLAB bit in LCR is set.
+const UART_DLL: u64 = UART_BASE;
 /// Interrupt enable register.
-const _UART_IER: u64 = UART_BASE + 1;
-/// FIFO control register.
-const _UART_FCR: u64 = UART_BASE + 2;
+/// IER BIT 0: enable the receiver ready interrupt.
+/// IER BIT 1: enable the transmitter empty interrupt.
+const UART_IER: u64 = UART_BASE + 1;
+/// Divisor latch MSB. It's accessed instead of IER when the DLAB bit in LCR is set.
+const UART_DLM: u64 = UART_BASE + 1;
+/// FIFO control register. It's write-only and shares the address with ISR.
+const UART_FCR: u64 = UART_BASE + 2;
 /// Interrupt status register.
 /// ISR BIT-0:
 ///     0 = an interrupt is pending and the ISR contents may be used as a pointer to the appropriate
 /// interrupt service routine.
 ///     1 = no interrupt is pending.
-const _UART_ISR: u64 = UART_BASE + 2;
+/// ISR BIT 1-3: the source of the interrupt with the highest priority.
+/// ISR BIT 6-7: set when the FIFO is enabled by FCR.
+const UART_ISR: u64 = UART_BASE + 2;
 /// Line control register.
-const _UART_LCR: u64 = UART_BASE + 3;
+/// LCR BIT 7: the divisor latch access bit (DLAB).
+const UART_LCR: u64 = UART_BASE + 3;
 /// Line status register.
 /// LSR BIT 0:
 ///     0 = no data in receive holding register or FIFO.
@@ -40,82 +54,233 @@ const _UART_LCR: u64 = UART_BASE + 3;
 /// LSR BIT 5:
 ///     0 = transmit holding register is full. 16550 will not accept any data for transmission.
 ///     1 = transmitter hold register (or FIFO) is empty. CPU can load the next character.
+/// LSR BIT 6:
+///     0 = transmitter holding and shift registers are full.
+///     1 = transmit holding register and shift register are empty.
 const UART_LSR: u64 = UART_BASE + 5;
 
 /// The receiver (RX).
 const UART_LSR_RX: u8 = 1;
 /// The transmitter (TX).
 const UART_LSR_TX: u8 = 1 << 5;
+/// The transmitter is idle.
+const UART_LSR_TEMT: u8 = 1 << 6;
+
+/// The receiver ready interrupt.
+const UART_IER_RDI: u8 = 1;
+/// The transmitter holding register empty interrupt.
+const UART_IER_THRI: u8 = 1 << 1;
+
+/// No interrupt is pending.
+const UART_ISR_NO_INT: u8 = 0x1;
+/// The transmitter holding register is empty.
+const UART_ISR_THRI: u8 = 0x2;
+/// Received data is available.
+const UART_ISR_RDI: u8 = 0x4;
+/// The FIFO is enabled.
+const UART_ISR_FIFO: u8 = 0xc0;
+
+/// The divisor latch access bit.
+const UART_LCR_DLAB: u8 = 1 << 7;
 
 /// The UART, the size of which is 0x100 (2**8).
 pub struct Uart {
-    uart: Arc<(Mutex<[u8; UART_SIZE as usize]>, Condvar)>,
-    interrupting: Arc<AtomicBool>,
+    /// The registers.
+    uart: [u8; UART_SIZE as usize],
+    /// The bytes read from stdin by the thread waiting for input. They are moved to the receive
+    /// holding register by `Uart::receive_host_input` between cycles, so that the guest sees an
+    /// input at a well-defined point of the execution.
+    input: Receiver<u8>,
+    /// The number of bytes in `input`. It's checked every cycle instead of `input`, because it's
+    /// cheaper.
+    input_len: Arc<AtomicUsize>,
+    /// The byte taken from `input` by `Uart::wait_for_host_input`. It's still counted in
+    /// `input_len`.
+    next_input: Option<u8>,
+    /// The interrupt enable register.
+    ier: u8,
+    /// The FIFO control register.
+    fcr: u8,
+    /// The divisor latch (DLL and DLM). The baud rate has no effect on the emulation.
+    divisor: [u8; 2],
+    /// True if the transmitter holding register empty interrupt is pending. It's cleared by
+    /// reading ISR or writing THR.
+    thre_pending: bool,
+    /// The level of the interrupt line in the previous check.
+    interrupt_level: bool,
 }
 
 impl Uart {
     /// Create a new UART object.
     pub fn new() -> Self {
-        let uart = Arc::new((Mutex::new([0; UART_SIZE as usize]), Condvar::new()));
-        let interrupting = Arc::new(AtomicBool::new(false));
-        {
-            let (uart, _cvar) = &*uart;
-            let mut uart = uart.lock().expect("failed to get an UART object");
-            // Transmitter hold register is empty. It allows input anytime.
-            uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_TX;
-        }
+        let mut uart = [0; UART_SIZE as usize];
+        // Transmitter hold register is empty. It allows input anytime.
+        uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_TX | UART_LSR_TEMT;
 
         // Create a new thread for waiting for input.
-        let mut byte = [0; 1];
-        let cloned_uart = uart.clone();
-        let cloned_interrupting = interrupting.clone();
-        let _uart_thread_for_read = thread::spawn(move || loop {
-            match io::stdin().read(&mut byte) {
-                Ok(_) => {
-                    let (uart, cvar) = &*cloned_uart;
-                    let mut uart = uart.lock().expect("failed to get an UART object");
-                    // Wait for the thread to start up.
-                    while (uart[(UART_LSR - UART_BASE) as usize] & UART_LSR_RX) == 1 {
-                        uart = cvar.wait(uart).expect("the mutex is poisoned");
+        let (sender, input) = mpsc::channel();
+        let input_len = Arc::new(AtomicUsize::new(0));
+        let cloned_input_len = input_len.clone();
+        let _uart_thread_for_read = thread::spawn(move || {
+            let mut byte = [0; 1];
+            loop {
+                match io::stdin().read(&mut byte) {
+                    // No more input comes after EOF.
+                    Ok(0) => break,
+                    Ok(_) => {
+                        // The UART has been dropped.
+                        if sender.send(byte[0]).is_err() {
+                            break;
+                        }
+                        cloned_input_len.fetch_add(1, Ordering::Release);
+                    }
+                    Err(e) => {
+                        error!("input via UART is error: {}", e);
                     }
-                    uart[0] = byte[0];
-                    cloned_interrupting.store(true, Ordering::Release);
-                    // Data has been receive.
-                    uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_RX;
-                }
-                Err(e) => {
-                    println!("input via UART is error: {}", e);
                 }
             }
         });
 
-        Self { uart, interrupting }
+        Self {
+            uart,
+            input,
+            input_len,
+            next_input: None,
+            ier: 0,
+            fcr: 0,
+            divisor: [0; 2],
+            thre_pending: false,
+            interrupt_level: false,
+        }
+    }
+
+    /// Return true if a byte is in the receive holding register.
+    fn is_rx_ready(&self) -> bool {
+        (self.uart[(UART_LSR - UART_BASE) as usize] & UART_LSR_RX) != 0
+    }
+
+    /// Move a byte read from stdin to the receive holding register if the register is empty, and
+    /// return the byte.
+    #[inline]
+    pub fn receive_host_input(&mut self) -> Option<u8> {
+        if self.is_rx_ready() || self.input_len.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        let byte = match self.next_input.take() {
+            Some(byte) => byte,
+            None => self.input.try_recv().ok()?,
+        };
+        self.input_len.fetch_sub(1, Ordering::Relaxed);
+        self.receive(byte);
+        Some(byte)
+    }
+
+    /// Wait for a byte from stdin for at most `timeout`. The byte is moved to the receive holding
+    /// register by `Uart::receive_host_input` as usual.
+    pub fn wait_for_host_input(&mut self, timeout: Duration) {
+        // A byte isn't received until the guest reads the previous one.
+        if self.is_rx_ready() {
+            thread::sleep(timeout);
+            return;
+        }
+        if self.next_input.is_some() || self.input_len.load(Ordering::Acquire) > 0 {
+            return;
+        }
+        match self.input.recv_timeout(timeout) {
+            Ok(byte) => self.next_input = Some(byte),
+            Err(RecvTimeoutError::Timeout) => {}
+            // No more input comes after EOF.
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(timeout),
+        }
+    }
+
+    /// Put a byte in the receive holding register as if it's received from the outside. A byte
+    /// which hasn't been read by the guest is overwritten.
+    pub fn receive(&mut self, byte: u8) {
+        self.uart[(UART_RHR - UART_BASE) as usize] = byte;
+        self.uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_RX;
+    }
+
+    /// Return the interrupt identification with the highest priority.
+    fn interrupt_id(&self) -> u8 {
+        if (self.ier & UART_IER_RDI) != 0 && self.is_rx_ready() {
+            UART_ISR_RDI
+        } else if (self.ier & UART_IER_THRI) != 0 && self.thre_pending {
+            UART_ISR_THRI
+        } else {
+            UART_ISR_NO_INT
+        }
+    }
+
+    /// Return true if an interrupt is newly raised since the last check. The PLIC latches the
+    /// interrupt, so only the rising edge of the interrupt line is reported.
+    pub fn is_interrupting(&mut self) -> bool {
+        let level = self.interrupt_id() != UART_ISR_NO_INT;
+        let rising = level && !self.interrupt_level;
+        self.interrupt_level = level;
+        rising
+    }
+
+    /// Write the registers to a snapshot.
+    pub(crate) fn save_snapshot<W: Write>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<(), SnapshotError> {
+        writer.write_bytes(&self.uart)?;
+        writer.write_u8(self.ier)?;
+        writer.write_u8(self.fcr)?;
+        writer.write_bytes(&self.divisor)?;
+        writer.write_bool(self.thre_pending)?;
+        writer.write_bool(self.interrupt_level)
     }
 
-    /// Return true if an interrupt is pending. Clear the interrupting flag by swapping a value.
-    pub fn is_interrupting(&self) -> bool {
-        self.interrupting.swap(false, Ordering::Acquire)
+    /// Restore the registers from a snapshot.
+    pub(crate) fn load_snapshot<R: Read>(
+        &mut self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<(), SnapshotError> {
+        reader.read_bytes(&mut self.uart)?;
+        self.ier = reader.read_u8()?;
+        self.fcr = reader.read_u8()?;
+        reader.read_bytes(&mut self.divisor)?;
+        self.thre_pending = reader.read_bool()?;
+        self.interrupt_level = reader.read_bool()?;
+        Ok(())
     }
 
-    /// Read a byte from the receive holding register.
+    /// Read a byte from a register located at `index` in UART.
     pub fn read(&mut self, index: u64, size: u8) -> Result<u64, Exception> {
         if size != BYTE {
             return Err(Exception::LoadAccessFault);
         }
 
-        let (uart, cvar) = &*self.uart;
-        let mut uart = uart.lock().expect("failed to get an UART object");
+        let dlab = (self.uart[(UART_LCR - UART_BASE) as usize] & UART_LCR_DLAB) != 0;
         match index {
+            UART_DLL if dlab => Ok(self.divisor[0] as u64),
+            UART_DLM if dlab => Ok(self.divisor[1] as u64),
             UART_RHR => {
-                cvar.notify_one();
-                uart[(UART_LSR - UART_BASE) as usize] &= !UART_LSR_RX;
-                Ok(uart[(UART_RHR - UART_BASE) as usize] as u64)
+                self.uart[(UART_LSR - UART_BASE) as usize] &= !UART_LSR_RX;
+                Ok(self.uart[(UART_RHR - UART_BASE) as usize] as u64)
             }
-            _ => Ok(uart[(index - UART_BASE) as usize] as u64),
+            UART_IER => Ok(self.ier as u64),
+            UART_ISR => {
+                let id = self.interrupt_id();
+                // Reading ISR clears the transmitter empty interrupt if it's the source.
+                if id == UART_ISR_THRI {
+                    self.thre_pending = false;
+                }
+                let fifo = if (self.fcr & 1) != 0 {
+                    UART_ISR_FIFO
+                } else {
+                    0
+                };
+                Ok((id | fifo) as u64)
+            }
+            _ => Ok(self.uart[(index - UART_BASE) as usize] as u64),
         }
     }
 
-    /// Write a byte to the transmit holding register.
+    /// Write a byte to a register located at `index` in UART.
     pub fn write(&mut self, index: u64, value: u8, size: u8) -> Result<(), Exception> {
         if size != BYTE {
             return Err(Exception::StoreAMOAccessFault);
@@ -131,15 +296,30 @@ impl Uart {
         // e.g. (riscv-pk):
         //   while ((uart16550[UART_REG_LSR << uart16550_reg_shift] & UART_REG_STATUS_TX) == 0);
         //   uart16550[UART_REG_QUEUE << uart16550_reg_shift] = ch;
-        let (uart, _cvar) = &*self.uart;
-        let mut uart = uart.lock().expect("failed to get an UART object");
+        let dlab = (self.uart[(UART_LCR - UART_BASE) as usize] & UART_LCR_DLAB) != 0;
         match index {
+            UART_DLL if dlab => self.divisor[0] = value,
+            UART_DLM if dlab => self.divisor[1] = value,
             UART_THR => {
                 print!("{}", value as char);
                 io::stdout().flush().expect("failed to flush stdout");
+                // The byte is sent immediately, so the transmitter holding register is empty
+                // again.
+                self.thre_pending = true;
+            }
+            UART_IER => {
+                // Enabling the transmitter empty interrupt raises it immediately because the
+                // transmitter holding register is always empty.
+                if (value & UART_IER_THRI) != 0 && (self.ier & UART_IER_THRI) == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = value & 0xf;
             }
+            UART_FCR => self.fcr = value,
+            // LSR is read-only.
+            UART_LSR => {}
             _ => {
-                uart[(index - UART_BASE) as usize] = value;
+                self.uart[(index - UART_BASE) as usize] = value;
             }
         }
         Ok(())
@@ -2,6 +2,9 @@
 //! (UART) for WebAssembly. The device is 16550a UART, which is used in the QEMU virt machine. See more information
 //! in http://byterunner.com/16550.html.
 
+use std::io::prelude::*;
+use std::time::Duration;
+
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsValue;
 use web_sys::Window;
@@ -9,6 +12,7 @@ use web_sys::Window;
 use crate::bus::{UART_BASE, UART_SIZE};
 use crate::cpu::BYTE;
 use crate::exception::Exception;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 
 #[wasm_bindgen]
 extern "C" {
@@ -107,6 +111,44 @@ impl Uart {
         false
     }
 
+    /// Return `None` because an input from the web page is put in the receive holding register by
+    /// `Uart::is_interrupting`. It exists to have the same interface as the UART for the CLI tool.
+    #[inline]
+    pub fn receive_host_input(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// Return without waiting, because the emulator on the web page can't block. It exists to have
+    /// the same interface as the UART for the CLI tool.
+    pub fn wait_for_host_input(&mut self, _timeout: Duration) {}
+
+    /// Put a byte in the receive holding register as if it's received from the outside.
+    pub fn receive(&mut self, byte: u8) {
+        self.uart[(UART_RHR - UART_BASE) as usize] = byte;
+        self.uart[(UART_LSR - UART_BASE) as usize] |= 1;
+    }
+
+    /// Write the registers to a snapshot.
+    pub(crate) fn save_snapshot<W: Write>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<(), SnapshotError> {
+        writer.write_bytes(&self.uart)?;
+        writer.write_u64(self.clock)?;
+        writer.write_bool(self.not_null)
+    }
+
+    /// Restore the registers from a snapshot.
+    pub(crate) fn load_snapshot<R: Read>(
+        &mut self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<(), SnapshotError> {
+        reader.read_bytes(&mut self.uart)?;
+        self.clock = reader.read_u64()?;
+        self.not_null = reader.read_bool()?;
+        Ok(())
+    }
+
     /// Read a byte from the receive holding register.
     pub fn read(&mut self, index: u64, size: u8) -> Result<u64, Exception> {
         if size != BYTE {
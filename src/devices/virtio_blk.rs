@@ -5,9 +5,15 @@
 //! 5.2 Block Device:
 //! https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-2390002
 
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::ops::Range;
+
 use crate::bus::VIRTIO_BASE;
 use crate::cpu::{Cpu, BYTE, DOUBLEWORD, HALFWORD, WORD};
 use crate::exception::Exception;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 
 /// The interrupt request of virtio.
 pub const VIRTIO_IRQ: u64 = 1;
@@ -170,15 +176,19 @@ impl VirtqueueAddr {
         // Used Ring        | 4         | 6 + 8∗(Queue Size)
 
         let base_addr = virtio.queue_pfn as u64 * virtio.guest_page_size as u64;
-        let align = virtio.queue_align as u64;
+        // Avoid division by 0 if the driver writes 0 to the alignment.
+        let align = cmp::max(virtio.queue_align, 1) as u64;
         let size = virtio.queue_num as u64;
-        let avail_ring_end = base_addr + (16 * size) + (6 + 2 * size);
+        let avail_ring_end = base_addr.wrapping_add(16 * size).wrapping_add(6 + 2 * size);
 
         Self {
             desc_addr: base_addr,
-            avail_addr: base_addr + 16 * size,
+            avail_addr: base_addr.wrapping_add(16 * size),
             // Used ring starts with the `queue_align` boundary after the available ring ends.
-            used_addr: (avail_ring_end.wrapping_div(align) + 1).wrapping_mul(align),
+            used_addr: avail_ring_end
+                .wrapping_div(align)
+                .wrapping_add(1)
+                .wrapping_mul(align),
         }
     }
 }
@@ -245,9 +255,10 @@ impl VirtqDesc {
 ///   le16 used_event; /* Only if VIRTIO_F_EVENT_IDX */
 /// };
 /// ```
+///
+/// The flags aren't read, because VIRTQ_AVAIL_F_NO_INTERRUPT is only a hint to the device.
 #[derive(Debug)]
 struct VirtqAvail {
-    flags: u16,
     idx: u16,
     ring_start_addr: u64,
 }
@@ -255,7 +266,6 @@ struct VirtqAvail {
 impl VirtqAvail {
     fn new(cpu: &mut Cpu, addr: u64) -> Result<Self, Exception> {
         Ok(Self {
-            flags: cpu.bus.read(addr, HALFWORD)? as u16,
             idx: cpu.bus.read(addr.wrapping_add(2), HALFWORD)? as u16,
             ring_start_addr: addr.wrapping_add(4),
         })
@@ -279,6 +289,9 @@ pub struct Virtio {
     config: [u8; 8],
     disk: Vec<u8>,
     virtqueue: Option<VirtqueueAddr>,
+    /// The original contents of the sectors which have been written since the disk image was
+    /// set, keyed by the sector number. A snapshot saves the disk as a delta from the image.
+    originals: BTreeMap<u64, Vec<u8>>,
 }
 
 impl Virtio {
@@ -314,6 +327,7 @@ impl Virtio {
             config,
             disk: Vec::new(),
             virtqueue: None,
+            originals: BTreeMap::new(),
         }
     }
 
@@ -365,6 +379,112 @@ impl Virtio {
         self.disk.extend(binary.iter().cloned());
     }
 
+    /// Returns the size of the disk image in bytes.
+    pub fn disk_size(&self) -> u64 {
+        self.disk.len() as u64
+    }
+
+    /// Writes the registers and the sectors which differ from the disk image to a snapshot.
+    pub(crate) fn save_snapshot<W: Write>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<(), SnapshotError> {
+        writer.write_u64(self.id)?;
+        writer.write_u32s(&self.device_features)?;
+        writer.write_u32(self.device_features_sel)?;
+        writer.write_u32s(&self.driver_features)?;
+        writer.write_u32(self.driver_features_sel)?;
+        writer.write_u32(self.guest_page_size)?;
+        writer.write_u32(self.queue_num)?;
+        writer.write_u32(self.queue_align)?;
+        writer.write_u32(self.queue_pfn)?;
+        writer.write_u32(self.queue_notify)?;
+        writer.write_u32(self.interrupt_status)?;
+        writer.write_u32(self.status)?;
+        writer.write_bytes(&self.config)?;
+        writer.write_bool(self.virtqueue.is_some())?;
+        if let Some(queue) = self.virtqueue {
+            writer.write_u64(queue.desc_addr)?;
+            writer.write_u64(queue.avail_addr)?;
+            writer.write_u64(queue.used_addr)?;
+        }
+
+        // A sector which has been written back to the original contents isn't saved.
+        let sectors: Vec<u64> = self
+            .originals
+            .iter()
+            .filter(|(sector, original)| self.disk[self.sector_range(**sector)] != original[..])
+            .map(|(sector, _)| *sector)
+            .collect();
+        writer.write_u64(sectors.len() as u64)?;
+        for sector in sectors {
+            writer.write_u64(sector)?;
+            writer.write_bytes(&self.disk[self.sector_range(sector)])?;
+        }
+        Ok(())
+    }
+
+    /// Restores the registers and the disk from a snapshot. The sectors written since the disk
+    /// image was set are reverted before the sectors in the snapshot are applied.
+    pub(crate) fn load_snapshot<R: Read>(
+        &mut self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<(), SnapshotError> {
+        self.id = reader.read_u64()?;
+        reader.read_u32s(&mut self.device_features)?;
+        self.device_features_sel = reader.read_u32()?;
+        reader.read_u32s(&mut self.driver_features)?;
+        self.driver_features_sel = reader.read_u32()?;
+        self.guest_page_size = reader.read_u32()?;
+        self.queue_num = reader.read_u32()?;
+        self.queue_align = reader.read_u32()?;
+        self.queue_pfn = reader.read_u32()?;
+        self.queue_notify = reader.read_u32()?;
+        self.interrupt_status = reader.read_u32()?;
+        self.status = reader.read_u32()?;
+        reader.read_bytes(&mut self.config)?;
+        self.virtqueue = if reader.read_bool()? {
+            Some(VirtqueueAddr {
+                desc_addr: reader.read_u64()?,
+                avail_addr: reader.read_u64()?,
+                used_addr: reader.read_u64()?,
+            })
+        } else {
+            None
+        };
+
+        for (sector, original) in self.originals.iter() {
+            let range = self.sector_range(*sector);
+            self.disk[range].copy_from_slice(original);
+        }
+        for _ in 0..reader.read_u64()? {
+            let sector = reader.read_u64()?;
+            if sector >= self.disk_size().div_ceil(SECTOR_SIZE) {
+                return Err(SnapshotError::Corrupted("disk sector"));
+            }
+            self.keep_original(sector);
+            let range = self.sector_range(sector);
+            reader.read_bytes(&mut self.disk[range])?;
+        }
+        Ok(())
+    }
+
+    /// Returns the range of the `sector` in the disk. The last sector may be shorter than
+    /// `SECTOR_SIZE` if the size of the disk isn't a multiple of it.
+    fn sector_range(&self, sector: u64) -> Range<usize> {
+        let start = (sector * SECTOR_SIZE) as usize;
+        start..cmp::min(start + SECTOR_SIZE as usize, self.disk.len())
+    }
+
+    /// Keeps the original contents of the `sector` before it's written for the first time.
+    fn keep_original(&mut self, sector: u64) {
+        let range = self.sector_range(sector);
+        let disk = &self.disk;
+        self.originals
+            .entry(sector)
+            .or_insert_with(|| disk[range].to_vec());
+    }
+
     /// Loads `size`-bit data from a register located at `addr` in the virtio block device.
     pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
         // `reg` is the value of a target register in the virtio block device and `offset` is the
@@ -379,7 +499,7 @@ impl Virtio {
             // See https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/virtio_disk.c#L86
             VENDOR_ID..=VENDOR_ID_END => (0x554d4551, addr - VENDOR_ID),
             DEVICE_FEATURES..=DEVICE_FEATURES_END => (
-                self.device_features[self.device_features_sel as usize],
+                Virtio::features(&self.device_features, self.device_features_sel),
                 addr - DEVICE_FEATURES,
             ),
             QUEUE_NUM_MAX..=QUEUE_NUM_MAX_END => (QUEUE_SIZE as u32, addr - QUEUE_NUM_MAX),
@@ -390,7 +510,7 @@ impl Virtio {
             STATUS..=STATUS_END => (self.status, addr - STATUS),
             CONFIG..=CONFIG_END => {
                 if size != BYTE {
-                    return Err(Exception::StoreAMOAccessFault);
+                    return Err(Exception::LoadAccessFault);
                 }
                 let index = addr - CONFIG;
                 (self.config[index as usize] as u32, 0)
@@ -417,7 +537,7 @@ impl Virtio {
                 (self.device_features_sel, addr - DEVICE_FEATURES_SEL)
             }
             DRIVER_FEATURES..=DRIVER_FEATURES_END => (
-                self.driver_features[self.driver_features_sel as usize],
+                Virtio::features(&self.driver_features, self.driver_features_sel),
                 addr - DRIVER_FEATURES,
             ),
             DRIVER_FEATURES_SEL..=DRIVER_FEATURES_SEL_END => {
@@ -425,8 +545,9 @@ impl Virtio {
             }
             GUEST_PAGE_SIZE..=GUEST_PAGE_SIZE_END => (self.guest_page_size, addr - GUEST_PAGE_SIZE),
             QUEUE_SEL..=QUEUE_SEL_END => {
+                // Multiple virtual queues are not supported.
                 if value != 0 {
-                    panic!("Multiple virtual queues are not supported.");
+                    return Err(Exception::StoreAMOAccessFault);
                 }
                 return Ok(());
             }
@@ -451,7 +572,7 @@ impl Virtio {
                     return Err(Exception::StoreAMOAccessFault);
                 }
                 let index = addr - CONFIG;
-                self.config[index as usize] = (value >> (index * 8)) as u8;
+                self.config[index as usize] = value as u8;
                 return Ok(());
             }
             _ => return Err(Exception::StoreAMOAccessFault),
@@ -479,7 +600,13 @@ impl Virtio {
         match addr {
             DEVICE_FEATURES_SEL..=DEVICE_FEATURES_SEL_END => self.device_features_sel = reg,
             DRIVER_FEATURES..=DRIVER_FEATURES_END => {
-                self.driver_features[self.driver_features_sel as usize] = reg
+                // Features beyond the supported words are ignored.
+                if let Some(features) = self
+                    .driver_features
+                    .get_mut(self.driver_features_sel as usize)
+                {
+                    *features = reg;
+                }
             }
             DRIVER_FEATURES_SEL..=DRIVER_FEATURES_SEL_END => self.driver_features_sel = reg,
             GUEST_PAGE_SIZE..=GUEST_PAGE_SIZE_END => self.guest_page_size = reg,
@@ -509,16 +636,35 @@ impl Virtio {
         Ok(())
     }
 
-    fn read_disk(&self, addr: u64) -> u64 {
-        self.disk[addr as usize] as u64
+    /// Returns the `sel`-th word of `features`. Words beyond the supported features are zero.
+    fn features(features: &[u32; 2], sel: u32) -> u32 {
+        match features.get(sel as usize) {
+            Some(word) => *word,
+            None => 0,
+        }
+    }
+
+    /// Reads a byte at `addr` in the disk. Raises an exception if `addr` is beyond the disk.
+    fn read_disk(&self, addr: u64) -> Result<u64, Exception> {
+        match self.disk.get(addr as usize) {
+            Some(byte) => Ok(*byte as u64),
+            None => Err(Exception::LoadAccessFault),
+        }
     }
 
-    fn write_disk(&mut self, addr: u64, value: u64) {
-        self.disk[addr as usize] = value as u8
+    /// Writes a byte at `addr` in the disk. Raises an exception if `addr` is beyond the disk.
+    fn write_disk(&mut self, addr: u64, value: u64) -> Result<(), Exception> {
+        if addr >= self.disk_size() {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+        self.keep_original(addr / SECTOR_SIZE);
+        self.disk[addr as usize] = value as u8;
+        Ok(())
     }
 
     /// Accesses the disk via virtio. This is an associated function which takes a `cpu` object to
-    /// read and write with a memory directly (DMA).
+    /// read and write with a memory directly (DMA). Raises an exception if a descriptor is
+    /// malformed or refers to an address out of the memory or the disk.
     pub fn disk_access(cpu: &mut Cpu) -> Result<(), Exception> {
         // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1460002
         // "Used Buffer Notification
@@ -531,17 +677,29 @@ impl Virtio {
         let avail = VirtqAvail::new(cpu, virtq.avail_addr)?;
 
         let head_index = cpu.bus.read(
-            avail.ring_start_addr + avail.idx as u64 % QUEUE_SIZE,
+            avail
+                .ring_start_addr
+                .wrapping_add(avail.idx as u64 % QUEUE_SIZE),
             HALFWORD,
         )?;
 
         // First descriptor.
-        let desc0 = VirtqDesc::new(cpu, virtq.desc_addr + VRING_DESC_SIZE * head_index)?;
-        assert_eq!(desc0.flags & VIRTQ_DESC_F_NEXT, 1);
+        let desc0 = VirtqDesc::new(
+            cpu,
+            virtq.desc_addr.wrapping_add(VRING_DESC_SIZE * head_index),
+        )?;
+        if desc0.flags & VIRTQ_DESC_F_NEXT == 0 {
+            return Err(Exception::LoadAccessFault);
+        }
 
         // Second descriptor.
-        let desc1 = VirtqDesc::new(cpu, virtq.desc_addr + VRING_DESC_SIZE * desc0.next)?;
-        assert_eq!(desc1.flags & VIRTQ_DESC_F_NEXT, 1);
+        let desc1 = VirtqDesc::new(
+            cpu,
+            virtq.desc_addr.wrapping_add(VRING_DESC_SIZE * desc0.next),
+        )?;
+        if desc1.flags & VIRTQ_DESC_F_NEXT == 0 {
+            return Err(Exception::LoadAccessFault);
+        }
 
         // 5.2.6 Device Operation
         // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2500006
@@ -553,28 +711,34 @@ impl Virtio {
         //   u8 status;
         // };
         let sector = cpu.bus.read(desc0.addr.wrapping_add(8), DOUBLEWORD)?;
+        let disk_addr = sector.wrapping_mul(SECTOR_SIZE);
 
         // Write to a device if the second bit of `flags` is set.
         match (desc1.flags & VIRTQ_DESC_F_WRITE) == 0 {
             true => {
                 // Read memory data and write it to a disk.
                 for i in 0..desc1.len {
-                    let data = cpu.bus.read(desc1.addr + i, BYTE)?;
-                    cpu.bus.virtio.write_disk(sector * SECTOR_SIZE + i, data);
+                    let data = cpu.bus.read(desc1.addr.wrapping_add(i), BYTE)?;
+                    cpu.bus.virtio.write_disk(disk_addr.wrapping_add(i), data)?;
                 }
             }
             false => {
                 // Read disk data and write it to memory.
                 for i in 0..desc1.len {
-                    let data = cpu.bus.virtio.read_disk(sector * SECTOR_SIZE + i);
-                    cpu.bus.write(desc1.addr + i, data, BYTE)?;
+                    let data = cpu.bus.virtio.read_disk(disk_addr.wrapping_add(i))?;
+                    cpu.bus.write(desc1.addr.wrapping_add(i), data, BYTE)?;
                 }
             }
         };
 
         // Third descriptor address.
-        let desc2 = VirtqDesc::new(cpu, virtq.desc_addr + VRING_DESC_SIZE * desc1.next)?;
-        assert_eq!(desc2.flags & VIRTQ_DESC_F_NEXT, 0);
+        let desc2 = VirtqDesc::new(
+            cpu,
+            virtq.desc_addr.wrapping_add(VRING_DESC_SIZE * desc1.next),
+        )?;
+        if desc2.flags & VIRTQ_DESC_F_NEXT != 0 {
+            return Err(Exception::LoadAccessFault);
+        }
         // Tell success.
         cpu.bus.write(desc2.addr, 0, BYTE)?;
 
@@ -0,0 +1,585 @@
+//! The disassembler module converts an RV64GC instruction into assembly with the ABI register
+//! names. It decodes the same instructions as `Cpu::execute_general` and
+//! `Cpu::execute_compressed`, and prints them without pseudo-instructions, e.g. `addi a0, zero, 1`
+//! instead of `li a0, 1`, so that each line corresponds to an instruction the CPU executes.
+
+use crate::csr::{csr_name, CsrAddress};
+
+/// The ABI names of the integer registers.
+pub(crate) const XREG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// The ABI names of the floating-point registers.
+pub(crate) const FREG_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// The names of the static rounding modes. The dynamic rounding mode (0b111) isn't printed.
+const ROUNDING_MODES: [&str; 5] = ["rne", "rtz", "rdn", "rup", "rmm"];
+
+/// Disassemble a 16-bit or 32-bit instruction located at `pc`. The targets of branches and jumps
+/// are shown as absolute addresses. An unknown instruction is shown as a `.2byte` or `.4byte`
+/// directive.
+pub fn disassemble(inst: u64, pc: u64) -> String {
+    // The lowest 2 bits of a 32-bit instruction are 0b11.
+    if inst & 0b11 == 0b11 {
+        let inst = inst & 0xffff_ffff;
+        disassemble_general(inst, pc).unwrap_or_else(|| format!(".4byte {:#010x}", inst))
+    } else {
+        let inst = inst & 0xffff;
+        disassemble_compressed(inst, pc).unwrap_or_else(|| format!(".2byte {:#06x}", inst))
+    }
+}
+
+/// Return the ABI name of an integer register.
+fn x(index: u64) -> &'static str {
+    XREG_NAMES[(index & 0x1f) as usize]
+}
+
+/// Return the ABI name of a floating-point register.
+fn f(index: u64) -> &'static str {
+    FREG_NAMES[(index & 0x1f) as usize]
+}
+
+/// Return the name of a CSR, or its address if it's not implemented.
+fn csr(addr: CsrAddress) -> String {
+    csr_name(addr).unwrap_or_else(|| format!("{:#x}", addr))
+}
+
+/// Return the absolute address of a branch or jump target.
+fn target(pc: u64, offset: i64) -> String {
+    format!("{:#x}", pc.wrapping_add(offset as u64))
+}
+
+/// Join a mnemonic and its operands.
+fn asm(name: &str, operands: &[&str]) -> String {
+    if operands.is_empty() {
+        name.to_string()
+    } else {
+        format!("{} {}", name, operands.join(", "))
+    }
+}
+
+/// Join a mnemonic and its operands followed by a rounding mode unless it's dynamic. Returns
+/// `None` if the rounding mode is reserved.
+fn asm_rm(name: &str, operands: &[&str], rm: u64) -> Option<String> {
+    let mut operands = operands.to_vec();
+    if rm != 0b111 {
+        operands.push(ROUNDING_MODES.get(rm as usize)?);
+    }
+    Some(asm(name, &operands))
+}
+
+/// Format the predecessor or successor set of `fence`, e.g. `iorw`.
+fn fence_set(set: u64) -> String {
+    let mut s: String = ["i", "o", "r", "w"]
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| (set >> (3 - i)) & 1 == 1)
+        .map(|(_, c)| *c)
+        .collect();
+    if s.is_empty() {
+        s.push('0');
+    }
+    s
+}
+
+/// Disassemble a 32-bit instruction. Returns `None` if it's unknown.
+fn disassemble_general(inst: u64, pc: u64) -> Option<String> {
+    let opcode = inst & 0x0000007f;
+    let rd = (inst & 0x00000f80) >> 7;
+    let rs1 = (inst & 0x000f8000) >> 15;
+    let rs2 = (inst & 0x01f00000) >> 20;
+    let rs3 = (inst & 0xf8000000) >> 27;
+    let funct3 = (inst & 0x00007000) >> 12;
+    let funct7 = (inst & 0xfe000000) >> 25;
+    let funct2 = (inst & 0x06000000) >> 25;
+
+    // imm[11:0] = inst[31:20]
+    let imm_i = (inst as i32 as i64) >> 20;
+    // imm[11:5|4:0] = inst[31:25|11:7]
+    let imm_s = (((inst & 0xfe000000) as i32 as i64) >> 20) | ((inst >> 7) & 0x1f) as i64;
+    // imm[12|10:5|4:1|11] = inst[31|30:25|11:8|7]
+    let imm_b = (((inst & 0x80000000) as i32 as i64) >> 19)
+        | ((inst & 0x80) << 4) as i64
+        | ((inst >> 20) & 0x7e0) as i64
+        | ((inst >> 7) & 0x1e) as i64;
+    // imm[20|10:1|11|19:12] = inst[31|30:21|20|19:12]
+    let imm_j = (((inst & 0x80000000) as i32 as i64) >> 11)
+        | (inst & 0xff000) as i64
+        | ((inst >> 9) & 0x800) as i64
+        | ((inst >> 20) & 0x7fe) as i64;
+    // imm[31:12] = inst[31:12]
+    let imm_u = format!("{:#x}", (inst >> 12) & 0xfffff);
+
+    let mem = |offset: i64, base: u64| format!("{}({})", offset, x(base));
+
+    let s = match opcode {
+        0x03 => {
+            let name = match funct3 {
+                0x0 => "lb",
+                0x1 => "lh",
+                0x2 => "lw",
+                0x3 => "ld",
+                0x4 => "lbu",
+                0x5 => "lhu",
+                0x6 => "lwu",
+                _ => return None,
+            };
+            asm(name, &[x(rd), &mem(imm_i, rs1)])
+        }
+        0x07 => {
+            let name = match funct3 {
+                0x2 => "flw",
+                0x3 => "fld",
+                _ => return None,
+            };
+            asm(name, &[f(rd), &mem(imm_i, rs1)])
+        }
+        0x0f => match funct3 {
+            0x0 => {
+                let pred = fence_set((inst >> 24) & 0xf);
+                let succ = fence_set((inst >> 20) & 0xf);
+                asm("fence", &[&pred, &succ])
+            }
+            0x1 => asm("fence.i", &[]),
+            _ => return None,
+        },
+        0x13 => {
+            let funct6 = funct7 >> 1;
+            let shamt = ((inst >> 20) & 0x3f).to_string();
+            let imm = imm_i.to_string();
+            match funct3 {
+                0x0 => asm("addi", &[x(rd), x(rs1), &imm]),
+                0x1 => asm("slli", &[x(rd), x(rs1), &shamt]),
+                0x2 => asm("slti", &[x(rd), x(rs1), &imm]),
+                0x3 => asm("sltiu", &[x(rd), x(rs1), &imm]),
+                0x4 => asm("xori", &[x(rd), x(rs1), &imm]),
+                0x5 => match funct6 {
+                    0x00 => asm("srli", &[x(rd), x(rs1), &shamt]),
+                    0x10 => asm("srai", &[x(rd), x(rs1), &shamt]),
+                    _ => return None,
+                },
+                0x6 => asm("ori", &[x(rd), x(rs1), &imm]),
+                0x7 => asm("andi", &[x(rd), x(rs1), &imm]),
+                _ => return None,
+            }
+        }
+        0x17 => asm("auipc", &[x(rd), &imm_u]),
+        0x1b => {
+            let shamt = ((inst >> 20) & 0x1f).to_string();
+            match funct3 {
+                0x0 => asm("addiw", &[x(rd), x(rs1), &imm_i.to_string()]),
+                0x1 => asm("slliw", &[x(rd), x(rs1), &shamt]),
+                0x5 => match funct7 {
+                    0x00 => asm("srliw", &[x(rd), x(rs1), &shamt]),
+                    0x20 => asm("sraiw", &[x(rd), x(rs1), &shamt]),
+                    _ => return None,
+                },
+                _ => return None,
+            }
+        }
+        0x23 => {
+            let name = match funct3 {
+                0x0 => "sb",
+                0x1 => "sh",
+                0x2 => "sw",
+                0x3 => "sd",
+                _ => return None,
+            };
+            asm(name, &[x(rs2), &mem(imm_s, rs1)])
+        }
+        0x27 => {
+            let name = match funct3 {
+                0x2 => "fsw",
+                0x3 => "fsd",
+                _ => return None,
+            };
+            asm(name, &[f(rs2), &mem(imm_s, rs1)])
+        }
+        0x2f => {
+            let funct5 = (funct7 & 0b1111100) >> 2;
+            let width = match funct3 {
+                0x2 => "w",
+                0x3 => "d",
+                _ => return None,
+            };
+            let name = match funct5 {
+                0x00 => "amoadd",
+                0x01 => "amoswap",
+                0x02 => "lr",
+                0x03 => "sc",
+                0x04 => "amoxor",
+                0x08 => "amoor",
+                0x0c => "amoand",
+                0x10 => "amomin",
+                0x14 => "amomax",
+                0x18 => "amominu",
+                0x1c => "amomaxu",
+                _ => return None,
+            };
+            // The acquire (aq) and release (rl) bits.
+            let ordering = match (funct7 >> 1) & 1 == 1 {
+                true if funct7 & 1 == 1 => ".aqrl",
+                true => ".aq",
+                false if funct7 & 1 == 1 => ".rl",
+                false => "",
+            };
+            let name = format!("{}.{}{}", name, width, ordering);
+            let addr = format!("({})", x(rs1));
+            match funct5 {
+                0x02 => asm(&name, &[x(rd), &addr]),
+                _ => asm(&name, &[x(rd), x(rs2), &addr]),
+            }
+        }
+        0x33 => {
+            let name = match (funct3, funct7) {
+                (0x0, 0x00) => "add",
+                (0x0, 0x01) => "mul",
+                (0x0, 0x20) => "sub",
+                (0x1, 0x00) => "sll",
+                (0x1, 0x01) => "mulh",
+                (0x2, 0x00) => "slt",
+                (0x2, 0x01) => "mulhsu",
+                (0x3, 0x00) => "sltu",
+                (0x3, 0x01) => "mulhu",
+                (0x4, 0x00) => "xor",
+                (0x4, 0x01) => "div",
+                (0x5, 0x00) => "srl",
+                (0x5, 0x01) => "divu",
+                (0x5, 0x20) => "sra",
+                (0x6, 0x00) => "or",
+                (0x6, 0x01) => "rem",
+                (0x7, 0x00) => "and",
+                (0x7, 0x01) => "remu",
+                _ => return None,
+            };
+            asm(name, &[x(rd), x(rs1), x(rs2)])
+        }
+        0x37 => asm("lui", &[x(rd), &imm_u]),
+        0x3b => {
+            let name = match (funct3, funct7) {
+                (0x0, 0x00) => "addw",
+                (0x0, 0x01) => "mulw",
+                (0x0, 0x20) => "subw",
+                (0x1, 0x00) => "sllw",
+                (0x4, 0x01) => "divw",
+                (0x5, 0x00) => "srlw",
+                (0x5, 0x01) => "divuw",
+                (0x5, 0x20) => "sraw",
+                (0x6, 0x01) => "remw",
+                (0x7, 0x01) => "remuw",
+                _ => return None,
+            };
+            asm(name, &[x(rd), x(rs1), x(rs2)])
+        }
+        0x43 | 0x47 | 0x4b | 0x4f => {
+            let name = match opcode {
+                0x43 => "fmadd",
+                0x47 => "fmsub",
+                0x4b => "fnmsub",
+                _ => "fnmadd",
+            };
+            let format = match funct2 {
+                0x0 => "s",
+                0x1 => "d",
+                _ => return None,
+            };
+            asm_rm(
+                &format!("{}.{}", name, format),
+                &[f(rd), f(rs1), f(rs2), f(rs3)],
+                funct3,
+            )?
+        }
+        0x53 => disassemble_fp(funct7, funct3, rd, rs1, rs2)?,
+        0x63 => {
+            let name = match funct3 {
+                0x0 => "beq",
+                0x1 => "bne",
+                0x4 => "blt",
+                0x5 => "bge",
+                0x6 => "bltu",
+                0x7 => "bgeu",
+                _ => return None,
+            };
+            asm(name, &[x(rs1), x(rs2), &target(pc, imm_b)])
+        }
+        0x67 => asm("jalr", &[x(rd), &mem(imm_i, rs1)]),
+        0x6f => asm("jal", &[x(rd), &target(pc, imm_j)]),
+        0x73 => {
+            let csr_addr = ((inst >> 20) & 0xfff) as CsrAddress;
+            match funct3 {
+                0x0 => match (rs2, funct7) {
+                    (0x0, 0x0) => asm("ecall", &[]),
+                    (0x1, 0x0) => asm("ebreak", &[]),
+                    (0x2, 0x0) => asm("uret", &[]),
+                    (0x2, 0x8) => asm("sret", &[]),
+                    (0x2, 0x18) => asm("mret", &[]),
+                    (0x5, 0x8) => asm("wfi", &[]),
+                    (_, 0x9) => asm("sfence.vma", &[x(rs1), x(rs2)]),
+                    (_, 0x11) => asm("hfence.bvma", &[x(rs1), x(rs2)]),
+                    (_, 0x51) => asm("hfence.gvma", &[x(rs1), x(rs2)]),
+                    _ => return None,
+                },
+                0x1 => asm("csrrw", &[x(rd), &csr(csr_addr), x(rs1)]),
+                0x2 => asm("csrrs", &[x(rd), &csr(csr_addr), x(rs1)]),
+                0x3 => asm("csrrc", &[x(rd), &csr(csr_addr), x(rs1)]),
+                // The rs1 field holds a 5-bit unsigned immediate (uimm).
+                0x5 => asm("csrrwi", &[x(rd), &csr(csr_addr), &rs1.to_string()]),
+                0x6 => asm("csrrsi", &[x(rd), &csr(csr_addr), &rs1.to_string()]),
+                0x7 => asm("csrrci", &[x(rd), &csr(csr_addr), &rs1.to_string()]),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    Some(s)
+}
+
+/// Disassemble a floating-point computational instruction (opcode 0x53). The `funct3` field holds
+/// the rounding mode for some instructions. Returns `None` if it's unknown.
+fn disassemble_fp(funct7: u64, funct3: u64, rd: u64, rs1: u64, rs2: u64) -> Option<String> {
+    // The lowest bit of funct7 selects single (0) or double (1) precision.
+    let format = if funct7 & 1 == 0 { "s" } else { "d" };
+    let s = match funct7 {
+        0x00 | 0x01 | 0x04 | 0x05 | 0x08 | 0x09 | 0x0c | 0x0d => {
+            let name = match funct7 >> 2 {
+                0x0 => "fadd",
+                0x1 => "fsub",
+                0x2 => "fmul",
+                _ => "fdiv",
+            };
+            asm_rm(
+                &format!("{}.{}", name, format),
+                &[f(rd), f(rs1), f(rs2)],
+                funct3,
+            )?
+        }
+        0x10 | 0x11 => {
+            let name = match funct3 {
+                0x0 => "fsgnj",
+                0x1 => "fsgnjn",
+                0x2 => "fsgnjx",
+                _ => return None,
+            };
+            asm(&format!("{}.{}", name, format), &[f(rd), f(rs1), f(rs2)])
+        }
+        0x14 | 0x15 => {
+            let name = match funct3 {
+                0x0 => "fmin",
+                0x1 => "fmax",
+                _ => return None,
+            };
+            asm(&format!("{}.{}", name, format), &[f(rd), f(rs1), f(rs2)])
+        }
+        0x20 => asm_rm("fcvt.s.d", &[f(rd), f(rs1)], funct3)?,
+        0x21 => asm_rm("fcvt.d.s", &[f(rd), f(rs1)], funct3)?,
+        0x2c | 0x2d => asm_rm(&format!("fsqrt.{}", format), &[f(rd), f(rs1)], funct3)?,
+        0x50 | 0x51 => {
+            let name = match funct3 {
+                0x0 => "fle",
+                0x1 => "flt",
+                0x2 => "feq",
+                _ => return None,
+            };
+            asm(&format!("{}.{}", name, format), &[x(rd), f(rs1), f(rs2)])
+        }
+        0x60 | 0x61 => {
+            let int = match rs2 {
+                0x0 => "w",
+                0x1 => "wu",
+                0x2 => "l",
+                0x3 => "lu",
+                _ => return None,
+            };
+            asm_rm(
+                &format!("fcvt.{}.{}", int, format),
+                &[x(rd), f(rs1)],
+                funct3,
+            )?
+        }
+        0x68 | 0x69 => {
+            let int = match rs2 {
+                0x0 => "w",
+                0x1 => "wu",
+                0x2 => "l",
+                0x3 => "lu",
+                _ => return None,
+            };
+            asm_rm(
+                &format!("fcvt.{}.{}", format, int),
+                &[f(rd), x(rs1)],
+                funct3,
+            )?
+        }
+        0x70 | 0x71 => {
+            let name = match (funct3, format) {
+                (0x0, "s") => "fmv.x.w",
+                (0x0, _) => "fmv.x.d",
+                (0x1, "s") => "fclass.s",
+                (0x1, _) => "fclass.d",
+                _ => return None,
+            };
+            asm(name, &[x(rd), f(rs1)])
+        }
+        0x78 => asm("fmv.w.x", &[f(rd), x(rs1)]),
+        0x79 => asm("fmv.d.x", &[f(rd), x(rs1)]),
+        _ => return None,
+    };
+    Some(s)
+}
+
+/// Disassemble a 16-bit compressed instruction. Returns `None` if it's unknown.
+fn disassemble_compressed(inst: u64, pc: u64) -> Option<String> {
+    // Unimplemented instruction, since all bits are 0.
+    if inst == 0 {
+        return None;
+    }
+    let opcode = inst & 0x3;
+    let funct3 = (inst >> 13) & 0x7;
+
+    // Registers x8 to x15 in the 3-bit fields.
+    let rd_p = ((inst >> 2) & 0x7) + 8;
+    let rs1_p = ((inst >> 7) & 0x7) + 8;
+    // Registers in the 5-bit fields.
+    let rd = (inst >> 7) & 0x1f;
+    let rs2 = (inst >> 2) & 0x1f;
+
+    // imm[5|4:0] = inst[12|6:2], sign-extended.
+    let imm6 = {
+        let imm = ((inst >> 7) & 0x20) | ((inst >> 2) & 0x1f);
+        ((imm << 58) as i64) >> 58
+    };
+    // shamt[5|4:0] = inst[12|6:2]
+    let shamt = (((inst >> 7) & 0x20) | ((inst >> 2) & 0x1f)).to_string();
+    // offset[5:3|7:6] = inst[12:10|6:5]
+    let offset_d = ((inst << 1) & 0xc0) | ((inst >> 7) & 0x38);
+    // offset[5:3|2|6] = inst[12:10|6|5]
+    let offset_w = ((inst << 1) & 0x40) | ((inst >> 7) & 0x38) | ((inst >> 4) & 0x4);
+
+    let mem = |offset: u64, base: u64| format!("{}({})", offset, x(base));
+
+    let s = match (opcode, funct3) {
+        // Quadrant 0.
+        (0, 0x0) => {
+            // nzuimm[5:4|9:6|2|3] = inst[12:11|10:7|6|5]
+            let nzuimm = ((inst >> 1) & 0x3c0)
+                | ((inst >> 7) & 0x30)
+                | ((inst >> 2) & 0x8)
+                | ((inst >> 4) & 0x4);
+            asm("c.addi4spn", &[x(rd_p), "sp", &nzuimm.to_string()])
+        }
+        (0, 0x1) => asm("c.fld", &[f(rd_p), &mem(offset_d, rs1_p)]),
+        (0, 0x2) => asm("c.lw", &[x(rd_p), &mem(offset_w, rs1_p)]),
+        (0, 0x3) => asm("c.ld", &[x(rd_p), &mem(offset_d, rs1_p)]),
+        (0, 0x5) => asm("c.fsd", &[f(rd_p), &mem(offset_d, rs1_p)]),
+        (0, 0x6) => asm("c.sw", &[x(rd_p), &mem(offset_w, rs1_p)]),
+        (0, 0x7) => asm("c.sd", &[x(rd_p), &mem(offset_d, rs1_p)]),
+        // Quadrant 1.
+        (1, 0x0) if rd == 0 && imm6 == 0 => asm("c.nop", &[]),
+        (1, 0x0) if rd == 0 => asm("c.nop", &[&imm6.to_string()]),
+        (1, 0x0) => asm("c.addi", &[x(rd), &imm6.to_string()]),
+        (1, 0x1) => asm("c.addiw", &[x(rd), &imm6.to_string()]),
+        (1, 0x2) => asm("c.li", &[x(rd), &imm6.to_string()]),
+        (1, 0x3) => match rd {
+            2 => {
+                // nzimm[9|4|6|8:7|5] = inst[12|6|5|4:3|2], sign-extended.
+                let nzimm = ((inst >> 3) & 0x200)
+                    | ((inst >> 2) & 0x10)
+                    | ((inst << 1) & 0x40)
+                    | ((inst << 4) & 0x180)
+                    | ((inst << 3) & 0x20);
+                let nzimm = ((nzimm << 54) as i64) >> 54;
+                asm("c.addi16sp", &["sp", &nzimm.to_string()])
+            }
+            _ => {
+                // nzimm[17|16:12] = inst[12|6:2], which is shown as a 20-bit value like lui.
+                let nzimm = imm6 as u64 & 0xfffff;
+                asm("c.lui", &[x(rd), &format!("{:#x}", nzimm)])
+            }
+        },
+        (1, 0x4) => match (inst >> 10) & 0x3 {
+            0x0 => asm("c.srli", &[x(rs1_p), &shamt]),
+            0x1 => asm("c.srai", &[x(rs1_p), &shamt]),
+            0x2 => asm("c.andi", &[x(rs1_p), &imm6.to_string()]),
+            _ => {
+                let name = match ((inst >> 12) & 0b1, (inst >> 5) & 0b11) {
+                    (0x0, 0x0) => "c.sub",
+                    (0x0, 0x1) => "c.xor",
+                    (0x0, 0x2) => "c.or",
+                    (0x0, 0x3) => "c.and",
+                    (0x1, 0x0) => "c.subw",
+                    (0x1, 0x1) => "c.addw",
+                    _ => return None,
+                };
+                asm(name, &[x(rs1_p), x(rd_p)])
+            }
+        },
+        (1, 0x5) => {
+            // offset[11|4|9:8|10|6|7|3:1|5] = inst[12|11|10:9|8|7|6|5:3|2], sign-extended.
+            let offset = ((inst >> 1) & 0x800)
+                | ((inst << 2) & 0x400)
+                | ((inst >> 1) & 0x300)
+                | ((inst << 1) & 0x80)
+                | ((inst >> 1) & 0x40)
+                | ((inst << 3) & 0x20)
+                | ((inst >> 7) & 0x10)
+                | ((inst >> 2) & 0xe);
+            let offset = ((offset << 52) as i64) >> 52;
+            asm("c.j", &[&target(pc, offset)])
+        }
+        (1, 0x6) | (1, 0x7) => {
+            // offset[8|4:3|7:6|2:1|5] = inst[12|11:10|6:5|4:3|2], sign-extended.
+            let offset = ((inst >> 4) & 0x100)
+                | ((inst << 1) & 0xc0)
+                | ((inst << 3) & 0x20)
+                | ((inst >> 7) & 0x18)
+                | ((inst >> 2) & 0x6);
+            let offset = ((offset << 55) as i64) >> 55;
+            let name = if funct3 == 0x6 { "c.beqz" } else { "c.bnez" };
+            asm(name, &[x(rs1_p), &target(pc, offset)])
+        }
+        // Quadrant 2.
+        (2, 0x0) => asm("c.slli", &[x(rd), &shamt]),
+        (2, 0x1) | (2, 0x3) => {
+            // offset[5|4:3|8:6] = inst[12|6:5|4:2]
+            let offset = ((inst << 4) & 0x1c0) | ((inst >> 7) & 0x20) | ((inst >> 2) & 0x18);
+            match funct3 {
+                0x1 => asm("c.fldsp", &[f(rd), &mem(offset, 2)]),
+                _ => asm("c.ldsp", &[x(rd), &mem(offset, 2)]),
+            }
+        }
+        (2, 0x2) => {
+            // offset[5|4:2|7:6] = inst[12|6:4|3:2]
+            let offset = ((inst << 4) & 0xc0) | ((inst >> 7) & 0x20) | ((inst >> 2) & 0x1c);
+            asm("c.lwsp", &[x(rd), &mem(offset, 2)])
+        }
+        (2, 0x4) => match ((inst >> 12) & 0x1, rs2) {
+            (0, 0) => asm("c.jr", &[x(rd)]),
+            (0, _) => asm("c.mv", &[x(rd), x(rs2)]),
+            (1, 0) if rd == 0 => asm("c.ebreak", &[]),
+            (1, 0) => asm("c.jalr", &[x(rd)]),
+            (_, _) => asm("c.add", &[x(rd), x(rs2)]),
+        },
+        (2, 0x5) | (2, 0x7) => {
+            // offset[5:3|8:6] = inst[12:10|9:7]
+            let offset = ((inst >> 1) & 0x1c0) | ((inst >> 7) & 0x38);
+            match funct3 {
+                0x5 => asm("c.fsdsp", &[f(rs2), &mem(offset, 2)]),
+                _ => asm("c.sdsp", &[x(rs2), &mem(offset, 2)]),
+            }
+        }
+        (2, 0x6) => {
+            // offset[5:2|7:6] = inst[12:9|8:7]
+            let offset = ((inst >> 1) & 0xc0) | ((inst >> 7) & 0x3c);
+            asm("c.swsp", &[x(rs2), &mem(offset, 2)])
+        }
+        _ => return None,
+    };
+    Some(s)
+}
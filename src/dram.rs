@@ -1,123 +1,293 @@
 //! The memory module contains the memory structure and implementation to read/write the memory.
 
+use std::convert::TryInto;
+use std::io::prelude::*;
+use std::rc::Rc;
+
 use crate::bus::DRAM_BASE;
 use crate::cpu::{BYTE, DOUBLEWORD, HALFWORD, WORD};
 use crate::exception::Exception;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 
 /// Default memory size (1GiB).
 pub const DRAM_SIZE: u64 = 1024 * 1024 * 1024;
 
-/// The memory used by the emulator.
+/// The size of a page of the memory, which is allocated on demand (1MiB).
+const DRAM_PAGE_SIZE: u64 = 0x100000;
+
+/// The size of a chunk of the memory in a snapshot. Chunks filled with zeros aren't saved.
+const SNAPSHOT_CHUNK_SIZE: u64 = 0x1000;
+
+/// A page of the memory.
+type Page = [u8; DRAM_PAGE_SIZE as usize];
+
+/// The memory used by the emulator. The backing storage is split into pages, and a page is
+/// allocated when it's written for the first time, so a large memory costs nothing until it's
+/// used. Pages that have never been written read as zeros.
 #[derive(Debug)]
 pub struct Dram {
-    pub dram: Vec<u8>,
-    code_size: u64,
+    pages: Vec<Option<Box<Page>>>,
+    /// The flags of the pages written since the last checkpoint or restore.
+    dirty: Vec<bool>,
+    size: u64,
+}
+
+/// The contents of the memory at a checkpoint for reverse execution. A page which hasn't been
+/// written since the previous checkpoint is shared with it, so a checkpoint only copies the pages
+/// written in the meantime.
+#[derive(Debug, Clone)]
+pub(crate) struct DramCheckpoint {
+    pages: Vec<Option<Rc<[u8]>>>,
 }
 
 impl Dram {
-    /// Create a new memory object with default memory size.
-    pub fn new() -> Self {
+    /// Create a new memory object with `size` bytes.
+    pub fn new(size: u64) -> Self {
+        let count = size.div_ceil(DRAM_PAGE_SIZE) as usize;
         Self {
-            dram: vec![0; DRAM_SIZE as usize],
-            code_size: 0,
+            pages: vec![None; count],
+            dirty: vec![false; count],
+            size,
         }
     }
 
-    /// Set the binary in the memory.
-    pub fn initialize(&mut self, binary: Vec<u8>) {
-        self.code_size = binary.len() as u64;
-        self.dram.splice(..binary.len(), binary.iter().cloned());
+    /// Return the size of the memory in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Set the binary in the memory. Raises an exception if the binary is larger than the memory.
+    pub fn initialize(&mut self, binary: Vec<u8>) -> Result<(), Exception> {
+        self.initialize_at(DRAM_BASE, &binary, binary.len() as u64)
+    }
+
+    /// Set the binary in the memory at `addr` and fill the rest of `size` bytes with zeros.
+    pub fn initialize_at(&mut self, addr: u64, binary: &[u8], size: u64) -> Result<(), Exception> {
+        let len = binary.len() as u64;
+        if size < len || !self.contains(addr, size) {
+            return Err(Exception::StoreAMOAccessFault);
+        }
+
+        let index = addr - DRAM_BASE;
+        for (i, chunk) in binary.chunks(DRAM_PAGE_SIZE as usize).enumerate() {
+            self.write_bytes(index + (i as u64) * DRAM_PAGE_SIZE, chunk);
+        }
+        self.clear_bytes(index + len, size - len);
+        Ok(())
     }
 
-    /// Load `size`-bit data from the memory.
+    /// Load `size`-bit data from the memory. Raises an exception if the data isn't entirely
+    /// within the memory.
     pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
         match size {
-            BYTE => Ok(self.read8(addr)),
-            HALFWORD => Ok(self.read16(addr)),
-            WORD => Ok(self.read32(addr)),
-            DOUBLEWORD => Ok(self.read64(addr)),
+            BYTE | HALFWORD | WORD | DOUBLEWORD if self.contains(addr, (size / 8) as u64) => {
+                Ok(self.read_bytes(addr - DRAM_BASE, (size / 8) as u64))
+            }
             _ => return Err(Exception::LoadAccessFault),
         }
     }
 
-    /// Store `size`-bit data to the memory.
+    /// Store `size`-bit data to the memory. Raises an exception if the data isn't entirely within
+    /// the memory.
     pub fn write(&mut self, addr: u64, value: u64, size: u8) -> Result<(), Exception> {
         match size {
-            BYTE => self.write8(addr, value),
-            HALFWORD => self.write16(addr, value),
-            WORD => self.write32(addr, value),
-            DOUBLEWORD => self.write64(addr, value),
+            BYTE | HALFWORD | WORD | DOUBLEWORD if self.contains(addr, (size / 8) as u64) => {
+                self.write_value(addr - DRAM_BASE, value, (size / 8) as u64)
+            }
             _ => return Err(Exception::StoreAMOAccessFault),
         }
         Ok(())
     }
 
-    /// Write a byte to the memory.
-    fn write8(&mut self, addr: u64, val: u64) {
-        let index = (addr - DRAM_BASE) as usize;
-        self.dram[index] = val as u8
-    }
-
-    /// Write 2 bytes to the memory with little endian.
-    fn write16(&mut self, addr: u64, val: u64) {
-        let index = (addr - DRAM_BASE) as usize;
-        self.dram[index] = (val & 0xff) as u8;
-        self.dram[index + 1] = ((val >> 8) & 0xff) as u8;
-    }
-
-    /// Write 4 bytes to the memory with little endian.
-    fn write32(&mut self, addr: u64, val: u64) {
-        let index = (addr - DRAM_BASE) as usize;
-        self.dram[index] = (val & 0xff) as u8;
-        self.dram[index + 1] = ((val >> 8) & 0xff) as u8;
-        self.dram[index + 2] = ((val >> 16) & 0xff) as u8;
-        self.dram[index + 3] = ((val >> 24) & 0xff) as u8;
-    }
-
-    /// Write 8 bytes to the memory with little endian.
-    fn write64(&mut self, addr: u64, val: u64) {
-        let index = (addr - DRAM_BASE) as usize;
-        self.dram[index] = (val & 0xff) as u8;
-        self.dram[index + 1] = ((val >> 8) & 0xff) as u8;
-        self.dram[index + 2] = ((val >> 16) & 0xff) as u8;
-        self.dram[index + 3] = ((val >> 24) & 0xff) as u8;
-        self.dram[index + 4] = ((val >> 32) & 0xff) as u8;
-        self.dram[index + 5] = ((val >> 40) & 0xff) as u8;
-        self.dram[index + 6] = ((val >> 48) & 0xff) as u8;
-        self.dram[index + 7] = ((val >> 56) & 0xff) as u8;
-    }
-
-    /// Read a byte from the memory.
-    fn read8(&self, addr: u64) -> u64 {
-        let index = (addr - DRAM_BASE) as usize;
-        self.dram[index] as u64
-    }
-
-    /// Read 2 bytes from the memory with little endian.
-    fn read16(&self, addr: u64) -> u64 {
-        let index = (addr - DRAM_BASE) as usize;
-        return (self.dram[index] as u64) | ((self.dram[index + 1] as u64) << 8);
-    }
-
-    /// Read 4 bytes from the memory with little endian.
-    fn read32(&self, addr: u64) -> u64 {
-        let index = (addr - DRAM_BASE) as usize;
-        return (self.dram[index] as u64)
-            | ((self.dram[index + 1] as u64) << 8)
-            | ((self.dram[index + 2] as u64) << 16)
-            | ((self.dram[index + 3] as u64) << 24);
-    }
-
-    /// Read 8 bytes from the memory with little endian.
-    fn read64(&self, addr: u64) -> u64 {
-        let index = (addr - DRAM_BASE) as usize;
-        return (self.dram[index] as u64)
-            | ((self.dram[index + 1] as u64) << 8)
-            | ((self.dram[index + 2] as u64) << 16)
-            | ((self.dram[index + 3] as u64) << 24)
-            | ((self.dram[index + 4] as u64) << 32)
-            | ((self.dram[index + 5] as u64) << 40)
-            | ((self.dram[index + 6] as u64) << 48)
-            | ((self.dram[index + 7] as u64) << 56);
+    /// Write the contents of the memory to a snapshot. Only the chunks which contain a nonzero
+    /// byte are written, with their offsets from the beginning of the memory.
+    pub(crate) fn save_snapshot<W: Write>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<(), SnapshotError> {
+        let chunks: Vec<(u64, &[u8])> = self
+            .pages
+            .iter()
+            .enumerate()
+            .filter_map(|(i, page)| page.as_ref().map(|page| (i as u64 * DRAM_PAGE_SIZE, page)))
+            .flat_map(|(base, page)| {
+                page.chunks(SNAPSHOT_CHUNK_SIZE as usize)
+                    .enumerate()
+                    .map(move |(i, chunk)| (base + i as u64 * SNAPSHOT_CHUNK_SIZE, chunk))
+            })
+            .filter(|(_, chunk)| chunk.iter().any(|&byte| byte != 0))
+            .collect();
+
+        writer.write_u64(chunks.len() as u64)?;
+        for (index, chunk) in chunks {
+            writer.write_u64(index)?;
+            writer.write_bytes(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the contents of the memory with the chunks in a snapshot. The rest of the memory
+    /// is filled with zeros.
+    pub(crate) fn load_snapshot<R: Read>(
+        &mut self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<(), SnapshotError> {
+        for page in self.pages.iter_mut() {
+            *page = None;
+        }
+        for dirty in self.dirty.iter_mut() {
+            *dirty = true;
+        }
+
+        let count = reader.read_u64()?;
+        let mut chunk = vec![0; SNAPSHOT_CHUNK_SIZE as usize];
+        for _ in 0..count {
+            let index = reader.read_u64()?;
+            if index % SNAPSHOT_CHUNK_SIZE != 0 || index >= self.size {
+                return Err(SnapshotError::Corrupted("memory chunk"));
+            }
+            reader.read_bytes(&mut chunk)?;
+            self.write_bytes(index, &chunk);
+        }
+        Ok(())
+    }
+
+    /// Take a checkpoint of the memory. `base` is the checkpoint taken or restored last, whose
+    /// pages are shared if they haven't been written since then. All pages are copied if it's
+    /// `None`.
+    pub(crate) fn checkpoint(&mut self, base: Option<&DramCheckpoint>) -> DramCheckpoint {
+        let pages = self
+            .pages
+            .iter()
+            .zip(self.dirty.iter())
+            .enumerate()
+            .map(|(i, (page, dirty))| match base {
+                Some(base) if !dirty => base.pages[i].clone(),
+                _ => page.as_ref().map(|page| Rc::from(&page[..])),
+            })
+            .collect();
+        for dirty in self.dirty.iter_mut() {
+            *dirty = false;
+        }
+        DramCheckpoint { pages }
+    }
+
+    /// Restore the memory from a checkpoint. `base` is the checkpoint taken or restored last, and
+    /// only the pages which differ from it or have been written since then are copied.
+    pub(crate) fn restore(&mut self, checkpoint: &DramCheckpoint, base: &DramCheckpoint) {
+        for (i, saved) in checkpoint.pages.iter().enumerate() {
+            let unchanged = match (saved, &base.pages[i]) {
+                (Some(saved), Some(base)) => Rc::ptr_eq(saved, base),
+                (None, None) => true,
+                _ => false,
+            };
+            if unchanged && !self.dirty[i] {
+                continue;
+            }
+            match saved {
+                Some(saved) => self
+                    .page_mut(i as u64 * DRAM_PAGE_SIZE)
+                    .copy_from_slice(saved),
+                None => self.pages[i] = None,
+            }
+        }
+        for dirty in self.dirty.iter_mut() {
+            *dirty = false;
+        }
+    }
+
+    /// Return true if `len` bytes at `addr` are entirely within the memory.
+    fn contains(&self, addr: u64, len: u64) -> bool {
+        addr >= DRAM_BASE && len <= self.size && addr - DRAM_BASE <= self.size - len
+    }
+
+    /// Read `len` bytes at the `index` from the beginning of the memory with little endian.
+    fn read_bytes(&self, index: u64, len: u64) -> u64 {
+        let offset = (index % DRAM_PAGE_SIZE) as usize;
+        if offset as u64 + len > DRAM_PAGE_SIZE {
+            // The data spans 2 pages.
+            return (0..len).fold(0, |value, i| {
+                value | (self.read_bytes(index + i, 1) << (i * 8))
+            });
+        }
+
+        let page = match &self.pages[(index / DRAM_PAGE_SIZE) as usize] {
+            Some(page) => page,
+            None => return 0,
+        };
+        let mut bytes = [0; 8];
+        match len {
+            1 => bytes[..1].copy_from_slice(&page[offset..offset + 1]),
+            2 => bytes[..2].copy_from_slice(&page[offset..offset + 2]),
+            4 => bytes[..4].copy_from_slice(&page[offset..offset + 4]),
+            _ => bytes.copy_from_slice(&page[offset..offset + 8]),
+        }
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Write the lower `len` bytes of `value` at the `index` from the beginning of the memory with
+    /// little endian.
+    fn write_value(&mut self, index: u64, value: u64, len: u64) {
+        let offset = (index % DRAM_PAGE_SIZE) as usize;
+        let bytes = value.to_le_bytes();
+        if offset as u64 + len > DRAM_PAGE_SIZE {
+            // The data spans 2 pages.
+            self.write_bytes(index, &bytes[..len as usize]);
+            return;
+        }
+
+        let page = self.page_mut(index);
+        match len {
+            1 => page[offset] = bytes[0],
+            2 => page[offset..offset + 2].copy_from_slice(&bytes[..2]),
+            4 => page[offset..offset + 4].copy_from_slice(&bytes[..4]),
+            _ => page[offset..offset + 8].copy_from_slice(&bytes),
+        }
+    }
+
+    /// Return the page including the `index` from the beginning of the memory to write it. The
+    /// page is allocated if it hasn't been yet, and marked as dirty.
+    fn page_mut(&mut self, index: u64) -> &mut Page {
+        let i = (index / DRAM_PAGE_SIZE) as usize;
+        self.dirty[i] = true;
+        self.pages[i].get_or_insert_with(|| {
+            // Allocate the page on the heap directly to avoid a large array on the stack.
+            vec![0; DRAM_PAGE_SIZE as usize]
+                .into_boxed_slice()
+                .try_into()
+                .expect("failed to allocate a page")
+        })
+    }
+
+    /// Write `data` at the `index` from the beginning of the memory. Pages are allocated if needed.
+    fn write_bytes(&mut self, index: u64, data: &[u8]) {
+        let mut index = index;
+        let mut data = data;
+        while !data.is_empty() {
+            let offset = (index % DRAM_PAGE_SIZE) as usize;
+            let len = data.len().min(DRAM_PAGE_SIZE as usize - offset);
+            self.page_mut(index)[offset..offset + len].copy_from_slice(&data[..len]);
+            index += len as u64;
+            data = &data[len..];
+        }
+    }
+
+    /// Fill `len` bytes at the `index` from the beginning of the memory with zeros. Pages which
+    /// haven't been allocated are already zeros, so they are left as they are.
+    fn clear_bytes(&mut self, index: u64, len: u64) {
+        let mut index = index;
+        let end = index + len;
+        while index < end {
+            let offset = (index % DRAM_PAGE_SIZE) as usize;
+            let len = ((end - index) as usize).min(DRAM_PAGE_SIZE as usize - offset);
+            let i = (index / DRAM_PAGE_SIZE) as usize;
+            if let Some(page) = &mut self.pages[i] {
+                self.dirty[i] = true;
+                for byte in page[offset..offset + len].iter_mut() {
+                    *byte = 0;
+                }
+            }
+            index += len as u64;
+        }
     }
 }
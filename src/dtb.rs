@@ -0,0 +1,376 @@
+//! The dtb module contains a writer for the flattened device tree (FDT) format and generates a
+//! device tree blob (DTB) which describes the emulated machine to the guest software.
+
+// Reference:
+// "Devicetree Specification Release v0.3"
+// https://github.com/devicetree-org/devicetree-specification/releases/tag/v0.3
+// QEMU virt machine:
+// https://github.com/qemu/qemu/blob/master/hw/riscv/virt.c
+
+use std::collections::HashMap;
+
+use crate::bus::{
+    Bus, CLINT_BASE, CLINT_SIZE, DRAM_BASE, PLIC_BASE, PLIC_SIZE, TEST_BASE, TEST_SIZE, UART_BASE,
+    UART_SIZE, VIRTIO_BASE, VIRTIO_SIZE,
+};
+use crate::devices::{plic::PLIC_NDEV, uart::UART_IRQ, virtio_blk::VIRTIO_IRQ};
+use crate::interrupt::Interrupt;
+
+/// The magic number at the beginning of a DTB.
+const FDT_MAGIC: u32 = 0xd00dfeed;
+/// The version of the device tree format.
+const FDT_VERSION: u32 = 17;
+/// The lowest version with which the format is backwards compatible.
+const FDT_LAST_COMP_VERSION: u32 = 16;
+/// The size of the header in bytes.
+const FDT_HEADER_SIZE: usize = 40;
+
+/// The token that starts a node.
+const FDT_BEGIN_NODE: u32 = 0x1;
+/// The token that ends a node.
+const FDT_END_NODE: u32 = 0x2;
+/// The token that starts a property.
+const FDT_PROP: u32 = 0x3;
+/// The token that ends the structure block.
+const FDT_END: u32 = 0x9;
+
+/// The frequency of the timer (10 MHz), which is the same as the QEMU virt machine.
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+/// The clock frequency of UART.
+const UART_CLOCK_FREQUENCY: u32 = 0x384000;
+/// The value written to the test finisher to power off the machine.
+const TEST_POWEROFF_VALUE: u32 = 0x5555;
+/// The value written to the test finisher to reset the machine.
+const TEST_REBOOT_VALUE: u32 = 0x7777;
+
+/// A writer to build a device tree blob in the flattened device tree format. Nodes and properties
+/// are appended in order, and `finish` returns the blob.
+pub struct FdtWriter {
+    /// The structure block, which contains the nodes and the properties.
+    structure: Vec<u8>,
+    /// The strings block, which contains the names of the properties.
+    strings: Vec<u8>,
+    /// The offsets of the names already stored in the strings block.
+    string_offsets: HashMap<String, u32>,
+    /// The number of nodes not ended yet.
+    depth: usize,
+}
+
+impl FdtWriter {
+    /// Create a new writer with empty blocks.
+    pub fn new() -> Self {
+        Self {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            string_offsets: HashMap::new(),
+            depth: 0,
+        }
+    }
+
+    /// Start a new node named `name`. The root node has an empty name.
+    pub fn begin_node(&mut self, name: &str) {
+        self.append_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    /// End the node started last.
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "no node to end");
+        self.append_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    /// Add a property with raw bytes to the current node.
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.append_u32(FDT_PROP);
+        self.append_u32(value.len() as u32);
+        self.append_u32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    /// Add a property without a value, e.g. `interrupt-controller`.
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    /// Add a property with a null-terminated string.
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+
+    /// Add a property with a list of null-terminated strings, e.g. `compatible` with multiple
+    /// values.
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut bytes = Vec::new();
+        for value in values {
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        self.property(name, &bytes);
+    }
+
+    /// Add a property with a 32-bit cell.
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    /// Add a property with a 64-bit value, which is encoded as 2 cells.
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    /// Add a property with a list of 32-bit cells.
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let bytes: Vec<u8> = cells
+            .iter()
+            .flat_map(|c| c.to_be_bytes().to_vec())
+            .collect();
+        self.property(name, &bytes);
+    }
+
+    /// Finish the structure block and return the device tree blob.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(0, self.depth, "all nodes should be ended");
+        self.append_u32(FDT_END);
+
+        // The memory reservation block must be aligned to 8 bytes. It has no entries but a
+        // terminator which is an entry with zero address and zero size.
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + 16;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            // The physical ID of the boot CPU.
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob = Vec::with_capacity(total_size);
+        for value in header.iter() {
+            blob.extend_from_slice(&value.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; 16]);
+        blob.append(&mut self.structure);
+        blob.append(&mut self.strings);
+        blob
+    }
+
+    /// Return the offset of `name` in the strings block. The name is stored if it doesn't exist
+    /// yet.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+
+    /// Append a big-endian 32-bit value to the structure block.
+    fn append_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Pad the structure block to a 4-byte boundary.
+    fn align(&mut self) {
+        while self.structure.len() & 0x3 != 0 {
+            self.structure.push(0);
+        }
+    }
+}
+
+impl Default for FdtWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Split a 64-bit value to 2 cells for `#address-cells = <2>` and `#size-cells = <2>`.
+fn cells(value: u64) -> [u32; 2] {
+    [(value >> 32) as u32, value as u32]
+}
+
+/// Generate a device tree blob which describes the memory, the harts and the devices connected
+/// to `bus`.
+pub fn create(bus: &Bus, hart_count: u64) -> Vec<u8> {
+    // Phandles: each hart uses 2 handles for the CPU node and the interrupt controller, and PLIC
+    // and the test finisher use the next handles.
+    let cpu_phandle = |hart: u64| (hart * 2 + 1) as u32;
+    let intc_phandle = |hart: u64| (hart * 2 + 2) as u32;
+    let plic_phandle = (hart_count * 2 + 1) as u32;
+    let test_phandle = plic_phandle + 1;
+
+    let mut fdt = FdtWriter::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", "riscv-virtio,qemu");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("bootargs", bus.bootargs());
+    fdt.property_string("stdout-path", &format!("/uart@{:x}", UART_BASE));
+    if let Some((start, end)) = bus.initrd() {
+        fdt.property_u64("linux,initrd-start", start);
+        fdt.property_u64("linux,initrd-end", end);
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("uart@{:x}", UART_BASE));
+    fdt.property_u32("interrupts", UART_IRQ as u32);
+    fdt.property_u32("interrupt-parent", plic_phandle);
+    fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+    fdt.property_cells("reg", &[cells(UART_BASE), cells(UART_SIZE)].concat());
+    fdt.property_string("compatible", "ns16550a");
+    fdt.end_node();
+
+    fdt.begin_node(&format!("virtio_mmio@{:x}", VIRTIO_BASE));
+    fdt.property_u32("interrupts", VIRTIO_IRQ as u32);
+    fdt.property_u32("interrupt-parent", plic_phandle);
+    fdt.property_cells("reg", &[cells(VIRTIO_BASE), cells(VIRTIO_SIZE)].concat());
+    fdt.property_string("compatible", "virtio,mmio");
+    fdt.end_node();
+
+    for (base, size, device) in bus.devices() {
+        let name = match device.node_name() {
+            Some(name) => name,
+            None => continue,
+        };
+        fdt.begin_node(&format!("{}@{:x}", name, base));
+        if let Some(irq) = device.irq() {
+            fdt.property_u32("interrupts", irq as u32);
+            fdt.property_u32("interrupt-parent", plic_phandle);
+        }
+        fdt.property_cells("reg", &[cells(base), cells(size)].concat());
+        device.node_properties(&mut fdt);
+        fdt.end_node();
+    }
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+
+    fdt.begin_node("cpu-map");
+    fdt.begin_node("cluster0");
+    for hart in 0..hart_count {
+        fdt.begin_node(&format!("core{}", hart));
+        fdt.property_u32("cpu", cpu_phandle(hart));
+        fdt.end_node();
+    }
+    fdt.end_node();
+    fdt.end_node();
+
+    for hart in 0..hart_count {
+        fdt.begin_node(&format!("cpu@{:x}", hart));
+        fdt.property_u32("phandle", cpu_phandle(hart));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", hart as u32);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", "rv64imafdcsu");
+        fdt.property_string("mmu-type", "riscv,sv39");
+
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", intc_phandle(hart));
+        fdt.end_node();
+
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", DRAM_BASE));
+    fdt.property_string("device_type", "memory");
+    fdt.property_cells("reg", &[cells(DRAM_BASE), cells(bus.dram_size())].concat());
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_null("ranges");
+
+    // PLIC has 2 contexts per hart, which are connected to the machine external interrupt and the
+    // supervisor external interrupt.
+    let mut plic_interrupts = Vec::new();
+    // CLINT is connected to the machine software interrupt and the machine timer interrupt.
+    let mut clint_interrupts = Vec::new();
+    for hart in 0..hart_count {
+        plic_interrupts.extend_from_slice(&[
+            intc_phandle(hart),
+            Interrupt::MachineExternalInterrupt.exception_code() as u32,
+            intc_phandle(hart),
+            Interrupt::SupervisorExternalInterrupt.exception_code() as u32,
+        ]);
+        clint_interrupts.extend_from_slice(&[
+            intc_phandle(hart),
+            Interrupt::MachineSoftwareInterrupt.exception_code() as u32,
+            intc_phandle(hart),
+            Interrupt::MachineTimerInterrupt.exception_code() as u32,
+        ]);
+    }
+
+    fdt.begin_node(&format!("interrupt-controller@{:x}", PLIC_BASE));
+    fdt.property_u32("phandle", plic_phandle);
+    fdt.property_u32("riscv,ndev", PLIC_NDEV as u32);
+    fdt.property_cells("reg", &[cells(PLIC_BASE), cells(PLIC_SIZE)].concat());
+    fdt.property_cells("interrupts-extended", &plic_interrupts);
+    fdt.property_null("interrupt-controller");
+    fdt.property_string("compatible", "riscv,plic0");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_u32("#address-cells", 0);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+    fdt.property_cells("interrupts-extended", &clint_interrupts);
+    fdt.property_cells("reg", &[cells(CLINT_BASE), cells(CLINT_SIZE)].concat());
+    fdt.property_string("compatible", "riscv,clint0");
+    fdt.end_node();
+
+    fdt.begin_node(&format!("test@{:x}", TEST_BASE));
+    fdt.property_u32("phandle", test_phandle);
+    fdt.property_cells("reg", &[cells(TEST_BASE), cells(TEST_SIZE)].concat());
+    fdt.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+    fdt.end_node();
+
+    fdt.end_node();
+
+    // Linux powers off and resets the machine by writing to the test finisher.
+    fdt.begin_node("poweroff");
+    fdt.property_u32("value", TEST_POWEROFF_VALUE);
+    fdt.property_u32("offset", 0);
+    fdt.property_u32("regmap", test_phandle);
+    fdt.property_string("compatible", "syscon-poweroff");
+    fdt.end_node();
+
+    fdt.begin_node("reboot");
+    fdt.property_u32("value", TEST_REBOOT_VALUE);
+    fdt.property_u32("offset", 0);
+    fdt.property_u32("regmap", test_phandle);
+    fdt.property_string("compatible", "syscon-reboot");
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
@@ -0,0 +1,372 @@
+//! The elf module contains a parser for the executable and linkable format (ELF). It reads the ELF
+//! header and the program headers so that loadable segments can be placed in the memory, and the
+//! symbol table so that addresses can be shown with symbol names.
+
+// Reference:
+// "Tool Interface Standard (TIS) Executable and Linking Format (ELF) Specification Version 1.2"
+// https://refspecs.linuxfoundation.org/elf/elf.pdf
+// "RISC-V ELF psABI specification"
+// https://github.com/riscv-non-isa/riscv-elf-psabi-doc
+
+use std::fmt;
+
+use crate::symbol::SymbolTable;
+
+/// The magic number at the beginning of an ELF file.
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+/// The size of `e_ident`, the identification bytes at the beginning of an ELF file.
+const EI_NIDENT: usize = 16;
+/// The index of the file class in `e_ident`.
+const EI_CLASS: usize = 4;
+/// The index of the data encoding in `e_ident`.
+const EI_DATA: usize = 5;
+
+/// 32-bit objects.
+const ELFCLASS32: u8 = 1;
+/// 64-bit objects.
+const ELFCLASS64: u8 = 2;
+/// Little endian data encoding.
+const ELFDATA2LSB: u8 = 1;
+/// The machine type for RISC-V.
+const EM_RISCV: u16 = 243;
+
+/// The program header type for a loadable segment.
+const PT_LOAD: u32 = 1;
+
+/// The section header type for a symbol table.
+const SHT_SYMTAB: u32 = 2;
+/// The section index for an undefined symbol.
+const SHN_UNDEF: u16 = 0;
+/// The symbol type for a section.
+const STT_SECTION: u8 = 3;
+/// The symbol type for a source file.
+const STT_FILE: u8 = 4;
+
+/// The errors that happen while parsing an ELF file.
+#[derive(Debug, PartialEq)]
+pub enum ElfError {
+    /// The file doesn't start with the ELF magic number.
+    InvalidMagic,
+    /// The file class is neither ELFCLASS32 nor ELFCLASS64.
+    UnsupportedClass(u8),
+    /// The data encoding is not little endian.
+    UnsupportedEncoding(u8),
+    /// The machine type is not RISC-V.
+    UnsupportedMachine(u16),
+    /// A header or a segment points outside of the file.
+    Truncated,
+    /// A loadable segment doesn't fit in the memory.
+    SegmentOutOfRange { addr: u64, size: u64 },
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::InvalidMagic => write!(f, "not an ELF file"),
+            ElfError::UnsupportedClass(class) => write!(f, "unsupported ELF class {}", class),
+            ElfError::UnsupportedEncoding(data) => {
+                write!(f, "unsupported ELF data encoding {}", data)
+            }
+            ElfError::UnsupportedMachine(machine) => {
+                write!(f, "unsupported ELF machine type {}", machine)
+            }
+            ElfError::Truncated => write!(f, "truncated ELF file"),
+            ElfError::SegmentOutOfRange { addr, size } => write!(
+                f,
+                "segment at {:#x} with {:#x} bytes is out of the memory",
+                addr, size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+/// The file class, which decides the size of addresses and offsets.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Class {
+    Elf32,
+    Elf64,
+}
+
+/// A program header that describes a segment.
+#[derive(Debug, PartialEq)]
+pub struct ProgramHeader {
+    /// The kind of the segment.
+    pub p_type: u32,
+    /// The offset from the beginning of the file at which the segment resides.
+    pub offset: u64,
+    /// The virtual address at which the segment resides in memory.
+    pub vaddr: u64,
+    /// The physical address at which the segment resides in memory.
+    pub paddr: u64,
+    /// The number of bytes in the file image of the segment.
+    pub filesz: u64,
+    /// The number of bytes in the memory image of the segment. The bytes after `filesz` are
+    /// filled with zeros (e.g. `.bss`).
+    pub memsz: u64,
+    /// The permission flags of the segment.
+    pub flags: u32,
+}
+
+impl ProgramHeader {
+    /// Return true if the segment should be placed in the memory.
+    pub fn is_loadable(&self) -> bool {
+        self.p_type == PT_LOAD
+    }
+}
+
+/// A section header that describes a section.
+#[derive(Debug, PartialEq)]
+pub struct SectionHeader {
+    /// The kind of the section.
+    pub sh_type: u32,
+    /// The address at which the first byte of the section resides in memory.
+    pub addr: u64,
+    /// The offset from the beginning of the file at which the section resides.
+    pub offset: u64,
+    /// The number of bytes in the section.
+    pub size: u64,
+    /// The index of an associated section, e.g. the string table used by a symbol table.
+    pub link: u32,
+    /// The size of each entry if the section holds a table of fixed-size entries.
+    pub entsize: u64,
+}
+
+/// A parsed ELF file.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    /// The file class.
+    pub class: Class,
+    /// The virtual address to which the system first transfers control.
+    pub entry: u64,
+    /// All the program headers.
+    pub program_headers: Vec<ProgramHeader>,
+    /// All the section headers.
+    pub section_headers: Vec<SectionHeader>,
+}
+
+impl<'a> Elf<'a> {
+    /// Return true if `data` starts with the ELF magic number.
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.len() >= ELF_MAGIC.len() && data[..ELF_MAGIC.len()] == ELF_MAGIC
+    }
+
+    /// Parse the ELF header, the program headers and the section headers in `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if !Elf::is_elf(data) {
+            return Err(ElfError::InvalidMagic);
+        }
+        if data.len() < EI_NIDENT {
+            return Err(ElfError::Truncated);
+        }
+
+        let class = match data[EI_CLASS] {
+            ELFCLASS32 => Class::Elf32,
+            ELFCLASS64 => Class::Elf64,
+            class => return Err(ElfError::UnsupportedClass(class)),
+        };
+        if data[EI_DATA] != ELFDATA2LSB {
+            return Err(ElfError::UnsupportedEncoding(data[EI_DATA]));
+        }
+
+        let mut elf = Self {
+            data,
+            class,
+            entry: 0,
+            program_headers: Vec::new(),
+            section_headers: Vec::new(),
+        };
+
+        let machine = elf.read16(18)?;
+        if machine != EM_RISCV {
+            return Err(ElfError::UnsupportedMachine(machine));
+        }
+
+        // The layout of the ELF header after `e_ident` differs between 32-bit and 64-bit objects
+        // only in the size of `e_entry`, `e_phoff` and `e_shoff`.
+        let (phoff, phentsize, phnum, shoff, shentsize, shnum) = match class {
+            Class::Elf32 => {
+                elf.entry = elf.read32(24)? as u64;
+                (
+                    elf.read32(28)? as u64,
+                    elf.read16(42)?,
+                    elf.read16(44)?,
+                    elf.read32(32)? as u64,
+                    elf.read16(46)?,
+                    elf.read16(48)?,
+                )
+            }
+            Class::Elf64 => {
+                elf.entry = elf.read64(24)?;
+                (
+                    elf.read64(32)?,
+                    elf.read16(54)?,
+                    elf.read16(56)?,
+                    elf.read64(40)?,
+                    elf.read16(58)?,
+                    elf.read16(60)?,
+                )
+            }
+        };
+
+        for i in 0..phnum as u64 {
+            let header = elf.program_header(phoff + i * phentsize as u64)?;
+            elf.program_headers.push(header);
+        }
+
+        for i in 0..shnum as u64 {
+            let header = elf.section_header(shoff + i * shentsize as u64)?;
+            elf.section_headers.push(header);
+        }
+
+        Ok(elf)
+    }
+
+    /// Collect the defined symbols in the symbol table (`.symtab`) with their names in the
+    /// associated string table (`.strtab`). Returns an empty table if the file is stripped.
+    pub fn symbols(&self) -> Result<SymbolTable, ElfError> {
+        let mut table = SymbolTable::new();
+
+        let symtab = match self
+            .section_headers
+            .iter()
+            .find(|s| s.sh_type == SHT_SYMTAB)
+        {
+            Some(symtab) => symtab,
+            None => return Ok(table),
+        };
+        let strtab = self
+            .section_headers
+            .get(symtab.link as usize)
+            .ok_or(ElfError::Truncated)?;
+        let names = self.bytes(strtab.offset, strtab.size)?;
+
+        let entsize = match (symtab.entsize, self.class) {
+            (0, Class::Elf32) => 16,
+            (0, Class::Elf64) => 24,
+            (entsize, _) => entsize,
+        };
+        for i in 0..symtab.size / entsize {
+            let offset = symtab.offset + i * entsize;
+            // Elf32_Sym and Elf64_Sym have the same fields in a different order.
+            let (name, value, size, info, shndx) = match self.class {
+                Class::Elf32 => (
+                    self.read32(offset)?,
+                    self.read32(offset + 4)? as u64,
+                    self.read32(offset + 8)? as u64,
+                    self.bytes(offset + 12, 1)?[0],
+                    self.read16(offset + 14)?,
+                ),
+                Class::Elf64 => (
+                    self.read32(offset)?,
+                    self.read64(offset + 8)?,
+                    self.read64(offset + 16)?,
+                    self.bytes(offset + 4, 1)?[0],
+                    self.read16(offset + 6)?,
+                ),
+            };
+
+            let kind = info & 0xf;
+            if shndx == SHN_UNDEF || kind == STT_SECTION || kind == STT_FILE {
+                continue;
+            }
+
+            let name = match names.get(name as usize..) {
+                Some(bytes) => bytes.split(|&b| b == 0).next().unwrap_or(&[]),
+                None => return Err(ElfError::Truncated),
+            };
+            if name.is_empty() {
+                continue;
+            }
+            table.insert(String::from_utf8_lossy(name).into_owned(), value, size);
+        }
+
+        Ok(table)
+    }
+
+    /// Return the program headers of the segments that should be placed in the memory.
+    pub fn loadable_segments(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.program_headers.iter().filter(|p| p.is_loadable())
+    }
+
+    /// Return the bytes of the file image of a segment.
+    pub fn segment_data(&self, header: &ProgramHeader) -> Result<&'a [u8], ElfError> {
+        self.bytes(header.offset, header.filesz)
+    }
+
+    /// Parse a program header located at `offset`.
+    fn program_header(&self, offset: u64) -> Result<ProgramHeader, ElfError> {
+        match self.class {
+            Class::Elf32 => Ok(ProgramHeader {
+                p_type: self.read32(offset)?,
+                offset: self.read32(offset + 4)? as u64,
+                vaddr: self.read32(offset + 8)? as u64,
+                paddr: self.read32(offset + 12)? as u64,
+                filesz: self.read32(offset + 16)? as u64,
+                memsz: self.read32(offset + 20)? as u64,
+                flags: self.read32(offset + 24)?,
+            }),
+            Class::Elf64 => Ok(ProgramHeader {
+                p_type: self.read32(offset)?,
+                flags: self.read32(offset + 4)?,
+                offset: self.read64(offset + 8)?,
+                vaddr: self.read64(offset + 16)?,
+                paddr: self.read64(offset + 24)?,
+                filesz: self.read64(offset + 32)?,
+                memsz: self.read64(offset + 40)?,
+            }),
+        }
+    }
+
+    /// Parse a section header located at `offset`.
+    fn section_header(&self, offset: u64) -> Result<SectionHeader, ElfError> {
+        match self.class {
+            Class::Elf32 => Ok(SectionHeader {
+                sh_type: self.read32(offset + 4)?,
+                addr: self.read32(offset + 12)? as u64,
+                offset: self.read32(offset + 16)? as u64,
+                size: self.read32(offset + 20)? as u64,
+                link: self.read32(offset + 24)?,
+                entsize: self.read32(offset + 36)? as u64,
+            }),
+            Class::Elf64 => Ok(SectionHeader {
+                sh_type: self.read32(offset + 4)?,
+                addr: self.read64(offset + 16)?,
+                offset: self.read64(offset + 24)?,
+                size: self.read64(offset + 32)?,
+                link: self.read32(offset + 40)?,
+                entsize: self.read64(offset + 56)?,
+            }),
+        }
+    }
+
+    /// Return `size` bytes located at `offset` in the file.
+    fn bytes(&self, offset: u64, size: u64) -> Result<&'a [u8], ElfError> {
+        let end = offset.checked_add(size).ok_or(ElfError::Truncated)?;
+        if end > self.data.len() as u64 {
+            return Err(ElfError::Truncated);
+        }
+        Ok(&self.data[offset as usize..end as usize])
+    }
+
+    /// Read 2 bytes from the file with little endian.
+    fn read16(&self, offset: u64) -> Result<u16, ElfError> {
+        let b = self.bytes(offset, 2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    /// Read 4 bytes from the file with little endian.
+    fn read32(&self, offset: u64) -> Result<u32, ElfError> {
+        let b = self.bytes(offset, 4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Read 8 bytes from the file with little endian.
+    fn read64(&self, offset: u64) -> Result<u64, ElfError> {
+        let b = self.bytes(offset, 8)?;
+        Ok(u64::from_le_bytes([
+            b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+        ]))
+    }
+}
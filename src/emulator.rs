@@ -1,22 +1,159 @@
 //! The emulator module represents an entire computer.
 
-use crate::cpu::Cpu;
-use crate::exception::Trap;
+use std::cmp;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::io::{Read, Write};
+use std::mem;
+use std::time::Duration;
+
+use log::{debug, error};
+
+use crate::bus::{AttachError, DRAM_BASE, DTB_REGION_SIZE, KERNEL_BASE};
+use crate::commit_log;
+use crate::cpu::{Cpu, HALFWORD, WORD};
+use crate::devices::{clint::TimerMode, mmio::Mmio, test_finisher::FinisherRequest, Device};
+use crate::disassembler::disassemble;
+use crate::dram::DRAM_SIZE;
+use crate::elf::{Elf, ElfError};
+use crate::exception::{Exception, Trap};
+use crate::replay::{self, InputEvent, InputRecord, ReplayError};
+use crate::reverse::{History, ReverseError};
+use crate::snapshot::{Header, SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::symbol::SymbolTable;
+use crate::watchpoint::{AddressSpace, Watchpoint, WatchpointHit};
+
+/// The maximum offset from `DRAM_BASE` where an initial ramdisk is placed.
+const INITRD_MAX_OFFSET: u64 = 0x800_0000;
+
+/// The configuration of an emulator, which is fixed at construction time.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The size of DRAM in bytes. The memory is allocated on demand, so a large size doesn't cost
+    /// until the guest uses it.
+    pub dram_size: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            dram_size: DRAM_SIZE,
+        }
+    }
+}
+
+/// The number of cycles executed by `Emulator::start` when the instructions are counted.
+const COUNT_LIMIT: u64 = 50_000_000;
+
+/// The maximum time for which `Emulator::wait_for_host` blocks while the hart waits for an
+/// interrupt, so that an interrupt from an attached device isn't delayed longer.
+const MAX_IDLE_WAIT: Duration = Duration::from_millis(10);
+
+/// The reason why `Emulator::run` stops executing the guest.
+#[derive(Debug, PartialEq)]
+pub enum ExitReason {
+    /// The number of cycles reached the limit.
+    LimitReached,
+    /// The program counter reached a breakpoint. The instruction at the address hasn't been
+    /// executed yet.
+    Breakpoint(u64),
+    /// An access to memory hit a watchpoint. The instruction accessing the memory has been
+    /// executed.
+    Watchpoint(WatchpointHit),
+    /// An exception which the guest can't handle happened at `pc`.
+    FatalTrap { exception: Exception, pc: u64 },
+    /// The hart waits for an interrupt (WFI), and the time can't be skipped until it comes. No
+    /// timer interrupt is armed or the timer doesn't advance, so only an interrupt from a device
+    /// can wake it up, or the timer follows the host clock.
+    WaitingForInterrupt,
+    /// The guest powered off the machine, or ended via HTIF, with an exit code, which is 0 for a
+    /// success.
+    Shutdown { code: u64 },
+    /// The guest requested to reset the machine.
+    Reset,
+    /// The execution backwards reached the oldest checkpoint, before which the history has been
+    /// discarded.
+    HistoryStart,
+}
+
+/// The error type for dumping the signature of a test.
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    /// The symbol which marks the signature isn't found in the loaded program.
+    SymbolNotFound(&'static str),
+    /// The signature isn't in the memory.
+    InvalidRange { begin: u64, end: u64 },
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::SymbolNotFound(name) => write!(f, "symbol `{}` not found", name),
+            SignatureError::InvalidRange { begin, end } => {
+                write!(f, "invalid signature from {:#x} to {:#x}", begin, end)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// The external inputs to the guest, which are recorded to a log or replayed from it.
+enum Inputs {
+    /// The inputs from the host are delivered to the guest and written to the log.
+    Record(Box<dyn Write>),
+    /// The inputs in the log are delivered at the recorded cycles, and the inputs from the host
+    /// are ignored.
+    Replay(VecDeque<InputRecord>),
+}
+
+/// Deliver an input recorded or replayed to the guest.
+fn deliver(cpu: &mut Cpu, event: InputEvent) {
+    match event {
+        InputEvent::Uart(byte) => cpu.bus.uart.receive(byte),
+        InputEvent::Time(mtime) => cpu.bus.clint.receive_time(mtime),
+    }
+}
 
 /// The emulator to hold a CPU.
 pub struct Emulator {
     /// The CPU which is the core implementation of this emulator.
     pub cpu: Cpu,
-    /// The debug flag. Output messages if it's true, otherwise output nothing.
+    /// The debug flag. Log every instruction executed at the debug level if it's true.
     pub is_debug: bool,
+    /// The symbol table of the loaded program. It's empty unless an ELF file with symbols is
+    /// loaded.
+    pub symbols: SymbolTable,
+    /// The addresses where `Emulator::run` stops.
+    breakpoints: HashSet<u64>,
+    /// The destination of the commit log. Nothing is recorded if it's `None`.
+    commit_log: Option<Box<dyn Write>>,
+    /// The number of cycles executed by `Emulator::run`.
+    cycle: u64,
+    /// The inputs recorded or replayed. The inputs from the host are delivered without being
+    /// recorded if it's `None`.
+    inputs: Option<Inputs>,
+    /// The history for reverse execution. It's `None` unless reverse execution is enabled.
+    history: Option<History>,
 }
 
 impl Emulator {
-    /// Constructor for an emulator.
+    /// Constructor for an emulator with the default configuration.
     pub fn new() -> Emulator {
+        Self::with_config(Config::default())
+    }
+
+    /// Constructor for an emulator with a configuration.
+    pub fn with_config(config: Config) -> Emulator {
         Self {
-            cpu: Cpu::new(),
+            cpu: Cpu::with_dram_size(config.dram_size),
             is_debug: false,
+            symbols: SymbolTable::new(),
+            breakpoints: HashSet::new(),
+            commit_log: None,
+            cycle: 0,
+            inputs: None,
+            history: None,
         }
     }
 
@@ -25,9 +162,297 @@ impl Emulator {
         self.cpu.reset()
     }
 
-    /// Set binary data to the beginning of the DRAM from the emulator console.
-    pub fn initialize_dram(&mut self, data: Vec<u8>) {
-        self.cpu.bus.initialize_dram(data);
+    /// Set binary data to the beginning of the DRAM from the emulator console. Raises an exception
+    /// if the data is larger than the DRAM.
+    pub fn initialize_dram(&mut self, data: Vec<u8>) -> Result<(), Exception> {
+        self.cpu.bus.initialize_dram(data)
+    }
+
+    /// Place the loadable segments of an ELF file at their physical addresses in the DRAM, and set
+    /// the entry point to the address which the reset vector jumps to. The bytes of a segment that
+    /// are not in the file image (e.g. `.bss`) are filled with zeros. The symbol table in the file
+    /// is also loaded to show symbol names in debug messages.
+    pub fn load_elf(&mut self, data: &[u8]) -> Result<(), ElfError> {
+        let elf = Elf::parse(data)?;
+        self.place_segments(&elf)?;
+
+        self.symbols = elf.symbols()?;
+        self.detect_htif();
+        self.cpu.bus.rom.set_start_addr(elf.entry);
+        Ok(())
+    }
+
+    /// Load a firmware such as OpenSBI's fw_jump or fw_dynamic, which is either an ELF file or a
+    /// raw binary placed at `DRAM_BASE`. The reset vector jumps to the firmware, and the device
+    /// tree blob is moved to the last 2 MiB of DRAM because the firmware modifies it.
+    pub fn load_bios(&mut self, data: &[u8]) -> Result<(), ElfError> {
+        let entry = self.load_image(data, DRAM_BASE)?;
+        self.cpu.bus.rom.set_start_addr(entry);
+        self.cpu
+            .bus
+            .place_dtb_in_dram()
+            .map_err(|_| ElfError::SegmentOutOfRange {
+                addr: DRAM_BASE,
+                size: DTB_REGION_SIZE,
+            })
+    }
+
+    /// Load a kernel which a firmware jumps to, which is either an ELF file or a raw binary placed
+    /// at `KERNEL_BASE`. The entry point is passed to the firmware as the next address in the
+    /// fw_dynamic information.
+    pub fn load_kernel(&mut self, data: &[u8]) -> Result<(), ElfError> {
+        let entry = self.load_image(data, KERNEL_BASE)?;
+        self.cpu.bus.rom.set_next_addr(entry);
+        Ok(())
+    }
+
+    /// Load an initial ramdisk (initrd or initramfs) and pass its location to the kernel in the
+    /// `linux,initrd-start` and `linux,initrd-end` properties of the device tree. Like QEMU, the
+    /// ramdisk is placed at half of DRAM, or 128 MiB above `DRAM_BASE` if DRAM is larger than
+    /// 256 MiB, so that it doesn't overlap a decompressed kernel.
+    pub fn load_initrd(&mut self, data: &[u8]) -> Result<(), ElfError> {
+        let addr = DRAM_BASE + cmp::min(self.cpu.bus.dram_size() / 2, INITRD_MAX_OFFSET);
+        let size = data.len() as u64;
+        self.cpu
+            .bus
+            .initialize_dram_at(addr, data, size)
+            .map_err(|_| ElfError::SegmentOutOfRange { addr, size })?;
+        self.cpu.bus.set_initrd(addr, addr + size);
+        Ok(())
+    }
+
+    /// Set the kernel command line passed in the `bootargs` property of the device tree.
+    pub fn set_bootargs(&mut self, bootargs: &str) {
+        self.cpu.bus.set_bootargs(bootargs);
+    }
+
+    /// Enable the host-target interface (HTIF) with the addresses of `tohost` and `fromhost` in
+    /// DRAM, for a program without symbols. A program can end the emulation with an exit code and
+    /// output characters to the console via the interface.
+    pub fn set_htif(&mut self, tohost: u64, fromhost: Option<u64>) {
+        self.cpu.bus.htif.enable(tohost, fromhost);
+    }
+
+    /// Write the commit log to `log`, in the same format as Spike's `--log-commits` option. Each
+    /// instruction executed by `Emulator::run` is written as a line with the privilege mode, the
+    /// program counter, the raw instruction and its writes to registers, CSRs and memory. The log
+    /// is flushed when `Emulator::start` returns.
+    pub fn set_commit_log(&mut self, log: Box<dyn Write>) {
+        self.commit_log = Some(log);
+    }
+
+    /// Set the mode of the timer, which `mtime` in CLINT and the `time` CSR count. It's
+    /// deterministic with a tick per instruction by default. The wall-clock mode isn't available
+    /// on `wasm32-unknown-unknown`, which has no monotonic clock.
+    pub fn set_timer_mode(&mut self, mode: TimerMode) {
+        self.cpu.bus.clint.set_mode(mode);
+    }
+
+    /// Record the external inputs to the guest, e.g. bytes received by UART, with the cycles when
+    /// they're delivered to `log`, so that the run can be reproduced by
+    /// `Emulator::replay_inputs`. Each input is flushed as soon as it's written, so that the log
+    /// survives even if the emulator is killed.
+    pub fn record_inputs(&mut self, log: Box<dyn Write>) {
+        self.inputs = Some(Inputs::Record(log));
+    }
+
+    /// Replay the external inputs recorded by `Emulator::record_inputs`. Each input is delivered
+    /// at the recorded cycle, and the inputs from the host are held until all the inputs in the log
+    /// have been delivered.
+    pub fn replay_inputs(&mut self, log: &str) -> Result<(), ReplayError> {
+        let records = replay::parse(log)?;
+        self.inputs = Some(Inputs::Replay(records.into()));
+        Ok(())
+    }
+
+    /// Return the number of cycles executed by `Emulator::run`. A cycle takes an interrupt if any
+    /// and executes an instruction, or waits for an interrupt.
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    /// Enable reverse execution, which allows `Emulator::step_back` and
+    /// `Emulator::reverse_continue` to go back to the current cycle at the earliest. A checkpoint
+    /// of the machine is taken every `interval` cycles, and the oldest one is discarded when
+    /// there are more than `max_checkpoints`. A checkpoint copies only the memory written since
+    /// the previous one, and going back re-executes the guest from the nearest checkpoint, so a
+    /// longer interval takes less memory but more time. The attached devices aren't restored, so
+    /// they must not affect the guest.
+    pub fn enable_reverse_execution(&mut self, interval: u64, max_checkpoints: usize) {
+        self.history = Some(History::new(
+            self.cycle,
+            interval,
+            max_checkpoints,
+            &mut self.cpu,
+        ));
+    }
+
+    /// Return true if reverse execution is enabled.
+    pub fn is_reverse_execution_enabled(&self) -> bool {
+        self.history.is_some()
+    }
+
+    /// Go back to the beginning of the previous cycle. It returns `ExitReason::LimitReached`
+    /// after going back a cycle like `Emulator::step`, or `ExitReason::HistoryStart` if the
+    /// current cycle is the oldest one in the history.
+    pub fn step_back(&mut self) -> Result<ExitReason, ReverseError> {
+        let history = self.history.as_ref().ok_or(ReverseError::NotEnabled)?;
+        if self.cycle <= history.oldest_cycle() {
+            return Ok(ExitReason::HistoryStart);
+        }
+        let target = self.cycle - 1;
+        self.restore_checkpoint(target);
+        self.reexecute(target);
+        Ok(ExitReason::LimitReached)
+    }
+
+    /// Execute the guest backwards until it reaches a breakpoint or a watchpoint, and return the
+    /// reason to stop. It stops at the beginning of the cycle where `Emulator::run` would stop
+    /// at a breakpoint, or where the instruction accessing the memory watched is executed, so a
+    /// watchpoint on writes finds the last store to the memory. It stops at the oldest
+    /// checkpoint with `ExitReason::HistoryStart` if neither is found.
+    pub fn reverse_continue(&mut self) -> Result<ExitReason, ReverseError> {
+        let history = self.history.as_ref().ok_or(ReverseError::NotEnabled)?;
+        let oldest = history.oldest_cycle();
+        let mut end = self.cycle;
+        while end > oldest {
+            // Search the interval between the nearest checkpoint and `end` for the last stop.
+            let start = self.restore_checkpoint(end - 1);
+            if let Some((cycle, reason)) = self.reexecute(end) {
+                self.restore_checkpoint(cycle);
+                self.reexecute(cycle);
+                return Ok(reason);
+            }
+            end = start;
+        }
+        if self.cycle != oldest {
+            self.restore_checkpoint(oldest);
+        }
+        Ok(ExitReason::HistoryStart)
+    }
+
+    /// Discard the history after the current cycle. It must be called before the state of the
+    /// machine is modified after going back, e.g. by a debugger, because the execution from the
+    /// checkpoints after the current cycle would be different from the new one.
+    pub fn truncate_history(&mut self) {
+        if let Some(history) = self.history.as_mut() {
+            history.truncate(self.cycle);
+        }
+    }
+
+    /// Restore the latest checkpoint at or before `cycle`, and return the cycle of the
+    /// checkpoint.
+    fn restore_checkpoint(&mut self, cycle: u64) -> u64 {
+        let history = self
+            .history
+            .as_mut()
+            .expect("reverse execution is not enabled");
+        self.cycle = history.restore(cycle, &mut self.cpu);
+        self.cpu.take_watchpoint_hit();
+        self.cycle
+    }
+
+    /// Execute the guest again from a restored checkpoint to the beginning of the `end` cycle,
+    /// and return the last cycle where `Emulator::run` stops at a breakpoint or a watchpoint
+    /// before it, with the reason. The commit log and debug messages are suppressed because the
+    /// instructions have been executed already.
+    fn reexecute(&mut self, end: u64) -> Option<(u64, ExitReason)> {
+        let commit_log = self.commit_log.take();
+        let is_debug = mem::replace(&mut self.is_debug, false);
+
+        // `Emulator::run` doesn't stop at a breakpoint in the first cycle.
+        let mut last = None;
+        if self.cycle < end && self.breakpoints.contains(&self.cpu.pc) {
+            last = Some((self.cycle, ExitReason::Breakpoint(self.cpu.pc)));
+        }
+        while self.cycle < end {
+            match self.run(end - self.cycle) {
+                ExitReason::Breakpoint(pc) => {
+                    last = Some((self.cycle, ExitReason::Breakpoint(pc)));
+                }
+                // The instruction which hit the watchpoint has been executed in the last cycle.
+                ExitReason::Watchpoint(hit) => {
+                    last = Some((self.cycle - 1, ExitReason::Watchpoint(hit)));
+                }
+                _ => {}
+            }
+        }
+
+        self.commit_log = commit_log;
+        self.is_debug = is_debug;
+        last
+    }
+
+    /// Attach a memory-mapped `device` which occupies `size` bytes from `base` to the system bus.
+    /// The device is described in the device tree if it has a node name.
+    pub fn attach_device(
+        &mut self,
+        base: u64,
+        size: u64,
+        device: Box<dyn Device>,
+    ) -> Result<(), AttachError> {
+        self.cpu.bus.attach(base, size, device)
+    }
+
+    /// Map `size` bytes from `base` to closures on the host. `read_fn` receives the physical
+    /// address and the size in bits, and returns the loaded value. `write_fn` receives the physical
+    /// address, the size in bits and the stored value.
+    pub fn map_mmio<R, W>(
+        &mut self,
+        base: u64,
+        size: u64,
+        read_fn: R,
+        write_fn: W,
+    ) -> Result<(), AttachError>
+    where
+        R: FnMut(u64, u8) -> u64 + 'static,
+        W: FnMut(u64, u8, u64) + 'static,
+    {
+        let mmio = Mmio::new(base, Box::new(read_fn), Box::new(write_fn));
+        self.attach_device(base, size, Box::new(mmio))
+    }
+
+    /// Load an ELF file, or a raw binary at `addr`, and return the entry point. The symbols in the
+    /// ELF file are added to the symbol table.
+    fn load_image(&mut self, data: &[u8], addr: u64) -> Result<u64, ElfError> {
+        if !Elf::is_elf(data) {
+            let size = data.len() as u64;
+            self.cpu
+                .bus
+                .initialize_dram_at(addr, data, size)
+                .map_err(|_| ElfError::SegmentOutOfRange { addr, size })?;
+            return Ok(addr);
+        }
+
+        let elf = Elf::parse(data)?;
+        self.place_segments(&elf)?;
+        self.symbols.extend(elf.symbols()?);
+        self.detect_htif();
+        Ok(elf.entry)
+    }
+
+    /// Enable the host-target interface (HTIF) if the loaded program has the `tohost` symbol, as
+    /// the riscv-tests and other programs for Spike do.
+    fn detect_htif(&mut self) {
+        if let Some(tohost) = self.symbols.address_of("tohost") {
+            let fromhost = self.symbols.address_of("fromhost");
+            self.cpu.bus.htif.enable(tohost, fromhost);
+        }
+    }
+
+    /// Place the loadable segments of an ELF file at their physical addresses in the DRAM.
+    fn place_segments(&mut self, elf: &Elf) -> Result<(), ElfError> {
+        for segment in elf.loadable_segments() {
+            let bytes = elf.segment_data(segment)?;
+            self.cpu
+                .bus
+                .initialize_dram_at(segment.paddr, bytes, segment.memsz)
+                .map_err(|_| ElfError::SegmentOutOfRange {
+                    addr: segment.paddr,
+                    size: segment.memsz,
+                })?;
+        }
+        Ok(())
     }
 
     /// Set binary data to the virtio disk from the emulator console.
@@ -43,7 +468,7 @@ impl Emulator {
     /// Start executing the emulator with limited range of program. This method is for test.
     /// No interrupts happen.
     pub fn test_start(&mut self, start: u64, end: u64) {
-        println!("----- test start -----");
+        debug!("----- test start -----");
         let mut count = 0;
         loop {
             count += 1;
@@ -55,98 +480,404 @@ impl Emulator {
                 return;
             }
 
+            let pc = self.cpu.pc;
             match self.cpu.execute() {
                 Ok(inst) => {
-                    println!("pc: {:#x}, inst: {:#x}", self.cpu.pc.wrapping_sub(4), inst);
+                    debug!("pc: {:#x}, inst: {:#x} {}", pc, inst, disassemble(inst, pc));
                     Trap::Requested
                 }
                 Err(exception) => {
-                    println!("pc: {:#x}, exception: {:?}", self.cpu.pc, exception);
+                    debug!("pc: {:#x}, exception: {:?}", self.cpu.pc, exception);
                     exception.take_trap(&mut self.cpu)
                 }
             };
         }
     }
 
-    /// Start executing the emulator for debug.
-    fn debug_start(&mut self) {
-        let mut count = 0;
-        loop {
-            count += 1;
-            if self.cpu.is_count && count > 50000000 {
-                return;
+    /// Start executing the emulator. It returns when the guest can't continue, e.g. a fatal trap
+    /// happens, the guest powers off the machine, or a breakpoint is hit. When the instructions
+    /// are counted, it returns after `COUNT_LIMIT` cycles.
+    pub fn start(&mut self) -> ExitReason {
+        let limit = if self.cpu.is_count {
+            COUNT_LIMIT
+        } else {
+            u64::MAX
+        };
+
+        let reason = loop {
+            match self.run(limit) {
+                // Keep waiting for an interrupt from a device, e.g. an input via UART, or the
+                // timer.
+                ExitReason::WaitingForInterrupt => self.wait_for_host(),
+                reason => break reason,
+            }
+        };
+
+        if let Some(log) = self.commit_log.as_mut() {
+            if let Err(e) = log.flush() {
+                error!("failed to flush the commit log: {}", e);
+            }
+        }
+        reason
+    }
+
+    /// Execute a cycle, which takes an interrupt if any and executes an instruction.
+    pub fn step(&mut self) -> ExitReason {
+        self.run(1)
+    }
+
+    /// Execute at most `limit` cycles, each of which takes an interrupt if any and executes an
+    /// instruction, and return the reason to stop. A breakpoint at the current program counter
+    /// is ignored in the first cycle, so that the execution can resume from a breakpoint.
+    pub fn run(&mut self, limit: u64) -> ExitReason {
+        for count in 0..limit {
+            if count > 0 && !self.breakpoints.is_empty() && self.breakpoints.contains(&self.cpu.pc)
+            {
+                return ExitReason::Breakpoint(self.cpu.pc);
             }
 
+            // Deliver an input from the host or the replay log to the guest.
+            if self.inputs.is_some() || self.history.is_some() {
+                self.deliver_inputs();
+            } else {
+                self.cpu.bus.uart.receive_host_input();
+                self.cpu.bus.clint.sample_host_clock();
+            }
+            self.cycle += 1;
+
             // Run a cycle on peripheral devices.
             self.cpu.devices_increment();
 
             // Take an interrupt.
-            match self.cpu.check_pending_interrupt() {
-                Some(interrupt) => interrupt.take_trap(&mut self.cpu),
-                None => {}
+            if let Some(interrupt) = self.cpu.check_pending_interrupt() {
+                interrupt.take_trap(&mut self.cpu);
             }
 
             // Execute an instruction.
-            let trap = match self.cpu.execute() {
+            let pc = self.cpu.pc;
+            let result = if self.commit_log.is_some() {
+                self.execute_with_commit_log()
+            } else {
+                self.cpu.execute()
+            };
+            match result {
                 Ok(inst) => {
-                    if self.is_debug {
-                        println!(
-                            "pc: {:#x}, inst: {:#x}, is_inst 16? {} pre_inst: {:#x}",
-                            self.cpu.pc.wrapping_sub(4),
+                    // The hart returns 0 without executing anything while it's waiting for an
+                    // interrupt.
+                    if self.is_debug && inst != 0 {
+                        debug!(
+                            "pc: {}, inst: {:#x} {}",
+                            self.symbols.symbolize(pc),
                             inst,
-                            // Check if an instruction is one of the compressed instructions.
-                            inst & 0b11 == 0 || inst & 0b11 == 1 || inst & 0b11 == 2,
-                            self.cpu.pre_inst,
+                            disassemble(inst, pc)
                         );
                     }
-                    // Return a placeholder trap.
-                    Trap::Requested
                 }
-                Err(exception) => exception.take_trap(&mut self.cpu),
-            };
-
-            match trap {
-                Trap::Fatal => {
-                    println!("pc: {:#x}, trap {:#?}", self.cpu.pc, trap);
-                    return;
+                Err(exception) => {
+                    // Show the faulting instruction before the trap moves the program counter
+                    // and changes the privilege mode.
+                    if let Trap::Fatal = exception.trap() {
+                        error!(
+                            "pc: {}, inst: {}, trap {:?}",
+                            self.symbols.symbolize(pc),
+                            self.disassemble_pc()
+                                .unwrap_or_else(|| String::from("(unavailable)")),
+                            exception
+                        );
+                        exception.take_trap(&mut self.cpu);
+                        return ExitReason::FatalTrap { exception, pc };
+                    }
+                    // The guest handles an access fault unless the trap handler can't be fetched
+                    // either, which repeats the fault forever. The trap isn't taken so that the
+                    // CSRs keep the previous trap.
+                    if exception == Exception::InstructionAccessFault
+                        && exception.trap_vector(&self.cpu) == pc
+                    {
+                        error!(
+                            "pc: {}, trap {:?} in the trap handler",
+                            self.symbols.symbolize(pc),
+                            exception
+                        );
+                        return ExitReason::FatalTrap { exception, pc };
+                    }
+                    exception.take_trap(&mut self.cpu);
                 }
-                _ => {}
+            }
+
+            if let Some(hit) = self.cpu.take_watchpoint_hit() {
+                return ExitReason::Watchpoint(WatchpointHit { pc, ..hit });
+            }
+
+            if let Some(request) = self.cpu.bus.test_finisher.take_request() {
+                return match request {
+                    FinisherRequest::Pass => ExitReason::Shutdown { code: 0 },
+                    // A failure is reported with a nonzero code even if the guest wrote 0, so
+                    // that it isn't mistaken for a success.
+                    FinisherRequest::Fail(0) => ExitReason::Shutdown { code: 1 },
+                    FinisherRequest::Fail(code) => ExitReason::Shutdown { code: code as u64 },
+                    FinisherRequest::Reset => ExitReason::Reset,
+                };
+            }
+            if let Some(code) = self.cpu.bus.htif.take_exit_code() {
+                return ExitReason::Shutdown { code };
+            }
+
+            if self.cpu.idle && !self.skip_idle_time() {
+                return ExitReason::WaitingForInterrupt;
             }
         }
+        ExitReason::LimitReached
     }
 
-    /// Start executing the emulator.
-    pub fn start(&mut self) {
-        if self.is_debug || self.cpu.is_count {
-            self.debug_start();
+    /// Disassemble the instruction at the current program counter. It returns `None` if the
+    /// instruction can't be fetched, e.g. the program counter points to an unmapped address.
+    pub fn disassemble_pc(&mut self) -> Option<String> {
+        let pc = self.cpu.pc;
+        let mut inst = self.cpu.fetch(HALFWORD).ok()?;
+        // The lowest 2 bits of a 32-bit instruction are 0b11.
+        if inst & 0b11 == 0b11 {
+            inst = self.cpu.fetch(WORD).ok()?;
         }
+        // The fetch by the emulator itself isn't an access by the guest.
+        self.cpu.take_watchpoint_hit();
+        Some(disassemble(inst, pc))
+    }
 
-        loop {
-            // Run a cycle on peripheral devices.
-            self.cpu.devices_increment();
-
-            // Take an interrupt.
-            match self.cpu.check_pending_interrupt() {
-                Some(interrupt) => interrupt.take_trap(&mut self.cpu),
-                None => {}
+    /// Deliver inputs to the guest in the record or replay mode, or with reverse execution, at the
+    /// beginning of a cycle. The log is disabled after an error so that the guest keeps running,
+    /// and the inputs from the host are delivered again after all the inputs in the replay log
+    /// have been delivered. A cycle which is re-executed after going back gets the inputs in the
+    /// history instead.
+    #[inline(never)]
+    fn deliver_inputs(&mut self) {
+        let cycle = self.cycle;
+        if let Some(history) = self.history.as_mut() {
+            if history.is_replaying(cycle) {
+                while let Some(event) = history.next_input(cycle) {
+                    deliver(&mut self.cpu, event);
+                }
+                return;
             }
+            history.begin_cycle(cycle, &mut self.cpu);
+        }
 
-            // Execute an instruction.
-            let trap = match self.cpu.execute() {
-                Ok(_) => {
-                    // Return a placeholder trap.
-                    Trap::Requested
+        if let Some(Inputs::Replay(records)) = self.inputs.as_mut() {
+            while let Some(record) = records.front() {
+                if record.cycle > cycle {
+                    return;
                 }
-                Err(exception) => exception.take_trap(&mut self.cpu),
-            };
+                deliver(&mut self.cpu, record.event);
+                if let Some(history) = self.history.as_mut() {
+                    history.record_input(*record);
+                }
+                records.pop_front();
+            }
+            self.inputs = None;
+            return;
+        }
 
-            match trap {
-                Trap::Fatal => {
-                    println!("pc: {:#x}, trap {:#?}", self.cpu.pc, trap);
-                    return;
+        let events = [
+            self.cpu.bus.uart.receive_host_input().map(InputEvent::Uart),
+            self.cpu.bus.clint.sample_host_clock().map(InputEvent::Time),
+        ];
+        for event in events.iter().flatten() {
+            let record = InputRecord {
+                cycle,
+                event: *event,
+            };
+            if let Some(history) = self.history.as_mut() {
+                history.record_input(record);
+            }
+            if let Some(Inputs::Record(log)) = self.inputs.as_mut() {
+                if let Err(e) =
+                    writeln!(log, "{}", replay::format(&record)).and_then(|_| log.flush())
+                {
+                    error!("failed to write the input log: {}", e);
+                    self.inputs = None;
                 }
-                _ => {}
             }
         }
     }
+
+    /// Skip the time while the hart waits for an interrupt, and return false if the emulator has to
+    /// wait for the host instead, i.e. no timer interrupt is armed or the timer follows the host
+    /// clock. In the deterministic mode of the timer, `mtime` jumps to the deadline unless an
+    /// interrupt is already pending or an attached device is busy, and the guest takes the
+    /// interrupt in the next cycle.
+    #[inline(never)]
+    fn skip_idle_time(&mut self) -> bool {
+        // The hart wakes up in the next cycle.
+        if self.cpu.is_interrupt_pending() {
+            return true;
+        }
+        if self.cpu.is_waiting_without_timer() {
+            return false;
+        }
+        if self.cpu.bus.is_device_busy() {
+            return true;
+        }
+        self.cpu.bus.clint.skip_to_deadline()
+    }
+
+    /// Block while the hart waits for an interrupt until an input comes from the host, or the
+    /// host clock reaches the deadline of the timer in the wall-clock mode. The clock is sampled in
+    /// the next cycle after the deadline, and the sample is recorded as an input. It doesn't block
+    /// while the inputs are replayed, or an attached device is busy. It blocks for at most
+    /// `MAX_IDLE_WAIT`, so that the caller can also poll other events, e.g. from a debugger.
+    pub fn wait_for_host(&mut self) {
+        let is_replaying = match (&self.inputs, &self.history) {
+            (Some(Inputs::Replay(_)), _) => true,
+            (_, Some(history)) => history.is_replaying(self.cycle),
+            _ => false,
+        };
+        if is_replaying || self.cpu.bus.is_device_busy() {
+            return;
+        }
+
+        let timeout = match self.cpu.bus.clint.host_time_until_deadline() {
+            Some(deadline) => deadline.min(MAX_IDLE_WAIT),
+            None => MAX_IDLE_WAIT,
+        };
+        self.cpu.bus.uart.wait_for_host_input(timeout);
+        if self.cpu.bus.clint.host_time_until_deadline() == Some(Duration::ZERO) {
+            self.cpu.bus.clint.request_host_clock_sample();
+        }
+    }
+
+    /// Execute an instruction and write a line of the commit log for it. An instruction raising an
+    /// exception isn't committed, so it's not written. The log is disabled after an error so that
+    /// the guest keeps running.
+    #[inline(never)]
+    fn execute_with_commit_log(&mut self) -> Result<u64, Exception> {
+        // The hart doesn't execute any instruction while it's waiting for an interrupt.
+        if self.cpu.idle {
+            return self.cpu.execute();
+        }
+
+        let (mode, pc) = (self.cpu.mode, self.cpu.pc);
+        self.cpu.begin_commit();
+        let result = self.cpu.execute();
+        let commit = self.cpu.end_commit();
+
+        if let (Ok(inst), Some(log)) = (&result, self.commit_log.as_mut()) {
+            let line = commit_log::format(0, mode, pc, *inst, &commit);
+            if let Err(e) = writeln!(log, "{}", line) {
+                error!("failed to write the commit log: {}", e);
+                self.commit_log = None;
+            }
+        }
+        result
+    }
+
+    /// Return the signature of a test in the RISC-V architectural test suite (riscv-arch-test),
+    /// which is the memory from the `begin_signature` symbol to the `end_signature` symbol. Each
+    /// line has a 32-bit word in hexadecimal, which is the format that riscof compares with the
+    /// reference model. The size of the signature must be a multiple of 4 bytes.
+    pub fn signature(&mut self) -> Result<String, SignatureError> {
+        let begin = self
+            .symbols
+            .address_of("begin_signature")
+            .ok_or(SignatureError::SymbolNotFound("begin_signature"))?;
+        let end = self
+            .symbols
+            .address_of("end_signature")
+            .ok_or(SignatureError::SymbolNotFound("end_signature"))?;
+        if end < begin || (end - begin) % 4 != 0 {
+            return Err(SignatureError::InvalidRange { begin, end });
+        }
+
+        let mut signature = String::new();
+        for addr in (begin..end).step_by(4) {
+            let word = self
+                .cpu
+                .bus
+                .read(addr, WORD)
+                .map_err(|_| SignatureError::InvalidRange { begin, end })?;
+            signature.push_str(&format!("{:08x}\n", word));
+        }
+        Ok(signature)
+    }
+
+    /// Save the entire state of the machine to `writer`, which can be restored by
+    /// `Emulator::load_snapshot` to resume from the same point, e.g. after a guest OS has booted.
+    /// It includes the registers, the CSRs, the privilege mode, the memory, the state of the
+    /// built-in devices including a pending shutdown, the locations of the device tree blob and the
+    /// initial ramdisk, the kernel command line and the sectors of the disk which differ from the
+    /// disk image. Breakpoints, watchpoints and the state of the attached devices aren't saved. A
+    /// snapshot is written in small pieces, so a file should be wrapped in `BufWriter`.
+    pub fn save_snapshot<W: Write>(&self, writer: W) -> Result<(), SnapshotError> {
+        let mut writer = SnapshotWriter::new(writer);
+        writer.write_header(&Header {
+            dram_size: self.cpu.bus.dram_size(),
+            disk_size: self.cpu.bus.virtio.disk_size(),
+        })?;
+        writer.write_u64(self.cycle)?;
+        self.cpu.save_snapshot(&mut writer)?;
+        self.cpu.bus.save_dram_snapshot(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Restore the state of the machine from a snapshot saved by `Emulator::save_snapshot`. The
+    /// emulator must have the same size of DRAM and the same disk image as the one which saved
+    /// the snapshot, which is checked before anything is restored. The state of the machine is
+    /// undefined if the snapshot turns out to be truncated or corrupted after the check.
+    pub fn load_snapshot<R: Read>(&mut self, reader: R) -> Result<(), SnapshotError> {
+        let mut reader = SnapshotReader::new(reader);
+        let header = reader.read_header()?;
+        let (dram_size, disk_size) = (self.cpu.bus.dram_size(), self.cpu.bus.virtio.disk_size());
+        if header.dram_size != dram_size {
+            return Err(SnapshotError::DramSizeMismatch {
+                expected: dram_size,
+                found: header.dram_size,
+            });
+        }
+        if header.disk_size != disk_size {
+            return Err(SnapshotError::DiskSizeMismatch {
+                expected: disk_size,
+                found: header.disk_size,
+            });
+        }
+        self.cycle = reader.read_u64()?;
+        self.cpu.load_snapshot(&mut reader)?;
+        self.cpu.bus.load_dram_snapshot(&mut reader)?;
+
+        // The history belongs to the execution before the snapshot is restored.
+        if let Some(history) = self.history.as_mut() {
+            history.reset(self.cycle, &mut self.cpu);
+        }
+        Ok(())
+    }
+
+    /// Stop the execution by `Emulator::run` before executing an instruction at `addr`. The
+    /// address is compared with the program counter, so it's a virtual address when paging is
+    /// enabled.
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Return true if a breakpoint is set at `addr`.
+    pub fn has_breakpoint(&self, addr: u64) -> bool {
+        self.breakpoints.contains(&addr)
+    }
+
+    /// Remove a breakpoint at `addr`. Returns false if it doesn't exist.
+    pub fn remove_breakpoint(&mut self, addr: u64) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    /// Stop the execution by `Emulator::run` after an access to memory hits a watchpoint.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        match watchpoint.space {
+            AddressSpace::Virtual => self.cpu.watchpoints.add(watchpoint),
+            AddressSpace::Physical => self.cpu.bus.watchpoints.add(watchpoint),
+        }
+    }
+
+    /// Remove a watchpoint. Returns false if it doesn't exist.
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        match watchpoint.space {
+            AddressSpace::Virtual => self.cpu.watchpoints.remove(watchpoint),
+            AddressSpace::Physical => self.cpu.bus.watchpoints.remove(watchpoint),
+        }
+    }
 }
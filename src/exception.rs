@@ -69,17 +69,12 @@ impl Exception {
         // "ECALL and EBREAK cause the receiving privilege mode’s epc register to be set to the
         // address of the ECALL or EBREAK instruction itself, not the address of the following
         // instruction."
-        match self {
-            Exception::Breakpoint
-            | Exception::EnvironmentCallFromUMode
-            | Exception::EnvironmentCallFromSMode
-            | Exception::EnvironmentCallFromMMode
-            // TODO: why page fault needs this?
-            | Exception::InstructionPageFault(_)
-            | Exception::LoadPageFault(_)
-            | Exception::StoreAMOPageFault(_) => pc,
-            _ => pc.wrapping_add(4),
-        }
+        // 3.1.15 Machine Exception Program Counter (mepc)
+        // "When a trap is taken into M-mode, mepc is written with the virtual address of the
+        // instruction that was interrupted or that encountered the exception."
+        // The program counter isn't incremented when an instruction raises an exception, so it's
+        // the address of the instruction for all exceptions.
+        pc
     }
 
     fn trap_value(&self, pc: u64) -> u64 {
@@ -107,6 +102,21 @@ impl Exception {
         }
     }
 
+    /// Return true if the exception is handled in S-mode.
+    fn is_delegated(&self, cpu: &Cpu) -> bool {
+        cpu.mode <= Mode::Supervisor
+            && ((cpu.state.read(MEDELEG) >> self.exception_code()) & 1) == 1
+    }
+
+    /// Return the address of the trap handler which the exception jumps to.
+    pub fn trap_vector(&self, cpu: &Cpu) -> u64 {
+        if self.is_delegated(cpu) {
+            cpu.state.read(STVEC) & !1
+        } else {
+            cpu.state.read(MTVEC) & !1
+        }
+    }
+
     /// Update CSRs and the program counter depending on an exception.
     pub fn take_trap(&self, cpu: &mut Cpu) -> Trap {
         // 1.2 Privilege Levels
@@ -127,12 +137,12 @@ impl Exception {
         // on page 37, with the index of the bit position equal to the value returned in the mcause
         // register (i.e., setting bit 8 allows user-mode environment calls to be delegated to a
         // lower-privilege trap handler)."
-        if previous_mode <= Mode::Supervisor && ((cpu.state.read(MEDELEG) >> cause) & 1) == 1 {
+        if self.is_delegated(cpu) {
             // Handle the trap in S-mode.
             cpu.mode = Mode::Supervisor;
 
             // Set the program counter to the supervisor trap-handler base address (stvec).
-            cpu.pc = (cpu.state.read(STVEC) & !1) as u64;
+            cpu.pc = self.trap_vector(cpu);
 
             // 4.1.9 Supervisor Exception Program Counter (sepc)
             // "The low bit of sepc (sepc[0]) is always zero."
@@ -172,7 +182,7 @@ impl Exception {
             cpu.mode = Mode::Machine;
 
             // Set the program counter to the machine trap-handler base address (mtvec).
-            cpu.pc = (cpu.state.read(MTVEC) & !1) as u64;
+            cpu.pc = self.trap_vector(cpu);
 
             // 3.1.15 Machine Exception Program Counter (mepc)
             // "The low bit of mepc (mepc[0]) is always zero."
@@ -213,16 +223,18 @@ impl Exception {
             }
         }
 
+        self.trap()
+    }
+
+    /// Return how the emulator treats the exception after it's taken.
+    pub fn trap(&self) -> Trap {
         match self {
-            Exception::InstructionAddressMisaligned | Exception::InstructionAccessFault => {
-                Trap::Fatal
-            }
+            Exception::InstructionAddressMisaligned => Trap::Fatal,
+            Exception::InstructionAccessFault => Trap::Contained,
             Exception::IllegalInstruction(_) => Trap::Invisible,
             Exception::Breakpoint => Trap::Requested,
-            Exception::LoadAddressMisaligned
-            | Exception::LoadAccessFault
-            | Exception::StoreAMOAddressMisaligned
-            | Exception::StoreAMOAccessFault => Trap::Fatal,
+            Exception::LoadAddressMisaligned | Exception::StoreAMOAddressMisaligned => Trap::Fatal,
+            Exception::LoadAccessFault | Exception::StoreAMOAccessFault => Trap::Contained,
             Exception::EnvironmentCallFromUMode
             | Exception::EnvironmentCallFromSMode
             | Exception::EnvironmentCallFromMMode => Trap::Requested,
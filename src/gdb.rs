@@ -0,0 +1,673 @@
+//! The gdb module contains a stub of the GDB remote serial protocol, which allows a debugger such
+//! as `riscv64-unknown-elf-gdb` to control the emulator over a TCP connection or a Unix socket.
+//! The stub supports reading and writing registers and memory, breakpoints, single steps,
+//! continuing, and interrupting a running guest with Ctrl-C. When reverse execution is enabled,
+//! `reverse-stepi` and `reverse-continue` of the debugger execute the guest backwards.
+//!
+//! ```text
+//! $ ./target/release/rvemu-cli -k <your-binary> -g localhost:1234
+//! $ riscv64-unknown-elf-gdb -ex "target remote localhost:1234" <your-binary>
+//! ```
+
+// Reference:
+// - https://sourceware.org/gdb/current/onlinedocs/gdb/Remote-Protocol.html
+// - https://sourceware.org/gdb/current/onlinedocs/gdb/RISC_002dV-Features.html
+
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use log::debug;
+
+use crate::cpu::{Mode, BYTE};
+use crate::csr::{csr_name, CsrAddress, FCSR, FFLAGS, FRM};
+use crate::disassembler::{FREG_NAMES, XREG_NAMES};
+use crate::emulator::{Emulator, ExitReason};
+use crate::watchpoint::{AddressSpace, WatchKind, Watchpoint, WatchpointHit};
+
+/// The number of cycles executed between checks for an interrupt from the debugger.
+const CYCLES_PER_POLL: u64 = 10_000;
+
+/// The register number of the program counter. The integer registers are numbered from 0 to 31.
+const PC_REGNUM: u64 = 32;
+/// The register number of `f0`. The floating-point registers are numbered from 33 to 64.
+const FIRST_FREG_REGNUM: u64 = 33;
+/// The register number of the CSR at address 0. A CSR is numbered from its address, as GDB does.
+const FIRST_CSR_REGNUM: u64 = 65;
+/// The register number of the virtual register for the privilege mode, which follows the CSRs.
+const PRIV_REGNUM: u64 = FIRST_CSR_REGNUM + 4096;
+
+/// The maximum size of a packet advertised to the debugger.
+const PACKET_SIZE: u64 = 0x4000;
+
+/// The signal numbers reported to the debugger when the guest stops.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// The byte sent by the debugger to interrupt a running guest, i.e., Ctrl-C.
+const INTERRUPT: u8 = 0x03;
+
+/// A connection to a debugger.
+pub trait Connection: Read + Write {
+    /// Switch the connection to the non-blocking mode, where a read returns an error of
+    /// `io::ErrorKind::WouldBlock` instead of waiting for data.
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// The reason why a debug session ends.
+#[derive(Debug, PartialEq)]
+pub enum SessionEnd {
+    /// The guest powered off or reset the machine.
+    Exited(ExitReason),
+    /// The debugger detached from the emulator or closed the connection, so the guest should keep
+    /// running without the debugger.
+    Detached,
+    /// The debugger killed the guest.
+    Killed,
+}
+
+/// The state of the guest after it's resumed.
+enum Stop {
+    /// The guest stopped with a signal, and the debugger can inspect it.
+    Signal(u8),
+    /// The guest stopped after an access to memory hit a watchpoint.
+    Watchpoint(WatchpointHit),
+    /// The guest executed backwards to the beginning of the history.
+    HistoryStart,
+    /// The guest ended the emulation.
+    Exited(ExitReason),
+    /// The connection was closed while the guest was running.
+    Disconnected,
+}
+
+/// A stub of the GDB remote serial protocol over a connection.
+pub struct GdbStub<C: Connection> {
+    /// The connection to the debugger.
+    conn: C,
+    /// The last packet sent, which is sent again if the debugger doesn't receive it correctly.
+    last_packet: Vec<u8>,
+    /// The acknowledgement flag. The debugger can turn off `+`/`-` acknowledgements with
+    /// `QStartNoAckMode`.
+    ack: bool,
+    /// The address flag. Memory is accessed with physical addresses if it's true, otherwise with
+    /// virtual addresses translated in the current privilege mode.
+    physical: bool,
+}
+
+impl<C: Connection> GdbStub<C> {
+    /// Create a new stub over a connection which is already established.
+    pub fn new(conn: C) -> Self {
+        Self {
+            conn,
+            last_packet: Vec::new(),
+            ack: true,
+            physical: false,
+        }
+    }
+
+    /// Serve the debugger until the session ends. The guest is stopped at the current program
+    /// counter until the debugger resumes it.
+    pub fn serve(&mut self, emu: &mut Emulator) -> io::Result<SessionEnd> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => return Ok(SessionEnd::Detached),
+            };
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            debug!("gdb: <- {}", packet);
+
+            let stop = match packet.chars().next() {
+                Some('c') => self.resume(emu, &packet[1..], false)?,
+                Some('s') => self.resume(emu, &packet[1..], true)?,
+                Some('D') => {
+                    self.write_packet(b"OK")?;
+                    return Ok(SessionEnd::Detached);
+                }
+                Some('k') => return Ok(SessionEnd::Killed),
+                Some('b') if packet == "bs" || packet == "bc" => {
+                    let result = if packet == "bs" {
+                        emu.step_back()
+                    } else {
+                        emu.reverse_continue()
+                    };
+                    match result {
+                        Ok(reason) => stop_from(reason),
+                        Err(_) => {
+                            self.write_packet(b"E01")?;
+                            continue;
+                        }
+                    }
+                }
+                _ => {
+                    let response = self.handle(emu, &packet);
+                    self.write_packet(response.as_bytes())?;
+                    continue;
+                }
+            };
+
+            match stop {
+                Stop::Signal(signal) => self.write_packet(format!("S{:02x}", signal).as_bytes())?,
+                Stop::Watchpoint(hit) => {
+                    let reason = match hit.watchpoint.kind {
+                        WatchKind::Write => "watch",
+                        WatchKind::Read => "rwatch",
+                        WatchKind::Access => "awatch",
+                        WatchKind::Execute => "hwbreak",
+                    };
+                    let response = format!("T{:02x}{}:{:x};", SIGTRAP, reason, hit.addr);
+                    self.write_packet(response.as_bytes())?;
+                }
+                Stop::HistoryStart => {
+                    let response = format!("T{:02x}replaylog:begin;", SIGTRAP);
+                    self.write_packet(response.as_bytes())?;
+                }
+                Stop::Exited(reason) => {
+                    let code = match reason {
+                        ExitReason::Shutdown { code } => code,
+                        _ => 0,
+                    };
+                    self.write_packet(format!("W{:02x}", code & 0xff).as_bytes())?;
+                    return Ok(SessionEnd::Exited(reason));
+                }
+                Stop::Disconnected => return Ok(SessionEnd::Detached),
+            }
+        }
+    }
+
+    /// Handle a packet which doesn't resume the guest, and return the response. An empty
+    /// response means the packet isn't supported.
+    fn handle(&mut self, emu: &mut Emulator, packet: &str) -> String {
+        // An empty packet, or one which begins with a non-ASCII character, isn't supported.
+        let (command, args) = match packet.get(..1) {
+            Some(command) => (command, &packet[1..]),
+            None => return String::new(),
+        };
+        let result = match command {
+            "?" => Some(format!("S{:02x}", SIGTRAP)),
+            "g" => Some(
+                (0..=PC_REGNUM)
+                    .map(|n| hex_u64(read_register(emu, n).unwrap_or(0)))
+                    .collect(),
+            ),
+            "G" => (0..=PC_REGNUM)
+                .map(|n| {
+                    let start = n as usize * 16;
+                    let value = parse_u64_le(args.get(start..start + 16)?)?;
+                    write_register(emu, n, value)
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|_| String::from("OK")),
+            "p" => u64::from_str_radix(args, 16)
+                .ok()
+                .and_then(|n| read_register(emu, n))
+                .map(hex_u64),
+            "P" => args.split_once('=').and_then(|(n, value)| {
+                let n = u64::from_str_radix(n, 16).ok()?;
+                write_register(emu, n, parse_u64_le(value)?)?;
+                Some(String::from("OK"))
+            }),
+            "m" => parse_addr_len(args).and_then(|(addr, len)| {
+                // Each byte is sent as 2 hex digits, and the debugger reads the rest by another
+                // packet if the response is shorter.
+                let len = cmp::min(len, PACKET_SIZE / 2);
+                let data = self.read_memory(emu, addr, len);
+                if data.is_empty() && len > 0 {
+                    None
+                } else {
+                    Some(data.iter().map(|byte| format!("{:02x}", byte)).collect())
+                }
+            }),
+            "M" => args.split_once(':').and_then(|(range, data)| {
+                let (addr, len) = parse_addr_len(range)?;
+                let data = parse_bytes(data)?;
+                if data.len() as u64 != len {
+                    return None;
+                }
+                self.write_memory(emu, addr, &data)?;
+                Some(String::from("OK"))
+            }),
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                let kind = fields.next();
+                let addr = fields.next().and_then(|a| u64::from_str_radix(a, 16).ok());
+                let len = fields.next().and_then(|l| u64::from_str_radix(l, 16).ok());
+                let kind = match (kind, addr, len) {
+                    // Software and hardware breakpoints are the same in the emulator, which stops
+                    // before executing an instruction at the address.
+                    (Some("0"), Some(addr), _) | (Some("1"), Some(addr), _) => {
+                        if command == "Z" {
+                            emu.add_breakpoint(addr);
+                        } else {
+                            emu.remove_breakpoint(addr);
+                        }
+                        return String::from("OK");
+                    }
+                    (Some("2"), Some(_), Some(_)) => WatchKind::Write,
+                    (Some("3"), Some(_), Some(_)) => WatchKind::Read,
+                    (Some("4"), Some(_), Some(_)) => WatchKind::Access,
+                    (Some(_), Some(_), _) => return String::new(),
+                    _ => return String::from("E01"),
+                };
+                // A watchpoint is in the same address space as memory accesses by the debugger.
+                let watchpoint = Watchpoint {
+                    space: if self.physical {
+                        AddressSpace::Physical
+                    } else {
+                        AddressSpace::Virtual
+                    },
+                    kind,
+                    addr: addr.unwrap_or_default(),
+                    len: len.unwrap_or_default(),
+                };
+                if command == "Z" {
+                    emu.add_watchpoint(watchpoint);
+                } else {
+                    emu.remove_watchpoint(&watchpoint);
+                }
+                Some(String::from("OK"))
+            }
+            "H" => Some(String::from("OK")),
+            "T" => Some(String::from("OK")),
+            "q" | "Q" => return self.handle_query(emu, packet),
+            _ => return String::new(),
+        };
+        // "E01" is a generic error, which the debugger shows as a failure of the command.
+        result.unwrap_or_else(|| String::from("E01"))
+    }
+
+    /// Handle a general query packet, which begins with `q` or `Q`.
+    fn handle_query(&mut self, emu: &Emulator, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            let mut features = format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+            if emu.is_reverse_execution_enabled() {
+                features.push_str(";ReverseStep+;ReverseContinue+");
+            }
+            return features;
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_addr_len(args) {
+                Some((offset, len)) => read_annex(&target_xml(), offset, len),
+                None => String::from("E01"),
+            };
+        }
+        if let Some(command) = packet.strip_prefix("qRcmd,") {
+            let command = parse_bytes(command).unwrap_or_default();
+            let output = self.monitor(&String::from_utf8_lossy(&command));
+            return output.bytes().map(|byte| format!("{:02x}", byte)).collect();
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.ack = false;
+                String::from("OK")
+            }
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            "qSymbol::" => String::from("OK"),
+            _ => String::new(),
+        }
+    }
+
+    /// Run a command sent by `monitor <command>` of the debugger, and return the output.
+    fn monitor(&mut self, command: &str) -> String {
+        match command.trim() {
+            "physical" => {
+                self.physical = true;
+                String::from("Memory is accessed with physical addresses.\n")
+            }
+            "virtual" => {
+                self.physical = false;
+                String::from("Memory is accessed with virtual addresses.\n")
+            }
+            "help" => String::from(
+                "physical -- Access memory with physical addresses.\n\
+                 virtual -- Access memory with virtual addresses translated in the current \
+                 privilege mode (default).\n",
+            ),
+            command => format!("Unknown monitor command: {}\n", command),
+        }
+    }
+
+    /// Resume the guest from `addr` if it's specified, and run it until it stops. The guest
+    /// executes only a cycle if `step` is true.
+    fn resume(&mut self, emu: &mut Emulator, addr: &str, step: bool) -> io::Result<Stop> {
+        if !addr.is_empty() {
+            match u64::from_str_radix(addr, 16) {
+                Ok(addr) => {
+                    emu.truncate_history();
+                    emu.cpu.pc = addr;
+                }
+                Err(_) => return Ok(Stop::Signal(SIGTRAP)),
+            }
+        }
+
+        if step {
+            return Ok(stop_from(emu.step()));
+        }
+
+        self.conn.set_nonblocking(true)?;
+        let mut first = true;
+        let stop = loop {
+            // `Emulator::run` doesn't stop at a breakpoint in the first cycle, so that the guest
+            // can continue from a breakpoint. Check it here when the guest is already running.
+            if !first && emu.has_breakpoint(emu.cpu.pc) {
+                break Stop::Signal(SIGTRAP);
+            }
+            first = false;
+
+            match emu.run(CYCLES_PER_POLL) {
+                ExitReason::LimitReached => {}
+                // Block as `Emulator::start` does instead of spinning, but only for a short time
+                // so that an interrupt from the debugger is still noticed.
+                ExitReason::WaitingForInterrupt => emu.wait_for_host(),
+                reason => break stop_from(reason),
+            }
+
+            let mut byte = [0];
+            match self.conn.read(&mut byte) {
+                Ok(0) => break Stop::Disconnected,
+                Ok(_) if byte[0] == INTERRUPT => break Stop::Signal(SIGINT),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        };
+        self.conn.set_nonblocking(false)?;
+        Ok(stop)
+    }
+
+    /// Read `len` bytes from `addr`. The result is shorter if some bytes can't be read.
+    fn read_memory(&self, emu: &mut Emulator, addr: u64, len: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..len {
+            let p_addr = match self.translate(emu, addr.wrapping_add(i)) {
+                Some(p_addr) => p_addr,
+                None => break,
+            };
+            match emu.cpu.bus.peek(p_addr, BYTE) {
+                Ok(byte) => data.push(byte as u8),
+                Err(_) => break,
+            }
+        }
+        data
+    }
+
+    /// Write `data` to `addr`. Returns `None` if some bytes can't be written.
+    fn write_memory(&self, emu: &mut Emulator, addr: u64, data: &[u8]) -> Option<()> {
+        // The execution after going back is a new one once the memory is modified.
+        emu.truncate_history();
+        let result = data.iter().enumerate().try_for_each(|(i, byte)| {
+            let p_addr = self.translate(emu, addr.wrapping_add(i as u64))?;
+            emu.cpu.bus.write(p_addr, *byte as u64, BYTE).ok()
+        });
+        // The accesses by the debugger aren't accesses by the guest.
+        emu.cpu.take_watchpoint_hit();
+        result
+    }
+
+    /// Translate an address from the debugger to a physical address. A debugger can read and
+    /// write a page which is only executable, e.g. to set a breakpoint in code, and the
+    /// translation doesn't modify the page table.
+    fn translate(&self, emu: &mut Emulator, addr: u64) -> Option<u64> {
+        if self.physical {
+            return Some(addr);
+        }
+        emu.cpu.translate_for_debugger(addr)
+    }
+
+    /// Read a packet, `$<data>#<checksum>`, and return the data. Returns `None` if the
+    /// connection is closed.
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                // The debugger didn't receive the last packet correctly.
+                Some(b'-') => {
+                    let packet = self.last_packet.clone();
+                    self.conn.write_all(&packet)?;
+                    self.conn.flush()?;
+                    continue;
+                }
+                // Ignore acknowledgements and an interrupt sent after the guest stopped.
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.conn.read_exact(&mut checksum)?;
+
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                == Some(checksum_of(&data));
+            if self.ack {
+                self.conn.write_all(if valid { b"+" } else { b"-" })?;
+                self.conn.flush()?;
+            }
+            if valid {
+                return Ok(Some(data));
+            }
+        }
+    }
+
+    /// Read a byte, or return `None` if the connection is closed.
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.conn.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Write a packet with `data`.
+    fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        debug!("gdb: -> {}", String::from_utf8_lossy(data));
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(format!("#{:02x}", checksum_of(data)).as_bytes());
+        self.conn.write_all(&packet)?;
+        self.conn.flush()?;
+        self.last_packet = packet;
+        Ok(())
+    }
+}
+
+/// Convert the reason why the emulator stopped into the state reported to the debugger.
+fn stop_from(reason: ExitReason) -> Stop {
+    match reason {
+        ExitReason::LimitReached | ExitReason::Breakpoint(_) | ExitReason::WaitingForInterrupt => {
+            Stop::Signal(SIGTRAP)
+        }
+        ExitReason::FatalTrap { .. } => Stop::Signal(SIGSEGV),
+        ExitReason::Watchpoint(hit) => Stop::Watchpoint(hit),
+        ExitReason::HistoryStart => Stop::HistoryStart,
+        reason => Stop::Exited(reason),
+    }
+}
+
+/// Read the register numbered `n` by the target description. Returns `None` if it doesn't exist.
+fn read_register(emu: &Emulator, n: u64) -> Option<u64> {
+    let cpu = &emu.cpu;
+    match n {
+        0..=31 => Some(cpu.xregs.read(n)),
+        PC_REGNUM => Some(cpu.pc),
+        FIRST_FREG_REGNUM..=64 => Some(cpu.fregs.read(n - FIRST_FREG_REGNUM).to_bits()),
+        PRIV_REGNUM => Some(cpu.mode as u64),
+        _ if n < PRIV_REGNUM => {
+            let addr = (n - FIRST_CSR_REGNUM) as CsrAddress;
+            csr_name(addr)?;
+            Some(cpu.state.read(addr))
+        }
+        _ => None,
+    }
+}
+
+/// Write the register numbered `n` by the target description. Returns `None` if it doesn't
+/// exist.
+fn write_register(emu: &mut Emulator, n: u64, value: u64) -> Option<()> {
+    // The execution after going back is a new one once a register is modified.
+    emu.truncate_history();
+    let cpu = &mut emu.cpu;
+    match n {
+        // x0 is hardwired to 0, and `XRegisters::write` ignores a write to it.
+        0..=31 => cpu.xregs.write(n, value),
+        PC_REGNUM => cpu.pc = value,
+        FIRST_FREG_REGNUM..=64 => cpu
+            .fregs
+            .write(n - FIRST_FREG_REGNUM, f64::from_bits(value)),
+        PRIV_REGNUM => {
+            cpu.mode = match value {
+                0b00 => Mode::User,
+                0b01 => Mode::Supervisor,
+                0b11 => Mode::Machine,
+                _ => return None,
+            }
+        }
+        _ if n < PRIV_REGNUM => {
+            let addr = (n - FIRST_CSR_REGNUM) as CsrAddress;
+            csr_name(addr)?;
+            cpu.state.write(addr, value);
+            // A new value of satp changes the address translation.
+            cpu.update_paging();
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Return the target description, which tells the debugger the registers and their numbers.
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\">\
+         <architecture>riscv:rv64</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (n, name) in XREG_NAMES.iter().enumerate() {
+        let kind = if *name == "sp" { "data_ptr" } else { "int" };
+        xml.push_str(&register_xml(name, n as u64, kind, None));
+    }
+    xml.push_str(&register_xml("pc", PC_REGNUM, "code_ptr", None));
+    xml.push_str("</feature><feature name=\"org.gnu.gdb.riscv.fpu\">");
+    for (i, name) in FREG_NAMES.iter().enumerate() {
+        let n = FIRST_FREG_REGNUM + i as u64;
+        xml.push_str(&register_xml(name, n, "ieee_double", None));
+    }
+    for addr in [FFLAGS, FRM, FCSR].iter() {
+        let name = csr_name(*addr).unwrap_or_default();
+        let n = FIRST_CSR_REGNUM + *addr as u64;
+        xml.push_str(&register_xml(&name, n, "int", Some("float")));
+    }
+    xml.push_str("</feature><feature name=\"org.gnu.gdb.riscv.csr\">");
+    for addr in 0..4096 {
+        if addr == FFLAGS || addr == FRM || addr == FCSR {
+            continue;
+        }
+        if let Some(name) = csr_name(addr) {
+            let n = FIRST_CSR_REGNUM + addr as u64;
+            xml.push_str(&register_xml(&name, n, "int", Some("system")));
+        }
+    }
+    xml.push_str("</feature><feature name=\"org.gnu.gdb.riscv.virtual\">");
+    xml.push_str(&register_xml("priv", PRIV_REGNUM, "int", None));
+    xml.push_str("</feature></target>");
+    xml
+}
+
+/// Return the description of a 64-bit register.
+fn register_xml(name: &str, n: u64, kind: &str, group: Option<&str>) -> String {
+    let group = group
+        .map(|group| format!(" group=\"{}\"", group))
+        .unwrap_or_default();
+    format!(
+        "<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"{}/>",
+        name, kind, n, group
+    )
+}
+
+/// Return `len` bytes of `annex` from `offset` for a `qXfer` packet. The response begins with
+/// `m` if more data follows, or `l` if it's the last part.
+fn read_annex(annex: &str, offset: u64, len: u64) -> String {
+    let bytes = annex.as_bytes();
+    let start = (offset as usize).min(bytes.len());
+    let end = start.saturating_add(len as usize).min(bytes.len());
+    let marker = if end < bytes.len() { 'm' } else { 'l' };
+    // The target description doesn't contain characters which must be escaped in a packet.
+    format!("{}{}", marker, String::from_utf8_lossy(&bytes[start..end]))
+}
+
+/// Return the checksum of a packet, which is the sum of the bytes modulo 256.
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Format a 64-bit value as hexadecimal bytes in the target byte order (little-endian).
+fn hex_u64(value: u64) -> String {
+    value
+        .to_le_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Parse hexadecimal bytes in the target byte order (little-endian) as a 64-bit value.
+fn parse_u64_le(hex: &str) -> Option<u64> {
+    let bytes = parse_bytes(hex)?;
+    if bytes.len() != 8 {
+        return None;
+    }
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes);
+    Some(u64::from_le_bytes(value))
+}
+
+/// Parse hexadecimal bytes, e.g. `2a00`.
+fn parse_bytes(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => u8::from_str_radix(std::str::from_utf8(&[*high, *low]).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parse `<addr>,<len>` in hexadecimal.
+fn parse_addr_len(args: &str) -> Option<(u64, u64)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        u64::from_str_radix(addr, 16).ok()?,
+        u64::from_str_radix(len, 16).ok()?,
+    ))
+}
@@ -6,7 +6,7 @@ use crate::{
 };
 
 /// All the interrupt kinds.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Interrupt {
     UserSoftwareInterrupt,
     SupervisorSoftwareInterrupt,
@@ -20,7 +20,8 @@ pub enum Interrupt {
 }
 
 impl Interrupt {
-    fn exception_code(&self) -> u64 {
+    /// Return the exception code of the interrupt.
+    pub fn exception_code(&self) -> u64 {
         match self {
             Interrupt::UserSoftwareInterrupt => 0,
             Interrupt::SupervisorSoftwareInterrupt => 1,
@@ -1,10 +1,9 @@
 //! RISC-V emulator core implementation.
 //!
 //! # How to use
-//! Create an `Emulator` object, place a binary data in DRAM and set the program counter to
-//! `DRAM_BASE`. The binary data must contain no headers for now. The example is here:
+//! Create an `Emulator` object and place a binary data in DRAM. The emulator starts at the reset
+//! vector in the mask ROM, which jumps to `DRAM_BASE`. The example is here:
 //! ```rust
-//! use rvemu::bus::DRAM_BASE;
 //! use rvemu::emulator::Emulator;
 //!
 //! fn main() {
@@ -16,9 +15,7 @@
 //!     // Create an emulator object.
 //!     let mut emu = Emulator::new();
 //!     // Place the binary data in the beginning of DRAM.
-//!     emu.initialize_dram(data);
-//!     // Set the program counter to 0x8000_0000, which is the address DRAM starts.
-//!     emu.initialize_pc(DRAM_BASE);
+//!     emu.initialize_dram(data).unwrap();
 //!     // Start the emulator.
 //!     emu.start();
 //!
@@ -28,15 +25,28 @@
 //! }
 //! ```
 //!
+//! An ELF file can be loaded by `Emulator::load_elf` instead, which places each loadable segment
+//! at its physical address and makes the reset vector jump to the entry point.
+//!
 //! See the example usage in
 //! [rvemu/lib/rvemu-cli/src/main.rs](https://github.com/d0iasm/rvemu/blob/master/lib/rvemu-cli/src/main.rs).
 
 pub mod bus;
+pub mod commit_log;
 pub mod cpu;
 pub mod csr;
 pub mod devices;
+pub mod disassembler;
 pub mod dram;
+pub mod dtb;
+pub mod elf;
 pub mod emulator;
 pub mod exception;
+pub mod gdb;
 pub mod interrupt;
+pub mod replay;
+pub mod reverse;
 pub mod rom;
+pub mod snapshot;
+pub mod symbol;
+pub mod watchpoint;
@@ -0,0 +1,87 @@
+//! The replay module contains the log of external inputs for deterministic record and replay. The
+//! emulator itself is deterministic, so a run is reproduced by delivering the same inputs at the
+//! same cycles. Each input is written as a line with the cycle counted by `Emulator::run` and the
+//! event, e.g. `123456 uart 0x61`. The inputs are bytes received by UART, and samples of the host
+//! clock when the timer follows it.
+
+use std::fmt;
+
+/// An external input delivered to the guest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEvent {
+    /// A byte received by UART.
+    Uart(u8),
+    /// A sample of the host clock, which is the new value of `mtime`, in the wall-clock mode of
+    /// the timer.
+    Time(u64),
+}
+
+/// An external input and the cycle when it's delivered, before the instruction in the cycle is
+/// executed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputRecord {
+    pub cycle: u64,
+    pub event: InputEvent,
+}
+
+/// The errors that happen while parsing an input log.
+#[derive(Debug, PartialEq)]
+pub enum ReplayError {
+    /// The line (1-based) isn't a valid input.
+    InvalidLine(usize),
+    /// The cycle of the line (1-based) is earlier than the previous input.
+    OutOfOrder(usize),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::InvalidLine(line) => write!(f, "invalid input at line {}", line),
+            ReplayError::OutOfOrder(line) => write!(f, "input out of order at line {}", line),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Format an input as a line of the log, e.g. `123456 uart 0x61`.
+pub fn format(record: &InputRecord) -> String {
+    match record.event {
+        InputEvent::Uart(byte) => format!("{} uart {:#04x}", record.cycle, byte),
+        InputEvent::Time(mtime) => format!("{} time {:#x}", record.cycle, mtime),
+    }
+}
+
+/// Parse an input log. Empty lines and lines starting with `#` are ignored.
+pub fn parse(log: &str) -> Result<Vec<InputRecord>, ReplayError> {
+    let mut records: Vec<InputRecord> = Vec::new();
+    for (i, line) in log.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let record = parse_line(line).ok_or(ReplayError::InvalidLine(i + 1))?;
+        if let Some(last) = records.last() {
+            if record.cycle < last.cycle {
+                return Err(ReplayError::OutOfOrder(i + 1));
+            }
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Parse a line of an input log.
+fn parse_line(line: &str) -> Option<InputRecord> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let cycle = fields.first()?.parse().ok()?;
+    let event = match fields[1..] {
+        ["uart", byte] => InputEvent::Uart(u8::from_str_radix(byte.strip_prefix("0x")?, 16).ok()?),
+        ["time", mtime] => {
+            InputEvent::Time(u64::from_str_radix(mtime.strip_prefix("0x")?, 16).ok()?)
+        }
+        _ => return None,
+    };
+    Some(InputRecord { cycle, event })
+}
@@ -0,0 +1,197 @@
+//! The reverse module contains the history of an execution for reverse execution. Checkpoints of
+//! the machine are taken periodically while the guest runs, and the emulator goes back in time by
+//! restoring the nearest checkpoint before the target cycle and executing forward to it. The
+//! inputs delivered to the guest are kept in the history, so that the execution from a checkpoint
+//! is the same as the original one.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::cpu::Cpu;
+use crate::dram::DramCheckpoint;
+use crate::replay::{InputEvent, InputRecord};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+/// The errors that happen while executing the guest backwards.
+#[derive(Debug, PartialEq)]
+pub enum ReverseError {
+    /// Reverse execution isn't enabled by `Emulator::enable_reverse_execution`.
+    NotEnabled,
+}
+
+impl fmt::Display for ReverseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReverseError::NotEnabled => write!(f, "reverse execution is not enabled"),
+        }
+    }
+}
+
+impl std::error::Error for ReverseError {}
+
+/// A checkpoint of the machine at the beginning of a cycle.
+struct Checkpoint {
+    /// The cycle when the checkpoint is taken.
+    cycle: u64,
+    /// The state of the hart and the built-in devices in the format of a snapshot.
+    state: Vec<u8>,
+    /// The contents of the memory.
+    dram: DramCheckpoint,
+}
+
+/// The history of an execution, which consists of the checkpoints and the inputs delivered since
+/// the oldest checkpoint.
+pub(crate) struct History {
+    /// The number of cycles between checkpoints.
+    interval: u64,
+    /// The maximum number of checkpoints. The oldest one is discarded when a new one is taken.
+    max_checkpoints: usize,
+    checkpoints: VecDeque<Checkpoint>,
+    /// The memory at the checkpoint taken or restored last. The pages written since then are
+    /// tracked by the memory.
+    base: Option<DramCheckpoint>,
+    inputs: VecDeque<InputRecord>,
+    /// The index of the next input to deliver while the execution is re-executed.
+    next_input: usize,
+    /// The cycle after the latest one executed. The cycles before it are re-executed with the
+    /// inputs in the history instead of the inputs from the host.
+    frontier: u64,
+}
+
+impl History {
+    /// Create a history which starts with a checkpoint at the beginning of `cycle`. `interval` and
+    /// `max_checkpoints` are at least 1.
+    pub fn new(cycle: u64, interval: u64, max_checkpoints: usize, cpu: &mut Cpu) -> Self {
+        let mut history = Self {
+            interval: interval.max(1),
+            max_checkpoints: max_checkpoints.max(1),
+            checkpoints: VecDeque::new(),
+            base: None,
+            inputs: VecDeque::new(),
+            next_input: 0,
+            frontier: cycle,
+        };
+        history.reset(cycle, cpu);
+        history
+    }
+
+    /// Discard the entire history, and start a new one with a checkpoint at the beginning of
+    /// `cycle`.
+    pub fn reset(&mut self, cycle: u64, cpu: &mut Cpu) {
+        self.checkpoints.clear();
+        self.base = None;
+        self.inputs.clear();
+        self.next_input = 0;
+        self.frontier = cycle;
+        self.take_checkpoint(cycle, cpu);
+    }
+
+    /// Return the cycle of the oldest checkpoint, before which the execution can't go back.
+    pub fn oldest_cycle(&self) -> u64 {
+        self.checkpoints
+            .front()
+            .map_or(self.frontier, |checkpoint| checkpoint.cycle)
+    }
+
+    /// Return true if `cycle` has been executed, so it's re-executed with the inputs in the
+    /// history.
+    pub fn is_replaying(&self, cycle: u64) -> bool {
+        cycle < self.frontier
+    }
+
+    /// Start executing a new `cycle` beyond the history. A checkpoint is taken before it if the
+    /// interval has passed since the latest one.
+    pub fn begin_cycle(&mut self, cycle: u64, cpu: &mut Cpu) {
+        let is_due = match self.checkpoints.back() {
+            Some(latest) => cycle >= latest.cycle + self.interval,
+            None => true,
+        };
+        if is_due {
+            self.take_checkpoint(cycle, cpu);
+        }
+        self.frontier = cycle + 1;
+    }
+
+    /// Add an input delivered in a new cycle.
+    pub fn record_input(&mut self, record: InputRecord) {
+        self.inputs.push_back(record);
+        self.next_input = self.inputs.len();
+    }
+
+    /// Return the next input in the history to deliver in `cycle`, which is re-executed.
+    pub fn next_input(&mut self, cycle: u64) -> Option<InputEvent> {
+        let record = self.inputs.get(self.next_input)?;
+        if record.cycle > cycle {
+            return None;
+        }
+        self.next_input += 1;
+        Some(record.event)
+    }
+
+    /// Take a checkpoint of the machine at the beginning of `cycle`.
+    fn take_checkpoint(&mut self, cycle: u64, cpu: &mut Cpu) {
+        let mut state = Vec::new();
+        cpu.save_snapshot(&mut SnapshotWriter::new(&mut state))
+            .expect("failed to save a checkpoint");
+        let dram = cpu.bus.checkpoint_dram(self.base.as_ref());
+        self.base = Some(dram.clone());
+        self.checkpoints
+            .push_back(Checkpoint { cycle, state, dram });
+
+        if self.checkpoints.len() > self.max_checkpoints {
+            self.checkpoints.pop_front();
+            // The inputs before the oldest checkpoint are never delivered again.
+            let oldest = self.oldest_cycle();
+            while self
+                .inputs
+                .front()
+                .is_some_and(|record| record.cycle < oldest)
+            {
+                self.inputs.pop_front();
+                self.next_input -= 1;
+            }
+        }
+    }
+
+    /// Restore the latest checkpoint at or before `cycle`, or the oldest one if there isn't, and
+    /// return the cycle of the checkpoint.
+    pub fn restore(&mut self, cycle: u64, cpu: &mut Cpu) -> u64 {
+        let index = self
+            .checkpoints
+            .iter()
+            .rposition(|checkpoint| checkpoint.cycle <= cycle)
+            .unwrap_or(0);
+        let checkpoint = &self.checkpoints[index];
+
+        cpu.load_snapshot(&mut SnapshotReader::new(&checkpoint.state[..]))
+            .expect("failed to restore a checkpoint");
+        let base = self.base.as_ref().expect("no checkpoint has been taken");
+        cpu.bus.restore_dram(&checkpoint.dram, base);
+        self.base = Some(checkpoint.dram.clone());
+        self.next_input = self
+            .inputs
+            .partition_point(|record| record.cycle < checkpoint.cycle);
+        checkpoint.cycle
+    }
+
+    /// Discard the history after `cycle`, so that the execution from `cycle` is a new one. The
+    /// inputs delivered after it are discarded too.
+    pub fn truncate(&mut self, cycle: u64) {
+        while self
+            .checkpoints
+            .back()
+            .is_some_and(|checkpoint| checkpoint.cycle > cycle)
+        {
+            self.checkpoints.pop_back();
+        }
+        while self
+            .inputs
+            .back()
+            .is_some_and(|record| record.cycle >= cycle)
+        {
+            self.inputs.pop_back();
+        }
+        self.next_input = self.inputs.len();
+        self.frontier = cycle;
+    }
+}
@@ -1,215 +1,156 @@
-//! The rom module contains the read-only memory structure and implementation to read the memory. ROM includes a device tree blob (DTB) compiled from a device tree source (DTS).
+//! The rom module contains the read-only memory structure and implementation to read the memory. ROM includes a reset vector and a device tree blob (DTB) generated by the dtb module.
 
-use crate::bus::MROM_BASE;
-use crate::cpu::{BYTE, DOUBLEWORD, HALFWORD, WORD};
-use crate::exception::Exception;
-
-use std::fs::File;
 use std::io::prelude::*;
-use std::process::Command;
-
-const DTS_FILE_NAME: &str = "rvemu.dts";
-const DTB_FILE_NAME: &str = "rvemu.dtb";
-
-/// Create a new dts file. If the file already existed, the old content is destroyed. Otherwise, a new file is created.
-fn create_dts() -> std::io::Result<()> {
-    // TODO: Make this content more flexible depending on the number of cpus.
-    // Reference code is https://github.com/riscv/riscv-isa-sim/blob/66b44bfbedda562a32e4a2cd0716afbf731b69cd/riscv/dts.cc#L38-L54
-    let content = r#"/dts-v1/;
-
-/ {
-    #address-cells = <0x02>;
-    #size-cells = <0x02>;
-    compatible = "riscv-virtio";
-    model = "riscv-virtio,qemu";
-
-    chosen {
-        bootargs = "root=/dev/vda ro console=ttyS0";
-        stdout-path = "/uart@10000000";
-    };
-
-    uart@10000000 {
-        interrupts = <0xa>;
-        interrupt-parent = <0x03>;
-        clock-frequency = <0x384000>;
-        reg = <0x0 0x10000000 0x0 0x100>;
-        compatible = "ns16550a";
-    };
-
-    virtio_mmio@10001000 {
-        interrupts = <0x01>;
-        interrupt-parent = <0x03>;
-        reg = <0x0 0x10001000 0x0 0x1000>;
-        compatible = "virtio,mmio";
-    };
-
-    cpus {
-        #address-cells = <0x01>;
-        #size-cells = <0x00>;
-        timebase-frequency = <0x989680>;
-
-        cpu-map {
-            cluster0 {
-                core0 {
-                    cpu = <0x01>;
-                };
-            };
-        };
-
-        cpu@0 {
-            phandle = <0x01>;
-            device_type = "cpu";
-            reg = <0x00>;
-            status = "okay";
-            compatible = "riscv";
-            riscv,isa = "rv64imafdcsu";
-            mmu-type = "riscv,sv48";
-
-            interrupt-controller {
-                #interrupt-cells = <0x01>;
-                interrupt-controller;
-                compatible = "riscv,cpu-intc";
-                phandle = <0x02>;
-            };
-        };
-    };
-
-	memory@80000000 {
-		device_type = "memory";
-		reg = <0x0 0x80000000 0x0 0x8000000>;
-	};
-
-    soc {
-        #address-cells = <0x02>;
-        #size-cells = <0x02>;
-        compatible = "simple-bus";
-        ranges;
-
-        interrupt-controller@c000000 {
-            phandle = <0x03>;
-            riscv,ndev = <0x35>;
-            reg = <0x00 0xc000000 0x00 0x4000000>;
-            interrupts-extended = <0x02 0x0b 0x02 0x09>;
-            interrupt-controller;
-            compatible = "riscv,plic0";
-            #interrupt-cells = <0x01>;
-            #address-cells = <0x00>;
-        };
-
-        clint@2000000 {
-            interrupts-extended = <0x02 0x03 0x02 0x07>;
-            reg = <0x00 0x2000000 0x00 0x10000>;
-            compatible = "riscv,clint0";
-        };
-    };
-};"#;
-
-    let mut dts = File::create(DTS_FILE_NAME)?;
-    dts.write_all(content.as_bytes())?;
-    Ok(())
-}
 
-/// Compile a dts file to a dtb file.
-fn compile_dts() -> std::io::Result<()> {
-    // dtc -I dts -O dtb -o <FILE_NAME>.dtb <FILE_NAME>.dts
-    Command::new("dtc")
-        .args(&["-I", "dts", "-O", "dtb", "-o", DTB_FILE_NAME, DTS_FILE_NAME])
-        .output()?;
-    Ok(())
-}
-
-/// Read a dtb file. First, create a dts file. Second, compile it to a dtb file. Finally, read the dtb file and return the binary content.
-fn dtb() -> std::io::Result<Vec<u8>> {
-    create_dts()?;
-    compile_dts()?;
-
-    let mut dtb = Vec::new();
-    File::open(DTB_FILE_NAME)?.read_to_end(&mut dtb)?;
-
-    Ok(dtb)
-}
-
-/// The read-only memory (ROM).
+use crate::bus::{DRAM_BASE, MROM_BASE, MROM_SIZE};
+use crate::cpu::{BYTE, DOUBLEWORD, HALFWORD, POINTER_TO_DTB, WORD};
+use crate::exception::Exception;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+// QEMU virt machine:
+// https://github.com/qemu/qemu/blob/master/hw/riscv/boot.c (riscv_setup_rom_reset_vec)
+// OpenSBI fw_dynamic:
+// https://github.com/riscv/opensbi/blob/master/include/sbi/fw_dynamic.h
+
+/// The size of the reset vector, which contains the instructions and 2 addresses below.
+const RESET_VECTOR_SIZE: usize = 0x28;
+/// The offset of the address which the reset vector jumps to.
+const START_ADDR_OFFSET: usize = 0x18;
+/// The offset of the address of the device tree blob passed in a1.
+const FDT_ADDR_OFFSET: usize = 0x20;
+/// The offset of the fw_dynamic information passed in a2. It's placed right after the reset
+/// vector.
+const FW_DYNAMIC_INFO_OFFSET: usize = RESET_VECTOR_SIZE;
+/// The offset of the device tree blob.
+const DTB_OFFSET: usize = (POINTER_TO_DTB - MROM_BASE) as usize;
+
+/// The address of the fw_dynamic information.
+pub const FW_DYNAMIC_INFO_ADDR: u64 = MROM_BASE + FW_DYNAMIC_INFO_OFFSET as u64;
+/// "OSBI" in ASCII, which is the magic number of the fw_dynamic information.
+const FW_DYNAMIC_INFO_MAGIC: u64 = 0x4942534f;
+/// The version of the fw_dynamic information.
+const FW_DYNAMIC_INFO_VERSION: u64 = 2;
+/// The privilege mode which the firmware switches to for the next booting stage (supervisor).
+const FW_DYNAMIC_INFO_NEXT_MODE_S: u64 = 1;
+
+/// The instructions of the reset vector. The 2 addresses following the instructions are set by
+/// `Rom::set_start_addr` and `Rom::set_fdt_addr`.
+const RESET_VECTOR: [u32; 6] = [
+    0x00000297, // 1: auipc t0, %pcrel_hi(fw_dyn)
+    0x02828613, // addi a2, t0, %pcrel_lo(1b)
+    0xf1402573, // csrr a0, mhartid
+    0x0202b583, // ld a1, 32(t0)
+    0x0182b283, // ld t0, 24(t0)
+    0x00028067, // jr t0
+];
+
+/// The read-only memory (ROM). It contains a reset vector, the fw_dynamic information for OpenSBI
+/// and a device tree blob.
 pub struct Rom {
     data: Vec<u8>,
 }
 
 impl Rom {
-    /// Create a new `rom` object.
-    pub fn new() -> Self {
-        let mut dtb = match dtb() {
-            Ok(dtb) => dtb,
-            Err(e) => {
-                // TODO: should fail?
-                println!("WARNING: failed to read a device tree binary: {}", e);
-                println!(
-                    "WARNING: maybe need to install dtc commend `apt install device-tree-compiler`"
-                );
-                Vec::new()
-            }
-        };
-
-        // TODO: set a reset vector correctly.
-        // 0x20 is the size of a reset vector.
-        let mut rom = vec![0; 32];
-        rom.append(&mut dtb);
-        let align = 0x1000;
-        rom.resize((rom.len() + align - 1) / align * align, 0);
+    /// Create a new `rom` object with a device tree blob. The reset vector jumps to the start of
+    /// DRAM by default.
+    pub fn new(dtb: Vec<u8>) -> Self {
+        let mut data = vec![0; DTB_OFFSET];
+        for (i, inst) in RESET_VECTOR.iter().enumerate() {
+            data[i * 4..i * 4 + 4].copy_from_slice(&inst.to_le_bytes());
+        }
 
-        Self { data: rom }
+        let mut rom = Self { data };
+        rom.set_start_addr(DRAM_BASE);
+        rom.set_fdt_addr(POINTER_TO_DTB);
+        rom.write_fw_dynamic_info(0, FW_DYNAMIC_INFO_MAGIC);
+        rom.write_fw_dynamic_info(1, FW_DYNAMIC_INFO_VERSION);
+        rom.set_next_addr(0);
+        rom.write_fw_dynamic_info(3, FW_DYNAMIC_INFO_NEXT_MODE_S);
+        // options
+        rom.write_fw_dynamic_info(4, 0);
+        // boot_hart
+        rom.write_fw_dynamic_info(5, 0);
+        rom.set_dtb(dtb);
+        rom
     }
 
     pub fn new_with_data(data: Vec<u8>) -> Rom {
         Rom { data }
     }
 
-    /// Load `size`-bit data from the memory.
-    pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
-        match size {
-            BYTE => Ok(self.read8(addr)),
-            HALFWORD => Ok(self.read16(addr)),
-            WORD => Ok(self.read32(addr)),
-            DOUBLEWORD => Ok(self.read64(addr)),
-            _ => return Err(Exception::LoadAccessFault),
-        }
+    /// Replace the device tree blob, which is placed after the fw_dynamic information.
+    pub fn set_dtb(&mut self, mut dtb: Vec<u8>) {
+        self.data.truncate(DTB_OFFSET);
+        self.data.append(&mut dtb);
+        let align = 0x1000;
+        self.data.resize(self.data.len().div_ceil(align) * align, 0);
     }
 
-    /// Store `size`-bit data to the memory. Returns the exception because the ROM is read-only.
-    pub fn write(&self, _addr: u64, _value: u64, _size: u8) -> Result<(), Exception> {
-        Err(Exception::StoreAMOAccessFault)
+    /// Set the address which the reset vector jumps to, e.g. the entry point of a firmware.
+    pub fn set_start_addr(&mut self, addr: u64) {
+        self.write_u64(START_ADDR_OFFSET, addr);
+    }
+
+    /// Set the address of the device tree blob which the reset vector passes in a1.
+    pub fn set_fdt_addr(&mut self, addr: u64) {
+        self.write_u64(FDT_ADDR_OFFSET, addr);
+    }
+
+    /// Set the address of the next booting stage in the fw_dynamic information, e.g. the entry
+    /// point of a kernel which OpenSBI jumps to.
+    pub fn set_next_addr(&mut self, addr: u64) {
+        self.write_fw_dynamic_info(2, addr);
+    }
+
+    /// Write the `index`-th field of the fw_dynamic information.
+    fn write_fw_dynamic_info(&mut self, index: usize, value: u64) {
+        self.write_u64(FW_DYNAMIC_INFO_OFFSET + index * 8, value);
     }
 
-    /// Read a byte from the rom.
-    fn read8(&self, addr: u64) -> u64 {
-        let index = (addr - MROM_BASE) as usize;
-        self.data[index] as u64
+    /// Write 8 bytes to the ROM in little endian. This is only for building the ROM contents.
+    fn write_u64(&mut self, offset: usize, value: u64) {
+        self.data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
     }
 
-    /// Read 2 bytes from the rom.
-    fn read16(&self, addr: u64) -> u64 {
-        let index = (addr - MROM_BASE) as usize;
-        return (self.data[index] as u64) | ((self.data[index + 1] as u64) << 8);
+    /// Write the contents of the ROM to a snapshot.
+    pub(crate) fn save_snapshot<W: Write>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<(), SnapshotError> {
+        writer.write_vec(&self.data)
     }
 
-    /// Read 4 bytes from the rom.
-    fn read32(&self, addr: u64) -> u64 {
-        let index = (addr - MROM_BASE) as usize;
-        return (self.data[index] as u64)
-            | ((self.data[index + 1] as u64) << 8)
-            | ((self.data[index + 2] as u64) << 16)
-            | ((self.data[index + 3] as u64) << 24);
+    /// Restore the contents of the ROM from a snapshot.
+    pub(crate) fn load_snapshot<R: Read>(
+        &mut self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<(), SnapshotError> {
+        self.data = reader.read_vec(MROM_SIZE)?;
+        Ok(())
     }
 
-    /// Read 8 bytes from the rom.
-    fn read64(&self, addr: u64) -> u64 {
-        let index = (addr - MROM_BASE) as usize;
-        return (self.data[index] as u64)
-            | ((self.data[index + 1] as u64) << 8)
-            | ((self.data[index + 2] as u64) << 16)
-            | ((self.data[index + 3] as u64) << 24)
-            | ((self.data[index + 4] as u64) << 32)
-            | ((self.data[index + 5] as u64) << 40)
-            | ((self.data[index + 6] as u64) << 48)
-            | ((self.data[index + 7] as u64) << 56);
+    /// Load `size`-bit data from the memory. The area after the contents reads as zeros.
+    pub fn read(&self, addr: u64, size: u8) -> Result<u64, Exception> {
+        match size {
+            BYTE | HALFWORD | WORD | DOUBLEWORD => Ok(self.read_bytes(addr, (size / 8) as u64)),
+            _ => Err(Exception::LoadAccessFault),
+        }
+    }
+
+    /// Store `size`-bit data to the memory. Returns the exception because the ROM is read-only.
+    pub fn write(&self, _addr: u64, _value: u64, _size: u8) -> Result<(), Exception> {
+        Err(Exception::StoreAMOAccessFault)
+    }
+
+    /// Read `len` bytes at `addr` from the rom with little endian.
+    fn read_bytes(&self, addr: u64, len: u64) -> u64 {
+        let index = addr.wrapping_sub(MROM_BASE);
+        (0..len).fold(0, |value, i| {
+            let byte = match self.data.get(index.wrapping_add(i) as usize) {
+                Some(byte) => *byte as u64,
+                None => 0,
+            };
+            value | (byte << (i * 8))
+        })
     }
 }
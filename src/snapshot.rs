@@ -0,0 +1,244 @@
+//! The snapshot module contains the format of a snapshot, which is the entire state of a machine
+//! saved by `Emulator::save_snapshot` and restored by `Emulator::load_snapshot`. A snapshot is a
+//! stream of little-endian values which starts with a header. Each component of the machine
+//! writes its state in a fixed order after the header, and reads it back in the same order.
+//!
+//! The memory is saved sparsely: only chunks which contain a nonzero byte are written. The disk
+//! is saved as a delta from the image set by `Emulator::initialize_disk`, so the emulator which
+//! loads a snapshot must have the same disk image.
+
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+/// The magic number at the beginning of a snapshot.
+const SNAPSHOT_MAGIC: [u8; 8] = *b"RVEMUSNP";
+/// The version of the snapshot format. It's incremented whenever the format changes.
+const SNAPSHOT_VERSION: u32 = 2;
+
+/// The errors that happen while saving or loading a snapshot.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// Reading or writing the snapshot failed, e.g. the snapshot is truncated.
+    Io(io::Error),
+    /// The snapshot doesn't start with the magic number.
+    InvalidMagic,
+    /// The snapshot was saved in a format which this emulator doesn't support.
+    UnsupportedVersion(u32),
+    /// The size of DRAM in the snapshot differs from the size of DRAM in the emulator.
+    DramSizeMismatch { expected: u64, found: u64 },
+    /// The size of the disk in the snapshot differs from the size of the disk image in the
+    /// emulator.
+    DiskSizeMismatch { expected: u64, found: u64 },
+    /// A value in the snapshot is out of range, e.g. an unknown privilege mode.
+    Corrupted(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "failed to access the snapshot: {}", e),
+            SnapshotError::InvalidMagic => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::DramSizeMismatch { expected, found } => write!(
+                f,
+                "snapshot has {:#x} bytes of DRAM but the emulator has {:#x} bytes",
+                found, expected
+            ),
+            SnapshotError::DiskSizeMismatch { expected, found } => write!(
+                f,
+                "snapshot has a disk of {:#x} bytes but the emulator has {:#x} bytes",
+                found, expected
+            ),
+            SnapshotError::Corrupted(what) => write!(f, "corrupted snapshot: invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+/// The header of a snapshot, which describes the configuration of the machine. It's checked
+/// before any state is restored.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Header {
+    /// The size of DRAM in bytes.
+    pub dram_size: u64,
+    /// The size of the disk image in bytes.
+    pub disk_size: u64,
+}
+
+/// The writer of a snapshot.
+pub(crate) struct SnapshotWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    /// Create a new writer which writes a snapshot to `inner`.
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Write the magic number, the version and the header.
+    pub fn write_header(&mut self, header: &Header) -> Result<(), SnapshotError> {
+        self.write_bytes(&SNAPSHOT_MAGIC)?;
+        self.write_u32(SNAPSHOT_VERSION)?;
+        self.write_u64(header.dram_size)?;
+        self.write_u64(header.disk_size)
+    }
+
+    pub fn write_u8(&mut self, value: u8) -> Result<(), SnapshotError> {
+        self.write_bytes(&[value])
+    }
+
+    pub fn write_bool(&mut self, value: bool) -> Result<(), SnapshotError> {
+        self.write_u8(value as u8)
+    }
+
+    pub fn write_u32(&mut self, value: u32) -> Result<(), SnapshotError> {
+        self.write_bytes(&value.to_le_bytes())
+    }
+
+    pub fn write_u64(&mut self, value: u64) -> Result<(), SnapshotError> {
+        self.write_bytes(&value.to_le_bytes())
+    }
+
+    /// Write an optional value as a flag followed by the value, which is 0 if it's `None`.
+    pub fn write_option(&mut self, value: Option<u64>) -> Result<(), SnapshotError> {
+        self.write_bool(value.is_some())?;
+        self.write_u64(value.unwrap_or(0))
+    }
+
+    pub fn write_u32s(&mut self, values: &[u32]) -> Result<(), SnapshotError> {
+        for value in values {
+            self.write_u32(*value)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_u64s(&mut self, values: &[u64]) -> Result<(), SnapshotError> {
+        for value in values {
+            self.write_u64(*value)?;
+        }
+        Ok(())
+    }
+
+    /// Write bytes whose length is fixed by the format.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        self.inner.write_all(bytes)?;
+        Ok(())
+    }
+
+    /// Write the length of bytes followed by the bytes.
+    pub fn write_vec(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        self.write_u64(bytes.len() as u64)?;
+        self.write_bytes(bytes)
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<(), SnapshotError> {
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+/// The reader of a snapshot.
+pub(crate) struct SnapshotReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    /// Create a new reader which reads a snapshot from `inner`.
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// Read and check the magic number and the version, and return the header.
+    pub fn read_header(&mut self) -> Result<Header, SnapshotError> {
+        let mut magic = [0; 8];
+        self.read_bytes(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::InvalidMagic);
+        }
+        let version = self.read_u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        Ok(Header {
+            dram_size: self.read_u64()?,
+            disk_size: self.read_u64()?,
+        })
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SnapshotError> {
+        let mut bytes = [0; 1];
+        self.read_bytes(&mut bytes)?;
+        Ok(bytes[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Corrupted("boolean")),
+        }
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SnapshotError> {
+        let mut bytes = [0; 4];
+        self.read_bytes(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut bytes = [0; 8];
+        self.read_bytes(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Read an optional value written by `SnapshotWriter::write_option`.
+    pub fn read_option(&mut self) -> Result<Option<u64>, SnapshotError> {
+        let is_some = self.read_bool()?;
+        let value = self.read_u64()?;
+        Ok(if is_some { Some(value) } else { None })
+    }
+
+    pub fn read_u32s(&mut self, values: &mut [u32]) -> Result<(), SnapshotError> {
+        for value in values.iter_mut() {
+            *value = self.read_u32()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_u64s(&mut self, values: &mut [u64]) -> Result<(), SnapshotError> {
+        for value in values.iter_mut() {
+            *value = self.read_u64()?;
+        }
+        Ok(())
+    }
+
+    /// Read bytes whose length is fixed by the format.
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), SnapshotError> {
+        self.inner.read_exact(bytes)?;
+        Ok(())
+    }
+
+    /// Read bytes written by `SnapshotWriter::write_vec`. The length must be at most `max_len`
+    /// so that a corrupted length doesn't allocate a huge buffer.
+    pub fn read_vec(&mut self, max_len: u64) -> Result<Vec<u8>, SnapshotError> {
+        let len = self.read_u64()?;
+        if len > max_len {
+            return Err(SnapshotError::Corrupted("length"));
+        }
+        let mut bytes = vec![0; len as usize];
+        self.read_bytes(&mut bytes)?;
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,84 @@
+//! The symbol module contains the symbol table which maps addresses to the names of functions and
+//! objects in a loaded program.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// A named address range in a program.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Symbol {
+    /// The name of the symbol.
+    pub name: String,
+    /// The address of the symbol.
+    pub addr: u64,
+    /// The size of the symbol in bytes. It's 0 if the size is unknown, e.g. a label in assembly.
+    pub size: u64,
+}
+
+/// The symbol table sorted by addresses.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: BTreeMap<u64, Symbol>,
+    /// The addresses of all the symbols by name, including the ones which share an address with
+    /// another symbol, e.g. `begin_signature` and the first data in it.
+    addresses: HashMap<String, u64>,
+}
+
+impl SymbolTable {
+    /// Create a new empty symbol table.
+    pub fn new() -> Self {
+        Self {
+            symbols: BTreeMap::new(),
+            addresses: HashMap::new(),
+        }
+    }
+
+    /// Return true if the table has no symbols.
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Add a symbol. When some symbols share the same address, the one with a size is preferred
+    /// because it's more likely to be a function or an object rather than a local label.
+    pub fn insert(&mut self, name: String, addr: u64, size: u64) {
+        self.addresses.insert(name.clone(), addr);
+        if let Some(existing) = self.symbols.get(&addr) {
+            if existing.size != 0 && size == 0 {
+                return;
+            }
+        }
+        self.symbols.insert(addr, Symbol { name, addr, size });
+    }
+
+    /// Add all the symbols in `other`, e.g. when a firmware and a kernel are loaded together.
+    pub fn extend(&mut self, other: SymbolTable) {
+        for (_, symbol) in other.symbols {
+            self.insert(symbol.name, symbol.addr, symbol.size);
+        }
+        self.addresses.extend(other.addresses);
+    }
+
+    /// Return the address of the symbol named `name`.
+    pub fn address_of(&self, name: &str) -> Option<u64> {
+        self.addresses.get(name).copied()
+    }
+
+    /// Return the symbol which contains `addr` and the offset from the start of the symbol.
+    pub fn lookup(&self, addr: u64) -> Option<(&Symbol, u64)> {
+        let (_, symbol) = self.symbols.range(..=addr).next_back()?;
+        let offset = addr - symbol.addr;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((symbol, offset))
+    }
+
+    /// Format `addr` with the symbol name and the offset, e.g. `0x80000044 <main+0x4>`. Only the
+    /// address is shown if no symbol contains it.
+    pub fn symbolize(&self, addr: u64) -> String {
+        match self.lookup(addr) {
+            Some((symbol, 0)) => format!("{:#x} <{}>", addr, symbol.name),
+            Some((symbol, offset)) => format!("{:#x} <{}+{:#x}>", addr, symbol.name, offset),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
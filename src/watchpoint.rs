@@ -0,0 +1,138 @@
+//! The watchpoint module contains watchpoints, which stop the emulator when a range of memory is
+//! accessed. A watchpoint on virtual addresses is checked by the CPU with the address before
+//! translation, and a watchpoint on physical addresses is checked by the system bus, so that it
+//! also catches accesses by devices, e.g. DMA by virtio.
+
+/// The kind of an access to memory, or the kinds of accesses which a watchpoint watches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    /// A load from memory.
+    Read,
+    /// A store to memory.
+    Write,
+    /// A load from or a store to memory. It's only used for watchpoints.
+    Access,
+    /// An instruction fetch.
+    Execute,
+}
+
+/// The address space of a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressSpace {
+    /// Addresses which the CPU accesses before the translation.
+    Virtual,
+    /// Addresses on the system bus.
+    Physical,
+}
+
+/// A watchpoint on `len` bytes from `addr`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub space: AddressSpace,
+    pub kind: WatchKind,
+    pub addr: u64,
+    pub len: u64,
+}
+
+impl Watchpoint {
+    /// Return true if a `size`-bit access of `kind` at `addr` hits the watchpoint.
+    fn is_hit(&self, kind: WatchKind, addr: u64, size: u8) -> bool {
+        let watched = match self.kind {
+            WatchKind::Access => kind == WatchKind::Read || kind == WatchKind::Write,
+            watched => kind == watched,
+        };
+        watched
+            && addr < self.addr.wrapping_add(self.len)
+            && self.addr < addr.wrapping_add((size / 8) as u64)
+    }
+}
+
+/// An access which hit a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchpointHit {
+    /// The watchpoint hit.
+    pub watchpoint: Watchpoint,
+    /// The address of the instruction executed in the cycle when the memory is accessed. It's set
+    /// by `Emulator::run`, because the system bus doesn't know it.
+    pub pc: u64,
+    /// The kind of the access, which is `Read`, `Write` or `Execute`.
+    pub kind: WatchKind,
+    /// The address accessed in the address space of the watchpoint.
+    pub addr: u64,
+    /// The size of the access in bits.
+    pub size: u8,
+    /// The value loaded, stored or fetched.
+    pub value: u64,
+}
+
+/// A set of watchpoints in the same address space, and the first hit which isn't taken yet.
+#[derive(Debug, Default)]
+pub struct Watchpoints {
+    watchpoints: Vec<Watchpoint>,
+    hit: Option<WatchpointHit>,
+}
+
+impl Watchpoints {
+    /// Create an empty set of watchpoints.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a watchpoint.
+    pub fn add(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Remove a watchpoint. Returns false if it doesn't exist.
+    pub fn remove(&mut self, watchpoint: &Watchpoint) -> bool {
+        match self.watchpoints.iter().position(|w| w == watchpoint) {
+            Some(index) => {
+                self.watchpoints.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Return true if no watchpoint is set.
+    pub fn is_empty(&self) -> bool {
+        self.watchpoints.is_empty()
+    }
+
+    /// Check a `size`-bit access of `kind` at `addr` with `value`, and record the hit if it's the
+    /// first one.
+    #[inline]
+    pub fn check(&mut self, kind: WatchKind, addr: u64, size: u8, value: u64) {
+        if !self.watchpoints.is_empty() {
+            self.check_slow(kind, addr, size, value);
+        }
+    }
+
+    /// Compare an access with the watchpoints. It's out of line to keep `check` cheap while no
+    /// watchpoint is set.
+    #[cold]
+    fn check_slow(&mut self, kind: WatchKind, addr: u64, size: u8, value: u64) {
+        match self.hit {
+            // The CPU fetches the lower 16 bits of an instruction to find its length, and then
+            // fetches the whole instruction if it's 32 bits.
+            Some(hit) if kind == WatchKind::Execute && hit.kind == kind && hit.addr == addr => {}
+            Some(_) => return,
+            None => {}
+        }
+        if let Some(watchpoint) = self.watchpoints.iter().find(|w| w.is_hit(kind, addr, size)) {
+            self.hit = Some(WatchpointHit {
+                watchpoint: *watchpoint,
+                pc: 0,
+                kind,
+                addr,
+                size,
+                value,
+            });
+        }
+    }
+
+    /// Return the first hit, if any, and clear it.
+    pub fn take_hit(&mut self) -> Option<WatchpointHit> {
+        self.hit.take()
+    }
+}
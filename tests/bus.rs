@@ -0,0 +1,273 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use rvemu::bus::{
+    AttachError, Bus, CLINT_BASE, DRAM_BASE, MROM_BASE, PLIC_BASE, TEST_BASE, UART_BASE, UART_SIZE,
+    VIRTIO_BASE,
+};
+use rvemu::cpu::{BYTE, DOUBLEWORD, WORD};
+use rvemu::devices::Device;
+use rvemu::emulator::{Config, Emulator};
+use rvemu::exception::Exception;
+
+/// 16 MiB.
+const SMALL_DRAM_SIZE: u64 = 0x100_0000;
+
+#[test]
+fn dram_end() {
+    let mut emu = Emulator::with_config(Config {
+        dram_size: SMALL_DRAM_SIZE,
+    });
+    let bus = &mut emu.cpu.bus;
+    let end = DRAM_BASE + SMALL_DRAM_SIZE;
+
+    assert_eq!(Ok(0), bus.read(end - 1, BYTE));
+    assert_eq!(Err(Exception::LoadAccessFault), bus.read(end, BYTE));
+    assert_eq!(Err(Exception::StoreAMOAccessFault), bus.write(end, 0, BYTE));
+    // Data spanning the end of DRAM isn't accessible.
+    assert_eq!(
+        Err(Exception::LoadAccessFault),
+        bus.read(end - 4, DOUBLEWORD)
+    );
+    assert_eq!(
+        Err(Exception::StoreAMOAccessFault),
+        bus.write(end - 4, 0, DOUBLEWORD)
+    );
+    assert_eq!(
+        Err(Exception::LoadAccessFault),
+        bus.read(u64::MAX - 3, DOUBLEWORD)
+    );
+    assert_eq!(
+        Err(Exception::StoreAMOAccessFault),
+        bus.initialize_dram_at(end - 4, &[0; 8], 8)
+    );
+}
+
+#[test]
+fn device_end() {
+    let mut bus = Bus::new();
+
+    assert_eq!(
+        Err(Exception::LoadAccessFault),
+        bus.read(UART_BASE + UART_SIZE, BYTE)
+    );
+    assert_eq!(
+        Err(Exception::StoreAMOAccessFault),
+        bus.write(UART_BASE + UART_SIZE, 0, BYTE)
+    );
+
+    // The area after the contents of the ROM reads as zeros, but data spanning the end of the ROM
+    // isn't accessible.
+    let rom_end = MROM_BASE + 0xf000;
+    assert_eq!(Ok(0), bus.read(rom_end - 8, DOUBLEWORD));
+    assert_eq!(
+        Err(Exception::LoadAccessFault),
+        bus.read(rom_end - 4, DOUBLEWORD)
+    );
+}
+
+#[test]
+fn invalid_virtio_access() {
+    let mut bus = Bus::new();
+
+    // Select the device features beyond the supported words.
+    bus.write(VIRTIO_BASE + 0x14, 5, WORD).unwrap();
+    assert_eq!(Ok(0), bus.read(VIRTIO_BASE + 0x10, WORD));
+    // Select the driver features beyond the supported words.
+    bus.write(VIRTIO_BASE + 0x24, 5, WORD).unwrap();
+    assert_eq!(Ok(()), bus.write(VIRTIO_BASE + 0x20, 1, WORD));
+    // Only a single virtual queue is supported.
+    assert_eq!(
+        Err(Exception::StoreAMOAccessFault),
+        bus.write(VIRTIO_BASE + 0x30, 1, WORD)
+    );
+    // The configuration space is accessible only by bytes.
+    bus.write(VIRTIO_BASE + 0x107, 0xab, BYTE).unwrap();
+    assert_eq!(Ok(0xab), bus.read(VIRTIO_BASE + 0x107, BYTE));
+    assert_eq!(
+        Err(Exception::LoadAccessFault),
+        bus.read(VIRTIO_BASE + 0x100, WORD)
+    );
+}
+
+/// A device which counts cycles and raises an interrupt when it's requested.
+/// 0x0 the number of cycles (read-only)
+/// 0x8 a scratch register
+/// 0x10 raise an interrupt (write-only)
+struct Counter {
+    cycles: Rc<Cell<u64>>,
+    scratch: u64,
+    interrupting: bool,
+}
+
+impl Device for Counter {
+    fn read(&mut self, offset: u64, _size: u8) -> Result<u64, Exception> {
+        match offset {
+            0x0 => Ok(self.cycles.get()),
+            0x8 => Ok(self.scratch),
+            _ => Err(Exception::LoadAccessFault),
+        }
+    }
+
+    fn write(&mut self, offset: u64, value: u64, _size: u8) -> Result<(), Exception> {
+        match offset {
+            0x8 => self.scratch = value,
+            0x10 => self.interrupting = true,
+            _ => return Err(Exception::StoreAMOAccessFault),
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        self.cycles.set(self.cycles.get() + 1);
+    }
+
+    fn irq(&self) -> Option<u64> {
+        Some(5)
+    }
+
+    fn is_interrupting(&mut self) -> bool {
+        let interrupting = self.interrupting;
+        self.interrupting = false;
+        interrupting
+    }
+}
+
+fn counter() -> Box<Counter> {
+    Box::new(Counter {
+        cycles: Rc::new(Cell::new(0)),
+        scratch: 0,
+        interrupting: false,
+    })
+}
+
+#[test]
+fn attached_device() {
+    let mut emu = Emulator::new();
+    let cycles = Rc::new(Cell::new(0));
+    let device = Box::new(Counter {
+        cycles: cycles.clone(),
+        scratch: 0,
+        interrupting: false,
+    });
+    let base = 0x2000_0000;
+    emu.attach_device(base, 0x1000, device).unwrap();
+
+    let cpu = &mut emu.cpu;
+    cpu.bus.write(base + 0x8, 42, DOUBLEWORD).unwrap();
+    assert_eq!(Ok(42), cpu.bus.read(base + 0x8, DOUBLEWORD));
+    // The device decides which registers exist.
+    assert_eq!(
+        Err(Exception::LoadAccessFault),
+        cpu.bus.read(base + 0x18, WORD)
+    );
+    assert_eq!(
+        Err(Exception::LoadAccessFault),
+        cpu.bus.read(base + 0xffc, DOUBLEWORD)
+    );
+
+    cpu.devices_increment();
+    cpu.devices_increment();
+    assert_eq!(2, cycles.get());
+    assert_eq!(Ok(2), cpu.bus.read(base, DOUBLEWORD));
+
+    // The interrupt is forwarded to PLIC.
+    cpu.bus.write(base + 0x10, 1, DOUBLEWORD).unwrap();
+    cpu.check_pending_interrupt();
+    assert_eq!(Ok(1 << 5), cpu.bus.read(PLIC_BASE + 0x1000, WORD));
+}
+
+#[test]
+fn attach_error() {
+    let mut bus = Bus::new();
+
+    assert_eq!(
+        Err(AttachError::InvalidRange {
+            base: 0x2000_0000,
+            size: 0
+        }),
+        bus.attach(0x2000_0000, 0, counter())
+    );
+    assert_eq!(
+        Err(AttachError::InvalidRange {
+            base: u64::MAX,
+            size: 0x10
+        }),
+        bus.attach(u64::MAX, 0x10, counter())
+    );
+    assert_eq!(
+        Err(AttachError::Overlap {
+            base: UART_BASE + 0x80,
+            size: 0x100
+        }),
+        bus.attach(UART_BASE + 0x80, 0x100, counter())
+    );
+    assert_eq!(
+        Err(AttachError::Overlap {
+            base: DRAM_BASE - 0x10,
+            size: 0x20
+        }),
+        bus.attach(DRAM_BASE - 0x10, 0x20, counter())
+    );
+    // Every built-in device is in the address map checked for overlaps.
+    for base in [
+        MROM_BASE,
+        TEST_BASE,
+        CLINT_BASE,
+        PLIC_BASE,
+        UART_BASE,
+        VIRTIO_BASE,
+    ] {
+        assert_eq!(
+            Err(AttachError::Overlap { base, size: 1 }),
+            bus.attach(base, 1, counter())
+        );
+    }
+
+    bus.attach(0x2000_0000, 0x1000, counter()).unwrap();
+    assert_eq!(
+        Err(AttachError::Overlap {
+            base: 0x2000_0800,
+            size: 0x1000
+        }),
+        bus.attach(0x2000_0800, 0x1000, counter())
+    );
+    // The interrupt request number is already used.
+    assert_eq!(
+        Err(AttachError::InvalidIrq(5)),
+        bus.attach(0x2000_1000, 0x1000, counter())
+    );
+}
+
+#[test]
+fn mmio_callbacks() {
+    let mut emu = Emulator::new();
+    let writes = Rc::new(RefCell::new(Vec::new()));
+    let log = writes.clone();
+    emu.map_mmio(
+        0x2000_0000,
+        0x100,
+        |addr, size| (addr & 0xff) + size as u64,
+        move |addr, size, value| log.borrow_mut().push((addr, size, value)),
+    )
+    .unwrap();
+
+    let data = vec![
+        0xb7, 0x02, 0x00, 0x20, // lui t0, 0x20000
+        0x13, 0x03, 0xa0, 0x02, // addi t1, zero, 42
+        0x23, 0xa2, 0x62, 0x00, // sw t1, 4(t0)
+        0x83, 0xa3, 0x82, 0x00, // lw t2, 8(t0)
+    ];
+    let len = data.len() as u64;
+    emu.initialize_dram(data).unwrap();
+    emu.initialize_pc(DRAM_BASE);
+    emu.test_start(DRAM_BASE, DRAM_BASE + len);
+
+    assert_eq!(vec![(0x2000_0004, WORD, 42)], *writes.borrow());
+    assert_eq!(8 + WORD as u64, emu.cpu.xregs.read(7));
+    // Accesses beyond the region aren't passed to the closures.
+    assert_eq!(
+        Err(Exception::LoadAccessFault),
+        emu.cpu.bus.read(0x2000_0100, BYTE)
+    );
+}
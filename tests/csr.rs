@@ -0,0 +1,142 @@
+mod helper;
+
+use rvemu::bus::DRAM_BASE;
+use rvemu::csr::MCAUSE;
+use rvemu::emulator::Emulator;
+
+#[test]
+fn misa_is_read_only() {
+    let mut emu = Emulator::new();
+
+    let data = vec![
+        0x73, 0x28, 0x10, 0x30, // csrr x16, misa
+        0x73, 0x10, 0x10, 0x30, // csrw misa, x0
+        0xf3, 0x28, 0x10, 0x30, // csrr x17, misa
+    ];
+    // RV64IMAFDCSU
+    let misa = 0x8000_0000_0014_112d;
+    let expected_xregs = helper::create_xregs(vec![(16, misa), (17, misa)]);
+    let expected_fregs = helper::create_fregs(vec![]);
+
+    helper::run(&mut emu, data, &expected_xregs, &expected_fregs);
+}
+
+#[test]
+fn mscratch() {
+    let mut emu = Emulator::new();
+
+    let data = vec![
+        0x13, 0x08, 0x50, 0x00, // addi x16, x0, 5
+        0x73, 0x10, 0x08, 0x34, // csrw mscratch, x16
+        0xf3, 0x28, 0x00, 0x34, // csrr x17, mscratch
+    ];
+    let expected_xregs = helper::create_xregs(vec![(16, 5), (17, 5)]);
+    let expected_fregs = helper::create_fregs(vec![]);
+
+    helper::run(&mut emu, data, &expected_xregs, &expected_fregs);
+}
+
+#[test]
+fn pmpaddr_warl() {
+    let mut emu = Emulator::new();
+
+    let data = vec![
+        0x13, 0x08, 0xf0, 0xff, // addi x16, x0, -1
+        0x73, 0x10, 0x08, 0x3b, // csrw pmpaddr0, x16
+        0xf3, 0x28, 0x00, 0x3b, // csrr x17, pmpaddr0
+    ];
+    // Only bits 53-0 are writable for RV64.
+    let expected_xregs = helper::create_xregs(vec![(16, !0), (17, (1 << 54) - 1)]);
+    let expected_fregs = helper::create_fregs(vec![]);
+
+    helper::run(&mut emu, data, &expected_xregs, &expected_fregs);
+}
+
+#[test]
+fn unimplemented_csr() {
+    let mut emu = Emulator::new();
+
+    let data = vec![
+        0x73, 0x28, 0xa0, 0x30, // csrr x16, menvcfg
+    ];
+    emu.initialize_dram(data).unwrap();
+    emu.initialize_pc(DRAM_BASE);
+    emu.test_start(DRAM_BASE, DRAM_BASE + 4);
+
+    // Illegal instruction.
+    assert_eq!(2, emu.cpu.state.read(MCAUSE));
+}
+
+#[test]
+fn satp_unsupported_mode() {
+    let mut emu = Emulator::new();
+
+    let data = vec![
+        0x13, 0x08, 0x90, 0x00, // addi x16, x0, 9
+        0x13, 0x18, 0xc8, 0x03, // slli x16, x16, 60
+        0x73, 0x10, 0x08, 0x18, // csrw satp, x16
+        0xf3, 0x28, 0x00, 0x18, // csrr x17, satp
+    ];
+    // Sv48 is not supported, so the write has no effect.
+    let expected_xregs = helper::create_xregs(vec![(16, 9 << 60), (17, 0)]);
+    let expected_fregs = helper::create_fregs(vec![]);
+
+    helper::run(&mut emu, data, &expected_xregs, &expected_fregs);
+}
+
+#[test]
+fn delegation_warl() {
+    let mut emu = Emulator::new();
+
+    let data = vec![
+        0x13, 0x08, 0xf0, 0xff, // addi x16, x0, -1
+        0x73, 0x10, 0x38, 0x30, // csrw mideleg, x16
+        0xf3, 0x28, 0x30, 0x30, // csrr x17, mideleg
+        0x73, 0x10, 0x28, 0x30, // csrw medeleg, x16
+        0x73, 0x29, 0x20, 0x30, // csrr x18, medeleg
+    ];
+    // Only the supervisor interrupts can be delegated, and an environment call from M-mode
+    // can't be delegated.
+    let expected_xregs = helper::create_xregs(vec![(16, !0), (17, 0x222), (18, !0 & !(1 << 11))]);
+    let expected_fregs = helper::create_fregs(vec![]);
+
+    helper::run(&mut emu, data, &expected_xregs, &expected_fregs);
+}
+
+#[test]
+fn satp_sv39() {
+    let mut emu = Emulator::new();
+
+    let data = vec![
+        0x13, 0x08, 0x80, 0x00, // addi x16, x0, 8
+        0x13, 0x18, 0xc8, 0x03, // slli x16, x16, 60
+        0x13, 0x68, 0x38, 0x12, // ori x16, x16, 0x123
+        0x73, 0x10, 0x08, 0x18, // csrw satp, x16
+        0xf3, 0x28, 0x00, 0x18, // csrr x17, satp
+    ];
+    // Sv39 is supported, so the mode and the PPN are written.
+    let satp = (8 << 60) | 0x123;
+    let expected_xregs = helper::create_xregs(vec![(16, satp), (17, satp)]);
+    let expected_fregs = helper::create_fregs(vec![]);
+
+    helper::run(&mut emu, data, &expected_xregs, &expected_fregs);
+}
+
+#[test]
+fn sie_follows_mideleg() {
+    let mut emu = Emulator::new();
+
+    let data = vec![
+        0x13, 0x08, 0xf0, 0xff, // addi x16, x0, -1
+        0x93, 0x08, 0x00, 0x02, // addi x17, x0, 0x20
+        0x73, 0x90, 0x38, 0x30, // csrw mideleg, x17
+        0x73, 0x10, 0x48, 0x10, // csrw sie, x16
+        0x73, 0x29, 0x40, 0x30, // csrr x18, mie
+        0xf3, 0x29, 0x40, 0x10, // csrr x19, sie
+    ];
+    // Only the delegated supervisor timer interrupt is enabled via sie.
+    let expected_xregs = helper::create_xregs(vec![(16, !0), (17, 0x20), (18, 0x20), (19, 0x20)]);
+    let expected_fregs = helper::create_fregs(vec![]);
+
+    helper::run(&mut emu, data, &expected_xregs, &expected_fregs);
+}
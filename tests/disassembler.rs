@@ -0,0 +1,87 @@
+use rvemu::bus::DRAM_BASE;
+use rvemu::disassembler::disassemble;
+
+#[test]
+fn rv64i() {
+    let pc = DRAM_BASE;
+    assert_eq!("addi a0, zero, 1", disassemble(0x00100513, pc));
+    assert_eq!("addiw a0, a0, -1", disassemble(0xfff5051b, pc));
+    assert_eq!("slli t1, t1, 48", disassemble(0x03031313, pc));
+    assert_eq!("sub a0, a1, a2", disassemble(0x40c58533, pc));
+    assert_eq!("lui a0, 0x80000", disassemble(0x80000537, pc));
+    assert_eq!("auipc t0, 0x1", disassemble(0x00001297, pc));
+    assert_eq!("ld a0, 8(sp)", disassemble(0x00813503, pc));
+    assert_eq!("sw t1, 0(t0)", disassemble(0x0062a023, pc));
+    assert_eq!("ecall", disassemble(0x00000073, pc));
+    assert_eq!("mret", disassemble(0x30200073, pc));
+    assert_eq!("fence iorw, iorw", disassemble(0x0ff0000f, pc));
+}
+
+#[test]
+fn branches_and_jumps() {
+    let pc = DRAM_BASE + 0x100;
+    // The targets are absolute addresses.
+    assert_eq!("beq a0, a1, 0x80000108", disassemble(0x00b50463, pc));
+    assert_eq!("bne t0, zero, 0x800000fc", disassemble(0xfe029ee3, pc));
+    assert_eq!("jal ra, 0x80000110", disassemble(0x010000ef, pc));
+    assert_eq!("jalr zero, 0(ra)", disassemble(0x00008067, pc));
+    assert_eq!("c.j 0x800000fe", disassemble(0xbffd, pc));
+    assert_eq!("c.beqz a0, 0x80000104", disassemble(0xc111, pc));
+}
+
+#[test]
+fn zicsr() {
+    let pc = DRAM_BASE;
+    assert_eq!("csrrw zero, mscratch, t0", disassemble(0x34029073, pc));
+    assert_eq!("csrrs a0, mhartid, zero", disassemble(0xf1402573, pc));
+    assert_eq!("csrrci zero, mstatus, 8", disassemble(0x30047073, pc));
+}
+
+#[test]
+fn rv64ma() {
+    let pc = DRAM_BASE;
+    assert_eq!("mul a0, a1, a2", disassemble(0x02c58533, pc));
+    assert_eq!("remuw a0, a0, a1", disassemble(0x02b5753b, pc));
+    assert_eq!("lr.w.aq t0, (a0)", disassemble(0x140522af, pc));
+    assert_eq!("sc.d.rl t1, a1, (a0)", disassemble(0x1ab5332f, pc));
+    assert_eq!("amoadd.d.aqrl a0, a1, (a2)", disassemble(0x06b6352f, pc));
+}
+
+#[test]
+fn rv64fd() {
+    let pc = DRAM_BASE;
+    assert_eq!("fld fa0, 0(a0)", disassemble(0x00053507, pc));
+    assert_eq!("fsw ft0, 4(sp)", disassemble(0x00012227, pc));
+    // The dynamic rounding mode isn't shown.
+    assert_eq!("fadd.d fa0, fa1, fa2", disassemble(0x02c5f553, pc));
+    assert_eq!("fcvt.w.s a0, fa0, rtz", disassemble(0xc0051553, pc));
+    assert_eq!("fmadd.s fa0, fa1, fa2, fa3", disassemble(0x68c5f543, pc));
+    assert_eq!("fnmsub.d fa0, fa1, fa2, fa3", disassemble(0x6ac5f54b, pc));
+    assert_eq!("fmv.x.d a0, fa0", disassemble(0xe2050553, pc));
+    assert_eq!("feq.s a0, fa0, fa1", disassemble(0xa0b52553, pc));
+}
+
+#[test]
+fn rvc() {
+    let pc = DRAM_BASE;
+    assert_eq!("c.addi a0, 1", disassemble(0x0505, pc));
+    assert_eq!("c.li a0, -1", disassemble(0x557d, pc));
+    assert_eq!("c.addi16sp sp, -48", disassemble(0x7179, pc));
+    assert_eq!("c.addi4spn a0, sp, 8", disassemble(0x0028, pc));
+    assert_eq!("c.ldsp ra, 8(sp)", disassemble(0x60a2, pc));
+    assert_eq!("c.sdsp ra, 8(sp)", disassemble(0xe406, pc));
+    assert_eq!("c.mv a0, a1", disassemble(0x852e, pc));
+    assert_eq!("c.jr ra", disassemble(0x8082, pc));
+    assert_eq!("c.fld fa0, 0(a0)", disassemble(0x2108, pc));
+    assert_eq!("c.nop", disassemble(0x0001, pc));
+    assert_eq!("c.ebreak", disassemble(0x9002, pc));
+}
+
+#[test]
+fn unknown() {
+    let pc = DRAM_BASE;
+    assert_eq!(".2byte 0x0000", disassemble(0x0000, pc));
+    assert_eq!(".4byte 0xffffffff", disassemble(0xffffffff, pc));
+    // The upper bits of a 16-bit instruction are ignored.
+    assert_eq!("c.addi a0, 1", disassemble(0xffff_0505, pc));
+}
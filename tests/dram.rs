@@ -0,0 +1,49 @@
+use rvemu::bus::DRAM_BASE;
+use rvemu::cpu::{DOUBLEWORD, WORD};
+use rvemu::emulator::{Config, Emulator};
+
+/// 16 MiB.
+const SMALL_DRAM_SIZE: u64 = 0x100_0000;
+
+#[test]
+fn configured_size() {
+    let emu = Emulator::with_config(Config {
+        dram_size: SMALL_DRAM_SIZE,
+    });
+
+    assert_eq!(SMALL_DRAM_SIZE, emu.cpu.bus.dram_size());
+    // The stack pointer is at the end of DRAM.
+    assert_eq!(DRAM_BASE + SMALL_DRAM_SIZE, emu.cpu.xregs.read(2));
+}
+
+#[test]
+fn sparse_memory() {
+    let mut emu = Emulator::with_config(Config {
+        dram_size: SMALL_DRAM_SIZE,
+    });
+    let bus = &mut emu.cpu.bus;
+
+    // Memory which has never been written reads as zeros.
+    assert_eq!(0, bus.read(DRAM_BASE + 0x1234, DOUBLEWORD).unwrap());
+
+    // The end of DRAM is accessible.
+    let last = DRAM_BASE + SMALL_DRAM_SIZE - 8;
+    bus.write(last, 0x0102_0304_0506_0708, DOUBLEWORD).unwrap();
+    assert_eq!(0x0102_0304_0506_0708, bus.read(last, DOUBLEWORD).unwrap());
+    assert_eq!(0x0506_0708, bus.read(last, WORD).unwrap());
+
+    // Data spanning pages, which are 1 MiB, is read and written with little endian.
+    let boundary = DRAM_BASE + 0x10_0000 - 4;
+    bus.write(boundary, 0x1122_3344_5566_7788, DOUBLEWORD)
+        .unwrap();
+    assert_eq!(
+        0x1122_3344_5566_7788,
+        bus.read(boundary, DOUBLEWORD).unwrap()
+    );
+    assert_eq!(0x1122_3344, bus.read(boundary + 4, WORD).unwrap());
+
+    // Initializing memory clears the rest of the region.
+    bus.initialize_dram_at(boundary - 4, &[0xff], 16).unwrap();
+    assert_eq!(0xff, bus.read(boundary - 4, DOUBLEWORD).unwrap());
+    assert_eq!(0, bus.read(boundary + 4, DOUBLEWORD).unwrap());
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+use rvemu::bus::{Bus, DRAM_BASE};
+use rvemu::cpu::{BYTE, POINTER_TO_DTB};
+use rvemu::devices::Device;
+use rvemu::dram::DRAM_SIZE;
+use rvemu::dtb::FdtWriter;
+use rvemu::emulator::{Config, Emulator};
+use rvemu::exception::Exception;
+
+fn be32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// Parse a device tree blob and return all properties keyed by "<node path>:<property name>".
+fn parse(dtb: &[u8]) -> HashMap<String, Vec<u8>> {
+    assert_eq!(0xd00dfeed, be32(dtb, 0));
+    assert_eq!(dtb.len(), be32(dtb, 4) as usize);
+    let off_struct = be32(dtb, 8) as usize;
+    let off_strings = be32(dtb, 12) as usize;
+
+    let mut props = HashMap::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut offset = off_struct;
+    loop {
+        let token = be32(dtb, offset);
+        offset += 4;
+        match token {
+            1 => {
+                let end = offset + dtb[offset..].iter().position(|b| *b == 0).unwrap();
+                path.push(String::from_utf8(dtb[offset..end].to_vec()).unwrap());
+                offset = (end + 1 + 3) & !3;
+            }
+            2 => {
+                path.pop();
+            }
+            3 => {
+                let len = be32(dtb, offset) as usize;
+                let name_offset = off_strings + be32(dtb, offset + 4) as usize;
+                let name_end =
+                    name_offset + dtb[name_offset..].iter().position(|b| *b == 0).unwrap();
+                let name = String::from_utf8(dtb[name_offset..name_end].to_vec()).unwrap();
+                let value = dtb[offset + 8..offset + 8 + len].to_vec();
+                props.insert(format!("{}:{}", path.join("/"), name), value);
+                offset = (offset + 8 + len + 3) & !3;
+            }
+            9 => break,
+            _ => panic!("unexpected token {:#x} at {:#x}", token, offset - 4),
+        }
+    }
+    assert!(path.is_empty());
+    props
+}
+
+#[test]
+fn fdt_writer() {
+    let mut fdt = FdtWriter::new();
+    fdt.begin_node("");
+    fdt.property_u32("#size-cells", 2);
+    fdt.begin_node("node@1");
+    fdt.property_string("compatible", "abc");
+    fdt.property_null("ranges");
+    fdt.property_cells("reg", &[1, 2]);
+    // The name is shared with the root node's property.
+    fdt.property_u32("#size-cells", 0);
+    fdt.end_node();
+    fdt.end_node();
+    let dtb = fdt.finish();
+
+    let props = parse(&dtb);
+    assert_eq!(5, props.len());
+    assert_eq!(vec![0, 0, 0, 2], props[":#size-cells"]);
+    assert_eq!(b"abc\0".to_vec(), props["/node@1:compatible"]);
+    assert_eq!(Vec::<u8>::new(), props["/node@1:ranges"]);
+    assert_eq!(vec![0, 0, 0, 1, 0, 0, 0, 2], props["/node@1:reg"]);
+    assert_eq!(vec![0, 0, 0, 0], props["/node@1:#size-cells"]);
+
+    // "#size-cells\0compatible\0ranges\0reg\0"
+    assert_eq!(34, be32(&dtb, 32));
+}
+
+/// Read a device tree blob in the ROM.
+fn read_dtb(bus: &mut Bus) -> Vec<u8> {
+    let mut header = Vec::new();
+    for i in 0..8 {
+        header.push(bus.read(POINTER_TO_DTB + i, BYTE).unwrap() as u8);
+    }
+    let total_size = be32(&header, 4) as u64;
+    let mut dtb = Vec::new();
+    for i in 0..total_size {
+        dtb.push(bus.read(POINTER_TO_DTB + i, BYTE).unwrap() as u8);
+    }
+    dtb
+}
+
+#[test]
+fn dtb_in_rom() {
+    let mut bus = Bus::new();
+
+    let props = parse(&read_dtb(&mut bus));
+    let mut reg = DRAM_BASE.to_be_bytes().to_vec();
+    reg.extend_from_slice(&DRAM_SIZE.to_be_bytes());
+    assert_eq!(reg, props["/memory@80000000:reg"]);
+    assert_eq!(b"ns16550a\0".to_vec(), props["/uart@10000000:compatible"]);
+    assert_eq!(
+        b"virtio,mmio\0".to_vec(),
+        props["/virtio_mmio@10001000:compatible"]
+    );
+    assert_eq!(
+        b"riscv,plic0\0".to_vec(),
+        props["/soc/interrupt-controller@c000000:compatible"]
+    );
+    assert_eq!(
+        b"riscv,clint0\0".to_vec(),
+        props["/soc/clint@2000000:compatible"]
+    );
+    assert_eq!(b"rv64imafdcsu\0".to_vec(), props["/cpus/cpu@0:riscv,isa"]);
+    // The UART and virtio nodes point to PLIC.
+    assert_eq!(
+        props["/soc/interrupt-controller@c000000:phandle"],
+        props["/uart@10000000:interrupt-parent"]
+    );
+    // The poweroff and reboot nodes write to the test finisher.
+    assert_eq!(
+        b"sifive,test1\0sifive,test0\0syscon\0".to_vec(),
+        props["/soc/test@100000:compatible"]
+    );
+    assert_eq!(props["/soc/test@100000:phandle"], props["/poweroff:regmap"]);
+    assert_eq!(vec![0, 0, 0x55, 0x55], props["/poweroff:value"]);
+    assert_eq!(vec![0, 0, 0x77, 0x77], props["/reboot:value"]);
+    // PLIC is connected to the machine and supervisor external interrupts of the hart.
+    assert_eq!(
+        vec![0, 0, 0, 2, 0, 0, 0, 11, 0, 0, 0, 2, 0, 0, 0, 9],
+        props["/soc/interrupt-controller@c000000:interrupts-extended"]
+    );
+}
+
+#[test]
+fn bootargs_and_initrd() {
+    let mut emu = Emulator::new();
+    emu.set_bootargs("console=ttyS0 rdinit=/sbin/init");
+    emu.load_initrd(&[1, 2, 3, 4]).unwrap();
+
+    let props = parse(&read_dtb(&mut emu.cpu.bus));
+    assert_eq!(
+        b"console=ttyS0 rdinit=/sbin/init\0".to_vec(),
+        props["/chosen:bootargs"]
+    );
+    // The ramdisk is placed 128 MiB above the beginning of DRAM.
+    let start = DRAM_BASE + 0x800_0000;
+    assert_eq!(
+        start.to_be_bytes().to_vec(),
+        props["/chosen:linux,initrd-start"]
+    );
+    assert_eq!(
+        (start + 4).to_be_bytes().to_vec(),
+        props["/chosen:linux,initrd-end"]
+    );
+    assert_eq!(1, emu.cpu.bus.read(start, BYTE).unwrap());
+    assert_eq!(4, emu.cpu.bus.read(start + 3, BYTE).unwrap());
+}
+
+#[test]
+fn chosen_without_initrd() {
+    let mut bus = Bus::new();
+
+    let props = parse(&read_dtb(&mut bus));
+    assert_eq!(
+        b"root=/dev/vda ro console=ttyS0\0".to_vec(),
+        props["/chosen:bootargs"]
+    );
+    assert_eq!(b"/uart@10000000\0".to_vec(), props["/chosen:stdout-path"]);
+    assert!(!props.contains_key("/chosen:linux,initrd-start"));
+    assert!(!props.contains_key("/chosen:linux,initrd-end"));
+}
+
+#[test]
+fn memory_node_follows_dram_size() {
+    let mut emu = Emulator::with_config(Config {
+        dram_size: 0x800_0000,
+    });
+
+    let props = parse(&read_dtb(&mut emu.cpu.bus));
+    let mut reg = DRAM_BASE.to_be_bytes().to_vec();
+    reg.extend_from_slice(&0x800_0000u64.to_be_bytes());
+    assert_eq!(reg, props["/memory@80000000:reg"]);
+}
+
+/// A device which has no registers but is described in the device tree.
+struct Dummy;
+
+impl Device for Dummy {
+    fn read(&mut self, _offset: u64, _size: u8) -> Result<u64, Exception> {
+        Err(Exception::LoadAccessFault)
+    }
+
+    fn write(&mut self, _offset: u64, _value: u64, _size: u8) -> Result<(), Exception> {
+        Err(Exception::StoreAMOAccessFault)
+    }
+
+    fn irq(&self) -> Option<u64> {
+        Some(3)
+    }
+
+    fn node_name(&self) -> Option<&str> {
+        Some("dummy")
+    }
+
+    fn node_properties(&self, fdt: &mut FdtWriter) {
+        fdt.property_string("compatible", "rvemu,dummy");
+    }
+}
+
+#[test]
+fn attached_device_node() {
+    let mut emu = Emulator::new();
+    emu.attach_device(0x2000_0000, 0x1000, Box::new(Dummy))
+        .unwrap();
+
+    let props = parse(&read_dtb(&mut emu.cpu.bus));
+    assert_eq!(
+        b"rvemu,dummy\0".to_vec(),
+        props["/dummy@20000000:compatible"]
+    );
+    assert_eq!(
+        vec![0, 0, 0, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0],
+        props["/dummy@20000000:reg"]
+    );
+    assert_eq!(vec![0, 0, 0, 3], props["/dummy@20000000:interrupts"]);
+    assert_eq!(
+        props["/soc/interrupt-controller@c000000:phandle"],
+        props["/dummy@20000000:interrupt-parent"]
+    );
+}
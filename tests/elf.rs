@@ -0,0 +1,203 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+use rvemu::bus::{DRAM_BASE, MROM_BASE};
+use rvemu::cpu::{BYTE, DOUBLEWORD};
+use rvemu::elf::ElfError;
+use rvemu::emulator::Emulator;
+
+/// Create an ELF file with one loadable segment which contains `code`. `memsz` can be larger than
+/// the size of `code` to have a zero-filled area like `.bss`.
+fn create_elf(is_64bit: bool, entry: u64, paddr: u64, code: &[u8], memsz: u64) -> Vec<u8> {
+    let mut elf = vec![0x7f, b'E', b'L', b'F'];
+    // EI_CLASS, EI_DATA (little endian), EI_VERSION, and padding.
+    elf.push(if is_64bit { 2 } else { 1 });
+    elf.extend_from_slice(&[1, 1]);
+    elf.resize(16, 0);
+    // e_type (ET_EXEC), e_machine (EM_RISCV), e_version.
+    elf.extend_from_slice(&2u16.to_le_bytes());
+    elf.extend_from_slice(&243u16.to_le_bytes());
+    elf.extend_from_slice(&1u32.to_le_bytes());
+
+    if is_64bit {
+        let phoff = 64u64;
+        let code_offset = phoff + 56;
+        elf.extend_from_slice(&entry.to_le_bytes());
+        elf.extend_from_slice(&phoff.to_le_bytes());
+        elf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        elf.extend_from_slice(&64u16.to_le_bytes()); // e_ehsize
+        elf.extend_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        elf.extend_from_slice(&1u16.to_le_bytes()); // e_phnum
+        elf.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum, e_shstrndx
+                                        // Program header.
+        elf.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+        elf.extend_from_slice(&7u32.to_le_bytes()); // RWX
+        elf.extend_from_slice(&code_offset.to_le_bytes());
+        elf.extend_from_slice(&(paddr | 0xffff_0000_0000_0000).to_le_bytes()); // p_vaddr
+        elf.extend_from_slice(&paddr.to_le_bytes());
+        elf.extend_from_slice(&(code.len() as u64).to_le_bytes());
+        elf.extend_from_slice(&memsz.to_le_bytes());
+        elf.extend_from_slice(&8u64.to_le_bytes()); // p_align
+    } else {
+        let phoff = 52u32;
+        let code_offset = phoff + 32;
+        elf.extend_from_slice(&(entry as u32).to_le_bytes());
+        elf.extend_from_slice(&phoff.to_le_bytes());
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_shoff
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        elf.extend_from_slice(&52u16.to_le_bytes()); // e_ehsize
+        elf.extend_from_slice(&32u16.to_le_bytes()); // e_phentsize
+        elf.extend_from_slice(&1u16.to_le_bytes()); // e_phnum
+        elf.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum, e_shstrndx
+                                        // Program header.
+        elf.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+        elf.extend_from_slice(&code_offset.to_le_bytes());
+        elf.extend_from_slice(&(paddr as u32).to_le_bytes()); // p_vaddr
+        elf.extend_from_slice(&(paddr as u32).to_le_bytes());
+        elf.extend_from_slice(&(code.len() as u32).to_le_bytes());
+        elf.extend_from_slice(&(memsz as u32).to_le_bytes());
+        elf.extend_from_slice(&7u32.to_le_bytes()); // RWX
+        elf.extend_from_slice(&8u32.to_le_bytes()); // p_align
+    }
+
+    elf.extend_from_slice(code);
+    elf
+}
+
+#[test]
+fn load_elf64_segment() {
+    let mut emu = Emulator::new();
+    let addr = DRAM_BASE + 0x20_0000;
+
+    // Dirty the area that should be zero-filled.
+    emu.cpu
+        .bus
+        .write(addr + 8, 0xffff_ffff_ffff_ffff, DOUBLEWORD)
+        .unwrap();
+
+    let code = [
+        0x93, 0x0f, 0xa0, 0x02, // addi x31, x0, 42
+    ];
+    let elf = create_elf(true, addr, addr, &code, 16);
+    emu.load_elf(&elf).unwrap();
+
+    // The reset vector jumps to the entry point.
+    assert_eq!(MROM_BASE, emu.cpu.pc);
+    for (i, byte) in code.iter().enumerate() {
+        assert_eq!(
+            *byte as u64,
+            emu.cpu.bus.read(addr + i as u64, BYTE).unwrap()
+        );
+    }
+    assert_eq!(0, emu.cpu.bus.read(addr + 8, DOUBLEWORD).unwrap());
+
+    emu.test_start(MROM_BASE, addr + code.len() as u64);
+    assert_eq!(addr + code.len() as u64, emu.cpu.pc);
+    assert_eq!(42, emu.cpu.xregs.read(31));
+}
+
+#[test]
+fn load_elf32_segment() {
+    let mut emu = Emulator::new();
+    let addr = DRAM_BASE + 0x1000;
+
+    let code = [
+        0x93, 0x0f, 0x50, 0x00, // addi x31, x0, 5
+    ];
+    let elf = create_elf(false, addr, addr, &code, code.len() as u64);
+    emu.load_elf(&elf).unwrap();
+
+    emu.test_start(MROM_BASE, addr + code.len() as u64);
+    assert_eq!(addr + code.len() as u64, emu.cpu.pc);
+    assert_eq!(5, emu.cpu.xregs.read(31));
+}
+
+#[test]
+fn load_elf_errors() {
+    let mut emu = Emulator::new();
+
+    assert_eq!(
+        Err(ElfError::InvalidMagic),
+        emu.load_elf(&[0x93, 0x0f, 0xa0, 0x02])
+    );
+
+    let elf = create_elf(true, 0x1000, 0x1000, &[0; 4], 4);
+    assert_eq!(
+        Err(ElfError::SegmentOutOfRange {
+            addr: 0x1000,
+            size: 4
+        }),
+        emu.load_elf(&elf)
+    );
+
+    let elf = create_elf(true, DRAM_BASE, DRAM_BASE, &[0; 4], 4);
+    assert_eq!(
+        Err(ElfError::Truncated),
+        emu.load_elf(&elf[..elf.len() - 1])
+    );
+}
+
+#[test]
+fn load_riscv_tests_elf() -> io::Result<()> {
+    let mut root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    root.push("tests/resources");
+
+    let mut elf = Vec::new();
+    File::open(root.join("original/rv64ui-p-add"))?.read_to_end(&mut elf)?;
+    // The same program without headers, made by `objcopy -O binary`.
+    let mut binary = Vec::new();
+    File::open(root.join("rv64ui_p_add"))?.read_to_end(&mut binary)?;
+
+    let mut emu = Emulator::new();
+    emu.load_elf(&elf).unwrap();
+
+    // The address which the reset vector jumps to.
+    assert_eq!(
+        DRAM_BASE,
+        emu.cpu.bus.read(MROM_BASE + 0x18, DOUBLEWORD).unwrap()
+    );
+    for (i, byte) in binary.iter().enumerate() {
+        assert_eq!(
+            *byte as u64,
+            emu.cpu.bus.read(DRAM_BASE + i as u64, BYTE).unwrap(),
+            "fails at {:#x}",
+            DRAM_BASE + i as u64
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn load_riscv_tests_symbols() -> io::Result<()> {
+    let mut root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    root.push("tests/resources/original/rv64ui-p-add");
+
+    let mut elf = Vec::new();
+    File::open(root.as_path())?.read_to_end(&mut elf)?;
+
+    let mut emu = Emulator::new();
+    emu.load_elf(&elf).unwrap();
+
+    assert_eq!(Some(0x8000_1000), emu.symbols.address_of("tohost"));
+    assert_eq!(None, emu.symbols.address_of(".text.init"));
+    assert_eq!("0x80000000 <_start>", emu.symbols.symbolize(0x8000_0000));
+    assert_eq!(
+        "0x80000044 <write_tohost+0x4>",
+        emu.symbols.symbolize(0x8000_0044)
+    );
+    assert_eq!("0x1000", emu.symbols.symbolize(0x1000));
+    Ok(())
+}
+
+#[test]
+fn load_stripped_elf() {
+    let mut emu = Emulator::new();
+    let elf = create_elf(true, DRAM_BASE, DRAM_BASE, &[0; 4], 4);
+    emu.load_elf(&elf).unwrap();
+
+    assert!(emu.symbols.is_empty());
+    assert_eq!("0x80000000", emu.symbols.symbolize(DRAM_BASE));
+}
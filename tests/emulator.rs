@@ -0,0 +1,223 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use rvemu::bus::DRAM_BASE;
+use rvemu::emulator::{Emulator, ExitReason, SignatureError};
+use rvemu::exception::Exception;
+
+fn setup(data: Vec<u8>) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data).unwrap();
+    emu.initialize_pc(DRAM_BASE);
+    emu
+}
+
+#[test]
+fn run_limit() {
+    let mut emu = setup(vec![
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+    ]);
+
+    assert_eq!(ExitReason::LimitReached, emu.step());
+    assert_eq!(DRAM_BASE + 4, emu.cpu.pc);
+    assert_eq!(ExitReason::LimitReached, emu.run(2));
+    assert_eq!(DRAM_BASE + 12, emu.cpu.pc);
+    assert_eq!(3, emu.cpu.xregs.read(10));
+}
+
+#[test]
+fn breakpoint() {
+    let mut emu = setup(vec![
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+    ]);
+    emu.add_breakpoint(DRAM_BASE + 8);
+
+    assert_eq!(ExitReason::Breakpoint(DRAM_BASE + 8), emu.run(100));
+    assert_eq!(2, emu.cpu.xregs.read(10));
+    // The execution resumes from the breakpoint.
+    assert_eq!(ExitReason::LimitReached, emu.step());
+    assert_eq!(3, emu.cpu.xregs.read(10));
+
+    assert!(emu.remove_breakpoint(DRAM_BASE + 8));
+    assert!(!emu.remove_breakpoint(DRAM_BASE + 8));
+}
+
+#[test]
+fn fatal_trap() {
+    let mut emu = setup(vec![
+        0x67, 0x00, 0x00, 0x00, // jalr zero, 0(zero)
+    ]);
+
+    assert_eq!(
+        ExitReason::FatalTrap {
+            exception: Exception::InstructionAccessFault,
+            pc: 0
+        },
+        emu.run(100)
+    );
+}
+
+#[test]
+fn disassemble_pc() {
+    let mut emu = setup(vec![
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x05, 0x05, // c.addi a0, 1
+    ]);
+
+    assert_eq!(Some(String::from("addi a0, a0, 1")), emu.disassemble_pc());
+    emu.step();
+    assert_eq!(Some(String::from("c.addi a0, 1")), emu.disassemble_pc());
+    emu.initialize_pc(0);
+    assert_eq!(None, emu.disassemble_pc());
+}
+
+#[test]
+fn waiting_for_interrupt() {
+    let mut emu = setup(vec![
+        0x73, 0x00, 0x50, 0x10, // wfi
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+    ]);
+
+    // No interrupt is enabled, so nothing can wake up the hart.
+    assert_eq!(ExitReason::WaitingForInterrupt, emu.run(100));
+    assert_eq!(DRAM_BASE + 4, emu.cpu.pc);
+    assert_eq!(0, emu.cpu.xregs.read(10));
+}
+
+/// A writer which keeps the output in a buffer shared with a test.
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn commit_log() {
+    let mut emu = setup(vec![
+        0x97, 0x02, 0x00, 0x00, // auipc t0, 0
+        0x13, 0x03, 0xa0, 0x02, // addi t1, zero, 42
+        0x23, 0xa0, 0x62, 0x10, // sw t1, 256(t0)
+        0x83, 0xa3, 0x02, 0x10, // lw t2, 256(t0)
+        0x73, 0x10, 0x03, 0x34, // csrrw zero, mscratch, t1
+        0x05, 0x03, // c.addi t1, 1
+        0x67, 0x00, 0x00, 0x00, // jalr zero, 0(zero)
+    ]);
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    emu.set_commit_log(Box::new(SharedBuffer(buffer.clone())));
+
+    emu.run(100);
+
+    // The fetch from address 0 raises an exception, so nothing is committed after `jalr`.
+    let log = String::from_utf8(buffer.borrow().clone()).unwrap();
+    let expected = [
+        "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000",
+        "core   0: 3 0x0000000080000004 (0x02a00313) x6  0x000000000000002a",
+        "core   0: 3 0x0000000080000008 (0x1062a023) mem 0x0000000080000100 0x0000002a",
+        "core   0: 3 0x000000008000000c (0x1002a383) x7  0x000000000000002a mem 0x0000000080000100",
+        "core   0: 3 0x0000000080000010 (0x34031073) c832_mscratch 0x000000000000002a",
+        "core   0: 3 0x0000000080000014 (0x0305) x6  0x000000000000002b",
+        "core   0: 3 0x0000000080000016 (0x00000067)",
+    ];
+    assert_eq!(expected.join("\n") + "\n", log);
+}
+
+#[test]
+fn test_finisher() {
+    let mut emu = setup(vec![
+        0xb7, 0x02, 0x10, 0x00, // lui t0, 0x100
+        0x37, 0x33, 0x05, 0x00, // lui t1, 0x53
+        0x13, 0x03, 0x33, 0x33, // addi t1, t1, 0x333
+        0x23, 0xa0, 0x62, 0x00, // sw t1, 0(t0)
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+    ]);
+
+    // 0x3333 reports a failure with the exit code in the upper 16 bits.
+    assert_eq!(ExitReason::Shutdown { code: 5 }, emu.run(100));
+    assert_eq!(DRAM_BASE + 16, emu.cpu.pc);
+
+    let mut emu = setup(vec![
+        0xb7, 0x02, 0x10, 0x00, // lui t0, 0x100
+        0x37, 0x33, 0x00, 0x00, // lui t1, 0x3
+        0x13, 0x03, 0x33, 0x33, // addi t1, t1, 0x333
+        0x23, 0xa0, 0x62, 0x00, // sw t1, 0(t0)
+    ]);
+    // A failure with the exit code 0 still ends with a nonzero code.
+    assert_eq!(ExitReason::Shutdown { code: 1 }, emu.run(100));
+
+    let mut emu = setup(vec![
+        0xb7, 0x02, 0x10, 0x00, // lui t0, 0x100
+        0x37, 0x53, 0x00, 0x00, // lui t1, 0x5
+        0x13, 0x03, 0x53, 0x55, // addi t1, t1, 0x555
+        0x23, 0xa0, 0x62, 0x00, // sw t1, 0(t0)
+    ]);
+    assert_eq!(ExitReason::Shutdown { code: 0 }, emu.run(100));
+
+    let mut emu = setup(vec![
+        0xb7, 0x02, 0x10, 0x00, // lui t0, 0x100
+        0x37, 0x73, 0x00, 0x00, // lui t1, 0x7
+        0x13, 0x03, 0x73, 0x77, // addi t1, t1, 0x777
+        0x23, 0xa0, 0x62, 0x00, // sw t1, 0(t0)
+    ]);
+    assert_eq!(ExitReason::Reset, emu.start());
+}
+
+#[test]
+fn signature() -> io::Result<()> {
+    let mut root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    root.push("tests/resources/original/rv64ui-p-ld");
+    let mut elf = Vec::new();
+    File::open(root.as_path())?.read_to_end(&mut elf)?;
+
+    let mut emu = Emulator::new();
+    emu.load_elf(&elf).unwrap();
+    assert_eq!(ExitReason::Shutdown { code: 0 }, emu.start());
+
+    // The data starts with 0x00ff00ff00ff00ff and 0xff00ff00ff00ff00, and the signature is
+    // aligned to 16 bytes.
+    let signature = emu.signature().unwrap();
+    assert!(signature.starts_with("00ff00ff\n00ff00ff\nff00ff00\nff00ff00\n"));
+    assert_eq!(0, signature.lines().count() % 4);
+    assert!(signature.lines().all(|line| line.len() == 8));
+    Ok(())
+}
+
+#[test]
+fn signature_without_symbols() {
+    let mut emu = setup(vec![
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+    ]);
+
+    assert_eq!(
+        Err(SignatureError::SymbolNotFound("begin_signature")),
+        emu.signature()
+    );
+}
+
+#[test]
+fn signature_unaligned_size() {
+    let mut emu = setup(vec![]);
+    let (begin, end) = (DRAM_BASE, DRAM_BASE + 6);
+    emu.symbols
+        .insert(String::from("begin_signature"), begin, 0);
+    emu.symbols.insert(String::from("end_signature"), end, 0);
+
+    // The last word would be read beyond `end_signature`.
+    assert_eq!(
+        Err(SignatureError::InvalidRange { begin, end }),
+        emu.signature()
+    );
+}
@@ -1,5 +1,5 @@
 use rvemu::bus::DRAM_BASE;
-use rvemu::csr::MEPC;
+use rvemu::csr::{MCAUSE, MEPC};
 use rvemu::emulator::Emulator;
 
 #[test]
@@ -12,12 +12,39 @@ fn illegal_isa() {
         0x93, 0x0f, 0x50, 0x00, // addi x31, x0, 5
     ];
 
-    emu.initialize_dram(data);
+    emu.initialize_dram(data).unwrap();
     emu.initialize_pc(DRAM_BASE);
 
     emu.start();
 
-    // TODO: correct?
-    //assert_eq!(4 + DRAM_BASE, emu.cpu.state.read(MEPC));
-    assert_eq!(8 + DRAM_BASE, emu.cpu.state.read(MEPC));
+    // MEPC points to the illegal instruction.
+    assert_eq!(4 + DRAM_BASE, emu.cpu.state.read(MEPC));
+}
+
+#[test]
+fn epc_points_to_faulting_instruction() {
+    let cases = [
+        // ecall
+        ([0x73, 0x00, 0x00, 0x00], 11),
+        // ebreak
+        ([0x73, 0x00, 0x10, 0x00], 3),
+        // lw t0, 0(zero)
+        ([0x83, 0x22, 0x00, 0x00], 5),
+        // sw t0, 0(zero)
+        ([0x23, 0x20, 0x50, 0x00], 7),
+    ];
+    for (inst, cause) in cases.iter() {
+        let mut emu = Emulator::new();
+        let mut data = vec![
+            0x13, 0x00, 0x00, 0x00, // nop
+        ];
+        data.extend_from_slice(inst);
+        emu.initialize_dram(data).unwrap();
+        emu.initialize_pc(DRAM_BASE);
+
+        emu.test_start(DRAM_BASE, DRAM_BASE + 8);
+
+        assert_eq!(*cause, emu.cpu.state.read(MCAUSE));
+        assert_eq!(4 + DRAM_BASE, emu.cpu.state.read(MEPC));
+    }
 }
@@ -0,0 +1,53 @@
+use rvemu::bus::{DRAM_BASE, DTB_REGION_SIZE, KERNEL_BASE, MROM_BASE};
+use rvemu::cpu::WORD;
+use rvemu::elf::ElfError;
+use rvemu::emulator::{Config, Emulator};
+
+#[test]
+fn boot_firmware_and_kernel() {
+    let mut emu = Emulator::new();
+
+    // A firmware jumps to the next address in the fw_dynamic information like OpenSBI.
+    let bios = vec![
+        0x83, 0x32, 0x06, 0x01, // ld t0, 16(a2)
+        0x67, 0x80, 0x02, 0x00, // jr t0
+    ];
+    let kernel = vec![
+        0x93, 0x0f, 0xa0, 0x02, // addi x31, x0, 42
+    ];
+    emu.load_bios(&bios).unwrap();
+    emu.load_kernel(&kernel).unwrap();
+
+    emu.test_start(MROM_BASE, KERNEL_BASE + kernel.len() as u64);
+
+    assert_eq!(KERNEL_BASE + 4, emu.cpu.pc);
+    assert_eq!(42, emu.cpu.xregs.read(31));
+
+    // The device tree blob is placed in DRAM so that the firmware can modify it.
+    let dtb_addr = emu.cpu.xregs.read(11);
+    assert_eq!(emu.cpu.bus.dtb_addr(), dtb_addr);
+    assert!(dtb_addr >= DRAM_BASE);
+    assert_eq!(
+        0xd00dfeed,
+        u32::from_be(emu.cpu.bus.read(dtb_addr, WORD).unwrap() as u32)
+    );
+    emu.cpu.bus.write(dtb_addr, 0, WORD).unwrap();
+}
+
+#[test]
+fn dram_too_small_for_dtb() {
+    // The device tree blob doesn't fit in 1 MiB of DRAM.
+    let mut emu = Emulator::with_config(Config {
+        dram_size: 0x10_0000,
+    });
+    let bios = vec![
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ];
+    assert_eq!(
+        Err(ElfError::SegmentOutOfRange {
+            addr: DRAM_BASE,
+            size: DTB_REGION_SIZE
+        }),
+        emu.load_bios(&bios)
+    );
+}
@@ -0,0 +1,381 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use std::rc::Rc;
+
+use rvemu::bus::DRAM_BASE;
+use rvemu::cpu::{Mode, DOUBLEWORD};
+use rvemu::emulator::{Emulator, ExitReason};
+use rvemu::gdb::{Connection, GdbStub, SessionEnd};
+
+/// A connection which reads packets prepared in advance and records the output. Only Ctrl-C can
+/// be read in the non-blocking mode, as GDB sends nothing else while the guest is running.
+struct MockConnection {
+    input: VecDeque<u8>,
+    output: Rc<RefCell<Vec<u8>>>,
+    nonblocking: bool,
+}
+
+impl Read for MockConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.nonblocking && self.input.front() != Some(&0x03) {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        match self.input.pop_front() {
+            Some(byte) => {
+                buf[0] = byte;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+impl Write for MockConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for MockConnection {
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+}
+
+/// Format a packet with its checksum.
+fn packet(data: &str) -> String {
+    let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    format!("${}#{:02x}", data, checksum)
+}
+
+/// Serve packets, which are raw bytes to send, and return the responses and the end of the
+/// session.
+fn serve(emu: &mut Emulator, packets: &[&str]) -> (Vec<String>, SessionEnd) {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let conn = MockConnection {
+        input: packets.concat().bytes().collect(),
+        output: output.clone(),
+        nonblocking: false,
+    };
+    let end = GdbStub::new(conn).serve(emu).unwrap();
+
+    let output = String::from_utf8(output.borrow().clone()).unwrap();
+    let responses = output
+        .split('$')
+        .skip(1)
+        .map(|response| {
+            let (data, checksum) = response.split_at(response.find('#').unwrap());
+            assert_eq!(packet(data), format!("${}{}", data, &checksum[..3]));
+            data.to_string()
+        })
+        .collect();
+    (responses, end)
+}
+
+fn setup(data: Vec<u8>) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data).unwrap();
+    emu.initialize_pc(DRAM_BASE);
+    emu
+}
+
+#[test]
+fn registers() {
+    let mut emu = setup(vec![]);
+    emu.cpu.xregs.write(10, 0x1234);
+
+    let (responses, end) = serve(
+        &mut emu,
+        &[
+            &packet("qSupported:swbreak+"),
+            &packet("g"),
+            &packet("p20"),
+            &packet("Pb=2a00000000000000"),
+            // mstatus is numbered 65 + 0x300 = 0x341.
+            &packet("P341=0800000000000000"),
+            // The privilege mode follows the CSRs.
+            &packet("p1041"),
+            &packet("D"),
+        ],
+    );
+
+    assert!(responses[0].contains("qXfer:features:read+"));
+    // x0, ..., x31, pc in little-endian.
+    assert_eq!(33 * 16, responses[1].len());
+    assert_eq!("3412000000000000", &responses[1][10 * 16..11 * 16]);
+    assert_eq!("0000008000000000", &responses[1][32 * 16..]);
+    assert_eq!("0000008000000000", responses[2]);
+    assert_eq!("OK", responses[3]);
+    assert_eq!(42, emu.cpu.xregs.read(11));
+    assert_eq!("OK", responses[4]);
+    // UXL and SXL are read-only.
+    assert_eq!(0xa_0000_0008, emu.cpu.state.read(0x300));
+    assert_eq!("0300000000000000", responses[5]);
+    assert_eq!(SessionEnd::Detached, end);
+}
+
+#[test]
+fn target_description() {
+    let mut emu = setup(vec![]);
+
+    let (responses, _) = serve(
+        &mut emu,
+        &[
+            &packet("qXfer:features:read:target.xml:0,80"),
+            &packet("qXfer:features:read:target.xml:0,100000"),
+        ],
+    );
+
+    assert!(responses[0].starts_with("m<?xml"));
+    assert_eq!(0x81, responses[0].len());
+    assert!(responses[1].starts_with("l<?xml"));
+    assert!(responses[1].contains("<architecture>riscv:rv64</architecture>"));
+    assert!(
+        responses[1].contains("<reg name=\"mstatus\" bitsize=\"64\" type=\"int\" regnum=\"833\"")
+    );
+}
+
+#[test]
+fn memory() {
+    let mut emu = setup(vec![
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+    ]);
+
+    let (responses, _) = serve(
+        &mut emu,
+        &[
+            &packet("m80000000,4"),
+            &packet("M80000004,2:3412"),
+            // The memory before DRAM isn't mapped.
+            &packet("m0,4"),
+            // A long read is cut at the packet size.
+            &packet("m80000000,ffffffffffffffff"),
+        ],
+    );
+
+    assert_eq!(vec!["13051500", "OK", "E01"], responses[..3].to_vec());
+    assert_eq!(0x4000, responses[3].len());
+    assert_eq!(Ok(0x1234), emu.cpu.bus.read(DRAM_BASE + 4, 16));
+}
+
+#[test]
+fn memory_with_paging() {
+    let mut emu = setup(vec![
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+    ]);
+    // Map a 1 GiB page at 0x4000_0000 to DRAM with V, R, W and X, but neither A nor D.
+    let root = DRAM_BASE + 0x10000;
+    let pte = ((DRAM_BASE >> 12) << 10) | 0xf;
+    emu.cpu.bus.write(root + 8, pte, DOUBLEWORD).unwrap();
+    let satp = (8 << 60) | (root >> 12);
+    emu.cpu.mode = Mode::Supervisor;
+
+    let (responses, _) = serve(
+        &mut emu,
+        &[
+            // satp is numbered 65 + 0x180 = 0x1c1.
+            &packet(&format!("P1c1={:016x}", satp.swap_bytes())),
+            &packet("m40000000,4"),
+            &packet("M40000004,2:3412"),
+        ],
+    );
+
+    assert_eq!(vec!["OK", "13051500", "OK"], responses);
+    assert_eq!(Ok(0x1234), emu.cpu.bus.read(DRAM_BASE + 4, 16));
+    // The debugger doesn't set the A and D bits.
+    assert_eq!(Ok(pte), emu.cpu.bus.read(root + 8, DOUBLEWORD));
+}
+
+#[test]
+fn malformed_packets() {
+    let mut emu = setup(vec![]);
+
+    let (responses, _) = serve(
+        &mut emu,
+        &[
+            &packet(""),
+            &packet("\u{e9}"),
+            // A register number which wraps to mstatus as a CSR address.
+            &packet("p10341"),
+            &packet("P10341=0800000000000000"),
+        ],
+    );
+
+    assert_eq!(vec!["", "", "E01", "E01"], responses);
+}
+
+#[test]
+fn breakpoint_and_step() {
+    let mut emu = setup(vec![
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+    ]);
+
+    let (responses, _) = serve(
+        &mut emu,
+        &[
+            &packet("Z0,80000008,4"),
+            &packet("c"),
+            &packet("p20"),
+            &packet("z0,80000008,4"),
+            &packet("s"),
+            &packet("p20"),
+        ],
+    );
+
+    assert_eq!(
+        vec![
+            "OK",
+            "S05",
+            "0800008000000000",
+            "OK",
+            "S05",
+            "0c00008000000000"
+        ],
+        responses
+    );
+    assert_eq!(3, emu.cpu.xregs.read(10));
+}
+
+#[test]
+fn interrupt() {
+    let mut emu = setup(vec![
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ]);
+
+    let (responses, end) = serve(&mut emu, &[&packet("c"), "\x03", &packet("k")]);
+
+    // SIGINT.
+    assert_eq!(vec!["S02"], responses);
+    assert_eq!(DRAM_BASE, emu.cpu.pc);
+    assert_eq!(SessionEnd::Killed, end);
+}
+
+#[test]
+fn interrupt_while_waiting_for_interrupt() {
+    let mut emu = setup(vec![
+        0x73, 0x00, 0x50, 0x10, // wfi
+    ]);
+
+    let (responses, end) = serve(&mut emu, &[&packet("c"), "\x03", &packet("k")]);
+
+    // SIGINT while the hart is idle.
+    assert_eq!(vec!["S02"], responses);
+    assert!(emu.cpu.idle);
+    assert_eq!(SessionEnd::Killed, end);
+}
+
+#[test]
+fn exit() {
+    let mut emu = setup(vec![
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x13, 0x03, 0x70, 0x00, // addi t1, zero, 7
+        0x23, 0xa0, 0x62, 0x00, // sw t1, 0(t0)
+    ]);
+    emu.set_htif(DRAM_BASE + 0x1000, None);
+
+    let (responses, end) = serve(&mut emu, &[&packet("c")]);
+
+    assert_eq!(vec!["W03"], responses);
+    assert_eq!(SessionEnd::Exited(ExitReason::Shutdown { code: 3 }), end);
+}
+
+#[test]
+fn monitor() {
+    let mut emu = setup(vec![]);
+    let hex = |s: &str| -> String { s.bytes().map(|b| format!("{:02x}", b)).collect() };
+
+    let (responses, _) = serve(&mut emu, &[&packet(&format!("qRcmd,{}", hex("physical")))]);
+
+    assert_eq!(
+        vec![hex("Memory is accessed with physical addresses.\n")],
+        responses
+    );
+}
+
+#[test]
+fn watchpoint() {
+    let mut emu = setup(vec![
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x13, 0x03, 0x70, 0x00, // addi t1, zero, 7
+        0x23, 0xa0, 0x62, 0x00, // sw t1, 0(t0)
+        0x13, 0x03, 0x80, 0x00, // addi t1, zero, 8
+    ]);
+
+    let (responses, _) = serve(
+        &mut emu,
+        &[
+            &packet("Z2,80001000,4"),
+            &packet("m80001000,4"),
+            &packet("c"),
+            &packet("z2,80001000,4"),
+            &packet("p20"),
+        ],
+    );
+
+    // The read by the debugger doesn't hit the watchpoint.
+    assert_eq!(
+        vec![
+            "OK",
+            "00000000",
+            "T05watch:80001000;",
+            "OK",
+            "0c00008000000000"
+        ],
+        responses
+    );
+}
+
+#[test]
+fn reverse() {
+    let program = vec![
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+    ];
+    let mut emu = setup(program.clone());
+    let (responses, _) = serve(&mut emu, &[&packet("qSupported"), &packet("bs")]);
+    assert!(!responses[0].contains("ReverseStep+"));
+    assert_eq!("E01", responses[1]);
+
+    let mut emu = setup(program);
+    emu.enable_reverse_execution(1, 8);
+    let (responses, _) = serve(
+        &mut emu,
+        &[
+            &packet("qSupported"),
+            &packet("Z0,80000008,4"),
+            &packet("c"),
+            &packet("s"),
+            &packet("bs"),
+            &packet("p20"),
+            &packet("bc"),
+            &packet("p20"),
+        ],
+    );
+
+    assert!(responses[0].contains("ReverseStep+;ReverseContinue+"));
+    assert_eq!(
+        vec![
+            "OK",
+            "S05",
+            "S05",
+            "S05",
+            "0800008000000000",
+            "T05replaylog:begin;",
+            "0000008000000000"
+        ],
+        responses[1..].to_vec()
+    );
+    assert_eq!(0, emu.cpu.xregs.read(10));
+}
@@ -40,7 +40,7 @@ pub fn run(
 
     emu.is_debug = true;
 
-    emu.initialize_dram(data);
+    emu.initialize_dram(data).unwrap();
     emu.initialize_pc(DRAM_BASE);
 
     emu.test_start(DRAM_BASE, DRAM_BASE + len);
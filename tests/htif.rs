@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+use rvemu::bus::DRAM_BASE;
+use rvemu::cpu::DOUBLEWORD;
+use rvemu::devices::htif::Htif;
+use rvemu::dram::DRAM_SIZE;
+use rvemu::emulator::{Emulator, ExitReason};
+
+/// The address of `tohost` used by the test programs, which is 4 KiB after the beginning of
+/// DRAM. `fromhost` follows it.
+const TOHOST: u64 = DRAM_BASE + 0x1000;
+const FROMHOST: u64 = TOHOST + 0x40;
+
+fn setup(data: Vec<u8>) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data).unwrap();
+    emu.initialize_pc(DRAM_BASE);
+    emu.set_htif(TOHOST, Some(FROMHOST));
+    emu
+}
+
+#[test]
+fn exit_code() {
+    let mut emu = setup(vec![
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x13, 0x03, 0x70, 0x00, // addi t1, zero, 7
+        0x23, 0xa0, 0x62, 0x00, // sw t1, 0(t0)
+    ]);
+
+    // The exit code is encoded as (code << 1) | 1.
+    assert_eq!(ExitReason::Shutdown { code: 3 }, emu.run(100));
+    assert_eq!(Ok(0), emu.cpu.bus.read(TOHOST, DOUBLEWORD));
+}
+
+#[test]
+fn console_putchar() {
+    let mut emu = setup(vec![
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x13, 0x03, 0x10, 0x10, // addi t1, zero, 0x101
+        0x13, 0x13, 0x03, 0x03, // slli t1, t1, 48
+        0x13, 0x03, 0x13, 0x04, // addi t1, t1, 0x41
+        0x23, 0xb0, 0x62, 0x00, // sd t1, 0(t0)
+    ]);
+
+    assert_eq!(ExitReason::LimitReached, emu.run(5));
+    // The host accepts the command and responds to it.
+    assert_eq!(Ok(0), emu.cpu.bus.read(TOHOST, DOUBLEWORD));
+    assert_eq!(
+        Ok(0x0101_0000_0000_0141),
+        emu.cpu.bus.read(FROMHOST, DOUBLEWORD)
+    );
+}
+
+#[test]
+fn syscall_proxy() {
+    let mut emu = setup(vec![
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x13, 0x83, 0x02, 0x10, // addi t1, t0, 0x100
+        0x23, 0xb0, 0x62, 0x00, // sd t1, 0(t0)
+    ]);
+    // write(1, "ok\n", 3)
+    let magic_mem = TOHOST + 0x100;
+    let buf = magic_mem + 0x40;
+    let mut args = Vec::new();
+    for arg in [64, 1, buf, 3].iter() {
+        args.extend_from_slice(&u64::to_le_bytes(*arg));
+    }
+    let bus = &mut emu.cpu.bus;
+    bus.initialize_dram_at(magic_mem, &args, args.len() as u64)
+        .unwrap();
+    bus.initialize_dram_at(buf, b"ok\n", 3).unwrap();
+
+    assert_eq!(ExitReason::LimitReached, emu.run(3));
+    // The return value is stored to the beginning of the arguments.
+    assert_eq!(Ok(3), emu.cpu.bus.read(magic_mem, DOUBLEWORD));
+    assert_eq!(Ok(1), emu.cpu.bus.read(FROMHOST, DOUBLEWORD));
+}
+
+#[test]
+fn syscall_write_out_of_dram() {
+    let mut emu = setup(vec![
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x13, 0x83, 0x02, 0x10, // addi t1, t0, 0x100
+        0x23, 0xb0, 0x62, 0x00, // sd t1, 0(t0)
+    ]);
+    // write(1, buf, u64::MAX) where buf is 3 bytes before the end of DRAM.
+    let magic_mem = TOHOST + 0x100;
+    let buf = DRAM_BASE + DRAM_SIZE - 3;
+    let mut args = Vec::new();
+    for arg in [64, 1, buf, u64::MAX].iter() {
+        args.extend_from_slice(&u64::to_le_bytes(*arg));
+    }
+    let bus = &mut emu.cpu.bus;
+    bus.initialize_dram_at(magic_mem, &args, args.len() as u64)
+        .unwrap();
+    bus.initialize_dram_at(buf, b"ok\n", 3).unwrap();
+
+    // The write stops at the end of DRAM.
+    assert_eq!(ExitReason::LimitReached, emu.run(3));
+    assert_eq!(Ok(3), emu.cpu.bus.read(magic_mem, DOUBLEWORD));
+}
+
+#[test]
+fn riscv_tests_elf() -> io::Result<()> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/resources/original/rv64ui-p-add");
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    // The riscv-tests run from the reset vector as-is, and `tohost` is found in the symbols.
+    let mut emu = Emulator::new();
+    emu.load_elf(&data).unwrap();
+    assert!(emu.cpu.bus.htif.addresses().is_some());
+    assert_eq!(ExitReason::Shutdown { code: 0 }, emu.start());
+    Ok(())
+}
+
+#[test]
+fn access_at_end_of_address_space() {
+    let mut htif = Htif::new();
+    htif.enable(TOHOST, Some(FROMHOST));
+
+    assert!(htif.is_tohost(TOHOST + 4, 64));
+    // The end of the access wraps around without overflowing.
+    assert!(!htif.is_tohost(u64::MAX - 3, 64));
+}
@@ -0,0 +1,95 @@
+use rvemu::bus::PLIC_BASE;
+use rvemu::cpu::{Mode, WORD};
+use rvemu::csr::{MIDELEG, MIE, MIP, MSTATUS, MTIP_BIT, SEIP_BIT, STIP_BIT};
+use rvemu::emulator::Emulator;
+use rvemu::interrupt::Interrupt;
+
+/// The address of the claim/complete register for context 1 (S-mode).
+const SCLAIM: u64 = PLIC_BASE + 0x201004;
+
+#[test]
+fn machine_interrupt_in_supervisor_mode() {
+    let mut emu = Emulator::new();
+    emu.cpu.mode = Mode::Supervisor;
+    emu.cpu.state.write(MIE, MTIP_BIT);
+    emu.cpu.state.write(MIP, MTIP_BIT);
+
+    // M-level interrupts are always enabled in a lower privilege mode, even if SIE and MIE are 0.
+    assert_eq!(
+        Some(Interrupt::MachineTimerInterrupt),
+        emu.cpu.check_pending_interrupt()
+    );
+}
+
+#[test]
+fn delegated_interrupt() {
+    let mut emu = Emulator::new();
+    emu.cpu.state.write(MIDELEG, STIP_BIT);
+    emu.cpu.state.write(MIE, STIP_BIT);
+    emu.cpu.state.write(MIP, STIP_BIT);
+    // Set MIE.
+    emu.cpu.state.write(MSTATUS, 1 << 3);
+
+    // S-level interrupts are never taken in M-mode.
+    assert_eq!(None, emu.cpu.check_pending_interrupt());
+
+    // S-level interrupts are disabled in S-mode if SIE is 0.
+    emu.cpu.mode = Mode::Supervisor;
+    assert_eq!(None, emu.cpu.check_pending_interrupt());
+
+    // Set SIE.
+    emu.cpu.state.write(MSTATUS, 1 << 1);
+    assert_eq!(
+        Some(Interrupt::SupervisorTimerInterrupt),
+        emu.cpu.check_pending_interrupt()
+    );
+}
+
+#[test]
+fn wfi_wakes_up_without_global_enable() {
+    let mut emu = Emulator::new();
+    emu.cpu.mode = Mode::Supervisor;
+    emu.cpu.idle = true;
+    emu.cpu.state.write(MIDELEG, STIP_BIT);
+    emu.cpu.state.write(MIE, STIP_BIT);
+    emu.cpu.state.write(MIP, STIP_BIT);
+
+    // The interrupt isn't taken because SIE is 0, but the hart resumes from WFI.
+    assert_eq!(None, emu.cpu.check_pending_interrupt());
+    assert!(!emu.cpu.idle);
+}
+
+#[test]
+fn plic_claim() {
+    let mut emu = Emulator::new();
+    let bus = &mut emu.cpu.bus;
+    // Set the priorities of the interrupt sources 10 and 33.
+    bus.write(PLIC_BASE + 10 * 4, 1, WORD).unwrap();
+    bus.write(PLIC_BASE + 33 * 4, 2, WORD).unwrap();
+    // Enable them for context 1.
+    bus.write(PLIC_BASE + 0x2080, 1 << 10, WORD).unwrap();
+    bus.write(PLIC_BASE + 0x2084, 1 << 1, WORD).unwrap();
+
+    bus.plic.update_pending(10);
+    bus.plic.update_pending(33);
+    assert_eq!(1 << 10, bus.read(PLIC_BASE + 0x1000, WORD).unwrap());
+    assert_eq!(1 << 1, bus.read(PLIC_BASE + 0x1004, WORD).unwrap());
+    assert!(bus.plic.is_interrupting(1));
+    assert!(!bus.plic.is_interrupting(0));
+
+    // The interrupt with the highest priority is claimed first, and claiming clears the pending
+    // bit.
+    assert_eq!(33, bus.read(SCLAIM, WORD).unwrap());
+    assert_eq!(0, bus.read(PLIC_BASE + 0x1004, WORD).unwrap());
+    assert_eq!(10, bus.read(SCLAIM, WORD).unwrap());
+    assert_eq!(0, bus.read(SCLAIM, WORD).unwrap());
+    assert!(!bus.plic.is_interrupting(1));
+
+    // The supervisor external interrupt follows the PLIC.
+    bus.plic.update_pending(10);
+    emu.cpu.check_pending_interrupt();
+    assert_eq!(SEIP_BIT, emu.cpu.state.read(MIP) & SEIP_BIT);
+    emu.cpu.bus.read(SCLAIM, WORD).unwrap();
+    emu.cpu.check_pending_interrupt();
+    assert_eq!(0, emu.cpu.state.read(MIP) & SEIP_BIT);
+}
@@ -0,0 +1,92 @@
+use rvemu::bus::DRAM_BASE;
+use rvemu::cpu::{Mode, DOUBLEWORD};
+use rvemu::csr::{MCAUSE, MEPC, MSTATUS, MTVAL, MTVEC};
+use rvemu::emulator::Emulator;
+
+/// The address of the root page table.
+const ROOT: u64 = DRAM_BASE + 0x10000;
+/// The virtual address of a 1 GiB page accessible by U-mode.
+const USER_PAGE: u64 = 0x4000_0000;
+
+/// Create an emulator in S-mode whose root page table maps `DRAM_BASE` to itself for S-mode and
+/// `USER_PAGE` to `DRAM_BASE` for U-mode, both with 1 GiB pages. The page for S-mode has neither
+/// the A nor the D bit set.
+fn setup(data: Vec<u8>) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data).unwrap();
+    emu.initialize_pc(DRAM_BASE);
+
+    let ppn = DRAM_BASE >> 12;
+    // V, R, W and X.
+    emu.cpu
+        .bus
+        .write(ROOT + 2 * 8, (ppn << 10) | 0xf, DOUBLEWORD)
+        .unwrap();
+    // V, R, W, U and A.
+    emu.cpu
+        .bus
+        .write(ROOT + 8, (ppn << 10) | 0x57, DOUBLEWORD)
+        .unwrap();
+
+    emu.cpu.xregs.write(5, (8 << 60) | (ROOT >> 12));
+    emu.cpu.xregs.write(7, USER_PAGE + 0x100);
+    emu.cpu.state.write(MTVEC, DRAM_BASE + 0x100);
+    emu.cpu.mode = Mode::Supervisor;
+    emu
+}
+
+#[test]
+fn supervisor_access_to_user_page() {
+    let data = vec![
+        0x73, 0x90, 0x02, 0x18, // csrw satp, x5
+        0x03, 0xb3, 0x03, 0x00, // ld x6, 0(x7)
+    ];
+    let mut emu = setup(data);
+    emu.test_start(DRAM_BASE, DRAM_BASE + 8);
+
+    // Load page fault because SUM is 0.
+    assert_eq!(13, emu.cpu.state.read(MCAUSE));
+    assert_eq!(DRAM_BASE + 4, emu.cpu.state.read(MEPC));
+    assert_eq!(USER_PAGE + 0x100, emu.cpu.state.read(MTVAL));
+
+    // The A bit is set by fetching instructions, but the D bit isn't because nothing is stored.
+    let pte = emu.cpu.bus.read(ROOT + 2 * 8, DOUBLEWORD).unwrap();
+    assert_eq!(0x40, pte & 0xc0);
+}
+
+#[test]
+fn permit_supervisor_user_memory_access() {
+    let data = vec![
+        0x73, 0x90, 0x02, 0x18, // csrw satp, x5
+        0x03, 0xb3, 0x03, 0x00, // ld x6, 0(x7)
+    ];
+    let mut emu = setup(data);
+    emu.cpu
+        .bus
+        .write(DRAM_BASE + 0x100, 0x1234, DOUBLEWORD)
+        .unwrap();
+    // Set SUM.
+    emu.cpu
+        .state
+        .write(MSTATUS, emu.cpu.state.read(MSTATUS) | (1 << 18));
+    emu.test_start(DRAM_BASE, DRAM_BASE + 8);
+
+    assert_eq!(DRAM_BASE + 8, emu.cpu.pc);
+    assert_eq!(0x1234, emu.cpu.xregs.read(6));
+}
+
+#[test]
+fn page_table_access_fault() {
+    let data = vec![
+        0x73, 0x90, 0x02, 0x18, // csrw satp, x5
+        0x23, 0xb0, 0x63, 0x00, // sd x6, 0(x7)
+    ];
+    let mut emu = setup(data);
+    // The page table for `USER_PAGE` is at address 0, which isn't mapped.
+    emu.cpu.bus.write(ROOT + 8, 0x1, DOUBLEWORD).unwrap();
+    emu.test_start(DRAM_BASE, DRAM_BASE + 8);
+
+    // A store access fault, not a load access fault, because the PTE is read for a store.
+    assert_eq!(7, emu.cpu.state.read(MCAUSE));
+    assert_eq!(DRAM_BASE + 4, emu.cpu.state.read(MEPC));
+}
@@ -0,0 +1,106 @@
+use rvemu::bus::DRAM_BASE;
+use rvemu::emulator::Emulator;
+use rvemu::replay::{format, parse, InputEvent, InputRecord, ReplayError};
+
+fn setup(data: Vec<u8>) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data).unwrap();
+    emu.initialize_pc(DRAM_BASE);
+    emu
+}
+
+/// A program which counts the polls of LSR in a2 until UART receives a byte, and then reads it
+/// into a0.
+fn poll_uart() -> Vec<u8> {
+    vec![
+        0xb7, 0x02, 0x00, 0x10, // lui t0, 0x10000
+        0x03, 0xc3, 0x52, 0x00, // lbu t1, 5(t0)
+        0x13, 0x73, 0x13, 0x00, // andi t1, t1, 1
+        0x13, 0x06, 0x16, 0x00, // addi a2, a2, 1
+        0xe3, 0x0a, 0x03, 0xfe, // beq t1, zero, -12
+        0x03, 0xc5, 0x02, 0x00, // lbu a0, 0(t0)
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ]
+}
+
+#[test]
+fn format_and_parse() {
+    let record = InputRecord {
+        cycle: 123456,
+        event: InputEvent::Uart(0x61),
+    };
+    assert_eq!("123456 uart 0x61", format(&record));
+
+    let log = "# comment\n123456 uart 0x61\n\n123456 uart 0x0a\n";
+    assert_eq!(
+        Ok(vec![
+            record,
+            InputRecord {
+                cycle: 123456,
+                event: InputEvent::Uart(0x0a),
+            }
+        ]),
+        parse(log)
+    );
+
+    assert_eq!(
+        "42 time 0x1e240",
+        format(&InputRecord {
+            cycle: 42,
+            event: InputEvent::Time(123456),
+        })
+    );
+    assert_eq!(
+        Ok(vec![InputRecord {
+            cycle: 42,
+            event: InputEvent::Time(123456),
+        }]),
+        parse("42 time 0x1e240")
+    );
+
+    assert_eq!(Err(ReplayError::InvalidLine(1)), parse("1 uart"));
+    assert_eq!(
+        Err(ReplayError::InvalidLine(2)),
+        parse("1 uart 0x61\n2 gpio 0x1")
+    );
+    assert_eq!(
+        Err(ReplayError::OutOfOrder(2)),
+        parse("10 uart 0x61\n9 uart 0x62")
+    );
+}
+
+#[test]
+fn replay() {
+    // The cycles are counted from 0, so the byte is delivered after `lbu` in the second poll and
+    // found by the third poll.
+    let mut emu = setup(poll_uart());
+    emu.replay_inputs("7 uart 0x61").unwrap();
+    emu.run(100);
+    assert_eq!(0x61, emu.cpu.xregs.read(10));
+    assert_eq!(3, emu.cpu.xregs.read(12));
+    assert_eq!(100, emu.cycle());
+
+    // The same inputs at a later cycle make a different run.
+    let mut emu = setup(poll_uart());
+    emu.replay_inputs("# later\n10 uart 0x62").unwrap();
+    emu.run(100);
+    assert_eq!(0x62, emu.cpu.xregs.read(10));
+    assert_eq!(4, emu.cpu.xregs.read(12));
+}
+
+#[test]
+fn replay_from_snapshot() {
+    let mut emu = setup(poll_uart());
+    emu.run(5);
+    let mut snapshot = Vec::new();
+    emu.save_snapshot(&mut snapshot).unwrap();
+
+    // The cycle is restored with the snapshot, so the inputs are delivered at the same points.
+    let mut restored = Emulator::new();
+    restored.load_snapshot(&snapshot[..]).unwrap();
+    assert_eq!(5, restored.cycle());
+    restored.replay_inputs("7 uart 0x61").unwrap();
+    restored.run(95);
+    assert_eq!(0x61, restored.cpu.xregs.read(10));
+    assert_eq!(3, restored.cpu.xregs.read(12));
+}
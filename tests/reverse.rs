@@ -0,0 +1,174 @@
+use rvemu::bus::DRAM_BASE;
+use rvemu::cpu::DOUBLEWORD;
+use rvemu::emulator::{Emulator, ExitReason};
+use rvemu::reverse::ReverseError;
+use rvemu::watchpoint::{AddressSpace, WatchKind, Watchpoint};
+
+fn setup(data: Vec<u8>) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data).unwrap();
+    emu.initialize_pc(DRAM_BASE);
+    emu
+}
+
+/// A loop which counts up a0 and stores it to the memory and `mscratch`. The store is executed in
+/// the cycles 2, 6, 10 and so on.
+fn counter() -> Vec<u8> {
+    vec![
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x23, 0xb0, 0xa2, 0x00, // sd a0, 0(t0)
+        0x73, 0x10, 0x05, 0x34, // csrrw zero, mscratch, a0
+        0x6f, 0xf0, 0x5f, 0xff, // jal zero, -12
+    ]
+}
+
+fn save(emu: &Emulator) -> Vec<u8> {
+    let mut snapshot = Vec::new();
+    emu.save_snapshot(&mut snapshot).unwrap();
+    snapshot
+}
+
+/// Return the state of the counter after `cycles` cycles executed without going back.
+fn counter_after(cycles: u64) -> Vec<u8> {
+    let mut emu = setup(counter());
+    emu.run(cycles);
+    save(&emu)
+}
+
+#[test]
+fn step_back() {
+    let mut emu = setup(counter());
+    assert_eq!(Err(ReverseError::NotEnabled), emu.step_back());
+
+    emu.enable_reverse_execution(16, 8);
+    emu.run(100);
+    assert_eq!(Ok(ExitReason::LimitReached), emu.step_back());
+    assert_eq!(99, emu.cycle());
+    assert_eq!(counter_after(99), save(&emu));
+
+    // Go back across checkpoints, and then forward again.
+    for _ in 0..40 {
+        emu.step_back().unwrap();
+    }
+    assert_eq!(counter_after(59), save(&emu));
+    emu.run(41);
+    assert_eq!(counter_after(100), save(&emu));
+}
+
+#[test]
+fn reverse_continue_to_write() {
+    let mut emu = setup(counter());
+    emu.enable_reverse_execution(16, 8);
+    emu.run(100);
+
+    // Find the last store to the memory, which is done before the instruction is executed.
+    let watchpoint = Watchpoint {
+        space: AddressSpace::Physical,
+        kind: WatchKind::Write,
+        addr: DRAM_BASE + 0x1000,
+        len: 8,
+    };
+    emu.add_watchpoint(watchpoint);
+    match emu.reverse_continue() {
+        Ok(ExitReason::Watchpoint(hit)) => {
+            assert_eq!(DRAM_BASE + 8, hit.pc);
+            assert_eq!(25, hit.value);
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert_eq!(98, emu.cycle());
+    assert_eq!(DRAM_BASE + 8, emu.cpu.pc);
+    assert_eq!(25, emu.cpu.xregs.read(10));
+    assert_eq!(Ok(24), emu.cpu.bus.read(DRAM_BASE + 0x1000, DOUBLEWORD));
+
+    // The previous store, and the store after it when the guest runs forward.
+    assert!(matches!(
+        emu.reverse_continue(),
+        Ok(ExitReason::Watchpoint(_))
+    ));
+    assert_eq!(94, emu.cycle());
+    assert!(matches!(emu.run(100), ExitReason::Watchpoint(_)));
+    assert_eq!(95, emu.cycle());
+    assert_eq!(Ok(24), emu.cpu.bus.read(DRAM_BASE + 0x1000, DOUBLEWORD));
+}
+
+#[test]
+fn reverse_continue_to_breakpoint() {
+    let mut emu = setup(counter());
+    emu.enable_reverse_execution(16, 8);
+    emu.run(100);
+
+    emu.add_breakpoint(DRAM_BASE + 4);
+    assert_eq!(
+        Ok(ExitReason::Breakpoint(DRAM_BASE + 4)),
+        emu.reverse_continue()
+    );
+    assert_eq!(97, emu.cycle());
+    assert_eq!(counter_after(97), save(&emu));
+    assert_eq!(
+        Ok(ExitReason::Breakpoint(DRAM_BASE + 4)),
+        emu.reverse_continue()
+    );
+    assert_eq!(93, emu.cycle());
+}
+
+#[test]
+fn history_start() {
+    let mut emu = setup(counter());
+    emu.run(5);
+    emu.enable_reverse_execution(10, 3);
+    emu.run(95);
+
+    // Only the checkpoints at the cycles 75, 85 and 95 are kept.
+    assert_eq!(Ok(ExitReason::HistoryStart), emu.reverse_continue());
+    assert_eq!(75, emu.cycle());
+    assert_eq!(counter_after(75), save(&emu));
+    assert_eq!(Ok(ExitReason::HistoryStart), emu.step_back());
+    assert_eq!(75, emu.cycle());
+}
+
+#[test]
+fn truncate_history() {
+    let mut emu = setup(counter());
+    emu.enable_reverse_execution(16, 8);
+    emu.run(100);
+    for _ in 0..50 {
+        emu.step_back().unwrap();
+    }
+
+    // A new execution from the cycle 50 with a different value.
+    emu.truncate_history();
+    emu.cpu.xregs.write(10, 1000);
+    emu.run(50);
+    let a0 = emu.cpu.xregs.read(10);
+    emu.step_back().unwrap();
+    emu.run(1);
+    assert_eq!(a0, emu.cpu.xregs.read(10));
+    assert_eq!(Ok(a0), emu.cpu.bus.read(DRAM_BASE + 0x1000, DOUBLEWORD));
+}
+
+#[test]
+fn inputs_in_history() {
+    // A program which polls LSR of UART until it receives a byte, and then reads it into a0.
+    let mut emu = setup(vec![
+        0xb7, 0x02, 0x00, 0x10, // lui t0, 0x10000
+        0x03, 0xc3, 0x52, 0x00, // lbu t1, 5(t0)
+        0x13, 0x73, 0x13, 0x00, // andi t1, t1, 1
+        0xe3, 0x0c, 0x03, 0xfe, // beq t1, zero, -8
+        0x03, 0xc5, 0x02, 0x00, // lbu a0, 0(t0)
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ]);
+    emu.enable_reverse_execution(4, 8);
+    emu.replay_inputs("10 uart 0x61").unwrap();
+    emu.run(30);
+    assert_eq!(0x61, emu.cpu.xregs.read(10));
+
+    // The input is delivered again from the history when the guest runs forward after going back.
+    for _ in 0..25 {
+        emu.step_back().unwrap();
+    }
+    assert_eq!(0, emu.cpu.xregs.read(10));
+    emu.run(25);
+    assert_eq!(0x61, emu.cpu.xregs.read(10));
+}
@@ -0,0 +1,328 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+use rvemu::emulator::{Emulator, ExitReason};
+
+/// The maximum number of cycles to run a test program. Every test finishes far before it.
+const MAX_CYCLES: u64 = 10_000_000;
+
+/// Run an ELF file of the riscv-tests in `tests/resources/original` as-is. A test program reports
+/// the result via HTIF, which is 0 on success or the number of the failed test case.
+fn run(name: &str) {
+    match run_elf(name) {
+        ExitReason::Shutdown { code: 0 } => {}
+        ExitReason::Shutdown { code } => panic!("{} failed at test case {}", name, code),
+        reason => panic!("{} stopped with {:?}", name, reason),
+    }
+}
+
+/// Run a test program which is known to fail, and check that it still fails in the same way.
+fn run_known_failure(name: &str, expected: ExitReason) {
+    assert_eq!(expected, run_elf(name), "{} changed the failure mode", name);
+}
+
+fn run_elf(name: &str) -> ExitReason {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests/resources/original");
+    path.push(name);
+    let mut data = Vec::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e));
+
+    let mut emu = Emulator::new();
+    emu.load_elf(&data).unwrap();
+    emu.run(MAX_CYCLES)
+}
+
+/// Add a test which runs a test program named like `rv64ui-p-add` for `rv64ui_p_add`, or the
+/// program given by `file`. A test which doesn't pass yet is checked to fail with the `ExitReason`
+/// given by `fails`, so that any change in the result is noticed.
+macro_rules! add_test {
+    ($name: ident) => {
+        #[test]
+        fn $name() {
+            run(&stringify!($name).replacen('_', "-", 2));
+        }
+    };
+    ($name: ident, file = $file: literal) => {
+        #[test]
+        fn $name() {
+            run($file);
+        }
+    };
+    ($name: ident, fails = $expected: expr) => {
+        #[test]
+        fn $name() {
+            run_known_failure(&stringify!($name).replacen('_', "-", 2), $expected);
+        }
+    };
+}
+
+// rv64ui-p-*
+add_test!(rv64ui_p_add);
+add_test!(rv64ui_p_addi);
+add_test!(rv64ui_p_addiw);
+add_test!(rv64ui_p_addw);
+add_test!(rv64ui_p_and);
+add_test!(rv64ui_p_andi);
+add_test!(rv64ui_p_auipc);
+add_test!(rv64ui_p_beq);
+add_test!(rv64ui_p_bge);
+add_test!(rv64ui_p_bgeu);
+add_test!(rv64ui_p_blt);
+add_test!(rv64ui_p_bltu);
+add_test!(rv64ui_p_bne);
+add_test!(rv64ui_p_fence_i);
+add_test!(rv64ui_p_jal);
+add_test!(rv64ui_p_jalr);
+add_test!(rv64ui_p_lb);
+add_test!(rv64ui_p_lbu);
+add_test!(rv64ui_p_ld);
+add_test!(rv64ui_p_lh);
+add_test!(rv64ui_p_lhu);
+add_test!(rv64ui_p_lui);
+add_test!(rv64ui_p_lw);
+add_test!(rv64ui_p_lwu);
+add_test!(rv64ui_p_or);
+add_test!(rv64ui_p_ori);
+add_test!(rv64ui_p_sb);
+add_test!(rv64ui_p_sd);
+add_test!(rv64ui_p_sh);
+add_test!(rv64ui_p_simple);
+add_test!(rv64ui_p_sll);
+add_test!(rv64ui_p_slli);
+add_test!(rv64ui_p_slliw);
+add_test!(rv64ui_p_sllw);
+add_test!(rv64ui_p_slt);
+add_test!(rv64ui_p_slti);
+add_test!(rv64ui_p_sltiu);
+add_test!(rv64ui_p_sltu);
+add_test!(rv64ui_p_sra);
+add_test!(rv64ui_p_srai);
+add_test!(rv64ui_p_sraiw);
+add_test!(rv64ui_p_sraw);
+add_test!(rv64ui_p_srl);
+add_test!(rv64ui_p_srli);
+add_test!(rv64ui_p_srliw);
+add_test!(rv64ui_p_srlw);
+add_test!(rv64ui_p_sub);
+add_test!(rv64ui_p_subw);
+add_test!(rv64ui_p_sw);
+add_test!(rv64ui_p_xor);
+add_test!(rv64ui_p_xori);
+
+// rv64ui-v-*
+add_test!(rv64ui_v_add);
+add_test!(rv64ui_v_addi);
+add_test!(rv64ui_v_addiw);
+add_test!(rv64ui_v_addw);
+add_test!(rv64ui_v_and);
+add_test!(rv64ui_v_andi);
+add_test!(rv64ui_v_auipc);
+add_test!(rv64ui_v_beq);
+add_test!(rv64ui_v_bge);
+add_test!(rv64ui_v_bgeu);
+add_test!(rv64ui_v_blt);
+add_test!(rv64ui_v_bltu);
+add_test!(rv64ui_v_bne);
+add_test!(rv64ui_v_fence_i);
+add_test!(rv64ui_v_jal);
+add_test!(rv64ui_v_jalr);
+add_test!(rv64ui_v_lb);
+add_test!(rv64ui_v_lbu);
+add_test!(rv64ui_v_ld);
+add_test!(rv64ui_v_lh);
+add_test!(rv64ui_v_lhu);
+add_test!(rv64ui_v_lui);
+add_test!(rv64ui_v_lw);
+add_test!(rv64ui_v_lwu);
+add_test!(rv64ui_v_or);
+add_test!(rv64ui_v_ori);
+add_test!(rv64ui_v_sb);
+add_test!(rv64ui_v_sd);
+add_test!(rv64ui_v_sh);
+add_test!(rv64ui_v_simple);
+add_test!(rv64ui_v_sll);
+add_test!(rv64ui_v_slli);
+add_test!(rv64ui_v_slliw);
+add_test!(rv64ui_v_sllw);
+add_test!(rv64ui_v_slt);
+add_test!(rv64ui_v_slti);
+add_test!(rv64ui_v_sltiu);
+add_test!(rv64ui_v_sltu);
+add_test!(rv64ui_v_sra);
+add_test!(rv64ui_v_srai);
+add_test!(rv64ui_v_sraiw);
+add_test!(rv64ui_v_sraw);
+add_test!(rv64ui_v_srl);
+add_test!(rv64ui_v_srli);
+add_test!(rv64ui_v_srliw);
+add_test!(rv64ui_v_srlw);
+add_test!(rv64ui_v_sub);
+add_test!(rv64ui_v_subw);
+add_test!(rv64ui_v_sw);
+add_test!(rv64ui_v_xor);
+add_test!(rv64ui_v_xori);
+
+// rv64um-p-*
+add_test!(rv64um_p_div);
+add_test!(rv64um_p_divu);
+add_test!(rv64um_p_divuw);
+add_test!(rv64um_p_divw);
+add_test!(rv64um_p_mul);
+add_test!(rv64um_p_mulh);
+add_test!(rv64um_p_mulhsu);
+add_test!(rv64um_p_mulhu);
+add_test!(rv64um_p_mulw);
+add_test!(rv64um_p_rem);
+add_test!(rv64um_p_remu);
+add_test!(rv64um_p_remuw);
+add_test!(rv64um_p_remw);
+
+// rv64um-v-*
+add_test!(rv64um_v_div);
+add_test!(rv64um_v_divu);
+add_test!(rv64um_v_divuw);
+add_test!(rv64um_v_divw);
+add_test!(rv64um_v_mul);
+add_test!(rv64um_v_mulh);
+add_test!(rv64um_v_mulhsu);
+add_test!(rv64um_v_mulhu);
+add_test!(rv64um_v_mulw);
+add_test!(rv64um_v_rem);
+add_test!(rv64um_v_remu);
+add_test!(rv64um_v_remuw);
+add_test!(rv64um_v_remw);
+
+// rv64ua-p-*
+add_test!(rv64ua_p_amoadd_d);
+add_test!(rv64ua_p_amoadd_w);
+add_test!(rv64ua_p_amoand_d);
+add_test!(rv64ua_p_amoand_w);
+add_test!(rv64ua_p_amomax_d);
+add_test!(rv64ua_p_amomax_w);
+add_test!(rv64ua_p_amomaxu_d);
+add_test!(rv64ua_p_amomaxu_w);
+add_test!(rv64ua_p_amomin_d);
+add_test!(rv64ua_p_amomin_w);
+add_test!(rv64ua_p_amominu_d);
+add_test!(rv64ua_p_amominu_w);
+add_test!(rv64ua_p_amoor_d);
+add_test!(rv64ua_p_amoor_w);
+add_test!(rv64ua_p_amoswap_d);
+add_test!(rv64ua_p_amoswap_w);
+add_test!(rv64ua_p_amoxor_d);
+add_test!(rv64ua_p_amoxor_w);
+add_test!(rv64ua_p_lrsc);
+
+// rv64ua-v-*
+add_test!(rv64ua_v_amoadd_d);
+add_test!(rv64ua_v_amoadd_w);
+add_test!(rv64ua_v_amoand_d);
+add_test!(rv64ua_v_amoand_w);
+add_test!(rv64ua_v_amomax_d);
+add_test!(rv64ua_v_amomax_w);
+add_test!(rv64ua_v_amomaxu_d);
+add_test!(rv64ua_v_amomaxu_w);
+add_test!(rv64ua_v_amomin_d);
+add_test!(rv64ua_v_amomin_w);
+add_test!(rv64ua_v_amominu_d);
+add_test!(rv64ua_v_amominu_w);
+add_test!(rv64ua_v_amoor_d);
+add_test!(rv64ua_v_amoor_w);
+add_test!(rv64ua_v_amoswap_d);
+add_test!(rv64ua_v_amoswap_w);
+add_test!(rv64ua_v_amoxor_d);
+add_test!(rv64ua_v_amoxor_w);
+add_test!(rv64ua_v_lrsc);
+
+// The floating-point tests checked with `fails` are known failures. Single-precision values are
+// kept converted to f64 instead of NaN-boxed, so `fmv.x.w` doesn't see the bits of a result, and
+// the accrued exception flags in fflags are never set.
+
+// rv64uf-p-*
+add_test!(rv64uf_p_fadd, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_p_fclass, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_p_fcmp, fails = ExitReason::Shutdown { code: 10 });
+add_test!(rv64uf_p_fcvt, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_p_fcvt_w, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_p_fdiv, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_p_fmadd, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_p_fmin, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_p_ldst);
+add_test!(rv64uf_p_move, fails = ExitReason::Shutdown { code: 3 });
+add_test!(rv64uf_p_recoding);
+
+// rv64uf-v-*
+add_test!(rv64uf_v_fadd, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_v_fclass, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_v_fcmp, fails = ExitReason::Shutdown { code: 10 });
+add_test!(rv64uf_v_fcvt, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_v_fcvt_w, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_v_fdiv, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_v_fmadd, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_v_fmin, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64uf_v_ldst);
+add_test!(rv64uf_v_move, fails = ExitReason::Shutdown { code: 3 });
+add_test!(rv64uf_v_recoding);
+
+// rv64ud-p-*
+add_test!(rv64ud_p_fadd, fails = ExitReason::Shutdown { code: 3 });
+add_test!(rv64ud_p_fclass, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64ud_p_fcmp, fails = ExitReason::Shutdown { code: 10 });
+add_test!(rv64ud_p_fcvt, fails = ExitReason::Shutdown { code: 7 });
+add_test!(rv64ud_p_fcvt_w, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64ud_p_fdiv, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64ud_p_fmadd, fails = ExitReason::Shutdown { code: 3 });
+add_test!(rv64ud_p_fmin, fails = ExitReason::Shutdown { code: 20 });
+add_test!(rv64ud_p_ldst, fails = ExitReason::Shutdown { code: 3 });
+add_test!(rv64ud_p_move, fails = ExitReason::Shutdown { code: 40 });
+add_test!(rv64ud_p_recoding, fails = ExitReason::Shutdown { code: 10 });
+add_test!(rv64ud_p_structural);
+
+// rv64ud-v-*
+add_test!(rv64ud_v_fadd, fails = ExitReason::Shutdown { code: 3 });
+add_test!(rv64ud_v_fclass, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64ud_v_fcmp, fails = ExitReason::Shutdown { code: 10 });
+add_test!(rv64ud_v_fcvt, fails = ExitReason::Shutdown { code: 7 });
+add_test!(rv64ud_v_fcvt_w, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64ud_v_fdiv, fails = ExitReason::Shutdown { code: 2 });
+add_test!(rv64ud_v_fmadd, fails = ExitReason::Shutdown { code: 3 });
+add_test!(rv64ud_v_fmin, fails = ExitReason::Shutdown { code: 20 });
+add_test!(rv64ud_v_ldst, fails = ExitReason::Shutdown { code: 3 });
+add_test!(rv64ud_v_move, fails = ExitReason::Shutdown { code: 40 });
+add_test!(rv64ud_v_recoding, fails = ExitReason::Shutdown { code: 10 });
+add_test!(rv64ud_v_structural);
+
+// rv64uc-p-*
+add_test!(rv64uc_p_rvc);
+
+// rv64uc-v-*
+add_test!(rv64uc_v_rvc);
+
+// rv64mi-p-*
+add_test!(rv64mi_p_access);
+add_test!(rv64mi_p_breakpoint);
+add_test!(rv64mi_p_illegal);
+add_test!(rv64mi_p_ma_addr);
+add_test!(rv64mi_p_ma_fetch);
+add_test!(rv64mi_p_mcsr);
+add_test!(rv64mi_p_sbreak);
+add_test!(rv64mi_p_scall);
+
+// rv64si-p-*
+add_test!(rv64si_p_csr);
+add_test!(rv64si_p_dirty);
+add_test!(rv64si_p_icache_alias, file = "rv64si-p-icache-alias");
+add_test!(rv64si_p_ma_fetch);
+add_test!(rv64si_p_sbreak);
+add_test!(rv64si_p_scall);
+add_test!(rv64si_p_wfi);
+
+// Known failures.
+// The trap handler of this build of rv64mi-p-csr expects the illegal `fsw` while FS is Off in
+// test case 10 to happen after test case 9, but it runs after test case 16, so the trap is taken
+// as a failure of test case 16.
+add_test!(rv64mi_p_csr, fails = ExitReason::Shutdown { code: 16 });
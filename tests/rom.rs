@@ -0,0 +1,55 @@
+use rvemu::bus::{DRAM_BASE, MROM_BASE};
+use rvemu::cpu::{DOUBLEWORD, POINTER_TO_DTB, WORD};
+use rvemu::emulator::Emulator;
+use rvemu::exception::Exception;
+use rvemu::rom::FW_DYNAMIC_INFO_ADDR;
+
+#[test]
+fn reset_vector() {
+    let mut emu = Emulator::new();
+    let data = vec![
+        0x93, 0x0f, 0xa0, 0x02, // addi x31, x0, 42
+    ];
+    emu.initialize_dram(data).unwrap();
+    // Dirty the registers set by the reset vector.
+    emu.cpu.xregs.write(10, 0xdead);
+    emu.cpu.xregs.write(11, 0xdead);
+
+    assert_eq!(MROM_BASE, emu.cpu.pc);
+    emu.test_start(MROM_BASE, DRAM_BASE + 4);
+
+    assert_eq!(DRAM_BASE + 4, emu.cpu.pc);
+    assert_eq!(42, emu.cpu.xregs.read(31));
+    // a0: hartid, a1: the address of DTB, a2: the address of the fw_dynamic information.
+    assert_eq!(0, emu.cpu.xregs.read(10));
+    assert_eq!(POINTER_TO_DTB, emu.cpu.xregs.read(11));
+    assert_eq!(FW_DYNAMIC_INFO_ADDR, emu.cpu.xregs.read(12));
+}
+
+#[test]
+fn fw_dynamic_info() {
+    let mut emu = Emulator::new();
+    emu.cpu.bus.rom.set_next_addr(DRAM_BASE + 0x20_0000);
+
+    let info: Vec<u64> = (0..6)
+        .map(|i| {
+            emu.cpu
+                .bus
+                .read(FW_DYNAMIC_INFO_ADDR + i * 8, DOUBLEWORD)
+                .unwrap()
+        })
+        .collect();
+    // magic, version, next_addr, next_mode, options and boot_hart.
+    assert_eq!(vec![0x4942534f, 2, DRAM_BASE + 0x20_0000, 1, 0, 0], info);
+
+    // The DTB follows the fw_dynamic information.
+    assert_eq!(
+        0xd00dfeed,
+        u32::from_be(emu.cpu.bus.read(POINTER_TO_DTB, WORD).unwrap() as u32)
+    );
+    // ROM is read-only.
+    assert_eq!(
+        Err(Exception::StoreAMOAccessFault),
+        emu.cpu.bus.write(MROM_BASE, 0, DOUBLEWORD)
+    );
+}
@@ -23,7 +23,7 @@ macro_rules! add_test {
             let len = data.len() as u64;
 
             let mut emu = Emulator::new();
-            emu.initialize_dram(data);
+            emu.initialize_dram(data).unwrap();
             emu.initialize_pc(DRAM_BASE);
 
             emu.test_start(DRAM_BASE, DRAM_BASE + len);
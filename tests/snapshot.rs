@@ -0,0 +1,224 @@
+use rvemu::bus::{DRAM_BASE, TEST_BASE, VIRTIO_BASE};
+use rvemu::cpu::{Mode, BYTE, DOUBLEWORD, HALFWORD, WORD};
+use rvemu::csr::MSCRATCH;
+use rvemu::emulator::{Config, Emulator, ExitReason};
+use rvemu::snapshot::SnapshotError;
+
+fn setup(data: Vec<u8>) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data).unwrap();
+    emu.initialize_pc(DRAM_BASE);
+    emu
+}
+
+/// A loop which counts up a0 and stores it to the memory and `mscratch`.
+fn counter() -> Vec<u8> {
+    vec![
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x23, 0xb0, 0xa2, 0x00, // sd a0, 0(t0)
+        0x73, 0x10, 0x05, 0x34, // csrrw zero, mscratch, a0
+        0x6f, 0xf0, 0x5f, 0xff, // jal zero, -12
+    ]
+}
+
+fn save(emu: &Emulator) -> Vec<u8> {
+    let mut snapshot = Vec::new();
+    emu.save_snapshot(&mut snapshot).unwrap();
+    snapshot
+}
+
+/// Read or write the `sector` of the disk from/to 512 bytes at `buf` via virtio, with a virtqueue
+/// of 8 descriptors at 64 KiB after the beginning of DRAM.
+fn disk_request(emu: &mut Emulator, sector: u64, buf: u64, write: bool) {
+    let queue = DRAM_BASE + 0x1_0000;
+    let (req, status) = (DRAM_BASE + 0x2_0000, DRAM_BASE + 0x2_2000);
+    let bus = &mut emu.cpu.bus;
+    // The device reads the buffer to write it to the disk.
+    let flags = if write { 1 } else { 1 | 2 };
+    let descriptors = [(req, 16, 1, 1), (buf, 512, flags, 2), (status, 1, 0, 0)];
+    for (i, (addr, len, flags, next)) in descriptors.iter().enumerate() {
+        let desc = queue + 16 * i as u64;
+        bus.write(desc, *addr, DOUBLEWORD).unwrap();
+        bus.write(desc + 8, *len, WORD).unwrap();
+        bus.write(desc + 12, *flags, HALFWORD).unwrap();
+        bus.write(desc + 14, *next, HALFWORD).unwrap();
+    }
+    bus.write(req + 8, sector, DOUBLEWORD).unwrap();
+    // GuestPageSize, QueueNum, QueuePFN and QueueNotify.
+    bus.write(VIRTIO_BASE + 0x28, 0x1000, WORD).unwrap();
+    bus.write(VIRTIO_BASE + 0x38, 8, WORD).unwrap();
+    bus.write(VIRTIO_BASE + 0x40, queue / 0x1000, WORD).unwrap();
+    bus.write(VIRTIO_BASE + 0x50, 0, WORD).unwrap();
+    emu.run(1);
+}
+
+fn fill(emu: &mut Emulator, addr: u64, byte: u8) {
+    for i in 0..512 {
+        emu.cpu.bus.write(addr + i, byte as u64, BYTE).unwrap();
+    }
+}
+
+fn is_filled(emu: &mut Emulator, addr: u64, byte: u8) -> bool {
+    (0..512).all(|i| emu.cpu.bus.read(addr + i, BYTE) == Ok(byte as u64))
+}
+
+#[test]
+fn round_trip() {
+    let mut emu = setup(counter());
+    emu.run(10);
+    emu.cpu.fregs.write(1, 1.5);
+    emu.cpu.mode = Mode::Supervisor;
+    let snapshot = save(&emu);
+    // Only the chunks of 1 GiB of DRAM which are used are saved.
+    assert!(snapshot.len() < 0x20000);
+
+    emu.run(100);
+
+    let mut restored = Emulator::new();
+    restored.load_snapshot(&snapshot[..]).unwrap();
+    assert_eq!(DRAM_BASE + 8, restored.cpu.pc);
+    assert_eq!(3, restored.cpu.xregs.read(10));
+    assert_eq!(1.5, restored.cpu.fregs.read(1));
+    assert_eq!(Mode::Supervisor, restored.cpu.mode);
+    assert_eq!(2, restored.cpu.state.read(MSCRATCH));
+    assert_eq!(Ok(2), restored.cpu.bus.read(DRAM_BASE + 0x1000, DOUBLEWORD));
+
+    // The restored machine behaves the same as the original one.
+    restored.run(100);
+    assert_eq!(emu.cpu.pc, restored.cpu.pc);
+    assert_eq!(emu.cpu.xregs.read(10), restored.cpu.xregs.read(10));
+    assert_eq!(
+        emu.cpu.bus.read(DRAM_BASE + 0x1000, DOUBLEWORD),
+        restored.cpu.bus.read(DRAM_BASE + 0x1000, DOUBLEWORD)
+    );
+    // Saving the restored machine at the same point reproduces the same snapshot.
+    assert_eq!(save(&emu), save(&restored));
+}
+
+#[test]
+fn rewind() {
+    let mut emu = setup(counter());
+    emu.run(10);
+    let snapshot = save(&emu);
+
+    emu.cpu.bus.write(DRAM_BASE + 0x10_0000, 1, BYTE).unwrap();
+    assert_eq!(ExitReason::LimitReached, emu.run(100));
+    emu.load_snapshot(&snapshot[..]).unwrap();
+
+    assert_eq!(DRAM_BASE + 8, emu.cpu.pc);
+    assert_eq!(3, emu.cpu.xregs.read(10));
+    // The memory written after the snapshot reads as zeros again.
+    assert_eq!(Ok(0), emu.cpu.bus.read(DRAM_BASE + 0x10_0000, BYTE));
+    assert_eq!(snapshot, save(&emu));
+}
+
+#[test]
+fn disk_delta() {
+    let buf = DRAM_BASE + 0x2_1000;
+    let mut emu = setup(vec![
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ]);
+    emu.initialize_disk(vec![0x2a; 1024]);
+
+    fill(&mut emu, buf, 0x55);
+    disk_request(&mut emu, 1, buf, true);
+    let snapshot = save(&emu);
+
+    // Overwrite both sectors after the snapshot.
+    fill(&mut emu, buf, 0x77);
+    disk_request(&mut emu, 0, buf, true);
+    disk_request(&mut emu, 1, buf, true);
+
+    // Sector 0 is reverted to the disk image, and sector 1 has the contents in the snapshot.
+    emu.load_snapshot(&snapshot[..]).unwrap();
+    disk_request(&mut emu, 0, buf, false);
+    assert!(is_filled(&mut emu, buf, 0x2a));
+    disk_request(&mut emu, 1, buf, false);
+    assert!(is_filled(&mut emu, buf, 0x55));
+
+    // Another emulator with the same disk image can restore the snapshot.
+    let mut restored = Emulator::new();
+    restored.initialize_disk(vec![0x2a; 1024]);
+    restored.load_snapshot(&snapshot[..]).unwrap();
+    fill(&mut restored, buf, 0);
+    disk_request(&mut restored, 1, buf, false);
+    assert!(is_filled(&mut restored, buf, 0x55));
+}
+
+#[test]
+fn boot_configuration() {
+    let mut emu = setup(counter());
+    emu.cpu.bus.place_dtb_in_dram().unwrap();
+    emu.set_bootargs("console=ttyS0");
+    emu.load_initrd(&[1, 2, 3, 4]).unwrap();
+    let snapshot = save(&emu);
+
+    let mut restored = Emulator::new();
+    restored.load_snapshot(&snapshot[..]).unwrap();
+    assert_eq!(emu.cpu.bus.dtb_addr(), restored.cpu.bus.dtb_addr());
+    assert_eq!("console=ttyS0", restored.cpu.bus.bootargs());
+    assert_eq!(emu.cpu.bus.initrd(), restored.cpu.bus.initrd());
+}
+
+#[test]
+fn pending_shutdown() {
+    // The guest has powered off via the test finisher, but the request isn't taken yet.
+    let mut emu = setup(counter());
+    emu.cpu.bus.write(TEST_BASE, 0x5555, WORD).unwrap();
+    let snapshot = save(&emu);
+
+    let mut restored = Emulator::new();
+    restored.load_snapshot(&snapshot[..]).unwrap();
+    assert_eq!(ExitReason::Shutdown { code: 0 }, restored.run(10));
+
+    // The guest has ended via HTIF with the exit code 3.
+    let mut emu = setup(counter());
+    emu.set_htif(DRAM_BASE + 0x2000, None);
+    emu.cpu
+        .bus
+        .write(DRAM_BASE + 0x2000, 7, DOUBLEWORD)
+        .unwrap();
+    let snapshot = save(&emu);
+
+    let mut restored = Emulator::new();
+    restored.load_snapshot(&snapshot[..]).unwrap();
+    assert_eq!(ExitReason::Shutdown { code: 3 }, restored.run(10));
+}
+
+#[test]
+fn invalid_snapshot() {
+    let mut emu = setup(counter());
+    emu.initialize_disk(vec![0; 512]);
+    let snapshot = save(&emu);
+
+    let mut small = Emulator::with_config(Config {
+        dram_size: 0x10_0000,
+    });
+    match small.load_snapshot(&snapshot[..]) {
+        Err(SnapshotError::DramSizeMismatch { expected, found }) => {
+            assert_eq!((0x10_0000, 1024 * 1024 * 1024), (expected, found));
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    // Nothing is restored if the configuration differs.
+    let mut diskless = setup(vec![]);
+    diskless.cpu.xregs.write(10, 42);
+    match diskless.load_snapshot(&snapshot[..]) {
+        Err(SnapshotError::DiskSizeMismatch { expected, found }) => {
+            assert_eq!((0, 512), (expected, found));
+        }
+        result => panic!("unexpected result: {:?}", result),
+    }
+    assert_eq!(42, diskless.cpu.xregs.read(10));
+
+    assert!(matches!(
+        emu.load_snapshot(&b"not a snapshot"[..]),
+        Err(SnapshotError::InvalidMagic)
+    ));
+    assert!(matches!(
+        emu.load_snapshot(&snapshot[..snapshot.len() - 1]),
+        Err(SnapshotError::Io(_))
+    ));
+}
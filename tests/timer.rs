@@ -0,0 +1,245 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::prelude::*;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rvemu::bus::DRAM_BASE;
+use rvemu::devices::{clint::TimerMode, Device};
+use rvemu::emulator::{Emulator, ExitReason};
+use rvemu::exception::Exception;
+
+fn setup(data: Vec<u8>) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data).unwrap();
+    emu.initialize_pc(DRAM_BASE);
+    emu
+}
+
+/// A loop which reads the `time` CSR into a0 and `mtime` in CLINT into a1.
+fn read_time() -> Vec<u8> {
+    vec![
+        0xb7, 0xc2, 0x00, 0x02, // lui t0, 0x200c
+        0x73, 0x25, 0x10, 0xc0, // csrr a0, time
+        0x83, 0xb5, 0x82, 0xff, // ld a1, -8(t0)
+        0x6f, 0xf0, 0x5f, 0xff, // jal zero, -12
+    ]
+}
+
+/// A program which sets the deadline of the timer to `mtimecmp`, enables the timer interrupt and
+/// waits for it, and then reads the `time` CSR into a0 and passes the test. The interrupt isn't
+/// taken because it's globally disabled in M-mode, but it wakes up the hart.
+fn wait_for_timer(mtimecmp: [u8; 8]) -> Vec<u8> {
+    let mut data = vec![0xb7, 0x42, 0x00, 0x02]; // lui t0, 0x2004
+    data.extend_from_slice(&mtimecmp); // li t1, mtimecmp
+    data.extend_from_slice(&[
+        0x23, 0xb0, 0x62, 0x00, // sd t1, 0(t0)
+        0x93, 0x03, 0x00, 0x08, // addi t2, zero, 0x80
+        0x73, 0x90, 0x43, 0x30, // csrrw zero, mie, t2
+        0x73, 0x00, 0x50, 0x10, // wfi
+        0x73, 0x25, 0x10, 0xc0, // csrr a0, time
+        0xb7, 0x02, 0x10, 0x00, // lui t0, 0x100
+        0x37, 0x53, 0x00, 0x00, // lui t1, 5
+        0x13, 0x03, 0x53, 0x55, // addi t1, t1, 0x555
+        0x23, 0xa0, 0x62, 0x00, // sw t1, 0(t0)
+    ]);
+    data
+}
+
+/// `li t1, 1000000`
+const ONE_MILLION: [u8; 8] = [
+    0x37, 0x43, 0x0f, 0x00, // lui t1, 0xf4
+    0x13, 0x03, 0x03, 0x24, // addi t1, t1, 0x240
+];
+
+/// `li t1, 100000`, which is 10 ms at 10 MHz.
+const ONE_HUNDRED_THOUSAND: [u8; 8] = [
+    0x37, 0x83, 0x01, 0x00, // lui t1, 0x18
+    0x13, 0x03, 0x03, 0x6a, // addi t1, t1, 0x6a0
+];
+
+/// A device which always has work in progress.
+struct Busy;
+
+impl Device for Busy {
+    fn read(&mut self, _offset: u64, _size: u8) -> Result<u64, Exception> {
+        Ok(0)
+    }
+
+    fn write(&mut self, _offset: u64, _value: u64, _size: u8) -> Result<(), Exception> {
+        Ok(())
+    }
+
+    fn is_busy(&self) -> bool {
+        true
+    }
+}
+
+/// A writer which keeps the output in a buffer shared with a test.
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn save(emu: &Emulator) -> Vec<u8> {
+    let mut snapshot = Vec::new();
+    emu.save_snapshot(&mut snapshot).unwrap();
+    snapshot
+}
+
+#[test]
+fn deterministic() {
+    let mut emu = setup(read_time());
+    emu.set_timer_mode(TimerMode::Deterministic {
+        ticks_per_instruction: 10,
+    });
+
+    // The timer advances before the instruction in each cycle is executed.
+    emu.run(3);
+    assert_eq!(20, emu.cpu.xregs.read(10));
+    assert_eq!(30, emu.cpu.xregs.read(11));
+    assert_eq!(30, emu.cpu.bus.clint.mtime());
+}
+
+#[test]
+fn write_mtime() {
+    let mut emu = setup(vec![
+        0xb7, 0xc2, 0x00, 0x02, // lui t0, 0x200c
+        0x13, 0x03, 0x80, 0x3e, // addi t1, zero, 1000
+        0x23, 0xbc, 0x62, 0xfe, // sd t1, -8(t0)
+        0x73, 0x25, 0x10, 0xc0, // csrr a0, time
+    ]);
+
+    emu.run(4);
+    assert_eq!(1001, emu.cpu.xregs.read(10));
+}
+
+#[test]
+fn wall_clock() {
+    let mut emu = setup(read_time());
+    emu.set_timer_mode(TimerMode::WallClock);
+    thread::sleep(Duration::from_millis(20));
+
+    // The host clock is sampled every 10000 cycles, and the timer stays the same between samples.
+    emu.run(9_999);
+    assert_eq!(0, emu.cpu.bus.clint.mtime());
+    emu.run(9);
+    let mtime = emu.cpu.bus.clint.mtime();
+    // 20 ms at 10 MHz.
+    assert!(mtime >= 200_000, "mtime: {}", mtime);
+    assert_eq!(mtime, emu.cpu.xregs.read(10));
+    assert_eq!(mtime, emu.cpu.xregs.read(11));
+}
+
+#[test]
+fn record_and_replay_wall_clock() {
+    let mut emu = setup(read_time());
+    emu.set_timer_mode(TimerMode::WallClock);
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    emu.record_inputs(Box::new(SharedBuffer(buffer.clone())));
+    emu.run(30_000);
+
+    // Each sample of the host clock is an input to the guest.
+    let log = String::from_utf8(buffer.borrow().clone()).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(3, lines.len());
+    assert!(lines[0].starts_with("9999 time "), "log: {}", log);
+
+    // The samples are replayed instead of the host clock, so the run is the same.
+    let mut replayed = setup(read_time());
+    replayed.set_timer_mode(TimerMode::WallClock);
+    thread::sleep(Duration::from_millis(20));
+    replayed.replay_inputs(&log).unwrap();
+    replayed.run(30_000);
+    assert_eq!(save(&emu), save(&replayed));
+}
+
+#[test]
+fn skip_idle_time() {
+    // The timer jumps to the deadline after `wfi` in the cycle 6.
+    let mut emu = setup(wait_for_timer(ONE_MILLION));
+    emu.run(8);
+    assert_eq!(1_000_000, emu.cpu.xregs.read(10));
+    assert_eq!(ExitReason::Shutdown { code: 0 }, emu.start());
+    assert_eq!(12, emu.cycle());
+
+    // The timer doesn't skip ahead of the ticks per instruction.
+    let mut emu = setup(wait_for_timer(ONE_MILLION));
+    emu.set_timer_mode(TimerMode::Deterministic {
+        ticks_per_instruction: 10,
+    });
+    emu.run(8);
+    assert_eq!(1_000_000, emu.cpu.xregs.read(10));
+
+    // A busy device keeps the hart waiting.
+    let mut emu = setup(wait_for_timer(ONE_MILLION));
+    emu.attach_device(0x2000_0000, 0x1000, Box::new(Busy))
+        .unwrap();
+    emu.run(8);
+    assert_eq!(0, emu.cpu.xregs.read(10));
+    assert_eq!(8, emu.cpu.bus.clint.mtime());
+
+    // The timer which doesn't advance never reaches the deadline.
+    let mut emu = setup(wait_for_timer(ONE_MILLION));
+    emu.set_timer_mode(TimerMode::Deterministic {
+        ticks_per_instruction: 0,
+    });
+    assert_eq!(ExitReason::WaitingForInterrupt, emu.run(100));
+    assert_eq!(7, emu.cycle());
+}
+
+#[test]
+fn wake_up_by_pending_interrupt() {
+    let mut data = vec![
+        0xb7, 0x02, 0x00, 0x02, // lui t0, 0x2000
+        0x13, 0x03, 0x10, 0x00, // addi t1, zero, 1
+        0x23, 0xa0, 0x62, 0x00, // sw t1, 0(t0)
+        0xb7, 0x42, 0x00, 0x02, // lui t0, 0x2004
+    ];
+    data.extend_from_slice(&ONE_MILLION); // li t1, 1000000
+    data.extend_from_slice(&[
+        0x23, 0xb0, 0x62, 0x00, // sd t1, 0(t0)
+        0x93, 0x03, 0x80, 0x08, // addi t2, zero, 0x88
+        0x73, 0x90, 0x43, 0x30, // csrrw zero, mie, t2
+        0x73, 0x00, 0x50, 0x10, // wfi
+        0x73, 0x25, 0x10, 0xc0, // csrr a0, time
+    ]);
+    let mut emu = setup(data);
+
+    // The software interrupt is pending when `wfi` is executed, so the timer doesn't skip to the
+    // deadline.
+    emu.run(11);
+    assert_eq!(11, emu.cpu.xregs.read(10));
+}
+
+#[test]
+fn wait_for_host_clock() {
+    let mut emu = setup(wait_for_timer(ONE_HUNDRED_THOUSAND));
+    emu.set_timer_mode(TimerMode::WallClock);
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    emu.record_inputs(Box::new(SharedBuffer(buffer.clone())));
+
+    // The emulator blocks until the deadline, and then the host clock is sampled at once instead
+    // of after 10000 cycles.
+    let start = Instant::now();
+    assert_eq!(ExitReason::Shutdown { code: 0 }, emu.start());
+    assert!(start.elapsed() >= Duration::from_millis(10));
+    assert!(emu.cpu.xregs.read(10) >= 100_000);
+    assert!(emu.cycle() < 10_000, "cycle: {}", emu.cycle());
+
+    // The run is reproduced without waiting.
+    let log = String::from_utf8(buffer.borrow().clone()).unwrap();
+    let mut replayed = setup(wait_for_timer(ONE_HUNDRED_THOUSAND));
+    replayed.set_timer_mode(TimerMode::WallClock);
+    replayed.replay_inputs(&log).unwrap();
+    assert_eq!(ExitReason::Shutdown { code: 0 }, replayed.start());
+    assert_eq!(save(&emu), save(&replayed));
+}
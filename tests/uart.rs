@@ -0,0 +1,48 @@
+use rvemu::bus::{Bus, UART_BASE};
+use rvemu::cpu::BYTE;
+
+/// Interrupt enable register.
+const IER: u64 = UART_BASE + 1;
+/// Interrupt status register.
+const ISR: u64 = UART_BASE + 2;
+/// Line control register.
+const LCR: u64 = UART_BASE + 3;
+/// Line status register.
+const LSR: u64 = UART_BASE + 5;
+
+#[test]
+fn transmitter_empty_interrupt() {
+    let mut bus = Bus::new();
+
+    // The transmitter is always empty and no interrupt is pending.
+    assert_eq!(0x60, bus.read(LSR, BYTE).unwrap());
+    assert_eq!(0x1, bus.read(ISR, BYTE).unwrap());
+    assert!(!bus.uart.is_interrupting());
+
+    // Enabling the transmitter empty interrupt raises it.
+    bus.write(IER, 0x2, BYTE).unwrap();
+    assert!(bus.uart.is_interrupting());
+    // Only the rising edge is reported.
+    assert!(!bus.uart.is_interrupting());
+    assert_eq!(0x2, bus.read(ISR, BYTE).unwrap());
+    // Reading ISR clears it.
+    assert_eq!(0x1, bus.read(ISR, BYTE).unwrap());
+}
+
+#[test]
+fn divisor_latch() {
+    let mut bus = Bus::new();
+
+    // Set DLAB and write the divisor, which shares the addresses with THR and IER.
+    bus.write(LCR, 0x80, BYTE).unwrap();
+    bus.write(UART_BASE, 0x3, BYTE).unwrap();
+    bus.write(IER, 0x0, BYTE).unwrap();
+    assert_eq!(0x3, bus.read(UART_BASE, BYTE).unwrap());
+
+    // Clear DLAB and enable the FIFO.
+    bus.write(LCR, 0x3, BYTE).unwrap();
+    bus.write(ISR, 0x7, BYTE).unwrap();
+    assert_eq!(0x3, bus.read(LCR, BYTE).unwrap());
+    assert_eq!(0x0, bus.read(IER, BYTE).unwrap());
+    assert_eq!(0xc1, bus.read(ISR, BYTE).unwrap());
+}
@@ -0,0 +1,168 @@
+use rvemu::bus::{DRAM_BASE, VIRTIO_BASE};
+use rvemu::cpu::{BYTE, DOUBLEWORD, HALFWORD, WORD};
+use rvemu::emulator::{Emulator, ExitReason};
+use rvemu::watchpoint::{AddressSpace, WatchKind, Watchpoint, WatchpointHit};
+
+fn setup(data: Vec<u8>) -> Emulator {
+    let mut emu = Emulator::new();
+    emu.initialize_dram(data).unwrap();
+    emu.initialize_pc(DRAM_BASE);
+    emu
+}
+
+fn watchpoint(space: AddressSpace, kind: WatchKind, addr: u64, len: u64) -> Watchpoint {
+    Watchpoint {
+        space,
+        kind,
+        addr,
+        len,
+    }
+}
+
+#[test]
+fn write() {
+    let mut emu = setup(vec![
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x03, 0xa3, 0x42, 0x00, // lw t1, 4(t0)
+        0x13, 0x03, 0x70, 0x00, // addi t1, zero, 7
+        0x23, 0xa3, 0x62, 0x00, // sw t1, 6(t0)
+        0x13, 0x03, 0x80, 0x00, // addi t1, zero, 8
+    ]);
+    let watch = watchpoint(
+        AddressSpace::Virtual,
+        WatchKind::Write,
+        DRAM_BASE + 0x1004,
+        4,
+    );
+    emu.add_watchpoint(watch);
+
+    // The load from the range doesn't hit the watchpoint. The store hits it partially.
+    assert_eq!(
+        ExitReason::Watchpoint(WatchpointHit {
+            watchpoint: watch,
+            pc: DRAM_BASE + 12,
+            kind: WatchKind::Write,
+            addr: DRAM_BASE + 0x1006,
+            size: WORD,
+            value: 7,
+        }),
+        emu.run(100)
+    );
+    // The store has been executed.
+    assert_eq!(DRAM_BASE + 16, emu.cpu.pc);
+    assert_eq!(Ok(7), emu.cpu.bus.read(DRAM_BASE + 0x1006, WORD));
+
+    assert!(emu.remove_watchpoint(&watch));
+    assert_eq!(ExitReason::LimitReached, emu.run(1));
+}
+
+#[test]
+fn read_and_access() {
+    let mut emu = setup(vec![
+        0x97, 0x12, 0x00, 0x00, // auipc t0, 1
+        0x23, 0x80, 0x02, 0x00, // sb zero, 0(t0)
+        0x03, 0xc3, 0x02, 0x00, // lbu t1, 0(t0)
+    ]);
+    let read = watchpoint(
+        AddressSpace::Virtual,
+        WatchKind::Read,
+        DRAM_BASE + 0x1000,
+        1,
+    );
+    let access = watchpoint(
+        AddressSpace::Virtual,
+        WatchKind::Access,
+        DRAM_BASE + 0x1000,
+        1,
+    );
+    emu.add_watchpoint(read);
+    emu.add_watchpoint(access);
+
+    match emu.run(100) {
+        ExitReason::Watchpoint(hit) => {
+            assert_eq!(access, hit.watchpoint);
+            assert_eq!(WatchKind::Write, hit.kind);
+            assert_eq!((DRAM_BASE + 4, BYTE), (hit.pc, hit.size));
+        }
+        reason => panic!("unexpected exit reason: {:?}", reason),
+    }
+    match emu.run(100) {
+        ExitReason::Watchpoint(hit) => {
+            assert_eq!(read, hit.watchpoint);
+            assert_eq!(WatchKind::Read, hit.kind);
+            assert_eq!((DRAM_BASE + 8, BYTE), (hit.pc, hit.size));
+        }
+        reason => panic!("unexpected exit reason: {:?}", reason),
+    }
+}
+
+#[test]
+fn execute() {
+    let mut emu = setup(vec![
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+        0x05, 0x05, // c.addi a0, 1
+        0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+    ]);
+    emu.add_watchpoint(watchpoint(
+        AddressSpace::Physical,
+        WatchKind::Execute,
+        DRAM_BASE + 6,
+        4,
+    ));
+
+    match emu.run(100) {
+        ExitReason::Watchpoint(hit) => {
+            // The whole instruction is fetched after the lower 16 bits.
+            assert_eq!((DRAM_BASE + 6, DRAM_BASE + 6), (hit.pc, hit.addr));
+            assert_eq!((WORD, 0x00150513), (hit.size, hit.value));
+        }
+        reason => panic!("unexpected exit reason: {:?}", reason),
+    }
+    assert_eq!(3, emu.cpu.xregs.read(10));
+}
+
+#[test]
+fn dma() {
+    let mut emu = setup(vec![
+        0x6f, 0x00, 0x00, 0x00, // jal zero, 0
+    ]);
+    emu.initialize_disk(vec![0x2a; 512]);
+
+    // A virtqueue with 8 descriptors at 64 KiB after the beginning of DRAM, which has a request to
+    // read the sector 0 into `buf`.
+    let queue = DRAM_BASE + 0x1_0000;
+    let (req, buf, status) = (
+        DRAM_BASE + 0x2_0000,
+        DRAM_BASE + 0x2_1000,
+        DRAM_BASE + 0x2_2000,
+    );
+    let bus = &mut emu.cpu.bus;
+    let descriptors = [(req, 16, 1, 1), (buf, 512, 1 | 2, 2), (status, 1, 0, 0)];
+    for (i, (addr, len, flags, next)) in descriptors.iter().enumerate() {
+        let desc = queue + 16 * i as u64;
+        bus.write(desc, *addr, DOUBLEWORD).unwrap();
+        bus.write(desc + 8, *len, WORD).unwrap();
+        bus.write(desc + 12, *flags, HALFWORD).unwrap();
+        bus.write(desc + 14, *next, HALFWORD).unwrap();
+    }
+    // GuestPageSize, QueueNum, QueuePFN and QueueNotify.
+    bus.write(VIRTIO_BASE + 0x28, 0x1000, WORD).unwrap();
+    bus.write(VIRTIO_BASE + 0x38, 8, WORD).unwrap();
+    bus.write(VIRTIO_BASE + 0x40, queue / 0x1000, WORD).unwrap();
+    bus.write(VIRTIO_BASE + 0x50, 0, WORD).unwrap();
+
+    let watch = watchpoint(AddressSpace::Physical, WatchKind::Write, buf + 0x100, 1);
+    emu.add_watchpoint(watch);
+
+    assert_eq!(
+        ExitReason::Watchpoint(WatchpointHit {
+            watchpoint: watch,
+            pc: DRAM_BASE,
+            kind: WatchKind::Write,
+            addr: buf + 0x100,
+            size: BYTE,
+            value: 0x2a,
+        }),
+        emu.run(100)
+    );
+}